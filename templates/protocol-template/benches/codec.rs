@@ -0,0 +1,101 @@
+/*!
+ * Protocol Name Codec Benchmarks
+ *
+ * Compares the allocating encode path with the buffer-reusing and vectored
+ * paths across payload sizes:
+ * 1. encode (fresh Vec per message) vs. encode_into (reused buffer)
+ * 2. encode + write_all (payload copied twice) vs. write_message (vectored)
+ * 3. decode and read_message for reference
+ *
+ * See: /docs/BENCHMARKING_STANDARDS.md
+ *
+ * Run with: cargo bench
+ */
+
+use std::io::Write;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use protocol_name::{decode, encode, encode_into, read_message, write_message, Message};
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 4 * 1024, 64 * 1024];
+
+fn benchmark_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in PAYLOAD_SIZES {
+        let message = Message::request(1, &vec![0xAB; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &message, |b, m| {
+            b.iter(|| black_box(encode(black_box(m)).unwrap()));
+        });
+
+        let mut buf = Vec::new();
+        group.bench_with_input(BenchmarkId::new("encode_into", size), &message, |b, m| {
+            b.iter(|| {
+                buf.clear();
+                encode_into(black_box(m), &mut buf).unwrap();
+                black_box(buf.len())
+            });
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    for size in PAYLOAD_SIZES {
+        let message = Message::request(1, &vec![0xAB; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let mut sink = Vec::with_capacity(size + 64);
+        group.bench_with_input(
+            BenchmarkId::new("encode_then_write_all", size),
+            &message,
+            |b, m| {
+                b.iter(|| {
+                    sink.clear();
+                    let bytes = encode(black_box(m)).unwrap();
+                    sink.write_all(&bytes).unwrap();
+                    black_box(sink.len())
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("write_message_vectored", size),
+            &message,
+            |b, m| {
+                b.iter(|| {
+                    sink.clear();
+                    write_message(&mut sink, black_box(m)).unwrap();
+                    black_box(sink.len())
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn benchmark_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for size in PAYLOAD_SIZES {
+        let bytes = encode(&Message::request(1, &vec![0xAB; size])).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("decode", size), &bytes, |b, bytes| {
+            b.iter(|| black_box(decode(black_box(bytes)).unwrap()));
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("read_message", size),
+            &bytes,
+            |b, bytes| {
+                b.iter(|| black_box(read_message(&mut black_box(bytes.as_slice())).unwrap()));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_encode, benchmark_write, benchmark_decode);
+criterion_main!(benches);
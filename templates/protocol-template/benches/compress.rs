@@ -0,0 +1,123 @@
+/*!
+ * Protocol Name Compression Benchmarks
+ *
+ * Measures the per-frame compression codec (SPEC.md Section 4.9) on JSON,
+ * which compresses well, and random bytes, which do not:
+ * 1. compression ratio, printed once before the timed runs
+ * 2. deflate throughput (bytes of input per second)
+ * 3. inflate throughput (bytes of output per second)
+ * 4. encode + decode of a whole frame, compressed vs. plain
+ *
+ * See: /docs/BENCHMARKING_STANDARDS.md
+ *
+ * Run with: cargo bench --bench compress
+ */
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use protocol_name::compress::{deflate, inflate, Compression};
+use protocol_name::rng::Rng;
+use protocol_name::{decode, encode, Message};
+
+const PAYLOAD_SIZES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+
+/// A JSON array of small records, truncated to `size` bytes
+fn json(size: usize) -> Vec<u8> {
+    let mut out = b"[".to_vec();
+    let mut i = 0;
+    while out.len() < size {
+        out.extend_from_slice(
+            format!(
+                "{{\"id\": {}, \"name\": \"user{}\", \"email\": \"user{}@example.com\", \"active\": {}, \"score\": {}}}, ",
+                i,
+                i,
+                i,
+                i % 2 == 0,
+                i * 37 % 1000
+            )
+            .as_bytes(),
+        );
+        i += 1;
+    }
+    out.truncate(size);
+    out
+}
+
+fn random(size: usize) -> Vec<u8> {
+    let mut rng = Rng::new(1);
+    (0..size).map(|_| rng.next_u64() as u8).collect()
+}
+
+fn inputs() -> Vec<(&'static str, usize, Vec<u8>)> {
+    PAYLOAD_SIZES
+        .iter()
+        .flat_map(|&size| [("json", size, json(size)), ("random", size, random(size))])
+        .collect()
+}
+
+fn report_ratios() {
+    eprintln!("\ncompression ratio (deflated / original):");
+    for (kind, size, input) in inputs() {
+        let compressed = deflate(&input).len();
+        eprintln!(
+            "  {:<6} {:>8} B -> {:>8} B  {:5.1}%",
+            kind,
+            size,
+            compressed,
+            100.0 * compressed as f64 / size as f64
+        );
+    }
+}
+
+fn benchmark_deflate(c: &mut Criterion) {
+    report_ratios();
+    let mut group = c.benchmark_group("deflate");
+    for (kind, size, input) in inputs() {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new(kind, size), &input, |b, input| {
+            b.iter(|| black_box(deflate(black_box(input))));
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_inflate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("inflate");
+    for (kind, size, input) in inputs() {
+        let compressed = deflate(&input);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(
+            BenchmarkId::new(kind, size),
+            &compressed,
+            |b, compressed| {
+                b.iter(|| black_box(inflate(black_box(compressed), size).unwrap()));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn benchmark_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_round_trip");
+    let compression = Compression::new();
+    for size in PAYLOAD_SIZES {
+        let message = Message::request(1, &json(size));
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("plain", size), &message, |b, m| {
+            b.iter(|| black_box(decode(&encode(black_box(m)).unwrap()).unwrap()));
+        });
+
+        group.bench_with_input(BenchmarkId::new("compressed", size), &message, |b, m| {
+            b.iter(|| black_box(decode(&compression.encode(black_box(m)).unwrap()).unwrap()));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    benchmark_deflate,
+    benchmark_inflate,
+    benchmark_frame
+);
+criterion_main!(benches);
@@ -2,7 +2,7 @@
 //!
 //! Run with: cargo run --example basic
 
-use protocol_name::{decode, encode, Message, MessageType};
+use protocol_name::{decode, encode, Message};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Protocol Name - Basic Example\n");
@@ -12,19 +12,29 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let request = Message::request(1, b"Hello, Protocol!");
     println!("   Type: {:?}", request.message_type());
     println!("   ID: {}", request.id);
-    println!("   Payload: {:?}", String::from_utf8_lossy(&request.payload));
+    println!(
+        "   Payload: {:?}",
+        String::from_utf8_lossy(&request.payload)
+    );
 
     // Encode the message
     println!("\n2. Encoding to bytes...");
     let bytes = encode(&request)?;
-    println!("   Bytes ({} total): {:02x?}", bytes.len(), &bytes[..std::cmp::min(20, bytes.len())]);
+    println!(
+        "   Bytes ({} total): {:02x?}",
+        bytes.len(),
+        &bytes[..std::cmp::min(20, bytes.len())]
+    );
 
     // Decode the message
     println!("\n3. Decoding from bytes...");
     let decoded = decode(&bytes)?;
     println!("   Type: {:?}", decoded.message_type());
     println!("   ID: {}", decoded.id);
-    println!("   Payload: {:?}", String::from_utf8_lossy(&decoded.payload));
+    println!(
+        "   Payload: {:?}",
+        String::from_utf8_lossy(&decoded.payload)
+    );
 
     // Create a response
     println!("\n4. Creating a response...");
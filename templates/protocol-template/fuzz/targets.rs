@@ -0,0 +1,113 @@
+//! Fuzz target bodies
+//!
+//! Shared by the cargo-fuzz binaries in `fuzz_targets/` and by
+//! `tests/fuzz.rs`, which replays the checked-in corpus and crashers and
+//! runs both targets offline from a seeded mutator. A target panics when an
+//! invariant breaks; returning normally, with or without a decode error, is
+//! a pass.
+
+use std::io::{self, Read};
+
+use protocol_name::batch::{Batch, BatchResponse};
+use protocol_name::flow::Window;
+use protocol_name::pubsub::{Event, Publication};
+use protocol_name::rpc::MethodCall;
+use protocol_name::{decode_all, decode_prefix, decode_strict, encode, ProtocolError};
+
+/// `decode` on arbitrary bytes
+///
+/// A frame that decodes must re-encode to a canonical frame holding the
+/// same message, and the typed payload parsers must accept or reject its
+/// payload without panicking.
+pub fn decode(data: &[u8]) {
+    let result = decode_prefix(data);
+    assert_eq!(
+        protocol_name::decode(data),
+        result.clone().map(|(message, _)| message)
+    );
+    let (message, consumed) = match result {
+        Ok(decoded) => decoded,
+        Err(error) => {
+            // Every error has a code for the peer and a description
+            let _ = (error.error_code(), error.to_string());
+            return;
+        }
+    };
+
+    assert!(
+        consumed <= data.len(),
+        "consumed {consumed} of {} bytes",
+        data.len()
+    );
+    if consumed == data.len() {
+        assert_eq!(decode_strict(data).as_ref(), Ok(&message));
+    } else {
+        assert_eq!(
+            decode_strict(data),
+            Err(ProtocolError::TrailingBytes(data.len() - consumed))
+        );
+    }
+
+    let bytes = encode(&message).expect("a decoded message re-encodes");
+    assert_eq!(bytes.len(), message.encoded_len());
+    assert_eq!(decode_strict(&bytes).as_ref(), Ok(&message));
+
+    let _ = Window::from_message(&message);
+    let _ = Batch::from_message(&message);
+    let _ = BatchResponse::from_message(&message);
+    let _ = Publication::from_message(&message);
+    let _ = Event::from_message(&message);
+    let _ = MethodCall::from_message(&message);
+}
+
+/// `read_message` on an arbitrary stream
+///
+/// The first byte sets how many bytes each `read` call returns, so frames
+/// arrive split at every possible point. Reading the rest as a stream must
+/// yield the same messages as decoding it as a buffer, and end with the same
+/// error. The exception is a bad field in a cut-off fixed header: a stream
+/// reader has to wait for all 8 bytes before checking them, so it reports
+/// the truncation where the buffer decoder reports the bad field.
+pub fn read_message(data: &[u8]) {
+    let Some((&first, stream)) = data.split_first() else {
+        return;
+    };
+    let mut reader = Chunked {
+        data: stream,
+        chunk: usize::from(first % 16) + 1,
+    };
+    let mut expected = decode_all(stream);
+    loop {
+        match (expected.next(), protocol_name::read_message(&mut reader)) {
+            (Some(Ok(expected)), Ok(actual)) => assert_eq!(actual, expected),
+            (None, Err(actual)) => {
+                assert!(actual.is_clean_eof(), "{actual:?} at the end of the stream");
+                return;
+            }
+            (Some(Err(expected)), Err(actual)) => {
+                let in_header = expected.offset().is_some_and(|offset| offset < 8);
+                assert!(
+                    actual == expected || (actual.is_truncated() && in_header),
+                    "read {actual:?}, decoded {expected:?}"
+                );
+                return;
+            }
+            (expected, actual) => panic!("read {actual:?}, decoded {expected:?}"),
+        }
+    }
+}
+
+/// Reader returning at most `chunk` bytes per call
+struct Chunked<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.chunk).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
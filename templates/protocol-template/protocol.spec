@@ -0,0 +1,47 @@
+# Protocol definition
+#
+# Single source of truth for the wire format. After editing, regenerate the
+# derived artifacts and commit them together:
+#
+#   cargo run --bin protocol-gen -- protocol.spec --spec-md SPEC.md
+#
+# See src/codegen.rs for the file format.
+
+protocol protocol-name "Protocol Name"
+magic 0x54 0x55
+version 1
+max_payload 1048576
+
+field magic    bytes[2] "Protocol identifier"
+field version  u8       "Protocol version"
+field type     u8       "Message type"
+field id       u32      "Request/response ID"
+field status   u8       "Response status or error code" when Response Error
+field length   u32      "Payload length and flags"
+field budget   u32      "Request budget in milliseconds (0xFFFFFFFF = no deadline)" if options when Request
+field priority u8       "Request priority, higher first (128 when absent)" if options when Request
+field payload  bytes    "Message payload"
+
+flag compressed 31 "Payload is compressed (Section 4.9)" deflate
+flag options    30 "Request options follow the length (Section 4.11)"
+
+type Request       0x01 "Client request"
+type Response      0x02 "Server response"
+type WindowUpdate  0x03 "Flow control credit grant"
+type Batch         0x04 "Batch of requests"
+type BatchResponse 0x05 "Per-item results of a batch"
+type Subscribe     0x06 "Subscribe to a topic pattern"
+type Unsubscribe   0x07 "Cancel a subscription"
+type Publish       0x08 "Publish to a topic"
+type Event         0x09 "Publication delivered to a subscriber"
+type Resume        0x0A "Open or resume a session"
+type Error         0xFF "Error message"
+
+error InvalidFormat    0x01 "Invalid message format"
+error UnknownType      0x02 "Unknown message type"
+error PayloadTooLarge  0x03 "Payload too large"
+error FlowControl      0x04 "Flow control window exceeded"
+error DuplicateId      0x05 "Request ID already in flight"
+error UnknownMethod    0x06 "Unknown method"
+error UnknownSession   0x07 "Unknown or expired session"
+error DeadlineExceeded 0x08 "Request deadline exceeded"
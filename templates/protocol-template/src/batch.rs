@@ -0,0 +1,404 @@
+//! Batched requests
+//!
+//! A `Batch` frame carries several requests in one payload and is answered
+//! by a single `BatchResponse` frame holding one result per request, in the
+//! same order (SPEC.md Section 4.6). Small requests then share one frame
+//! header and one write.
+//!
+//! [`Batch`] and [`BatchResponse`] convert between the embedded messages and
+//! the frame payloads. [`Client::queue`](crate::client::Client::queue)
+//! groups requests into batches automatically.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::{Message, MessageType, ProtocolError, VERSION};
+
+/// Bytes before each request's payload in a `Batch`: ID and length
+pub const ITEM_HEADER_SIZE: usize = 8;
+
+/// Bytes before each result's payload in a `BatchResponse`: type, ID,
+/// status and length
+pub const RESULT_HEADER_SIZE: usize = 10;
+
+/// Bytes before the first item: the item count
+pub const COUNT_SIZE: usize = 4;
+
+/// When a client sends the requests it has queued
+///
+/// A batch is sent as soon as it reaches `max_items` requests or
+/// `max_bytes` of payload, or when a request is queued `max_delay` or more
+/// after the first one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchWindow {
+    /// Requests per batch
+    pub max_items: usize,
+    /// Batch payload bytes
+    pub max_bytes: usize,
+    /// Time the first queued request may wait
+    pub max_delay: Duration,
+}
+
+impl BatchWindow {
+    /// Window used by [`Client`](crate::client::Client) unless configured
+    pub const DEFAULT: BatchWindow = BatchWindow {
+        max_items: 32,
+        max_bytes: 64 * 1024,
+        max_delay: Duration::from_millis(1),
+    };
+
+    /// Create a window
+    pub fn new(max_items: usize, max_bytes: usize, max_delay: Duration) -> Self {
+        Self {
+            max_items,
+            max_bytes,
+            max_delay,
+        }
+    }
+}
+
+impl Default for BatchWindow {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The requests carried by one `Batch` frame
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    /// ID of the batch frame, answered by the `BatchResponse`
+    pub id: u32,
+    /// Embedded requests, in the order they are handled
+    pub requests: Vec<Message>,
+}
+
+impl Batch {
+    /// An empty batch
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Append a request
+    pub fn push(&mut self, id: u32, payload: &[u8]) {
+        self.requests.push(Message::request(id, payload));
+    }
+
+    /// Number of requests
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Whether the batch holds no requests
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Length of the frame payload this batch encodes to
+    pub fn payload_len(&self) -> usize {
+        COUNT_SIZE
+            + self
+                .requests
+                .iter()
+                .map(|r| ITEM_HEADER_SIZE + r.payload.len())
+                .sum::<usize>()
+    }
+
+    /// Read the requests carried by a `Batch` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        expect_type(message, MessageType::Batch)?;
+        let mut items = Items::new(&message.payload)?;
+        let mut requests =
+            Vec::with_capacity(items.count.min(message.payload.len() / ITEM_HEADER_SIZE));
+        for _ in 0..items.count {
+            let id = items.u32("request ID")?;
+            let payload = items.payload()?;
+            requests.push(Message::request(id, payload));
+        }
+        items.finish()?;
+        Ok(Self {
+            id: message.id,
+            requests,
+        })
+    }
+
+    /// Build the `Batch` message carrying these requests
+    ///
+    /// The size limit is enforced when the message is encoded.
+    pub fn to_message(&self) -> Message {
+        let mut payload = Vec::with_capacity(self.payload_len());
+        payload.extend_from_slice(&count(self.requests.len()).to_be_bytes());
+        for request in &self.requests {
+            payload.extend_from_slice(&request.id.to_be_bytes());
+            payload.extend_from_slice(&count(request.payload.len()).to_be_bytes());
+            payload.extend_from_slice(&request.payload);
+        }
+        framed(MessageType::Batch, self.id, payload)
+    }
+}
+
+/// The results carried by one `BatchResponse` frame
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchResponse {
+    /// ID of the batch answered
+    pub id: u32,
+    /// One `Response` or `Error` per request, in request order
+    pub results: Vec<Message>,
+}
+
+impl BatchResponse {
+    /// Collect results for the batch `id`
+    pub fn new(id: u32, results: Vec<Message>) -> Self {
+        Self { id, results }
+    }
+
+    /// Length of the frame payload these results encode to
+    pub fn payload_len(&self) -> usize {
+        COUNT_SIZE
+            + self
+                .results
+                .iter()
+                .map(|r| RESULT_HEADER_SIZE + r.payload.len())
+                .sum::<usize>()
+    }
+
+    /// Read the results carried by a `BatchResponse` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        expect_type(message, MessageType::BatchResponse)?;
+        let mut items = Items::new(&message.payload)?;
+        let mut results =
+            Vec::with_capacity(items.count.min(message.payload.len() / RESULT_HEADER_SIZE));
+        for _ in 0..items.count {
+            let message_type = match items.u8("result type")? {
+                0x02 => MessageType::Response,
+                0xFF => MessageType::Error,
+                other => {
+                    return Err(ProtocolError::InvalidPayload(format!(
+                        "batch result type must be Response or Error, got 0x{:02X}",
+                        other
+                    )))
+                }
+            };
+            let id = items.u32("result ID")?;
+            let status = items.u8("result status")?;
+            let payload = items.payload()?;
+            results.push(Message {
+                version: VERSION,
+                message_type,
+                id,
+                status: Some(status),
+                options: None,
+                payload: payload.to_vec(),
+            });
+        }
+        items.finish()?;
+        Ok(Self {
+            id: message.id,
+            results,
+        })
+    }
+
+    /// Build the `BatchResponse` message carrying these results
+    ///
+    /// Results other than `Response` and `Error` messages are rejected.
+    pub fn to_message(&self) -> Result<Message, ProtocolError> {
+        let mut payload = Vec::with_capacity(self.payload_len());
+        payload.extend_from_slice(&count(self.results.len()).to_be_bytes());
+        for result in &self.results {
+            let status = match (result.message_type, result.status) {
+                (MessageType::Response | MessageType::Error, Some(status)) => status,
+                _ => {
+                    return Err(ProtocolError::InvalidPayload(format!(
+                        "batch results must be responses or errors, got {:?}",
+                        result.message_type
+                    )))
+                }
+            };
+            payload.push(result.message_type as u8);
+            payload.extend_from_slice(&result.id.to_be_bytes());
+            payload.push(status);
+            payload.extend_from_slice(&count(result.payload.len()).to_be_bytes());
+            payload.extend_from_slice(&result.payload);
+        }
+        Ok(framed(MessageType::BatchResponse, self.id, payload))
+    }
+}
+
+fn expect_type(message: &Message, expected: MessageType) -> Result<(), ProtocolError> {
+    if message.message_type != expected {
+        return Err(ProtocolError::InvalidPayload(format!(
+            "expected {:?}, got {:?}",
+            expected, message.message_type
+        )));
+    }
+    Ok(())
+}
+
+fn framed(message_type: MessageType, id: u32, payload: Vec<u8>) -> Message {
+    Message {
+        version: VERSION,
+        message_type,
+        id,
+        status: None,
+        options: None,
+        payload,
+    }
+}
+
+/// Lengths beyond `u32` cannot be framed; encoding rejects them anyway
+fn count(len: usize) -> u32 {
+    u32::try_from(len).unwrap_or(u32::MAX)
+}
+
+/// Reads the items of a batch payload
+struct Items<'a> {
+    rest: &'a [u8],
+    count: usize,
+}
+
+impl<'a> Items<'a> {
+    fn new(payload: &'a [u8]) -> Result<Self, ProtocolError> {
+        let mut items = Self {
+            rest: payload,
+            count: 0,
+        };
+        items.count = items.u32("item count")? as usize;
+        Ok(items)
+    }
+
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], ProtocolError> {
+        if self.rest.len() < len {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "batch truncated in {}: need {} bytes, have {}",
+                what,
+                len,
+                self.rest.len()
+            )));
+        }
+        let (head, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, ProtocolError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4, what)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn payload(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let len = self.u32("item length")? as usize;
+        self.take(len, "item payload")
+    }
+
+    fn finish(self) -> Result<(), ProtocolError> {
+        if !self.rest.is_empty() {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "{} bytes after the last batch item",
+                self.rest.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_batch_round_trip() {
+        let mut batch = Batch::new(9);
+        batch.push(1, b"a");
+        batch.push(2, b"");
+        batch.push(3, b"xyz");
+
+        let message = batch.to_message();
+        assert_eq!(message.message_type, MessageType::Batch);
+        assert_eq!(message.id, 9);
+        assert_eq!(message.status, None);
+        assert_eq!(message.payload.len(), batch.payload_len());
+        assert_eq!(&message.payload[..9], &[0, 0, 0, 3, 0, 0, 0, 1, 0]);
+        assert_eq!(Batch::from_message(&message), Ok(batch));
+    }
+
+    #[test]
+    fn test_batch_response_round_trip() {
+        let response = BatchResponse::new(
+            9,
+            vec![
+                Message::response(1, 0, b"ok"),
+                Message::error(2, 0x05, "duplicate"),
+            ],
+        );
+        let message = response.to_message().unwrap();
+        assert_eq!(message.message_type, MessageType::BatchResponse);
+        assert_eq!(message.payload.len(), response.payload_len());
+        assert_eq!(BatchResponse::from_message(&message), Ok(response));
+
+        let invalid = BatchResponse::new(9, vec![Message::request(1, b"")]);
+        assert!(matches!(
+            invalid.to_message(),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn test_empty_batch() {
+        let message = Batch::new(4).to_message();
+        assert_eq!(message.payload, [0, 0, 0, 0]);
+        assert!(Batch::from_message(&message).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_malformed_batches() {
+        let mut message = Batch {
+            id: 1,
+            requests: vec![Message::request(1, b"abc")],
+        }
+        .to_message();
+
+        // Truncated item, trailing bytes, inflated count, wrong frame type
+        let mut truncated = message.clone();
+        truncated.payload.pop();
+        let mut trailing = message.clone();
+        trailing.payload.push(0);
+        let mut inflated = message.clone();
+        inflated.payload[3] = 200;
+        for bad in [truncated, trailing, inflated] {
+            assert!(matches!(
+                Batch::from_message(&bad),
+                Err(ProtocolError::InvalidPayload(_))
+            ));
+        }
+
+        message.message_type = MessageType::BatchResponse;
+        assert!(matches!(
+            Batch::from_message(&message),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unknown_result_type() {
+        let mut message = BatchResponse::new(1, vec![Message::response(1, 0, b"")])
+            .to_message()
+            .unwrap();
+        message.payload[COUNT_SIZE] = 0x01;
+        assert!(matches!(
+            BatchResponse::from_message(&message),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+//! Protocol code generator
+//!
+//! Derives the codec module, SPEC.md wire-format section and starter vectors
+//! from a declarative spec file.
+//!
+//! ## Usage
+//!
+//! ```bash
+//! # Write codec.rs, wire-format.md and vectors.rs into generated/
+//! protocol-gen protocol.spec --out-dir generated
+//!
+//! # Refresh the generated section of SPEC.md in place
+//! protocol-gen protocol.spec --spec-md SPEC.md
+//!
+//! # Fail if SPEC.md is out of date (for CI)
+//! protocol-gen protocol.spec --spec-md SPEC.md --check
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use protocol_name::codegen::ProtocolSpec;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.is_empty() || matches!(args[0].as_str(), "-h" | "--help" | "help") {
+        print_usage();
+        process::exit(if args.is_empty() { 1 } else { 0 });
+    }
+
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn print_usage() {
+    eprintln!("protocol-gen - Generate protocol artifacts from a spec file");
+    eprintln!();
+    eprintln!("USAGE:");
+    eprintln!("    protocol-gen <SPEC_FILE> [OPTIONS]");
+    eprintln!();
+    eprintln!("OPTIONS:");
+    eprintln!("    --out-dir <DIR>     Write codec.rs, wire-format.md and vectors.rs");
+    eprintln!("    --spec-md <FILE>    Replace the generated section of SPEC.md");
+    eprintln!("    --check             Compare instead of writing; exit 1 on drift");
+    eprintln!();
+    eprintln!("Without --out-dir or --spec-md the codec is printed to stdout.");
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let spec_path = PathBuf::from(&args[0]);
+    let mut out_dir = None;
+    let mut spec_md = None;
+    let mut check = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out-dir" => {
+                i += 1;
+                out_dir = Some(PathBuf::from(
+                    args.get(i).ok_or("Missing value for --out-dir")?,
+                ));
+            }
+            "--spec-md" => {
+                i += 1;
+                spec_md = Some(PathBuf::from(
+                    args.get(i).ok_or("Missing value for --spec-md")?,
+                ));
+            }
+            "--check" => check = true,
+            arg => return Err(format!("Unknown argument: {}", arg)),
+        }
+        i += 1;
+    }
+
+    let source =
+        fs::read_to_string(&spec_path).map_err(|e| format!("{}: {}", spec_path.display(), e))?;
+    let spec =
+        ProtocolSpec::parse(&source).map_err(|e| format!("{}: {}", spec_path.display(), e))?;
+    let source_name = spec_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut outputs = Vec::new();
+    if let Some(dir) = &out_dir {
+        outputs.push((dir.join("codec.rs"), spec.generate_codec(&source_name)));
+        outputs.push((dir.join("wire-format.md"), spec.generate_wire_format()));
+        outputs.push((dir.join("vectors.rs"), spec.generate_vectors(&source_name)));
+    }
+    if let Some(path) = &spec_md {
+        let current = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let updated = spec
+            .splice_wire_format(&current)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        outputs.push((path.clone(), updated));
+    }
+
+    if outputs.is_empty() {
+        print!("{}", spec.generate_codec(&source_name));
+        return Ok(());
+    }
+
+    let mut stale = Vec::new();
+    for (path, contents) in &outputs {
+        if check {
+            if fs::read_to_string(path).ok().as_deref() != Some(contents.as_str()) {
+                stale.push(path.display().to_string());
+            }
+        } else {
+            write_file(path, contents)?;
+            println!("wrote {}", path.display());
+        }
+    }
+
+    if !stale.is_empty() {
+        return Err(format!(
+            "out of date: {} (rerun protocol-gen)",
+            stale.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    fs::write(path, contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
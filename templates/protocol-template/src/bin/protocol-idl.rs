@@ -0,0 +1,123 @@
+//! Service stub generator
+//!
+//! Derives Rust client/server stubs and a TypeScript client from an
+//! interface definition file (see `src/idl.rs` for the format).
+//!
+//! ## Usage
+//!
+//! ```bash
+//! # Write the Rust stubs and the TypeScript client
+//! protocol-idl calculator.idl --rust src/calculator.rs --ts web/calculator.ts
+//!
+//! # Fail if either is out of date (for CI)
+//! protocol-idl calculator.idl --rust src/calculator.rs --ts web/calculator.ts --check
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use protocol_name::idl::Idl;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.is_empty() || matches!(args[0].as_str(), "-h" | "--help" | "help") {
+        print_usage();
+        process::exit(if args.is_empty() { 1 } else { 0 });
+    }
+
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn print_usage() {
+    eprintln!("protocol-idl - Generate service stubs from an interface definition");
+    eprintln!();
+    eprintln!("USAGE:");
+    eprintln!("    protocol-idl <IDL_FILE> [OPTIONS]");
+    eprintln!();
+    eprintln!("OPTIONS:");
+    eprintln!("    --rust <FILE>    Write the Rust records, server traits and clients");
+    eprintln!("    --ts <FILE>      Write the TypeScript client");
+    eprintln!("    --check          Compare instead of writing; exit 1 on drift");
+    eprintln!();
+    eprintln!("Without --rust or --ts the Rust stubs are printed to stdout.");
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let idl_path = PathBuf::from(&args[0]);
+    let mut rust = None;
+    let mut ts = None;
+    let mut check = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--rust" => {
+                i += 1;
+                rust = Some(PathBuf::from(
+                    args.get(i).ok_or("Missing value for --rust")?,
+                ));
+            }
+            "--ts" => {
+                i += 1;
+                ts = Some(PathBuf::from(args.get(i).ok_or("Missing value for --ts")?));
+            }
+            "--check" => check = true,
+            arg => return Err(format!("Unknown argument: {}", arg)),
+        }
+        i += 1;
+    }
+
+    let source =
+        fs::read_to_string(&idl_path).map_err(|e| format!("{}: {}", idl_path.display(), e))?;
+    let idl = Idl::parse(&source).map_err(|e| format!("{}: {}", idl_path.display(), e))?;
+    let source_name = idl_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut outputs = Vec::new();
+    if let Some(path) = rust {
+        outputs.push((path, idl.generate_rust(&source_name)));
+    }
+    if let Some(path) = ts {
+        outputs.push((path, idl.generate_typescript(&source_name)));
+    }
+
+    if outputs.is_empty() {
+        print!("{}", idl.generate_rust(&source_name));
+        return Ok(());
+    }
+
+    let mut stale = Vec::new();
+    for (path, contents) in &outputs {
+        if check {
+            if fs::read_to_string(path).ok().as_deref() != Some(contents.as_str()) {
+                stale.push(path.display().to_string());
+            }
+        } else {
+            write_file(path, contents)?;
+            println!("wrote {}", path.display());
+        }
+    }
+
+    if !stale.is_empty() {
+        return Err(format!(
+            "out of date: {} (rerun protocol-idl)",
+            stale.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+    }
+    fs::write(path, contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
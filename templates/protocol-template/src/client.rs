@@ -0,0 +1,1168 @@
+//! Request/response client
+//!
+//! [`Client`] sends requests over any [`Transport`] and waits for the
+//! matching response. IDs are assigned sequentially starting at 1; because
+//! servers answer in request order (SPEC.md Section 4.2), the next frame
+//! read is expected to carry the ID just sent.
+//!
+//! Requests are subject to the server's flow-control window (SPEC.md
+//! Section 4.5). `WindowUpdate` messages are consumed by the client and
+//! never returned from [`recv`](Client::recv). When the window is
+//! exhausted, [`send`](Client::send) waits for credit or fails, depending
+//! on the configured [`Backpressure`].
+//!
+//! Requests passed to [`queue`](Client::queue) are grouped into `Batch`
+//! frames (SPEC.md Section 4.6) according to a [`BatchWindow`] and their
+//! results are returned one by one from [`recv`](Client::recv), exactly as
+//! if each had been sent on its own. There is no background timer: the
+//! delay is checked when a request is queued, and [`flush`](Client::flush)
+//! or `recv` sends whatever is queued.
+//!
+//! [`call_method`](Client::call_method) calls a method served by a
+//! [`Router`](crate::rpc::Router) (SPEC.md Section 4.8), encoding the
+//! arguments and decoding the result with the payload traits.
+//!
+//! [`subscribe`](Client::subscribe) and [`publish`](Client::publish) use
+//! the server's broker (SPEC.md Section 4.7). Events are read off the same
+//! connection as responses; they are set aside while waiting for a response
+//! and returned by [`next_event`](Client::next_event).
+//!
+//! After [`open_session`](Client::open_session), requests and batches are
+//! kept until answered. [`reconnect`](Client::reconnect) moves the client
+//! to a new connection, resumes the session there and sends every
+//! unanswered request again; the server answers a request it already
+//! handled from its cache, so none runs twice (SPEC.md Section 4.10). A
+//! client built [`with_reconnect`](Client::with_reconnect) does this by
+//! itself when the connection fails, and calls in progress simply carry
+//! on. A replayed request carries only what is left of its budget, and one
+//! whose deadline has passed meanwhile fails with
+//! [`ProtocolError::DeadlineExceeded`] instead of being sent again.
+//! Subscriptions and unanswered publish/subscribe messages are not carried
+//! over.
+//!
+//! Calls made with [`call`](Client::call) and
+//! [`call_method`](Client::call_method) carry a budget when the client has
+//! a [`with_timeout`](Client::with_timeout), or when they are made while a
+//! server handler is answering a request with a deadline (SPEC.md Section
+//! 4.11): the budget is whatever is left of the tighter of the two. A call
+//! whose budget is already spent fails with
+//! [`ProtocolError::DeadlineExceeded`] without being sent; one the server
+//! could not start in time is answered with a `DeadlineExceeded` error.
+//! [`with_priority`](Client::with_priority) sets the priority they carry.
+//! Batched requests carry neither.
+//!
+//! With [`with_compression`](Client::with_compression), large payloads are
+//! sent compressed (SPEC.md Section 4.9). Compressed frames from the server
+//! are always accepted.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use protocol_name::client::Client;
+//!
+//! let mut client = Client::connect_tcp("127.0.0.1:9000").unwrap();
+//! let response = client.call(b"hello").unwrap();
+//! assert!(response.is_success());
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::batch::{Batch, BatchResponse, BatchWindow, ITEM_HEADER_SIZE};
+use crate::compress::Compression;
+use crate::deadline::{self, RequestOptions};
+use crate::flow::{Backpressure, Credit, Window};
+use crate::observe::Observer;
+use crate::pubsub::{Event, Publication};
+use crate::rpc::{MethodCall, LIST_METHODS};
+use crate::session::{Resume, SessionId};
+use crate::transport::{ChildProcess, Transport};
+use crate::{
+    read_message, write_message, Decode, Encode, Message, MessageType, ProtocolError,
+    MAX_PAYLOAD_SIZE,
+};
+
+/// A client for one connection
+pub struct Client<T> {
+    transport: T,
+    next_id: u32,
+    observer: Option<Arc<dyn Observer>>,
+    credit: Credit,
+    backpressure: Backpressure,
+    /// Messages read while waiting for credit, not yet returned by `recv`
+    inbox: VecDeque<Message>,
+    /// Requests sent whose response has not been read
+    outstanding: usize,
+    batch_window: BatchWindow,
+    /// Requests queued for the next batch and when the first was queued
+    queued: Option<(Batch, Instant)>,
+    /// IDs of the requests in each batch sent but not answered
+    batches: HashMap<u32, Vec<u32>>,
+    /// Events read but not yet returned by `next_event`
+    events: VecDeque<Event>,
+    compression: Option<Compression>,
+    timeout: Option<Duration>,
+    priority: Option<u8>,
+    session: Option<SessionId>,
+    /// Requests and batches sent in the session and not yet answered, in
+    /// the order sent, with the deadline each carries
+    unacknowledged: VecDeque<(Message, Option<Instant>)>,
+    /// Opens a new connection when the current one fails
+    connect: Option<Connect<T>>,
+    /// Set while resuming, when a failure is not recovered from
+    resuming: bool,
+}
+
+type Connect<T> = Box<dyn FnMut() -> io::Result<T> + Send>;
+
+impl<T: fmt::Debug> fmt::Debug for Client<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("transport", &self.transport)
+            .field("next_id", &self.next_id)
+            .field("credit", &self.credit)
+            .field("outstanding", &self.outstanding)
+            .field(
+                "queued",
+                &self.queued.as_ref().map_or(0, |(batch, _)| batch.len()),
+            )
+            .field("events", &self.events.len())
+            .field("compression", &self.compression)
+            .field("timeout", &self.timeout)
+            .field("priority", &self.priority)
+            .field("session", &self.session)
+            .field("unacknowledged", &self.unacknowledged.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport> Client<T> {
+    /// Wrap an established transport
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            next_id: 1,
+            observer: None,
+            credit: Credit::new(),
+            backpressure: Backpressure::default(),
+            inbox: VecDeque::new(),
+            outstanding: 0,
+            batch_window: BatchWindow::default(),
+            queued: None,
+            batches: HashMap::new(),
+            events: VecDeque::new(),
+            compression: None,
+            timeout: None,
+            priority: None,
+            session: None,
+            unacknowledged: VecDeque::new(),
+            connect: None,
+            resuming: false,
+        }
+    }
+
+    /// Choose when requests passed to [`queue`](Self::queue) are sent
+    pub fn with_batch_window(mut self, window: BatchWindow) -> Self {
+        self.batch_window = window;
+        self
+    }
+
+    /// Choose whether [`send`](Self::send) waits for credit (the default)
+    /// or fails fast when the window is exhausted
+    ///
+    /// Either way, the first request waits for the server's initial grant.
+    pub fn with_backpressure(mut self, backpressure: Backpressure) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Report frames, decode errors and call latency to `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Compress outgoing payloads as `compression` decides
+    ///
+    /// Flow control still counts payloads at their uncompressed size.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Give each call at most `timeout` to be answered
+    ///
+    /// The server drops a call it cannot start in time. A call made while
+    /// handling a request with a tighter deadline gets that deadline
+    /// instead.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Scheduling priority of each call: higher values are handled first
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Reconnect with `connect` whenever the connection fails while a
+    /// session is open
+    ///
+    /// The failed operation then carries on over the new connection, after
+    /// [`reconnect`](Self::reconnect) has replayed the unanswered requests.
+    /// The error is returned if `connect` or the resumption fails.
+    pub fn with_reconnect<F>(mut self, connect: F) -> Self
+    where
+        F: FnMut() -> io::Result<T> + Send + 'static,
+    {
+        self.connect = Some(Box::new(connect));
+        self
+    }
+
+    /// Open a session on the server, returning its ID
+    ///
+    /// Must be the first request on the connection. Requests left
+    /// unanswered in an earlier session are forgotten.
+    pub fn open_session(&mut self) -> Result<SessionId, ProtocolError> {
+        self.unacknowledged.clear();
+        let session = self.handshake(&Resume::open())?;
+        self.session = Some(session);
+        Ok(session)
+    }
+
+    /// Session opened by [`open_session`](Self::open_session)
+    pub fn session(&self) -> Option<SessionId> {
+        self.session
+    }
+
+    /// Requests and batches sent in the session and not yet answered, in
+    /// the order sent
+    ///
+    /// After a failed [`reconnect`](Self::reconnect), these are the
+    /// requests whose fate is unknown.
+    pub fn unacknowledged(&self) -> impl Iterator<Item = &Message> {
+        self.unacknowledged.iter().map(|(message, _)| message)
+    }
+
+    /// Carry on over a new connection, returning the old one
+    ///
+    /// With a session open, resume it and send every unanswered request
+    /// again, in order; their responses are read as if nothing had
+    /// happened. Without one, responses still due on the old connection are
+    /// lost. Messages already read are kept either way.
+    ///
+    /// If the server no longer knows the session, fails with
+    /// [`ProtocolError::Rejected`] carrying `UnknownSession`, leaving the
+    /// requests in [`unacknowledged`](Self::unacknowledged).
+    ///
+    /// A replayed request carries only the budget it has left. One whose
+    /// deadline has passed is not sent again: it is forgotten, and once the
+    /// others are replayed this fails with
+    /// [`ProtocolError::DeadlineExceeded`] carrying its ID.
+    pub fn reconnect(&mut self, transport: T) -> Result<T, ProtocolError> {
+        let old = mem::replace(&mut self.transport, transport);
+        self.credit = Credit::new();
+        self.outstanding = 0;
+        let unacknowledged = &self.unacknowledged;
+        self.batches
+            .retain(|id, _| unacknowledged.iter().any(|(message, _)| message.id == *id));
+        let Some(session) = self.session else {
+            return Ok(old);
+        };
+
+        let mut requests = Vec::with_capacity(self.unacknowledged.len());
+        let mut expired = None;
+        let now = Instant::now();
+        self.unacknowledged.retain(|(message, deadline)| {
+            match (message.options, deadline) {
+                (Some(options), Some(deadline)) => {
+                    let options = options.with_budget(deadline.saturating_duration_since(now));
+                    if options.is_expired() {
+                        expired.get_or_insert(message.id);
+                        return false;
+                    }
+                    requests.push(message.clone().with_options(options));
+                }
+                _ => requests.push(message.clone()),
+            }
+            true
+        });
+
+        self.resuming = true;
+        let replay = requests.iter().map(|request| request.id).collect();
+        let result = self
+            .handshake(&Resume::new(session, replay))
+            .and_then(|_| requests.iter().try_for_each(|request| self.send(request)));
+        self.resuming = false;
+        result?;
+        match expired {
+            Some(id) => Err(ProtocolError::DeadlineExceeded(id)),
+            None => Ok(old),
+        }
+    }
+
+    /// Send a `Resume` and read the session ID it is answered with
+    ///
+    /// Messages read before the answer stay in the inbox.
+    fn handshake(&mut self, resume: &Resume) -> Result<SessionId, ProtocolError> {
+        let id = self.next_id();
+        let read = self.inbox.len();
+        self.send(&resume.to_message(id))?;
+        while self.inbox.len() == read {
+            self.read_frame()?;
+        }
+        let reply = self.inbox.remove(read).expect("a reply was read");
+        if reply.id != id {
+            return Err(ProtocolError::UnexpectedResponse {
+                expected: id,
+                actual: reply.id,
+            });
+        }
+        if reply.message_type == MessageType::Error {
+            return Err(ProtocolError::Rejected {
+                id,
+                code: reply.status.unwrap_or(0),
+                message: String::from_utf8_lossy(&reply.payload).into_owned(),
+            });
+        }
+        SessionId::from_payload(&reply.payload)
+    }
+
+    /// Reconnect and resume after `error`, if it lost the connection and
+    /// the client can
+    fn recover(&mut self, error: ProtocolError) -> Result<(), ProtocolError> {
+        if !matches!(error, ProtocolError::Io { .. }) || self.resuming || self.session.is_none() {
+            return Err(error);
+        }
+        let Some(connect) = self.connect.as_mut() else {
+            return Err(error);
+        };
+        let transport = connect()?;
+        self.reconnect(transport)?;
+        Ok(())
+    }
+
+    /// Send a request and wait for its response
+    ///
+    /// Returns the response or error message sent by the server; check
+    /// [`Message::is_success`] to tell them apart.
+    pub fn call(&mut self, payload: &[u8]) -> Result<Message, ProtocolError> {
+        let id = self.next_id();
+        let started = Instant::now();
+        self.send(&self.scheduled(Message::request(id, payload))?)?;
+        let response = self.recv()?;
+        if response.id != id {
+            return Err(ProtocolError::UnexpectedResponse {
+                expected: id,
+                actual: response.id,
+            });
+        }
+        if let Some(observer) = &self.observer {
+            observer.request_completed(&response, started.elapsed());
+        }
+        Ok(response)
+    }
+
+    /// Call a method and decode its result
+    ///
+    /// An error reply, such as an unknown method, is returned as
+    /// [`ProtocolError::Rejected`].
+    pub fn call_method<A, R>(&mut self, method: &str, args: &A) -> Result<R, ProtocolError>
+    where
+        A: Encode + ?Sized,
+        R: Decode,
+    {
+        let id = self.next_id();
+        let started = Instant::now();
+        self.send(&self.scheduled(MethodCall::new(method, args).to_message(id))?)?;
+        let response = self.expect_response(id);
+        if let (Some(observer), Ok(response)) = (&self.observer, &response) {
+            observer.request_completed(response, started.elapsed());
+        }
+        response?.payload_as()
+    }
+
+    /// List the methods the server provides
+    pub fn methods(&mut self) -> Result<Vec<String>, ProtocolError> {
+        self.call_method(LIST_METHODS, &())
+    }
+
+    /// Attach the budget and priority a call carries
+    ///
+    /// Fails if the budget is already spent.
+    fn scheduled(&self, request: Message) -> Result<Message, ProtocolError> {
+        let budget = match (self.timeout, deadline::remaining()) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        if budget.is_none() && self.priority.is_none() {
+            return Ok(request);
+        }
+        let mut options = RequestOptions::new();
+        if let Some(priority) = self.priority {
+            options = options.with_priority(priority);
+        }
+        if let Some(budget) = budget {
+            options = options.with_budget(budget);
+            if options.is_expired() {
+                return Err(ProtocolError::DeadlineExceeded(request.id));
+            }
+        }
+        Ok(request.with_options(options))
+    }
+
+    /// Send a single message without waiting for a reply
+    ///
+    /// Messages the server answers consume flow-control credit; see
+    /// [`Backpressure`].
+    pub fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        let consumes_credit = message.message_type.is_request();
+        if consumes_credit {
+            self.acquire(message.payload.len())?;
+        }
+        // Replays are already kept
+        let kept = self.session.is_some()
+            && !self.resuming
+            && matches!(
+                message.message_type,
+                MessageType::Request | MessageType::Batch
+            );
+        if kept {
+            let deadline = message
+                .options
+                .and_then(|options| options.deadline(Instant::now()));
+            self.unacknowledged.push_back((message.clone(), deadline));
+        }
+        let written = match &self.compression {
+            Some(compression) => compression.write_message(&mut self.transport, message),
+            None => write_message(&mut self.transport, message),
+        };
+        if let Err(e) = written.and_then(|()| Ok(self.transport.flush()?)) {
+            // A kept message is sent again once the client has reconnected
+            return if kept { self.recover(e) } else { Err(e) };
+        }
+        if consumes_credit {
+            self.credit.consume(message.payload.len())?;
+            self.outstanding += 1;
+        }
+        if let Some(observer) = &self.observer {
+            observer.frame_sent(message, message.encoded_len());
+        }
+        Ok(())
+    }
+
+    /// Send several requests in one batch and wait for their results
+    ///
+    /// Returns one response or error per payload, in order. If the server
+    /// rejects the batch as a whole, every request gets that error.
+    pub fn call_batch(&mut self, payloads: &[&[u8]]) -> Result<Vec<Message>, ProtocolError> {
+        if payloads.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut batch = Batch::new(self.next_id());
+        for payload in payloads {
+            let id = self.next_id();
+            batch.push(id, payload);
+        }
+        self.send_batch(&batch)?;
+        batch
+            .requests
+            .iter()
+            .map(|request| {
+                let result = self.recv()?;
+                if result.id != request.id {
+                    return Err(ProtocolError::UnexpectedResponse {
+                        expected: request.id,
+                        actual: result.id,
+                    });
+                }
+                Ok(result)
+            })
+            .collect()
+    }
+
+    /// Queue a request to be sent in a batch, returning its ID
+    ///
+    /// The batch is sent once it fills the [`BatchWindow`] or its delay has
+    /// passed; call [`flush`](Self::flush) to send it sooner. Read the
+    /// result with [`recv`](Self::recv).
+    pub fn queue(&mut self, payload: &[u8]) -> Result<u32, ProtocolError> {
+        let fits = |batch: &Batch| {
+            batch.payload_len() + ITEM_HEADER_SIZE + payload.len() <= MAX_PAYLOAD_SIZE
+        };
+        if matches!(&self.queued, Some((batch, _)) if !fits(batch)) {
+            self.flush()?;
+        }
+        if self.queued.is_none() {
+            let batch = Batch::new(self.next_id());
+            self.queued = Some((batch, Instant::now()));
+        }
+        let id = self.next_id();
+        let window = self.batch_window;
+        let (batch, started) = self.queued.as_mut().expect("a batch is queued");
+        batch.push(id, payload);
+        if batch.len() >= window.max_items
+            || batch.payload_len() >= window.max_bytes
+            || started.elapsed() >= window.max_delay
+        {
+            self.flush()?;
+        }
+        Ok(id)
+    }
+
+    /// Send any queued requests now
+    pub fn flush(&mut self) -> Result<(), ProtocolError> {
+        match self.queued.take() {
+            Some((batch, _)) => self.send_batch(&batch),
+            None => Ok(()),
+        }
+    }
+
+    /// Requests queued but not yet sent
+    pub fn queued(&self) -> usize {
+        self.queued.as_ref().map_or(0, |(batch, _)| batch.len())
+    }
+
+    fn send_batch(&mut self, batch: &Batch) -> Result<(), ProtocolError> {
+        self.send(&batch.to_message())?;
+        let ids = batch.requests.iter().map(|r| r.id).collect();
+        self.batches.insert(batch.id, ids);
+        Ok(())
+    }
+
+    /// Subscribe to a topic pattern, returning the subscription's ID
+    ///
+    /// Events for it may arrive as soon as the server has subscribed, even
+    /// before this returns.
+    pub fn subscribe(&mut self, pattern: &str) -> Result<u32, ProtocolError> {
+        let id = self.next_id();
+        self.send(&Message::subscribe(id, pattern))?;
+        self.expect_response(id)?;
+        Ok(id)
+    }
+
+    /// Cancel a subscription
+    ///
+    /// No events for it are read after this returns, but events already
+    /// read are still returned by [`next_event`](Self::next_event).
+    pub fn unsubscribe(&mut self, subscription: u32) -> Result<(), ProtocolError> {
+        let id = self.next_id();
+        self.send(&Message::unsubscribe(id, subscription))?;
+        self.expect_response(id)?;
+        Ok(())
+    }
+
+    /// Publish to a topic, returning the number of subscriptions it was
+    /// delivered to
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<u32, ProtocolError> {
+        let id = self.next_id();
+        self.send(&Publication::new(topic, data).to_message(id))?;
+        let response = self.expect_response(id)?;
+        let matched = <[u8; 4]>::try_from(response.payload.as_slice()).map_err(|_| {
+            ProtocolError::InvalidPayload(format!(
+                "publish response of {} bytes, expected 4",
+                response.payload.len()
+            ))
+        })?;
+        Ok(u32::from_be_bytes(matched))
+    }
+
+    /// Wait for the next event on any subscription
+    ///
+    /// Responses read while waiting are kept for [`recv`](Self::recv).
+    pub fn next_event(&mut self) -> Result<Event, ProtocolError> {
+        self.flush()?;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            self.read_frame()?;
+        }
+    }
+
+    /// Take an event already read, without waiting
+    pub fn try_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Read the reply to `id`, turning an error message into
+    /// [`ProtocolError::Rejected`]
+    fn expect_response(&mut self, id: u32) -> Result<Message, ProtocolError> {
+        let reply = self.recv()?;
+        if reply.id != id {
+            return Err(ProtocolError::UnexpectedResponse {
+                expected: id,
+                actual: reply.id,
+            });
+        }
+        if reply.message_type == MessageType::Error {
+            return Err(ProtocolError::Rejected {
+                id,
+                code: reply.status.unwrap_or(0),
+                message: String::from_utf8_lossy(&reply.payload).into_owned(),
+            });
+        }
+        Ok(reply)
+    }
+
+    /// Read the next message from the server
+    ///
+    /// Queued requests are sent first. The results of a batch are returned
+    /// one at a time, as if each request had been sent on its own.
+    pub fn recv(&mut self) -> Result<Message, ProtocolError> {
+        if let Some(message) = self.inbox.pop_front() {
+            return Ok(message);
+        }
+        self.flush()?;
+        loop {
+            self.read_frame()?;
+            if let Some(message) = self.inbox.pop_front() {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Flow-control credit currently available
+    pub fn credit(&self) -> Window {
+        self.credit.available()
+    }
+
+    /// Wait until the window covers a request of `payload_len` bytes
+    fn acquire(&mut self, payload_len: usize) -> Result<(), ProtocolError> {
+        while !self.credit.covers(payload_len) {
+            // Servers return credit before the responses that free it, so
+            // with nothing outstanding no more credit is coming
+            if self.credit.is_granted()
+                && (self.backpressure == Backpressure::FailFast || self.outstanding == 0)
+            {
+                let available = self.credit.available();
+                return Err(ProtocolError::WindowExhausted {
+                    requests: available.requests,
+                    bytes: available.bytes,
+                });
+            }
+            self.read_frame()?;
+        }
+        Ok(())
+    }
+
+    /// Read one frame into the inbox, absorbing window updates, unpacking
+    /// batch results and setting events aside
+    fn read_frame(&mut self) -> Result<(), ProtocolError> {
+        let result = read_message(&mut self.transport);
+        if let Some(observer) = &self.observer {
+            match &result {
+                Ok(message) => observer.frame_received(message, message.encoded_len()),
+                Err(ProtocolError::Io { .. }) => {}
+                Err(e) => observer.decode_error(e),
+            }
+        }
+        let message = match result {
+            Ok(message) => message,
+            Err(e) => return self.recover(e),
+        };
+        match message.message_type {
+            MessageType::WindowUpdate => self.credit.grant(Window::from_message(&message)?),
+            MessageType::Event => self.events.push_back(Event::from_message(&message)?),
+            MessageType::BatchResponse => {
+                self.outstanding = self.outstanding.saturating_sub(1);
+                let response = BatchResponse::from_message(&message)?;
+                self.acknowledge(response.id);
+                self.batches.remove(&response.id);
+                self.inbox.extend(response.results);
+            }
+            MessageType::Response | MessageType::Error if message.id != 0 => {
+                self.outstanding = self.outstanding.saturating_sub(1);
+                self.acknowledge(message.id);
+                match self.batches.remove(&message.id) {
+                    // The whole batch was rejected: report it for each request
+                    Some(ids) => self.inbox.extend(ids.into_iter().map(|id| Message {
+                        id,
+                        ..message.clone()
+                    })),
+                    None => self.inbox.push_back(message),
+                }
+            }
+            _ => self.inbox.push_back(message),
+        }
+        Ok(())
+    }
+
+    /// Forget the kept request or batch `id`, now answered
+    fn acknowledge(&mut self, id: u32) {
+        if let Some(index) = self
+            .unacknowledged
+            .iter()
+            .position(|(message, _)| message.id == id)
+        {
+            self.unacknowledged.remove(index);
+        }
+    }
+
+    /// Allocate the next request ID, skipping 0 on wraparound
+    pub fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    /// Get a reference to the underlying transport
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    /// Get a mutable reference to the underlying transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Unwrap the underlying transport
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl Client<TcpStream> {
+    /// Connect over TCP
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Client<UnixStream> {
+    /// Connect to a Unix domain socket
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        UnixStream::connect(path).map(Self::new)
+    }
+}
+
+impl Client<ChildProcess> {
+    /// Spawn a child process and talk to it over its stdin/stdout
+    pub fn spawn(command: Command) -> io::Result<Self> {
+        ChildProcess::spawn(command).map(Self::new)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+    use std::time::Duration;
+
+    /// Replays canned server bytes and records what the client wrote
+    struct Scripted {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl io::Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl io::Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn scripted(replies: &[Message]) -> Client<Scripted> {
+        scripted_with_window(Window::DEFAULT, replies)
+    }
+
+    fn scripted_with_window(window: Window, replies: &[Message]) -> Client<Scripted> {
+        let mut input = encode(&window.to_message()).unwrap();
+        for reply in replies {
+            input.extend(encode(reply).unwrap());
+        }
+        Client::new(Scripted {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_call_assigns_sequential_ids() {
+        let mut client = scripted(&[Message::response(1, 0, b"a"), Message::response(2, 0, b"b")]);
+        assert_eq!(client.call(b"x").unwrap().payload, b"a");
+        assert_eq!(client.call(b"y").unwrap().payload, b"b");
+
+        let mut expected = encode(&Message::request(1, b"x")).unwrap();
+        expected.extend(encode(&Message::request(2, b"y")).unwrap());
+        assert_eq!(client.get_ref().output, expected);
+    }
+
+    #[test]
+    fn test_call_rejects_mismatched_id() {
+        let mut client = scripted(&[Message::response(7, 0, b"")]);
+        assert_eq!(
+            client.call(b""),
+            Err(ProtocolError::UnexpectedResponse {
+                expected: 1,
+                actual: 7
+            })
+        );
+    }
+
+    #[test]
+    fn test_window_updates_are_absorbed() {
+        let mut client = scripted_with_window(
+            Window::new(1, 100),
+            &[
+                Window::new(1, 1).to_message(),
+                Message::response(1, 0, b"a"),
+                Message::response(2, 0, b"b"),
+            ],
+        );
+        assert_eq!(client.call(b"x").unwrap().payload, b"a");
+        assert_eq!(client.credit(), Window::new(1, 100));
+        assert_eq!(client.call(b"y").unwrap().payload, b"b");
+    }
+
+    #[test]
+    fn test_fail_fast_when_window_exhausted() {
+        let mut client = scripted_with_window(Window::new(1, 100), &[])
+            .with_backpressure(Backpressure::FailFast);
+        client.send(&Message::request(1, b"first")).unwrap();
+        assert_eq!(
+            client.send(&Message::request(2, b"second")),
+            Err(ProtocolError::WindowExhausted {
+                requests: 0,
+                bytes: 95
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_larger_than_window_fails() {
+        let mut client = scripted_with_window(Window::new(4, 10), &[]);
+        assert!(matches!(
+            client.call(&[0; 11]),
+            Err(ProtocolError::WindowExhausted { .. })
+        ));
+    }
+
+    #[test]
+    fn test_call_batch() {
+        let results = vec![
+            Message::response(2, 0, b"A"),
+            Message::error(3, 0x01, "bad"),
+        ];
+        let mut client = scripted(&[BatchResponse::new(1, results.clone()).to_message().unwrap()]);
+        assert_eq!(client.call_batch(&[b"a", b"b"]).unwrap(), results);
+
+        let mut batch = Batch::new(1);
+        batch.push(2, b"a");
+        batch.push(3, b"b");
+        assert_eq!(
+            client.get_ref().output,
+            encode(&batch.to_message()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_batch_rejection_reported_per_request() {
+        let mut client = scripted(&[Message::error(1, 0x05, "duplicate")]);
+        let results = client.call_batch(&[b"a", b"b"]).unwrap();
+        assert_eq!(
+            results,
+            vec![
+                Message::error(2, 0x05, "duplicate"),
+                Message::error(3, 0x05, "duplicate")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queue_sends_full_batches() {
+        let mut client =
+            scripted(&[]).with_batch_window(BatchWindow::new(2, 1 << 20, Duration::from_secs(60)));
+        assert_eq!(client.queue(b"a").unwrap(), 2);
+        assert!(client.get_ref().output.is_empty());
+        assert_eq!(client.queue(b"b").unwrap(), 3);
+        assert_eq!(client.queued(), 0);
+
+        let mut batch = Batch::new(1);
+        batch.push(2, b"a");
+        batch.push(3, b"b");
+        assert_eq!(
+            client.get_ref().output,
+            encode(&batch.to_message()).unwrap()
+        );
+
+        // A third request starts the next batch
+        assert_eq!(client.queue(b"c").unwrap(), 5);
+        assert_eq!(client.queued(), 1);
+        client.flush().unwrap();
+        assert_eq!(client.queued(), 0);
+    }
+
+    #[test]
+    fn test_queue_sends_after_delay() {
+        let mut client = scripted(&[]).with_batch_window(BatchWindow::new(
+            100,
+            1 << 20,
+            Duration::from_millis(5),
+        ));
+        client.queue(b"a").unwrap();
+        assert_eq!(client.queued(), 1);
+        std::thread::sleep(Duration::from_millis(10));
+        client.queue(b"b").unwrap();
+        assert_eq!(client.queued(), 0);
+        assert!(!client.get_ref().output.is_empty());
+    }
+
+    #[test]
+    fn test_events_are_set_aside() {
+        let event = Event {
+            subscription: 1,
+            dropped: 0,
+            topic: "orders.eu".to_string(),
+            data: b"42".to_vec(),
+        };
+        let mut client = scripted(&[
+            event.to_message(),
+            Message::response(1, 0, b""),
+            Message::response(2, 0, &1u32.to_be_bytes()),
+        ]);
+        assert_eq!(client.subscribe("orders.>").unwrap(), 1);
+        assert_eq!(client.publish("orders.eu", b"42").unwrap(), 1);
+        assert_eq!(client.try_event(), Some(event));
+        assert_eq!(client.try_event(), None);
+
+        let mut expected = encode(&Message::subscribe(1, "orders.>")).unwrap();
+        expected.extend(encode(&Publication::new("orders.eu", b"42").to_message(2)).unwrap());
+        assert_eq!(client.get_ref().output, expected);
+    }
+
+    #[test]
+    fn test_pubsub_error_is_rejected() {
+        let mut client = scripted(&[Message::error(1, 0x01, "bad pattern")]);
+        match client.subscribe("a.>.b") {
+            Err(ProtocolError::Rejected {
+                id: 1,
+                code: 0x01,
+                message,
+            }) => assert_eq!(message, "bad pattern"),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_call_method() {
+        let mut client = scripted(&[
+            Message::response_typed(1, 0, &5u32),
+            Message::error(2, 0x06, "unknown method \"sub\""),
+        ]);
+        assert_eq!(client.call_method::<_, u32>("add", &(2u32, 3u32)), Ok(5));
+        assert!(matches!(
+            client.call_method::<_, u32>("sub", &(2u32, 3u32)),
+            Err(ProtocolError::Rejected {
+                id: 2,
+                code: 0x06,
+                ..
+            })
+        ));
+        let first = encode(&MethodCall::new("add", &(2u32, 3u32)).to_message(1)).unwrap();
+        assert!(client.get_ref().output.starts_with(&first));
+    }
+
+    #[test]
+    fn test_calls_carry_timeout_and_priority() {
+        let mut client = scripted(&[Message::response(1, 0, b""), Message::response(2, 0, b"")])
+            .with_timeout(Duration::from_secs(2))
+            .with_priority(7);
+        client.call(b"x").unwrap();
+        // The handler's deadline is tighter than the client's timeout
+        let deadline = Instant::now() + Duration::from_millis(500);
+        deadline::with_deadline(Some(deadline), || client.call(b"y")).unwrap();
+
+        let sent: Vec<_> = crate::decode_all(&client.get_ref().output)
+            .map(Result::unwrap)
+            .collect();
+        let first = sent[0].options.unwrap();
+        assert_eq!(first.priority(), 7);
+        assert!(first
+            .budget_ms()
+            .is_some_and(|ms| ms > 1_900 && ms <= 2_000));
+        assert!(sent[1]
+            .options
+            .unwrap()
+            .budget_ms()
+            .is_some_and(|ms| ms <= 500));
+    }
+
+    #[test]
+    fn test_spent_budget_fails_without_sending() {
+        let mut client = scripted(&[]);
+        let result = deadline::with_deadline(Some(Instant::now()), || client.call(b"x"));
+        assert_eq!(result, Err(ProtocolError::DeadlineExceeded(1)));
+        assert!(client.get_ref().output.is_empty());
+
+        // Without a deadline or options, requests are sent as before
+        let mut client = scripted(&[Message::response(1, 0, b"")]);
+        client.call(b"x").unwrap();
+        assert_eq!(
+            client.get_ref().output,
+            encode(&Message::request(1, b"x")).unwrap()
+        );
+    }
+
+    fn transport(replies: &[Message]) -> Scripted {
+        scripted(replies).into_inner()
+    }
+
+    #[test]
+    fn test_reconnect_resumes_session_and_replays() {
+        let session = SessionId([7; 16]);
+        let mut client = scripted(&[
+            Message::response(1, 0, &session.0),
+            Message::response(2, 0, b"A"),
+        ]);
+        assert_eq!(client.open_session(), Ok(session));
+        let id = client.next_id();
+        client.send(&Message::request(id, b"a")).unwrap();
+        let mut batch = Batch::new(client.next_id());
+        batch.push(client.next_id(), b"b");
+        client.send_batch(&batch).unwrap();
+        assert_eq!(client.recv().unwrap().payload, b"A");
+        assert_eq!(
+            client.unacknowledged().map(|m| m.id).collect::<Vec<_>>(),
+            [3]
+        );
+
+        let results = BatchResponse::new(3, vec![Message::response(4, 0, b"B")])
+            .to_message()
+            .unwrap();
+        let old = client
+            .reconnect(transport(&[Message::response(5, 0, &session.0), results]))
+            .unwrap();
+        assert!(old.output.ends_with(&encode(&batch.to_message()).unwrap()));
+        assert_eq!(client.recv().unwrap().payload, b"B");
+        assert_eq!(client.unacknowledged().count(), 0);
+
+        let mut expected = encode(&Resume::new(session, vec![3]).to_message(5)).unwrap();
+        expected.extend(encode(&batch.to_message()).unwrap());
+        assert_eq!(client.get_ref().output, expected);
+    }
+
+    #[test]
+    fn test_failed_resume_keeps_requests() {
+        let session = SessionId([7; 16]);
+        let mut client = scripted(&[Message::response(1, 0, &session.0)]);
+        client.open_session().unwrap();
+        let id = client.next_id();
+        client.send(&Message::request(id, b"a")).unwrap();
+        let rejected = client.reconnect(transport(&[Message::error(3, 0x07, "unknown session")]));
+        assert!(matches!(
+            rejected,
+            Err(ProtocolError::Rejected {
+                id: 3,
+                code: 0x07,
+                ..
+            })
+        ));
+        assert_eq!(
+            client.unacknowledged().map(|m| m.id).collect::<Vec<_>>(),
+            [2]
+        );
+    }
+
+    #[test]
+    fn test_reconnects_when_the_connection_fails() {
+        let session = SessionId([7; 16]);
+        let mut spare = Some(transport(&[
+            Message::response(3, 0, &session.0),
+            Message::response(2, 0, b"A"),
+        ]));
+        let mut client =
+            scripted(&[Message::response(1, 0, &session.0)]).with_reconnect(move || {
+                spare
+                    .take()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))
+            });
+        client.open_session().unwrap();
+        // The first connection ends before the response arrives
+        assert_eq!(client.call(b"a").unwrap().payload, b"A");
+
+        let mut expected = encode(&Resume::new(session, vec![2]).to_message(3)).unwrap();
+        expected.extend(encode(&Message::request(2, b"a")).unwrap());
+        assert_eq!(client.get_ref().output, expected);
+
+        // Only one spare connection
+        assert!(matches!(client.call(b"b"), Err(ProtocolError::Io { .. })));
+    }
+
+    #[test]
+    fn test_replays_carry_the_budget_left() {
+        let session = SessionId([7; 16]);
+        let mut client = scripted(&[Message::response(1, 0, &session.0)]);
+        client.open_session().unwrap();
+        let short = RequestOptions::new().with_budget(Duration::from_millis(10));
+        let long = RequestOptions::new().with_budget(Duration::from_secs(60));
+        for (payload, options) in [(b"a", short), (b"b", long)] {
+            let id = client.next_id();
+            let request = Message::request(id, payload).with_options(options);
+            client.send(&request).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(20));
+
+        let expired = client.reconnect(transport(&[Message::response(4, 0, &session.0)]));
+        assert!(matches!(expired, Err(ProtocolError::DeadlineExceeded(2))));
+        assert_eq!(
+            client.unacknowledged().map(|m| m.id).collect::<Vec<_>>(),
+            [3]
+        );
+
+        // Only the live request is resumed and sent, with less budget
+        let mut output = client.get_ref().output.as_slice();
+        assert_eq!(
+            read_message(&mut output).unwrap(),
+            Resume::new(session, vec![3]).to_message(4)
+        );
+        let replayed = read_message(&mut output).unwrap();
+        assert_eq!(replayed.id, 3);
+        assert!(replayed.options.unwrap().budget().unwrap() <= Duration::from_millis(59_980));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_no_requests_kept_without_a_session() {
+        let mut client = scripted(&[]);
+        client.send(&Message::request(1, b"a")).unwrap();
+        assert_eq!(client.unacknowledged().count(), 0);
+        client.reconnect(transport(&[])).unwrap();
+        assert!(client.get_ref().output.is_empty());
+    }
+
+    #[test]
+    fn test_next_id_skips_zero() {
+        let mut client = scripted(&[]);
+        client.next_id = u32::MAX;
+        assert_eq!(client.next_id(), u32::MAX);
+        assert_eq!(client.next_id(), 1);
+    }
+}
@@ -0,0 +1,2003 @@
+//! Declarative protocol definitions and code generation.
+//!
+//! A protocol cloned from this template is described once, in a small
+//! line-based spec file, and `protocol-gen` derives everything that has to
+//! agree with it:
+//!
+//! - the Rust codec module (`MessageType`, `Message`, `encode`, `decode`,
+//!   `read_message`, `write_message`)
+//! - the SPEC.md wire-format section
+//! - a starter set of compliance vectors
+//!
+//! ## Spec File Format
+//!
+//! One directive per line. `#` starts a comment, descriptions are quoted.
+//!
+//! ```text
+//! protocol protocol-name "Protocol Name"
+//! magic 0x54 0x55
+//! version 1
+//! max_payload 1048576
+//!
+//! field magic   bytes[2] "Protocol identifier"
+//! field version u8       "Protocol version"
+//! field type    u8       "Message type"
+//! field id      u32      "Request/response ID"
+//! field status  u8       "Response status" when Response Error
+//! field length  u32      "Payload length and flags"
+//! field budget  u32      "Request budget" if options when Request
+//! field payload bytes    "Message payload"
+//!
+//! flag compressed 31 "Payload is compressed" deflate
+//! flag options    30 "Request options follow the length"
+//!
+//! type Request  0x01 "Client request"
+//! type Response 0x02 "Server response"
+//! type Error    0xFF "Error message"
+//!
+//! error InvalidFormat 0x01 "Invalid message format"
+//! ```
+//!
+//! The field names `magic`, `version`, `type`, `length` and `payload` have
+//! fixed meanings; every other field is a big-endian unsigned integer that is
+//! copied into `Message` verbatim. A `when` clause makes a field present only
+//! for the listed message types.
+//!
+//! A `flag` names a bit of the length field; the bits below the lowest flag
+//! carry the payload length. `deflate` marks the compression flag: a payload
+//! sent with it set is its original size as a 4-byte big-endian integer,
+//! followed by a raw DEFLATE stream (RFC 1951). The generated codec frames
+//! such payloads but does not inflate them, and reports them as
+//! `CompressedPayload`.
+//!
+//! Any other flag guards the integer fields that name it with `if`. They sit
+//! between `length` and `payload` and are present only while the flag is
+//! set; the encoder sets it when one of them has a value, sending any other
+//! as 0. A guarded field with a `when` clause makes the flag an error on
+//! other message types.
+
+use std::fmt::{self, Write as _};
+
+/// Marker opening the generated section in SPEC.md
+pub const SPEC_BEGIN_MARKER: &str = "<!-- BEGIN GENERATED: protocol-gen wire-format -->";
+
+/// Marker closing the generated section in SPEC.md
+pub const SPEC_END_MARKER: &str = "<!-- END GENERATED: protocol-gen wire-format -->";
+
+/// Size of the original-size prefix of a compressed payload
+pub const SIZE_PREFIX: usize = 4;
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// Error produced while parsing a spec file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecError {
+    /// 1-based line number (0 when the error concerns the file as a whole)
+    pub line: usize,
+    /// Human-readable description
+    pub message: String,
+}
+
+impl SpecError {
+    pub(crate) fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+/// Width of an unsigned big-endian integer field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntWidth {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl IntWidth {
+    /// Size in bytes on the wire
+    pub fn size(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+            Self::U64 => 8,
+        }
+    }
+
+    /// Largest representable value
+    pub fn max_value(self) -> u64 {
+        match self {
+            Self::U64 => u64::MAX,
+            other => (1u64 << (other.size() * 8)) - 1,
+        }
+    }
+
+    /// Rust type name
+    pub fn rust_type(self) -> &'static str {
+        match self {
+            Self::U8 => "u8",
+            Self::U16 => "u16",
+            Self::U32 => "u32",
+            Self::U64 => "u64",
+        }
+    }
+
+    fn parse(token: &str) -> Option<Self> {
+        match token {
+            "u8" => Some(Self::U8),
+            "u16" => Some(Self::U16),
+            "u32" => Some(Self::U32),
+            "u64" => Some(Self::U64),
+            _ => None,
+        }
+    }
+}
+
+/// Role of a field in the frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    /// Fixed magic bytes
+    Magic,
+    /// Protocol version byte
+    Version,
+    /// Message type byte
+    Type,
+    /// Plain integer carried in `Message`
+    Int(IntWidth),
+    /// Payload length prefix
+    Length(IntWidth),
+    /// Variable-length payload (always last)
+    Payload,
+}
+
+/// A single field of the frame layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpec {
+    /// Field name (also the `Message` field name for integer fields)
+    pub name: String,
+    /// Field role and width
+    pub kind: FieldKind,
+    /// Size in bytes (`None` for the payload)
+    pub size: Option<usize>,
+    /// One-line description
+    pub description: String,
+    /// Message types carrying this field (empty = all)
+    pub when: Vec<String>,
+    /// Length field flag that must be set for the field to be present
+    pub flag: Option<String>,
+}
+
+impl FieldSpec {
+    /// Whether the field is present for every message type
+    pub fn is_conditional(&self) -> bool {
+        !self.when.is_empty()
+    }
+
+    /// Whether the field is present only while a length field flag is set
+    pub fn is_flagged(&self) -> bool {
+        self.flag.is_some()
+    }
+
+    /// Whether the field may be absent from a frame (`Option` in `Message`)
+    pub fn is_optional(&self) -> bool {
+        self.is_conditional() || self.is_flagged()
+    }
+
+    /// Whether the field is present for the given message type
+    pub fn applies_to(&self, type_name: &str) -> bool {
+        self.when.is_empty() || self.when.iter().any(|t| t == type_name)
+    }
+}
+
+/// A flag bit of the length field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagSpec {
+    /// Flag name
+    pub name: String,
+    /// Bit number in the length field (0 = least significant)
+    pub bit: u32,
+    /// One-line description
+    pub description: String,
+    /// Whether the flag marks a compressed payload (size prefix + DEFLATE)
+    pub deflate: bool,
+}
+
+impl FlagSpec {
+    /// The flag's bit as a mask over the length field
+    pub fn mask(&self) -> u64 {
+        1 << self.bit
+    }
+
+    /// Name of the generated constant
+    pub fn const_name(&self) -> String {
+        self.name.to_ascii_uppercase()
+    }
+}
+
+/// A message type definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeSpec {
+    /// Variant name
+    pub name: String,
+    /// Wire value
+    pub value: u8,
+    /// One-line description
+    pub description: String,
+}
+
+/// An error code definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorSpec {
+    /// Variant name
+    pub name: String,
+    /// Wire value
+    pub code: u8,
+    /// One-line description
+    pub description: String,
+}
+
+/// A complete protocol definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolSpec {
+    /// Package name (kebab-case)
+    pub name: String,
+    /// Human-readable title
+    pub title: String,
+    /// Magic bytes
+    pub magic: Vec<u8>,
+    /// Protocol version
+    pub version: u8,
+    /// Maximum payload size in bytes
+    pub max_payload: usize,
+    /// Frame layout in wire order
+    pub fields: Vec<FieldSpec>,
+    /// Flag bits of the length field
+    pub flags: Vec<FlagSpec>,
+    /// Message types
+    pub message_types: Vec<TypeSpec>,
+    /// Error codes
+    pub error_codes: Vec<ErrorSpec>,
+}
+
+/// Expected outcome of decoding a vector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    /// Decodes to a message with these values
+    Message {
+        /// Message type name
+        message_type: String,
+        /// Integer field values (`None` = field absent for this type)
+        fields: Vec<(String, Option<u64>)>,
+        /// Payload bytes
+        payload: Vec<u8>,
+    },
+    /// Fails with this `ProtocolError` variant name
+    Error(String),
+}
+
+/// A compliance vector derived from a spec
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// Identifier, usable as a test function suffix
+    pub name: String,
+    /// One-line description
+    pub description: String,
+    /// Encoded bytes
+    pub bytes: Vec<u8>,
+    /// Expected decode result
+    pub expected: Expected,
+}
+
+// ============================================================================
+// Parsing
+// ============================================================================
+
+impl ProtocolSpec {
+    /// Parse a spec file
+    pub fn parse(source: &str) -> Result<Self, SpecError> {
+        let mut name = None;
+        let mut title = None;
+        let mut magic = None;
+        let mut version = None;
+        let mut max_payload = None;
+        let mut fields = Vec::new();
+        let mut flags = Vec::new();
+        let mut message_types = Vec::new();
+        let mut error_codes = Vec::new();
+
+        for (index, raw) in source.lines().enumerate() {
+            let line_no = index + 1;
+            let tokens = tokenize(raw).map_err(|e| SpecError::new(line_no, e))?;
+            let Some((directive, args)) = tokens.split_first() else {
+                continue;
+            };
+
+            match directive.as_str() {
+                "protocol" => {
+                    let [pkg, rest @ ..] = args else {
+                        return Err(SpecError::new(line_no, "protocol requires a name"));
+                    };
+                    name = Some(pkg.clone());
+                    title = rest.first().cloned();
+                }
+                "magic" => {
+                    if args.is_empty() || args.len() > 8 {
+                        return Err(SpecError::new(line_no, "magic requires 1 to 8 bytes"));
+                    }
+                    let bytes = args
+                        .iter()
+                        .map(|a| {
+                            parse_u8(a).ok_or_else(|| {
+                                SpecError::new(line_no, format!("invalid byte: {}", a))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    magic = Some(bytes);
+                }
+                "version" => {
+                    let value = args.first().and_then(|a| parse_u8(a));
+                    version =
+                        Some(value.ok_or_else(|| {
+                            SpecError::new(line_no, "version requires a byte value")
+                        })?);
+                }
+                "max_payload" => {
+                    let value = args.first().and_then(|a| parse_u64(a));
+                    let value = value.ok_or_else(|| {
+                        SpecError::new(line_no, "max_payload requires an integer")
+                    })?;
+                    max_payload = Some(value as usize);
+                }
+                "field" => fields.push(parse_field(line_no, args)?),
+                "flag" => flags.push(parse_flag(line_no, args)?),
+                "type" => {
+                    let (name, value, description) = parse_named_value(line_no, "type", args)?;
+                    message_types.push(TypeSpec {
+                        name,
+                        value,
+                        description,
+                    });
+                }
+                "error" => {
+                    let (name, code, description) = parse_named_value(line_no, "error", args)?;
+                    error_codes.push(ErrorSpec {
+                        name,
+                        code,
+                        description,
+                    });
+                }
+                other => {
+                    return Err(SpecError::new(
+                        line_no,
+                        format!("unknown directive: {}", other),
+                    ))
+                }
+            }
+        }
+
+        let name = name.ok_or_else(|| SpecError::new(0, "missing `protocol` directive"))?;
+        // Default to 1 MB, capped by what the length field can express below
+        // its flags
+        let lowest_flag = flags.iter().map(FlagSpec::mask).min();
+        let length_max = fields
+            .iter()
+            .find_map(|f: &FieldSpec| match f.kind {
+                FieldKind::Length(width) => Some(width.max_value()),
+                _ => None,
+            })
+            .into_iter()
+            .chain(lowest_flag.map(|mask| mask - 1))
+            .min()
+            .map_or(usize::MAX, |max| max as usize);
+        let spec = Self {
+            title: title.unwrap_or_else(|| name.clone()),
+            name,
+            magic: magic.ok_or_else(|| SpecError::new(0, "missing `magic` directive"))?,
+            version: version.ok_or_else(|| SpecError::new(0, "missing `version` directive"))?,
+            max_payload: max_payload.unwrap_or((1024 * 1024).min(length_max)),
+            fields,
+            flags,
+            message_types,
+            error_codes,
+        };
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<(), SpecError> {
+        let position =
+            |kind: fn(&FieldKind) -> bool| self.fields.iter().position(|f| kind(&f.kind));
+
+        let magic_at = position(|k| *k == FieldKind::Magic)
+            .ok_or_else(|| SpecError::new(0, "missing `magic` field"))?;
+        let type_at = position(|k| *k == FieldKind::Type)
+            .ok_or_else(|| SpecError::new(0, "missing `type` field"))?;
+        position(|k| *k == FieldKind::Version)
+            .ok_or_else(|| SpecError::new(0, "missing `version` field"))?;
+        let length_at = position(|k| matches!(k, FieldKind::Length(_)))
+            .ok_or_else(|| SpecError::new(0, "missing `length` field"))?;
+        let payload_at = position(|k| *k == FieldKind::Payload)
+            .ok_or_else(|| SpecError::new(0, "missing `payload` field"))?;
+
+        if magic_at != 0 {
+            return Err(SpecError::new(0, "`magic` must be the first field"));
+        }
+        if payload_at != self.fields.len() - 1
+            || length_at > payload_at
+            || self.fields[length_at + 1..payload_at]
+                .iter()
+                .any(|f| !f.is_flagged())
+        {
+            return Err(SpecError::new(
+                0,
+                "`length` and `payload` must be the last two fields, apart from flagged fields",
+            ));
+        }
+        if self.fields[magic_at].size != Some(self.magic.len()) {
+            return Err(SpecError::new(
+                0,
+                "`magic` field size does not match the magic directive",
+            ));
+        }
+
+        for (index, field) in self.fields.iter().enumerate() {
+            if self.fields[..index].iter().any(|f| f.name == field.name) {
+                return Err(SpecError::new(
+                    0,
+                    format!("duplicate field: {}", field.name),
+                ));
+            }
+            if field.is_conditional() {
+                if index < type_at {
+                    return Err(SpecError::new(
+                        0,
+                        format!("conditional field `{}` precedes `type`", field.name),
+                    ));
+                }
+                if !matches!(field.kind, FieldKind::Int(_)) {
+                    return Err(SpecError::new(
+                        0,
+                        format!("only integer fields may be conditional: {}", field.name),
+                    ));
+                }
+                for type_name in &field.when {
+                    if !self.message_types.iter().any(|t| &t.name == type_name) {
+                        return Err(SpecError::new(
+                            0,
+                            format!(
+                                "field `{}` refers to unknown type {}",
+                                field.name, type_name
+                            ),
+                        ));
+                    }
+                }
+            }
+            if let Some(flag_name) = &field.flag {
+                if index < length_at || index > payload_at {
+                    return Err(SpecError::new(
+                        0,
+                        format!(
+                            "flagged field `{}` must sit between `length` and `payload`",
+                            field.name
+                        ),
+                    ));
+                }
+                if !matches!(field.kind, FieldKind::Int(_)) {
+                    return Err(SpecError::new(
+                        0,
+                        format!("only integer fields may be flagged: {}", field.name),
+                    ));
+                }
+                match self.flags.iter().find(|f| &f.name == flag_name) {
+                    None => {
+                        return Err(SpecError::new(
+                            0,
+                            format!(
+                                "field `{}` refers to unknown flag {}",
+                                field.name, flag_name
+                            ),
+                        ))
+                    }
+                    Some(flag) if flag.deflate => {
+                        return Err(SpecError::new(
+                            0,
+                            format!("`deflate` flag `{}` cannot guard fields", flag_name),
+                        ))
+                    }
+                    Some(flag) => {
+                        if self.flagged_fields(flag).any(|f| f.when != field.when) {
+                            return Err(SpecError::new(
+                                0,
+                                format!("fields guarded by `{}` need the same `when`", flag_name),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.message_types.is_empty() {
+            return Err(SpecError::new(0, "at least one message type is required"));
+        }
+        for (index, t) in self.message_types.iter().enumerate() {
+            if self.message_types[..index]
+                .iter()
+                .any(|o| o.name == t.name || o.value == t.value)
+            {
+                return Err(SpecError::new(
+                    0,
+                    format!("duplicate message type: {} ({:#04x})", t.name, t.value),
+                ));
+            }
+        }
+        for (index, e) in self.error_codes.iter().enumerate() {
+            if self.error_codes[..index]
+                .iter()
+                .any(|o| o.name == e.name || o.code == e.code)
+            {
+                return Err(SpecError::new(
+                    0,
+                    format!("duplicate error code: {} ({:#04x})", e.name, e.code),
+                ));
+            }
+        }
+
+        if let Some(FieldKind::Length(width)) = self.fields.get(length_at).map(|f| f.kind) {
+            if self.max_payload as u64 > width.max_value() {
+                return Err(SpecError::new(
+                    0,
+                    "max_payload does not fit in the length field",
+                ));
+            }
+        }
+
+        let length_bits = self.length_width().size() as u32 * 8;
+        for (index, flag) in self.flags.iter().enumerate() {
+            if self.flags[..index]
+                .iter()
+                .any(|o| o.name == flag.name || o.bit == flag.bit)
+            {
+                return Err(SpecError::new(
+                    0,
+                    format!("duplicate flag: {} (bit {})", flag.name, flag.bit),
+                ));
+            }
+            if flag.bit >= length_bits {
+                return Err(SpecError::new(
+                    0,
+                    format!("flag `{}` is outside the length field", flag.name),
+                ));
+            }
+            if self.max_payload as u64 >= flag.mask() {
+                return Err(SpecError::new(
+                    0,
+                    format!("flag `{}` overlaps lengths up to max_payload", flag.name),
+                ));
+            }
+            if !flag.deflate && self.flagged_fields(flag).next().is_none() {
+                return Err(SpecError::new(
+                    0,
+                    format!("flag `{}` guards no field", flag.name),
+                ));
+            }
+        }
+        if self.flags.iter().filter(|f| f.deflate).count() > 1 {
+            return Err(SpecError::new(0, "at most one flag may be `deflate`"));
+        }
+
+        Ok(())
+    }
+
+    /// Rust crate name derived from the package name
+    pub fn crate_name(&self) -> String {
+        self.name.replace('-', "_")
+    }
+
+    /// Plain integer fields, in wire order
+    pub fn int_fields(&self) -> impl Iterator<Item = (&FieldSpec, IntWidth)> {
+        self.fields.iter().filter_map(|f| match f.kind {
+            FieldKind::Int(width) => Some((f, width)),
+            _ => None,
+        })
+    }
+
+    /// Width of the payload length prefix
+    pub fn length_width(&self) -> IntWidth {
+        self.fields
+            .iter()
+            .find_map(|f| match f.kind {
+                FieldKind::Length(width) => Some(width),
+                _ => None,
+            })
+            .unwrap_or(IntWidth::U32)
+    }
+
+    /// Bits of the length field that carry the payload length
+    pub fn length_mask(&self) -> u64 {
+        self.flags
+            .iter()
+            .fold(self.length_width().max_value(), |mask, flag| {
+                mask & !flag.mask()
+            })
+    }
+
+    /// The flag marking compressed payloads, if any
+    pub fn deflate_flag(&self) -> Option<&FlagSpec> {
+        self.flags.iter().find(|f| f.deflate)
+    }
+
+    /// Fields guarded by `flag`, in wire order
+    pub fn flagged_fields<'a>(&'a self, flag: &'a FlagSpec) -> impl Iterator<Item = &'a FieldSpec> {
+        self.fields
+            .iter()
+            .filter(move |f| f.flag.as_ref() == Some(&flag.name))
+    }
+
+    /// Flags that guard fields, i.e. every flag but `deflate`
+    pub fn field_flags(&self) -> impl Iterator<Item = &FlagSpec> {
+        self.flags.iter().filter(|f| !f.deflate)
+    }
+
+    /// Header size for a message type with every flagged field present,
+    /// excluding the payload
+    pub fn header_size(&self, type_name: &str) -> usize {
+        self.fields
+            .iter()
+            .filter(|f| f.applies_to(type_name))
+            .filter_map(|f| f.size)
+            .sum()
+    }
+
+    /// Smallest header across all message types, without flagged fields
+    pub fn min_header_size(&self) -> usize {
+        self.message_types
+            .iter()
+            .map(|t| {
+                self.fields
+                    .iter()
+                    .filter(|f| f.applies_to(&t.name) && !f.is_flagged())
+                    .filter_map(|f| f.size)
+                    .sum()
+            })
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Largest header across all message types
+    pub fn max_header_size(&self) -> usize {
+        self.message_types
+            .iter()
+            .map(|t| self.header_size(&t.name))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+fn parse_field(line_no: usize, args: &[String]) -> Result<FieldSpec, SpecError> {
+    let [name, ty, description, rest @ ..] = args else {
+        return Err(SpecError::new(
+            line_no,
+            "field requires a name, type and description",
+        ));
+    };
+
+    let (flag, rest) = match rest {
+        [kw, flag, rest @ ..] if kw == "if" => (Some(flag.clone()), rest),
+        rest => (None, rest),
+    };
+    let when = match rest {
+        [] => Vec::new(),
+        [kw, types @ ..] if kw == "when" && !types.is_empty() => types.to_vec(),
+        _ => {
+            return Err(SpecError::new(
+                line_no,
+                "expected `if <flag>` or `when <Type>...` after the description",
+            ))
+        }
+    };
+
+    let bytes_len = ty
+        .strip_prefix("bytes[")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|n| n.parse::<usize>().ok());
+    let width = IntWidth::parse(ty);
+
+    let (kind, size) = match (name.as_str(), width, bytes_len) {
+        ("magic", _, Some(n)) => (FieldKind::Magic, Some(n)),
+        ("version", Some(IntWidth::U8), _) => (FieldKind::Version, Some(1)),
+        ("type", Some(IntWidth::U8), _) => (FieldKind::Type, Some(1)),
+        ("length", Some(w @ (IntWidth::U16 | IntWidth::U32)), _) => {
+            (FieldKind::Length(w), Some(w.size()))
+        }
+        ("payload", None, None) if ty == "bytes" => (FieldKind::Payload, None),
+        ("magic" | "version" | "type" | "length" | "payload", _, _) => {
+            return Err(SpecError::new(
+                line_no,
+                format!("unsupported type `{}` for field `{}`", ty, name),
+            ))
+        }
+        (_, Some(w), _) => (FieldKind::Int(w), Some(w.size())),
+        _ => {
+            return Err(SpecError::new(
+                line_no,
+                format!("unsupported field type: {}", ty),
+            ))
+        }
+    };
+
+    if !is_identifier(name) {
+        return Err(SpecError::new(
+            line_no,
+            format!("invalid field name: {}", name),
+        ));
+    }
+
+    Ok(FieldSpec {
+        name: name.clone(),
+        kind,
+        size,
+        description: description.clone(),
+        when,
+        flag,
+    })
+}
+
+fn parse_flag(line_no: usize, args: &[String]) -> Result<FlagSpec, SpecError> {
+    let [name, bit, description, rest @ ..] = args else {
+        return Err(SpecError::new(
+            line_no,
+            "flag requires a name, bit and description",
+        ));
+    };
+
+    let deflate = match rest {
+        [] => false,
+        [kw] if kw == "deflate" => true,
+        _ => {
+            return Err(SpecError::new(
+                line_no,
+                "expected nothing or `deflate` after the description",
+            ))
+        }
+    };
+    if !is_identifier(name) {
+        return Err(SpecError::new(
+            line_no,
+            format!("invalid flag name: {}", name),
+        ));
+    }
+    let bit = parse_u64(bit)
+        .filter(|bit| *bit < 64)
+        .ok_or_else(|| SpecError::new(line_no, format!("invalid bit: {}", bit)))?;
+
+    Ok(FlagSpec {
+        name: name.clone(),
+        bit: bit as u32,
+        description: description.clone(),
+        deflate,
+    })
+}
+
+fn parse_named_value(
+    line_no: usize,
+    directive: &str,
+    args: &[String],
+) -> Result<(String, u8, String), SpecError> {
+    let [name, value, description] = args else {
+        return Err(SpecError::new(
+            line_no,
+            format!("{} requires a name, value and description", directive),
+        ));
+    };
+    if !is_identifier(name) || !name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        return Err(SpecError::new(
+            line_no,
+            format!("{} name must be CamelCase: {}", directive, name),
+        ));
+    }
+    let value = parse_u8(value)
+        .ok_or_else(|| SpecError::new(line_no, format!("invalid byte: {}", value)))?;
+    Ok((name.clone(), value, description.clone()))
+}
+
+/// Split a line into whitespace-separated tokens, honouring double quotes
+pub(crate) fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c == '#' {
+            break;
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(ch) => token.push(ch),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() || ch == '#' {
+                    break;
+                }
+                token.push(ch);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_u64(token: &str) -> Option<u64> {
+    match token
+        .strip_prefix("0x")
+        .or_else(|| token.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => token.parse().ok(),
+    }
+}
+
+fn parse_u8(token: &str) -> Option<u8> {
+    parse_u64(token).and_then(|v| u8::try_from(v).ok())
+}
+
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub(crate) fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("0x{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// ============================================================================
+// Vectors
+// ============================================================================
+
+impl ProtocolSpec {
+    /// Encode a frame by the spec's layout (used to derive vectors)
+    fn encode_frame(
+        &self,
+        type_spec: &TypeSpec,
+        values: &[(String, Option<u64>)],
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let value_of = |field: &FieldSpec| {
+            values
+                .iter()
+                .find(|(name, _)| name == &field.name)
+                .and_then(|(_, v)| *v)
+        };
+        // A flag is set when any field it guards has a value
+        let set: Vec<&FlagSpec> = self
+            .field_flags()
+            .filter(|flag| self.flagged_fields(flag).any(|f| value_of(f).is_some()))
+            .collect();
+        let present = |field: &FieldSpec| match &field.flag {
+            Some(name) => set.iter().any(|flag| &flag.name == name),
+            None => field.applies_to(&type_spec.name),
+        };
+
+        let mut buf = Vec::new();
+        for field in self.fields.iter().filter(|f| present(f)) {
+            match field.kind {
+                FieldKind::Magic => buf.extend_from_slice(&self.magic),
+                FieldKind::Version => buf.push(self.version),
+                FieldKind::Type => buf.push(type_spec.value),
+                FieldKind::Int(width) => {
+                    let value = value_of(field).unwrap_or(0);
+                    buf.extend_from_slice(&value.to_be_bytes()[8 - width.size()..]);
+                }
+                FieldKind::Length(width) => {
+                    let length = set
+                        .iter()
+                        .fold(payload.len() as u64, |length, flag| length | flag.mask());
+                    buf.extend_from_slice(&length.to_be_bytes()[8 - width.size()..]);
+                }
+                FieldKind::Payload => buf.extend_from_slice(payload),
+            }
+        }
+        buf
+    }
+
+    /// Sample field values, with the fields guarded by `flag` present
+    fn sample_values(
+        &self,
+        type_spec: &TypeSpec,
+        seed: u64,
+        flag: Option<&FlagSpec>,
+    ) -> Vec<(String, Option<u64>)> {
+        self.int_fields()
+            .enumerate()
+            .map(|(i, (field, width))| {
+                let flagged = match &field.flag {
+                    Some(name) => flag.is_some_and(|flag| &flag.name == name),
+                    None => true,
+                };
+                let value = (field.applies_to(&type_spec.name) && flagged)
+                    .then(|| (seed + i as u64) & width.max_value());
+                (field.name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Starter compliance vectors: one minimal and one payload-carrying frame
+    /// per message type, plus the standard invalid frames
+    pub fn vectors(&self) -> Vec<Vector> {
+        let mut vectors = Vec::new();
+
+        for (index, type_spec) in self.message_types.iter().enumerate() {
+            let seed = index as u64 * 2 + 1;
+            for (suffix, payload, seed) in [
+                ("minimal", &b""[..], seed),
+                ("with_payload", &b"hello"[..], seed + 1),
+            ] {
+                let fields = self.sample_values(type_spec, seed, None);
+                vectors.push(Vector {
+                    name: format!("{}_{}", snake_case(&type_spec.name), suffix),
+                    description: format!(
+                        "{} with {}",
+                        type_spec.name,
+                        if payload.is_empty() {
+                            "empty payload".to_string()
+                        } else {
+                            format!("{:?} payload", String::from_utf8_lossy(payload))
+                        }
+                    ),
+                    bytes: self.encode_frame(type_spec, &fields, payload),
+                    expected: Expected::Message {
+                        message_type: type_spec.name.clone(),
+                        fields,
+                        payload: payload.to_vec(),
+                    },
+                });
+            }
+        }
+
+        for flag in self.field_flags() {
+            for (index, type_spec) in self.message_types.iter().enumerate() {
+                if !self
+                    .flagged_fields(flag)
+                    .all(|f| f.applies_to(&type_spec.name))
+                {
+                    continue;
+                }
+                let fields = self.sample_values(type_spec, index as u64 * 2 + 1, Some(flag));
+                vectors.push(Vector {
+                    name: format!("{}_with_{}", snake_case(&type_spec.name), flag.name),
+                    description: format!(
+                        "{} with `{}` set and \"hi\" payload",
+                        type_spec.name, flag.name
+                    ),
+                    bytes: self.encode_frame(type_spec, &fields, b"hi"),
+                    expected: Expected::Message {
+                        message_type: type_spec.name.clone(),
+                        fields,
+                        payload: b"hi".to_vec(),
+                    },
+                });
+            }
+        }
+
+        let first = &self.message_types[0];
+        let minimal = self.encode_frame(first, &self.sample_values(first, 1, None), b"");
+
+        let mut bad_magic = minimal.clone();
+        bad_magic[..self.magic.len()]
+            .iter_mut()
+            .for_each(|b| *b = !*b);
+        vectors.push(Vector {
+            name: "invalid_magic".to_string(),
+            description: "Magic bytes inverted".to_string(),
+            bytes: bad_magic,
+            expected: Expected::Error("InvalidMagic".to_string()),
+        });
+
+        if let Some(version_at) = self.offset_of(first, FieldKind::Version) {
+            let mut bad_version = minimal.clone();
+            bad_version[version_at] = self.version.wrapping_add(0x80);
+            vectors.push(Vector {
+                name: "unsupported_version".to_string(),
+                description: "Version byte not supported".to_string(),
+                bytes: bad_version,
+                expected: Expected::Error("UnsupportedVersion".to_string()),
+            });
+        }
+
+        let unused_type = (0..=u8::MAX).rev().find(|v| {
+            !self.message_types.iter().any(|t| t.value == *v) && !(0xF0..=0xFE).contains(v)
+        });
+        if let (Some(type_at), Some(unused)) = (self.offset_of(first, FieldKind::Type), unused_type)
+        {
+            let mut bad_type = minimal.clone();
+            bad_type[type_at] = unused;
+            vectors.push(Vector {
+                name: "unknown_type".to_string(),
+                description: format!("Unassigned message type {:#04x}", unused),
+                bytes: bad_type,
+                expected: Expected::Error("UnknownType".to_string()),
+            });
+        }
+
+        vectors.push(Vector {
+            name: "incomplete_message".to_string(),
+            description: "Truncated header".to_string(),
+            bytes: minimal[..minimal.len() - 1].to_vec(),
+            expected: Expected::Error("IncompleteMessage".to_string()),
+        });
+
+        if let Some(flag) = self.deflate_flag() {
+            let size = self.max_payload as u64 + 1;
+            let prefix = (size as u32).to_be_bytes();
+            let mut too_large =
+                self.encode_frame(first, &self.sample_values(first, 1, None), &prefix);
+            self.set_flag(&mut too_large, first, flag);
+            vectors.push(Vector {
+                name: format!("{}_size_too_large", flag.name),
+                description: format!("`{}` payload declaring {} bytes", flag.name, size),
+                bytes: too_large,
+                expected: Expected::Error("PayloadTooLarge".to_string()),
+            });
+        }
+
+        for flag in self.field_flags() {
+            let Some(other) = self
+                .message_types
+                .iter()
+                .find(|t| !self.flagged_fields(flag).all(|f| f.applies_to(&t.name)))
+            else {
+                continue;
+            };
+            let mut misplaced = self.encode_frame(other, &self.sample_values(other, 1, None), b"");
+            self.set_flag(&mut misplaced, other, flag);
+            vectors.push(Vector {
+                name: format!("{}_on_{}", flag.name, snake_case(&other.name)),
+                description: format!("`{}` set on {}", flag.name, other.name),
+                bytes: misplaced,
+                expected: Expected::Error("UnexpectedFlag".to_string()),
+            });
+        }
+
+        vectors
+    }
+
+    /// Set `flag` in the length field of an encoded frame
+    fn set_flag(&self, frame: &mut [u8], type_spec: &TypeSpec, flag: &FlagSpec) {
+        let width = self.length_width();
+        if let Some(at) = self.offset_of(type_spec, FieldKind::Length(width)) {
+            let field = &mut frame[at..at + width.size()];
+            let value = field
+                .iter()
+                .fold(0u64, |value, b| value << 8 | u64::from(*b))
+                | flag.mask();
+            field.copy_from_slice(&value.to_be_bytes()[8 - width.size()..]);
+        }
+    }
+
+    fn offset_of(&self, type_spec: &TypeSpec, kind: FieldKind) -> Option<usize> {
+        let mut offset = 0;
+        for field in self.fields.iter().filter(|f| f.applies_to(&type_spec.name)) {
+            if field.kind == kind {
+                return Some(offset);
+            }
+            offset += field.size.unwrap_or(0);
+        }
+        None
+    }
+}
+
+// ============================================================================
+// Generators
+// ============================================================================
+
+impl ProtocolSpec {
+    /// Generate the Rust codec module
+    pub fn generate_codec(&self, source_name: &str) -> String {
+        let mut out = String::new();
+        let magic_len = self.magic.len();
+        let length_type = self.length_width().rust_type();
+
+        let _ = writeln!(out, "//! # {}", self.title);
+        let _ = writeln!(out, "//!");
+        let _ = writeln!(
+            out,
+            "//! Codec generated by `protocol-gen` from `{}`.",
+            source_name
+        );
+        let _ = writeln!(
+            out,
+            "//! Do not edit by hand; change the spec file and regenerate."
+        );
+        out.push('\n');
+        out.push_str("use std::io::{self, Read, Write};\n\n");
+
+        // Constants
+        let _ = writeln!(out, "/// Protocol magic bytes");
+        let _ = writeln!(
+            out,
+            "pub const MAGIC: [u8; {}] = [{}];",
+            magic_len,
+            hex_list(&self.magic)
+        );
+        out.push('\n');
+        let _ = writeln!(out, "/// Current protocol version");
+        let _ = writeln!(out, "pub const VERSION: u8 = {};", self.version);
+        out.push('\n');
+        let _ = writeln!(out, "/// Maximum payload size");
+        let _ = writeln!(
+            out,
+            "pub const MAX_PAYLOAD_SIZE: usize = {};",
+            self.max_payload
+        );
+        out.push('\n');
+        for flag in &self.flags {
+            let _ = writeln!(out, "/// Length field flag: {}", flag.description);
+            let _ = writeln!(
+                out,
+                "pub const {}: {} = 0x{:X};",
+                flag.const_name(),
+                length_type,
+                flag.mask()
+            );
+            out.push('\n');
+        }
+
+        // MessageType
+        out.push_str("/// Message type identifier\n");
+        out.push_str(
+            "#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n#[repr(u8)]\npub enum MessageType {\n",
+        );
+        for t in &self.message_types {
+            let _ = writeln!(out, "    /// {}", t.description);
+            let _ = writeln!(out, "    {} = 0x{:02X},", t.name, t.value);
+        }
+        out.push_str("}\n\n");
+
+        out.push_str("impl TryFrom<u8> for MessageType {\n    type Error = ProtocolError;\n\n");
+        out.push_str(
+            "    fn try_from(value: u8) -> Result<Self, ProtocolError> {\n        match value {\n",
+        );
+        for t in &self.message_types {
+            let _ = writeln!(
+                out,
+                "            0x{:02X} => Ok(MessageType::{}),",
+                t.value, t.name
+            );
+        }
+        out.push_str(
+            "            _ => Err(ProtocolError::UnknownType(value)),\n        }\n    }\n}\n\n",
+        );
+
+        // ErrorCode
+        if !self.error_codes.is_empty() {
+            out.push_str("/// Application error codes carried in error messages\n");
+            out.push_str(
+                "#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n#[repr(u8)]\npub enum ErrorCode {\n",
+            );
+            for e in &self.error_codes {
+                let _ = writeln!(out, "    /// {}", e.description);
+                let _ = writeln!(out, "    {} = 0x{:02X},", e.name, e.code);
+            }
+            out.push_str("}\n\n");
+            out.push_str("impl ErrorCode {\n    /// Look up an error code by its wire value\n");
+            out.push_str(
+                "    pub fn from_u8(value: u8) -> Option<Self> {\n        match value {\n",
+            );
+            for e in &self.error_codes {
+                let _ = writeln!(
+                    out,
+                    "            0x{:02X} => Some(ErrorCode::{}),",
+                    e.code, e.name
+                );
+            }
+            out.push_str("            _ => None,\n        }\n    }\n}\n\n");
+        }
+
+        // ProtocolError
+        out.push_str("/// Protocol error types\n#[derive(Debug, Clone, PartialEq, Eq)]\npub enum ProtocolError {\n");
+        let _ = writeln!(
+            out,
+            "    /// Invalid magic bytes\n    InvalidMagic([u8; {}]),",
+            magic_len
+        );
+        out.push_str("    /// Unsupported protocol version\n    UnsupportedVersion(u8),\n");
+        out.push_str("    /// Unknown message type\n    UnknownType(u8),\n");
+        out.push_str("    /// Payload exceeds maximum size\n    PayloadTooLarge(usize),\n");
+        out.push_str("    /// Incomplete message (not enough bytes)\n    IncompleteMessage,\n");
+        if !self.flags.is_empty() {
+            out.push_str("    /// Malformed payload\n    InvalidPayload(String),\n");
+        }
+        if self.field_flags().next().is_some() {
+            out.push_str("    /// The length field sets the named flag, which this message type\n");
+            out.push_str("    /// cannot carry\n    UnexpectedFlag(&'static str, MessageType),\n");
+        }
+        if self.deflate_flag().is_some() {
+            out.push_str(
+                "    /// Compressed payload of the given original size, which this codec\n",
+            );
+            out.push_str("    /// does not inflate\n    CompressedPayload(usize),\n");
+        }
+        out.push_str("    /// I/O error\n    Io(String),\n}\n\n");
+
+        out.push_str("impl std::fmt::Display for ProtocolError {\n");
+        out.push_str("    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {\n        match self {\n");
+        out.push_str("            Self::InvalidMagic(magic) => {\n                write!(f, \"invalid magic: \")?;\n");
+        out.push_str("                magic.iter().try_for_each(|b| write!(f, \"{:02x}\", b))\n            }\n");
+        out.push_str("            Self::UnsupportedVersion(v) => write!(f, \"unsupported version: {}\", v),\n");
+        out.push_str(
+            "            Self::UnknownType(t) => write!(f, \"unknown message type: {:02x}\", t),\n",
+        );
+        out.push_str("            Self::PayloadTooLarge(size) => write!(f, \"payload too large: {} bytes\", size),\n");
+        out.push_str("            Self::IncompleteMessage => write!(f, \"incomplete message\"),\n");
+        if !self.flags.is_empty() {
+            out.push_str("            Self::InvalidPayload(msg) => write!(f, \"invalid payload: {}\", msg),\n");
+        }
+        if self.field_flags().next().is_some() {
+            out.push_str("            Self::UnexpectedFlag(flag, message_type) => {\n");
+            out.push_str("                write!(f, \"{:?} frames cannot carry `{}`\", message_type, flag)\n            }\n");
+        }
+        if self.deflate_flag().is_some() {
+            out.push_str("            Self::CompressedPayload(size) => {\n");
+            out.push_str("                write!(f, \"compressed payload not supported ({} bytes inflated)\", size)\n            }\n");
+        }
+        out.push_str("            Self::Io(msg) => write!(f, \"I/O error: {}\", msg),\n        }\n    }\n}\n\n");
+        out.push_str("impl std::error::Error for ProtocolError {}\n\n");
+        out.push_str(
+            "impl From<io::Error> for ProtocolError {\n    fn from(err: io::Error) -> Self {\n",
+        );
+        out.push_str("        ProtocolError::Io(err.to_string())\n    }\n}\n\n");
+
+        // Message
+        out.push_str("/// A protocol message\n#[derive(Debug, Clone, PartialEq, Eq)]\npub struct Message {\n");
+        out.push_str("    /// Protocol version\n    pub version: u8,\n");
+        out.push_str("    /// Message type\n    pub message_type: MessageType,\n");
+        for (field, width) in self.int_fields() {
+            let _ = writeln!(out, "    /// {}", field.description);
+            if field.is_optional() {
+                let _ = writeln!(
+                    out,
+                    "    pub {}: Option<{}>,",
+                    field.name,
+                    width.rust_type()
+                );
+            } else {
+                let _ = writeln!(out, "    pub {}: {},", field.name, width.rust_type());
+            }
+        }
+        out.push_str("    /// Message payload\n    pub payload: Vec<u8>,\n}\n\n");
+
+        out.push_str("impl Message {\n    /// Get the message type\n");
+        out.push_str("    pub fn message_type(&self) -> MessageType {\n        self.message_type\n    }\n}\n\n");
+
+        // Encoding
+        out.push_str("/// Encode a message to bytes\n");
+        out.push_str("pub fn encode(message: &Message) -> Result<Vec<u8>, ProtocolError> {\n");
+        out.push_str("    let payload_len = message.payload.len();\n");
+        out.push_str("    if payload_len > MAX_PAYLOAD_SIZE {\n        return Err(ProtocolError::PayloadTooLarge(payload_len));\n    }\n\n");
+        let _ = writeln!(
+            out,
+            "    let mut buf = Vec::with_capacity({} + payload_len);",
+            self.max_header_size()
+        );
+        for flag in self.field_flags() {
+            let present: Vec<String> = self
+                .flagged_fields(flag)
+                .map(|f| format!("message.{}.is_some()", f.name))
+                .collect();
+            let mut condition = present.join(" || ");
+            if let Some(field) = self.flagged_fields(flag).find(|f| f.is_conditional()) {
+                if present.len() > 1 {
+                    condition = format!("({})", condition);
+                }
+                condition = format!(
+                    "{} && {}",
+                    self.type_pattern(field, "message.message_type"),
+                    condition
+                );
+            }
+            let _ = writeln!(out, "    let has_{} = {};", flag.name, condition);
+        }
+        for field in &self.fields {
+            match field.kind {
+                FieldKind::Magic => out.push_str("    buf.extend_from_slice(&MAGIC);\n"),
+                FieldKind::Version => out.push_str("    buf.push(message.version);\n"),
+                FieldKind::Type => out.push_str("    buf.push(message.message_type as u8);\n"),
+                FieldKind::Int(_) if field.is_flagged() => {
+                    let flag = field.flag.as_deref().unwrap_or_default();
+                    let _ = writeln!(out, "    if has_{} {{", flag);
+                    let _ = writeln!(
+                        out,
+                        "        buf.extend_from_slice(&message.{}.unwrap_or_default().to_be_bytes());\n    }}",
+                        field.name
+                    );
+                }
+                FieldKind::Int(_) if field.is_conditional() => {
+                    let _ = writeln!(
+                        out,
+                        "    if {} {{",
+                        self.type_pattern(field, "message.message_type")
+                    );
+                    let _ = writeln!(
+                        out,
+                        "        buf.extend_from_slice(&message.{}.unwrap_or_default().to_be_bytes());\n    }}",
+                        field.name
+                    );
+                }
+                FieldKind::Int(_) => {
+                    let _ = writeln!(
+                        out,
+                        "    buf.extend_from_slice(&message.{}.to_be_bytes());",
+                        field.name
+                    );
+                }
+                FieldKind::Length(_) if self.field_flags().next().is_some() => {
+                    let _ = writeln!(out, "    let mut length = payload_len as {};", length_type);
+                    for flag in self.field_flags() {
+                        let _ = writeln!(
+                            out,
+                            "    if has_{} {{\n        length |= {};\n    }}",
+                            flag.name,
+                            flag.const_name()
+                        );
+                    }
+                    out.push_str("    buf.extend_from_slice(&length.to_be_bytes());\n");
+                }
+                FieldKind::Length(_) => {
+                    let _ = writeln!(
+                        out,
+                        "    buf.extend_from_slice(&(payload_len as {}).to_be_bytes());",
+                        length_type
+                    );
+                }
+                FieldKind::Payload => {
+                    out.push_str("    buf.extend_from_slice(&message.payload);\n")
+                }
+            }
+        }
+        out.push_str("\n    Ok(buf)\n}\n\n");
+
+        out.push_str("/// Write a message to a writer\n");
+        out.push_str("pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), ProtocolError> {\n");
+        out.push_str(
+            "    let bytes = encode(message)?;\n    writer.write_all(&bytes)?;\n    Ok(())\n}\n\n",
+        );
+
+        // Decoding
+        out.push_str("fn take<const N: usize>(bytes: &[u8], offset: &mut usize) -> Result<[u8; N], ProtocolError> {\n");
+        out.push_str("    let slice = bytes.get(*offset..*offset + N).ok_or(ProtocolError::IncompleteMessage)?;\n");
+        out.push_str(
+            "    *offset += N;\n    Ok(slice.try_into().expect(\"slice has length N\"))\n}\n\n",
+        );
+
+        out.push_str("/// Decode a message from bytes\n");
+        out.push_str("pub fn decode(bytes: &[u8]) -> Result<Message, ProtocolError> {\n");
+        out.push_str("    let mut offset = 0;\n");
+        self.emit_field_reads(&mut out, |len| {
+            format!("take::<{}>(bytes, &mut offset)?", len)
+        });
+        out.push_str("    let payload = bytes\n        .get(offset..offset + payload_len)\n");
+        out.push_str("        .ok_or(ProtocolError::IncompleteMessage)?\n        .to_vec();\n\n");
+        self.emit_payload_checks(&mut out);
+        self.emit_message_literal(&mut out);
+        out.push_str("}\n\n");
+
+        out.push_str("fn read_exact<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], ProtocolError> {\n");
+        out.push_str(
+            "    let mut buf = [0u8; N];\n    reader.read_exact(&mut buf)?;\n    Ok(buf)\n}\n\n",
+        );
+
+        out.push_str("/// Read a message from a reader\n");
+        out.push_str(
+            "pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {\n",
+        );
+        self.emit_field_reads(&mut out, |len| format!("read_exact::<_, {}>(reader)?", len));
+        out.push_str("    let mut payload = vec![0u8; payload_len];\n    reader.read_exact(&mut payload)?;\n\n");
+        self.emit_payload_checks(&mut out);
+        self.emit_message_literal(&mut out);
+        out.push_str("}\n");
+
+        if self.deflate_flag().is_some() {
+            out.push_str("\n/// Error for a compressed payload, which this codec frames but does not\n/// inflate\n");
+            out.push_str("fn compressed_payload(payload: &[u8]) -> ProtocolError {\n");
+            let _ = writeln!(
+                out,
+                "    let Some(prefix) = payload.get(..{}) else {{",
+                SIZE_PREFIX
+            );
+            out.push_str("        return ProtocolError::InvalidPayload(format!(\n");
+            out.push_str("            \"compressed payload of {} bytes has no size prefix\",\n");
+            out.push_str("            payload.len()\n        ));\n    };\n");
+            out.push_str("    let size = u32::from_be_bytes(prefix.try_into().expect(\"prefix has 4 bytes\")) as usize;\n");
+            out.push_str(
+                "    if size > MAX_PAYLOAD_SIZE {\n        ProtocolError::PayloadTooLarge(size)\n",
+            );
+            out.push_str(
+                "    } else {\n        ProtocolError::CompressedPayload(size)\n    }\n}\n",
+            );
+        }
+
+        out
+    }
+
+    fn type_pattern(&self, field: &FieldSpec, subject: &str) -> String {
+        let variants: Vec<String> = field
+            .when
+            .iter()
+            .map(|t| format!("MessageType::{}", t))
+            .collect();
+        format!("matches!({}, {})", subject, variants.join(" | "))
+    }
+
+    /// Emit `let` bindings for every header field. `read` renders an
+    /// expression yielding a `[u8; len]` array.
+    fn emit_field_reads(&self, out: &mut String, read: impl Fn(usize) -> String) {
+        for field in &self.fields {
+            match field.kind {
+                FieldKind::Magic => {
+                    let _ = writeln!(out, "    let magic = {};", read(self.magic.len()));
+                    out.push_str("    if magic != MAGIC {\n        return Err(ProtocolError::InvalidMagic(magic));\n    }\n");
+                }
+                FieldKind::Version => {
+                    let _ = writeln!(out, "    let [version] = {};", read(1));
+                    out.push_str("    if version != VERSION {\n        return Err(ProtocolError::UnsupportedVersion(version));\n    }\n");
+                }
+                FieldKind::Type => {
+                    let _ = writeln!(out, "    let [type_byte] = {};", read(1));
+                    out.push_str("    let message_type = MessageType::try_from(type_byte)?;\n");
+                }
+                FieldKind::Int(width) => {
+                    let expr = format!(
+                        "{}::from_be_bytes({})",
+                        width.rust_type(),
+                        read(width.size())
+                    );
+                    if let Some(flag) = &field.flag {
+                        let flag = self.flags.iter().find(|f| &f.name == flag);
+                        let _ = writeln!(
+                            out,
+                            "    let {} = if length & {} != 0 {{",
+                            field.name,
+                            flag.map(FlagSpec::const_name).unwrap_or_default()
+                        );
+                        let _ = writeln!(
+                            out,
+                            "        Some({})\n    }} else {{\n        None\n    }};",
+                            expr
+                        );
+                    } else if field.is_conditional() {
+                        let _ = writeln!(
+                            out,
+                            "    let {} = if {} {{",
+                            field.name,
+                            self.type_pattern(field, "message_type")
+                        );
+                        let _ = writeln!(
+                            out,
+                            "        Some({})\n    }} else {{\n        None\n    }};",
+                            expr
+                        );
+                    } else {
+                        let _ = writeln!(out, "    let {} = {};", field.name, expr);
+                    }
+                }
+                FieldKind::Length(width) if !self.flags.is_empty() => {
+                    let _ = writeln!(
+                        out,
+                        "    let length = {}::from_be_bytes({});",
+                        width.rust_type(),
+                        read(width.size())
+                    );
+                    let masks: Vec<String> = self.flags.iter().map(|f| f.const_name()).collect();
+                    let masks = match masks.as_slice() {
+                        [one] => one.clone(),
+                        many => format!("({})", many.join(" | ")),
+                    };
+                    let _ = writeln!(out, "    let payload_len = (length & !{}) as usize;", masks);
+                    out.push_str("    if payload_len > MAX_PAYLOAD_SIZE {\n        return Err(ProtocolError::PayloadTooLarge(payload_len));\n    }\n");
+                    for flag in self.field_flags() {
+                        let Some(field) = self.flagged_fields(flag).find(|f| f.is_conditional())
+                        else {
+                            continue;
+                        };
+                        let _ = writeln!(
+                            out,
+                            "    if length & {} != 0 && !{} {{",
+                            flag.const_name(),
+                            self.type_pattern(field, "message_type")
+                        );
+                        let _ = writeln!(
+                            out,
+                            "        return Err(ProtocolError::UnexpectedFlag(\"{}\", message_type));\n    }}",
+                            flag.name
+                        );
+                    }
+                }
+                FieldKind::Length(width) => {
+                    let _ = writeln!(
+                        out,
+                        "    let payload_len = {}::from_be_bytes({}) as usize;",
+                        width.rust_type(),
+                        read(width.size())
+                    );
+                    out.push_str("    if payload_len > MAX_PAYLOAD_SIZE {\n        return Err(ProtocolError::PayloadTooLarge(payload_len));\n    }\n");
+                }
+                FieldKind::Payload => {}
+            }
+        }
+    }
+
+    /// Emit the checks on a payload that has been read
+    fn emit_payload_checks(&self, out: &mut String) {
+        if let Some(flag) = self.deflate_flag() {
+            let _ = writeln!(out, "    if length & {} != 0 {{", flag.const_name());
+            out.push_str("        return Err(compressed_payload(&payload));\n    }\n\n");
+        }
+    }
+
+    fn emit_message_literal(&self, out: &mut String) {
+        out.push_str("    Ok(Message {\n        version,\n        message_type,\n");
+        for (field, _) in self.int_fields() {
+            let _ = writeln!(out, "        {},", field.name);
+        }
+        out.push_str("        payload,\n    })\n");
+    }
+
+    /// Generate the SPEC.md wire-format section (including markers)
+    pub fn generate_wire_format(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{}", SPEC_BEGIN_MARKER);
+        out.push_str("## 2. Wire Format\n\n### 2.1 Overview\n\n");
+        out.push_str("Every message is a single frame. Fields appear in this order:\n\n```\n");
+        let cells: Vec<String> = self
+            .fields
+            .iter()
+            .map(|f| {
+                if f.is_optional() {
+                    format!("[{}]", f.name)
+                } else {
+                    f.name.clone()
+                }
+            })
+            .collect();
+        let width = cells.iter().map(|c| c.len()).max().unwrap_or(0) + 2;
+        let border: String = cells
+            .iter()
+            .map(|_| format!("+{}", "-".repeat(width)))
+            .collect::<String>()
+            + "+";
+        let _ = writeln!(out, "{}", border);
+        let row: String = cells
+            .iter()
+            .map(|c| format!("|{:^width$}", c, width = width))
+            .collect::<String>()
+            + "|";
+        let _ = writeln!(out, "{}", row);
+        let _ = writeln!(out, "{}", border);
+        out.push_str("```\n\n");
+        if self.fields.iter().any(FieldSpec::is_flagged) {
+            out.push_str(
+                "Fields in brackets are present only for some message types or while a\nlength flag is set.\n\n",
+            );
+        } else {
+            out.push_str("Fields in brackets are present only for some message types.\n\n");
+        }
+
+        out.push_str("### 2.2 Header\n\n| Field | Size | Description | Present |\n|-------|------|-------------|---------|\n");
+        for f in &self.fields {
+            let size = match f.size {
+                Some(1) => "1 byte".to_string(),
+                Some(n) => format!("{} bytes", n),
+                None => "variable".to_string(),
+            };
+            let mut present = if f.is_conditional() {
+                f.when.join(", ")
+            } else {
+                "always".to_string()
+            };
+            if let Some(flag) = &f.flag {
+                present = if f.is_conditional() {
+                    format!("{}, if `{}` is set", present, flag)
+                } else {
+                    format!("if `{}` is set", flag)
+                };
+            }
+            let mut description = f.description.clone();
+            match f.kind {
+                FieldKind::Magic => {
+                    let _ = write!(
+                        description,
+                        " (`{}`)",
+                        self.magic
+                            .iter()
+                            .map(|b| format!("0x{:02X}", b))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    );
+                }
+                FieldKind::Version => {
+                    let _ = write!(description, " (currently {})", self.version);
+                }
+                _ => {}
+            }
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} |",
+                f.name, size, description, present
+            );
+        }
+        out.push('\n');
+
+        out.push_str("### 2.3 Message Types\n\n| Type | Value | Description |\n|------|-------|-------------|\n");
+        for t in &self.message_types {
+            let _ = writeln!(
+                out,
+                "| {} | 0x{:02X} | {} |",
+                t.name, t.value, t.description
+            );
+        }
+        out.push('\n');
+
+        out.push_str(
+            "### 2.4 Encoding\n\n- All integers are big-endian\n- Strings are UTF-8 encoded\n",
+        );
+        let _ = writeln!(
+            out,
+            "- The length field is a {}-byte unsigned integer",
+            self.length_width().size()
+        );
+        if !self.flags.is_empty() {
+            let _ = writeln!(
+                out,
+                "- The low {} bits of the length field give the payload length; flags\n  use the bits above:",
+                self.flags.iter().map(|f| f.bit).min().unwrap_or(0)
+            );
+            let mut flags: Vec<&FlagSpec> = self.flags.iter().collect();
+            flags.sort_by_key(|f| std::cmp::Reverse(f.bit));
+            for flag in flags {
+                let _ = write!(
+                    out,
+                    "  - Bit {} (`{}`): {}",
+                    flag.bit, flag.name, flag.description
+                );
+                if flag.deflate {
+                    let _ = write!(
+                        out,
+                        ".\n    The payload is then its original size ({} bytes, big-endian)\n    followed by a raw DEFLATE stream (RFC 1951)",
+                        SIZE_PREFIX
+                    );
+                } else {
+                    let fields: Vec<String> = self
+                        .flagged_fields(flag)
+                        .map(|f| format!("`{}`", f.name))
+                        .collect();
+                    let (fields, verb) = match fields.split_last() {
+                        Some((last, rest)) if !rest.is_empty() => {
+                            (format!("{} and {}", rest.join(", "), last), "follow")
+                        }
+                        _ => (fields.concat(), "follows"),
+                    };
+                    let _ = write!(out, ".\n    When set, {} {} the length field", fields, verb);
+                    if let Some(field) = self.flagged_fields(flag).find(|f| f.is_conditional()) {
+                        let _ = write!(
+                            out,
+                            ";\n    only {} frames may set it",
+                            field.when.join(", ")
+                        );
+                    }
+                }
+                out.push('\n');
+            }
+        }
+        let _ = writeln!(
+            out,
+            "- Payloads larger than {} bytes MUST be rejected",
+            self.max_payload
+        );
+        let _ = writeln!(
+            out,
+            "- Header size is {} to {} bytes depending on message type{}",
+            self.min_header_size(),
+            self.max_header_size(),
+            if self.fields.iter().any(FieldSpec::is_flagged) {
+                " and flags"
+            } else {
+                ""
+            }
+        );
+        out.push('\n');
+
+        if !self.error_codes.is_empty() {
+            out.push_str("### 2.5 Error Codes\n\n| Error Code | Name | Meaning |\n|------------|------|---------|\n");
+            for e in &self.error_codes {
+                let _ = writeln!(out, "| 0x{:02X} | {} | {} |", e.code, e.name, e.description);
+            }
+            out.push('\n');
+        }
+
+        out.push_str(SPEC_END_MARKER);
+        out.push('\n');
+        out
+    }
+
+    /// Replace the generated section of an existing SPEC.md
+    pub fn splice_wire_format(&self, spec_md: &str) -> Result<String, SpecError> {
+        let start = spec_md
+            .find(SPEC_BEGIN_MARKER)
+            .ok_or_else(|| SpecError::new(0, "SPEC.md has no generated wire-format section"))?;
+        let end = spec_md[start..]
+            .find(SPEC_END_MARKER)
+            .map(|i| start + i + SPEC_END_MARKER.len())
+            .ok_or_else(|| SpecError::new(0, "SPEC.md generated section is not terminated"))?;
+        let end = if spec_md[end..].starts_with('\n') {
+            end + 1
+        } else {
+            end
+        };
+
+        Ok(format!(
+            "{}{}{}",
+            &spec_md[..start],
+            self.generate_wire_format(),
+            &spec_md[end..]
+        ))
+    }
+
+    /// Generate a starter vectors test file for the generated crate
+    pub fn generate_vectors(&self, source_name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "//! Starter compliance vectors for {}", self.title);
+        out.push_str("//!\n");
+        let _ = writeln!(
+            out,
+            "//! Generated by `protocol-gen` from `{}`. Extend with",
+            source_name
+        );
+        out.push_str("//! hand-written cases as the protocol grows.\n\n");
+        let _ = writeln!(
+            out,
+            "use {}::{{decode, encode, MessageType, ProtocolError}};",
+            self.crate_name()
+        );
+
+        for vector in self.vectors() {
+            out.push('\n');
+            let _ = writeln!(out, "#[test]\nfn vector_{}() {{", vector.name);
+            let _ = writeln!(out, "    // {}", vector.description);
+            let _ = writeln!(
+                out,
+                "    let bytes: Vec<u8> = vec![{}];",
+                hex_list(&vector.bytes)
+            );
+            match &vector.expected {
+                Expected::Message {
+                    message_type,
+                    fields,
+                    payload,
+                } => {
+                    out.push_str(
+                        "\n    let message = decode(&bytes).expect(\"Should decode\");\n\n",
+                    );
+                    let _ = writeln!(
+                        out,
+                        "    assert_eq!(message.message_type, MessageType::{});",
+                        message_type
+                    );
+                    for (name, value) in fields {
+                        let conditional = self
+                            .fields
+                            .iter()
+                            .any(|f| &f.name == name && f.is_optional());
+                        match (conditional, value) {
+                            (true, Some(v)) => {
+                                let _ =
+                                    writeln!(out, "    assert_eq!(message.{}, Some({}));", name, v);
+                            }
+                            (true, None) => {
+                                let _ = writeln!(out, "    assert_eq!(message.{}, None);", name);
+                            }
+                            (false, v) => {
+                                let _ = writeln!(
+                                    out,
+                                    "    assert_eq!(message.{}, {});",
+                                    name,
+                                    v.unwrap_or(0)
+                                );
+                            }
+                        }
+                    }
+                    let _ = writeln!(
+                        out,
+                        "    assert_eq!(message.payload, b\"{}\");",
+                        String::from_utf8_lossy(payload)
+                    );
+                    out.push_str(
+                        "    assert_eq!(encode(&message).expect(\"Should encode\"), bytes);\n",
+                    );
+                }
+                Expected::Error(variant) => {
+                    let pattern = if variant == "IncompleteMessage" {
+                        variant.clone()
+                    } else {
+                        format!("{}(_)", variant)
+                    };
+                    let _ = writeln!(out, "\n    let result = decode(&bytes);");
+                    let _ = writeln!(
+                        out,
+                        "    assert!(matches!(result, Err(ProtocolError::{})));",
+                        pattern
+                    );
+                }
+            }
+            out.push_str("}\n");
+        }
+
+        out
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+protocol demo "Demo"
+magic 0xAB
+version 3
+field magic   bytes[1] "Magic"
+field version u8       "Version"
+field type    u8       "Type"
+field seq     u16      "Sequence" when Ping
+field length  u16      "Length"
+field payload bytes    "Payload"
+type Ping 0x01 "Ping"
+type Pong 0x02 "Pong"
+"#;
+
+    #[test]
+    fn test_parse_minimal() {
+        let spec = ProtocolSpec::parse(MINIMAL).unwrap();
+        assert_eq!(spec.name, "demo");
+        assert_eq!(spec.magic, vec![0xAB]);
+        assert_eq!(spec.fields.len(), 6);
+        assert_eq!(spec.header_size("Ping"), 7);
+        assert_eq!(spec.header_size("Pong"), 5);
+        assert_eq!(spec.length_width(), IntWidth::U16);
+    }
+
+    #[test]
+    fn test_parse_reports_line() {
+        let err = ProtocolSpec::parse("protocol x\nmagic 0x100\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn test_rejects_misplaced_payload() {
+        let source = MINIMAL.replace("field payload bytes    \"Payload\"\n", "")
+            .replace("field length  u16      \"Length\"\n", "field length u16 \"Length\"\nfield payload bytes \"Payload\"\nfield tail u8 \"Tail\"\n");
+        assert!(ProtocolSpec::parse(&source).is_err());
+    }
+
+    #[test]
+    fn test_flags_share_the_length_field() {
+        let source = format!("{}flag packed 15 \"Packed\" deflate\n", MINIMAL);
+        let spec = ProtocolSpec::parse(&source).unwrap();
+        assert_eq!(spec.max_payload, 0x7FFF);
+        assert_eq!(spec.length_mask(), 0x7FFF);
+        assert_eq!(spec.deflate_flag().map(|f| f.mask()), Some(0x8000));
+
+        let too_large = spec
+            .vectors()
+            .into_iter()
+            .find(|v| v.name == "packed_size_too_large")
+            .unwrap();
+        assert_eq!(
+            too_large.bytes,
+            vec![0xAB, 3, 0x01, 0x00, 0x01, 0x80, 0x04, 0x00, 0x00, 0x80, 0x00]
+        );
+
+        let overlapping = format!("{}max_payload 0x8000\n", source);
+        assert!(ProtocolSpec::parse(&overlapping).is_err());
+        let outside = format!("{}flag wide 16 \"Wide\" deflate\n", MINIMAL);
+        assert!(ProtocolSpec::parse(&outside).is_err());
+    }
+
+    #[test]
+    fn test_flagged_fields_follow_the_length() {
+        let source = MINIMAL.replace(
+            "field payload",
+            "field ttl u8 \"TTL\" if timed when Ping\nfield payload",
+        ) + "flag timed 15 \"TTL follows the length\"\n";
+        let spec = ProtocolSpec::parse(&source).unwrap();
+        assert_eq!(spec.header_size("Ping"), 8);
+        assert_eq!(spec.min_header_size(), 5);
+
+        let vectors = spec.vectors();
+        let timed = vectors
+            .iter()
+            .find(|v| v.name == "ping_with_timed")
+            .unwrap();
+        assert_eq!(
+            timed.bytes,
+            vec![0xAB, 3, 0x01, 0x00, 0x01, 0x80, 0x02, 0x02, b'h', b'i']
+        );
+        let misplaced = vectors.iter().find(|v| v.name == "timed_on_pong").unwrap();
+        assert_eq!(
+            misplaced.expected,
+            Expected::Error("UnexpectedFlag".to_string())
+        );
+
+        // Flagged fields sit between length and payload, and need their flag
+        let before_length = source
+            .replace("field ttl u8 \"TTL\" if timed when Ping\n", "")
+            .replace(
+                "field length",
+                "field ttl u8 \"TTL\" if timed\nfield length",
+            );
+        assert!(ProtocolSpec::parse(&before_length).is_err());
+        let unflagged = source.replace(" if timed when Ping", "");
+        assert!(ProtocolSpec::parse(&unflagged).is_err());
+        let unknown = source.replace("if timed", "if late");
+        assert!(ProtocolSpec::parse(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_vectors_cover_every_type() {
+        let spec = ProtocolSpec::parse(MINIMAL).unwrap();
+        let vectors = spec.vectors();
+        assert!(vectors.iter().any(|v| v.name == "ping_minimal"));
+        assert!(vectors.iter().any(|v| v.name == "pong_with_payload"));
+        let ping = vectors.iter().find(|v| v.name == "ping_minimal").unwrap();
+        assert_eq!(ping.bytes, vec![0xAB, 3, 0x01, 0x00, 0x01, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,982 @@
+//! Per-frame payload compression
+//!
+//! Any frame's payload may be sent compressed. The sender says so by setting
+//! the top bit of the length field ([`COMPRESSED`]), which is otherwise
+//! always clear because lengths never exceed `MAX_PAYLOAD_SIZE` (SPEC.md
+//! Section 4.9). The length then counts the compressed payload: the original
+//! size as a 4-byte big-endian integer, followed by a raw DEFLATE stream
+//! (RFC 1951).
+//!
+//! The choice is made frame by frame, so there is nothing to negotiate up
+//! front: [`Compression`] compresses payloads of at least a minimum size,
+//! and only when that makes them smaller. Receivers accept both forms;
+//! [`decode`](crate::decode) and [`read_message`](crate::read_message)
+//! decompress transparently, so a message decodes the same however it was
+//! sent.
+//!
+//! Decompression is bounded. A declared size above `MAX_PAYLOAD_SIZE` is
+//! rejected before anything is inflated, and the stream must inflate to
+//! exactly the declared size, so a small frame cannot expand without limit.
+//!
+//! [`deflate`] writes fixed-Huffman blocks, or stored blocks for data that
+//! does not compress. [`inflate`] accepts any DEFLATE stream, including the
+//! dynamic-Huffman blocks written by zlib.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use protocol_name::compress::Compression;
+//! use protocol_name::{decode, Message};
+//!
+//! let message = Message::request(1, "{\"key\": \"value\"}, ".repeat(100).as_bytes());
+//! let bytes = Compression::new().encode(&message).unwrap();
+//! assert!(bytes.len() < message.encoded_len() / 10);
+//! assert_eq!(decode(&bytes).unwrap(), message);
+//! ```
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use crate::deadline::OPTIONS;
+use crate::{encode_header, Field, Message, ProtocolError, MAX_HEADER_SIZE, MAX_PAYLOAD_SIZE};
+#[cfg(feature = "std")]
+use crate::{write_all_vectored, write_message};
+
+/// Flag in the length field marking a compressed payload
+pub const COMPRESSED: u32 = 0x8000_0000;
+
+/// Size of the original-size prefix of a compressed payload
+pub const SIZE_PREFIX: usize = 4;
+
+/// When to compress outgoing payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Compression {
+    /// Smallest payload compressed unless configured
+    pub const DEFAULT_MIN_SIZE: usize = 256;
+
+    /// Compress payloads of at least [`DEFAULT_MIN_SIZE`](Self::DEFAULT_MIN_SIZE) bytes
+    pub fn new() -> Self {
+        Self {
+            min_size: Self::DEFAULT_MIN_SIZE,
+        }
+    }
+
+    /// Compress only payloads of at least `bytes` bytes
+    ///
+    /// Small payloads rarely shrink enough to pay for the size prefix and
+    /// the work of compressing them.
+    pub fn with_min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Smallest payload compressed
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// The compressed form of `payload` (size prefix and DEFLATE stream), or
+    /// `None` if it should be sent as is
+    ///
+    /// Payloads below the minimum size are not compressed, nor are those
+    /// compression would not make smaller.
+    pub fn compress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < self.min_size || payload.len() > MAX_PAYLOAD_SIZE {
+            return None;
+        }
+        let mut compressed = Vec::with_capacity(SIZE_PREFIX + payload.len() / 2);
+        compressed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        deflate_into(payload, &mut compressed);
+        (compressed.len() < payload.len()).then_some(compressed)
+    }
+
+    /// Encode a message, compressing its payload if worthwhile
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.encode_into(message, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Encode a message by appending to an existing buffer, compressing its
+    /// payload if worthwhile
+    ///
+    /// On error, `buf` is left unchanged.
+    pub fn encode_into(&self, message: &Message, buf: &mut Vec<u8>) -> Result<(), ProtocolError> {
+        let mut header = [0u8; MAX_HEADER_SIZE];
+        let header_len = encode_header(message, &mut header)?;
+        let compressed = self.compress(&message.payload);
+        let payload = match &compressed {
+            Some(compressed) => {
+                mark_compressed(&mut header, message, compressed.len());
+                compressed
+            }
+            None => &message.payload,
+        };
+        buf.reserve(header_len + payload.len());
+        buf.extend_from_slice(&header[..header_len]);
+        buf.extend_from_slice(payload);
+        Ok(())
+    }
+
+    /// Write a message, compressing its payload if worthwhile
+    #[cfg(feature = "std")]
+    pub fn write_message<W: Write>(
+        &self,
+        writer: &mut W,
+        message: &Message,
+    ) -> Result<(), ProtocolError> {
+        let Some(compressed) = self.compress(&message.payload) else {
+            return write_message(writer, message);
+        };
+        let mut header = [0u8; MAX_HEADER_SIZE];
+        let header_len = encode_header(message, &mut header)?;
+        mark_compressed(&mut header, message, compressed.len());
+        write_all_vectored(writer, &header[..header_len], &compressed)?;
+        Ok(())
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rewrite the length field of `message`'s encoded header, keeping its
+/// options flag
+fn mark_compressed(header: &mut [u8], message: &Message, compressed_len: usize) {
+    let length = Field::Length.offset() + usize::from(message.status.is_some());
+    let field = &mut header[length..length + 4];
+    let flags = u32::from_be_bytes([field[0], field[1], field[2], field[3]]) & OPTIONS;
+    field.copy_from_slice(&(compressed_len as u32 | COMPRESSED | flags).to_be_bytes());
+}
+
+/// Decompress the payload of a frame whose length field carried
+/// [`COMPRESSED`]
+///
+/// `length_offset` locates the length field for errors.
+pub(crate) fn expand(payload: &[u8], length_offset: usize) -> Result<Vec<u8>, ProtocolError> {
+    let prefix: [u8; SIZE_PREFIX] = payload
+        .get(..SIZE_PREFIX)
+        .and_then(|prefix| prefix.try_into().ok())
+        .ok_or_else(|| {
+            ProtocolError::InvalidPayload(format!(
+                "compressed payload of {} bytes has no size prefix",
+                payload.len()
+            ))
+        })?;
+    let size = u32::from_be_bytes(prefix) as usize;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::PayloadTooLarge {
+            offset: length_offset,
+            size,
+            max: MAX_PAYLOAD_SIZE,
+        });
+    }
+    let data = inflate(&payload[SIZE_PREFIX..], size)?;
+    if data.len() != size {
+        return Err(ProtocolError::InvalidPayload(format!(
+            "compressed payload inflated to {} bytes, {} declared",
+            data.len(),
+            size
+        )));
+    }
+    Ok(data)
+}
+
+// ============================================================================
+// DEFLATE Tables (RFC 1951 Section 3.2.5)
+// ============================================================================
+
+/// Base match length of length symbols 257..=285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Extra bits of length symbols 257..=285
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distance of distance symbols 0..=29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Extra bits of distance symbols 0..=29
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which code length code lengths are sent in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// End-of-block symbol
+const END_OF_BLOCK: u16 = 256;
+
+/// Largest amount of data in one stored block
+const MAX_STORED: usize = 0xFFFF;
+
+// ============================================================================
+// Compression
+// ============================================================================
+
+/// Distance a match may reach back
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// Candidates examined per position; bounds the work on repetitive input
+const MAX_CHAIN: usize = 64;
+const NO_POSITION: usize = usize::MAX;
+
+/// Compress `input` into a raw DEFLATE stream
+pub fn deflate(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    deflate_into(input, &mut out);
+    out
+}
+
+fn deflate_into(input: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    let mut writer = BitWriter::new(out);
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman)
+    writer.bits(0b011, 3);
+
+    let mut matcher = Matcher::new(input);
+    let mut pos = 0;
+    while pos < input.len() {
+        let (length, distance) = matcher.longest_match(pos);
+        if length >= MIN_MATCH {
+            write_match(&mut writer, length, distance);
+            for p in pos..pos + length {
+                matcher.insert(p);
+            }
+            pos += length;
+        } else {
+            write_fixed_symbol(&mut writer, u16::from(input[pos]));
+            matcher.insert(pos);
+            pos += 1;
+        }
+    }
+    write_fixed_symbol(&mut writer, END_OF_BLOCK);
+    writer.finish();
+
+    if out.len() - start > stored_len(input.len()) {
+        out.truncate(start);
+        write_stored(input, out);
+    }
+}
+
+/// Finds earlier occurrences of the bytes at a position through hash chains
+/// of 3-byte prefixes
+struct Matcher<'a> {
+    input: &'a [u8],
+    /// Most recent position with each hash
+    head: Vec<usize>,
+    /// Previous position with the same hash, for the last `WINDOW_SIZE`
+    /// positions (indexed modulo the window)
+    prev: Vec<usize>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            head: vec![NO_POSITION; 1 << HASH_BITS],
+            prev: vec![NO_POSITION; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.input[pos..pos + MIN_MATCH];
+        let key = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.input.len() {
+            let hash = self.hash(pos);
+            self.prev[pos % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = pos;
+        }
+    }
+
+    /// Length and distance of the longest match for `pos`, or a length of 0
+    fn longest_match(&self, pos: usize) -> (usize, usize) {
+        let max_len = MAX_MATCH.min(self.input.len() - pos);
+        if max_len < MIN_MATCH {
+            return (0, 0);
+        }
+        let (mut best_len, mut best_distance) = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = 0;
+        let target = &self.input[pos..pos + max_len];
+        while candidate != NO_POSITION && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+            let earlier = &self.input[candidate..candidate + max_len];
+            // Only a candidate agreeing at the current best length can beat it
+            let probe = best_len.min(max_len - 1);
+            if earlier[probe] == target[probe] {
+                let len = earlier
+                    .iter()
+                    .zip(target)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_distance = pos - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+            }
+            // Entries are overwritten only once they fall out of the window,
+            // which ends the walk
+            candidate = self.prev[candidate % WINDOW_SIZE];
+            chain += 1;
+        }
+        (best_len, best_distance)
+    }
+}
+
+/// Write a literal, length or end-of-block symbol with the fixed code
+fn write_fixed_symbol(writer: &mut BitWriter<'_>, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    writer.huffman(code, len);
+}
+
+fn write_match(writer: &mut BitWriter<'_>, length: usize, distance: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .expect("length is at least 3");
+    write_fixed_symbol(writer, 257 + index as u16);
+    writer.bits(
+        length as u32 - u32::from(LENGTH_BASE[index]),
+        LENGTH_EXTRA[index],
+    );
+
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .expect("distance is at least 1");
+    writer.huffman(index as u16, 5);
+    writer.bits(
+        distance as u32 - u32::from(DISTANCE_BASE[index]),
+        DISTANCE_EXTRA[index],
+    );
+}
+
+/// Size of `len` bytes sent as stored blocks
+fn stored_len(len: usize) -> usize {
+    len.div_ceil(MAX_STORED).max(1) * 5 + len
+}
+
+fn write_stored(input: &[u8], out: &mut Vec<u8>) {
+    let mut chunks = input.chunks(MAX_STORED).peekable();
+    if chunks.peek().is_none() {
+        // An empty stream still needs its final block
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        // BFINAL, BTYPE = 00 (stored), then padding to the byte boundary
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Packs bits least significant first, as DEFLATE requires
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    len: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    /// Write the low `count` bits of `value`
+    fn bits(&mut self, value: u32, count: u8) {
+        self.acc |= value << self.len;
+        self.len += count;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which is packed most significant bit first
+    fn huffman(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len);
+        self.bits(u32::from(reversed), len);
+    }
+
+    /// Flush the last partial byte
+    fn finish(self) {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+    }
+}
+
+// ============================================================================
+// Decompression
+// ============================================================================
+
+/// Decompress a raw DEFLATE stream, failing if it would produce more than
+/// `limit` bytes
+///
+/// The stream must end exactly at the end of `input`, apart from the
+/// padding bits of its last byte.
+pub fn inflate(input: &[u8], limit: usize) -> Result<Vec<u8>, ProtocolError> {
+    let mut reader = BitReader::new(input);
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => inflate_stored(&mut reader, &mut out, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_codes(&mut reader, &mut out, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_codes(&mut reader, &mut out, limit, &literals, &distances)?;
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        if last {
+            break;
+        }
+    }
+    let trailing = input.len() - reader.pos;
+    if trailing > 0 {
+        return Err(corrupt(&format!("{} trailing bytes", trailing)));
+    }
+    Ok(out)
+}
+
+fn corrupt(message: &str) -> ProtocolError {
+    ProtocolError::InvalidPayload(format!("invalid DEFLATE stream: {}", message))
+}
+
+/// Fail if `out` cannot grow by `len` bytes within `limit`
+fn reserve(out: &[u8], len: usize, limit: usize) -> Result<(), ProtocolError> {
+    if len > limit - out.len() {
+        return Err(ProtocolError::InvalidPayload(format!(
+            "compressed payload inflates beyond {} bytes",
+            limit
+        )));
+    }
+    Ok(())
+}
+
+fn inflate_stored(
+    reader: &mut BitReader<'_>,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), ProtocolError> {
+    reader.align();
+    let len = reader.bits(16)? as u16;
+    let complement = reader.bits(16)? as u16;
+    if len != !complement {
+        return Err(corrupt("stored block length does not match its complement"));
+    }
+    let len = usize::from(len);
+    reserve(out, len, limit)?;
+    let data = reader
+        .input
+        .get(reader.pos..reader.pos + len)
+        .ok_or_else(|| corrupt("unexpected end of data"))?;
+    out.extend_from_slice(data);
+    reader.pos += len;
+    Ok(())
+}
+
+fn inflate_codes(
+    reader: &mut BitReader<'_>,
+    out: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), ProtocolError> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < END_OF_BLOCK {
+            reserve(out, 1, limit)?;
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let index = usize::from(symbol - 257);
+        if index >= LENGTH_BASE.len() {
+            return Err(corrupt("invalid length symbol"));
+        }
+        let length = usize::from(LENGTH_BASE[index]) + reader.bits(LENGTH_EXTRA[index])? as usize;
+
+        let index = usize::from(distances.decode(reader)?);
+        if index >= DISTANCE_BASE.len() {
+            return Err(corrupt("invalid distance symbol"));
+        }
+        let distance =
+            usize::from(DISTANCE_BASE[index]) + reader.bits(DISTANCE_EXTRA[index])? as usize;
+        if distance > out.len() {
+            return Err(corrupt("distance reaches before the start of the data"));
+        }
+
+        reserve(out, length, limit)?;
+        let start = out.len() - distance;
+        for i in start..start + length {
+            out.push(out[i]);
+        }
+    }
+}
+
+/// The fixed literal/length and distance codes
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).expect("fixed literal code is complete");
+    let distances = Huffman::new(&[5; 30]).expect("fixed distance code is valid");
+    (literals, distances)
+}
+
+/// Read the code definitions at the start of a dynamic block
+fn dynamic_codes(reader: &mut BitReader<'_>) -> Result<(Huffman, Huffman), ProtocolError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(corrupt("too many length or distance codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let total = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("repeat with no previous length"))?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > total {
+            return Err(corrupt("code lengths repeat past the end"));
+        }
+        lengths.resize(lengths.len() + repeat, length);
+    }
+    if lengths[usize::from(END_OF_BLOCK)] == 0 {
+        return Err(corrupt("no end-of-block code"));
+    }
+
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Ok((literals, distances))
+}
+
+/// A canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build the code from the code length of each symbol (0 for unused)
+    ///
+    /// Over-subscribed lengths are rejected. An incomplete code is allowed;
+    /// its unassigned codes fail to decode.
+    fn new(lengths: &[u8]) -> Result<Self, ProtocolError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(corrupt("over-subscribed code lengths"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.iter().filter(|&&len| len != 0).count()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                let slot = &mut offsets[usize::from(len)];
+                symbols[usize::from(*slot)] = symbol as u16;
+                *slot += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader<'_>) -> Result<u16, ProtocolError> {
+        // First code of the current length, and the index of its symbol
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+/// Reads bits least significant first
+struct BitReader<'a> {
+    input: &'a [u8],
+    /// Next byte to load
+    pos: usize,
+    acc: u32,
+    len: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    /// Read `count` bits (at most 16)
+    ///
+    /// Bytes are loaded only as needed, so fewer than 8 bits are buffered
+    /// between calls.
+    fn bits(&mut self, count: u8) -> Result<u32, ProtocolError> {
+        while self.len < count {
+            let byte = *self
+                .input
+                .get(self.pos)
+                .ok_or_else(|| corrupt("unexpected end of data"))?;
+            self.acc |= u32::from(byte) << self.len;
+            self.pos += 1;
+            self.len += 8;
+        }
+        let value = self.acc & ((1 << count) - 1);
+        self.acc >>= count;
+        self.len -= count;
+        Ok(value)
+    }
+
+    /// Discard the rest of the current byte
+    fn align(&mut self) {
+        self.acc = 0;
+        self.len = 0;
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::deadline::RequestOptions;
+    use crate::decode;
+    #[cfg(feature = "std")]
+    use crate::reader::FrameReader;
+    use crate::rng::Rng;
+    #[cfg(feature = "std")]
+    use crate::{encode, read_message};
+    use alloc::string::String;
+
+    fn json(records: usize) -> Vec<u8> {
+        let records: Vec<String> = (0..records)
+            .map(|i| {
+                format!(
+                    "{{\"id\": {}, \"name\": \"item {}\", \"tags\": [\"a\", \"b\"], \"ok\": {}}}",
+                    i,
+                    i,
+                    i % 3 == 0
+                )
+            })
+            .collect();
+        format!("[{}]", records.join(", ")).into_bytes()
+    }
+
+    fn random(rng: &mut Rng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let mut rng = Rng::new(42);
+        let noise = random(&mut rng, 40_000);
+        let inputs = [
+            Vec::new(),
+            b"a".to_vec(),
+            b"abcabcabcabc".to_vec(),
+            json(2_000),
+            vec![0; 100_000],
+            random(&mut rng, 70_000),
+            // Repeats at the edge of the window and beyond it
+            [&noise[..32_000], &noise[..32_000]].concat(),
+            [&noise[..], &noise[..]].concat(),
+        ];
+        for input in &inputs {
+            let compressed = deflate(input);
+            assert!(
+                compressed.len() <= stored_len(input.len()),
+                "{} bytes",
+                input.len()
+            );
+            assert_eq!(
+                inflate(&compressed, input.len()).as_ref(),
+                Ok(input),
+                "{} bytes",
+                input.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_deflate_vector() {
+        // Literals "abc", then a match of 9 at distance 3 (SPEC.md Section 4.9)
+        assert_eq!(
+            deflate(b"abcabcabcabc"),
+            [0x4B, 0x4C, 0x4A, 0x86, 0x23, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_incompressible_input_is_stored() {
+        let input = random(&mut Rng::new(7), 100_000);
+        let compressed = deflate(&input);
+        assert_eq!(compressed.len(), stored_len(input.len()));
+        assert_eq!(
+            compressed[0] & 0b111,
+            0b000,
+            "first block is stored and not final"
+        );
+        assert_eq!(inflate(&compressed, input.len()), Ok(input));
+    }
+
+    #[test]
+    fn test_inflates_dynamic_blocks() {
+        // zlib's raw deflate of a 96-byte a/b string, level 9: one dynamic block
+        let stream = [
+            0x3D, 0x8C, 0xC1, 0x11, 0x00, 0x30, 0x08, 0xC2, 0x66, 0x4D, 0xF6, 0x1F, 0xA2, 0x47,
+            0x51, 0x7D, 0x08, 0x44, 0x4E, 0x50, 0xC0, 0x4C, 0x25, 0x29, 0xA6, 0xA1, 0x20, 0x24,
+            0xD7, 0xDD, 0x57, 0xE0, 0x13, 0x46, 0x5D, 0x3B, 0x4F, 0xD0, 0x07,
+        ];
+        let expected = "aabbaaabbbbbaabbbbaaababbbabbbbabbbaaaababaabbabaabbabbabbbabbbaabaababaabaabbababaababaaabababb";
+        assert_eq!(stream[0] >> 1 & 0b11, 2);
+        assert_eq!(inflate(&stream, 96), Ok(expected.as_bytes().to_vec()));
+    }
+
+    /// Assemble a stream bit by bit
+    fn stream(write: impl FnOnce(&mut BitWriter<'_>)) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = BitWriter::new(&mut out);
+        write(&mut writer);
+        writer.finish();
+        out
+    }
+
+    #[test]
+    fn test_rejects_corrupt_streams() {
+        let valid = deflate(b"abcabcabcabc");
+        // Fixed block: literal 'a', then a match of 3 at distance 2
+        let too_far = stream(|w| {
+            w.bits(0b011, 3);
+            write_fixed_symbol(w, u16::from(b'a'));
+            write_match(w, 3, 2);
+            write_fixed_symbol(w, END_OF_BLOCK);
+        });
+        // Dynamic block giving three code length codes a length of 1
+        let over_subscribed = stream(|w| {
+            w.bits(0b101, 3);
+            w.bits(0, 5 + 5 + 4);
+            for len in [1, 1, 1, 0] {
+                w.bits(len, 3);
+            }
+        });
+        let cases: [(&[u8], &str); 6] = [
+            (&[0x07], "invalid block type"),
+            (&valid[..3], "unexpected end of data"),
+            (&[valid.as_slice(), &[0]].concat(), "1 trailing bytes"),
+            (
+                &[0x01, 0x01, 0x00, 0xFF, 0xFF, 0x00],
+                "does not match its complement",
+            ),
+            (&too_far, "distance reaches before the start"),
+            (&over_subscribed, "over-subscribed"),
+        ];
+        for (stream, expected) in cases {
+            match inflate(stream, 1024) {
+                Err(ProtocolError::InvalidPayload(message)) => {
+                    assert!(message.contains(expected), "{}", message)
+                }
+                other => panic!("{:02X?}: expected {:?}, got {:?}", stream, expected, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inflate_is_bounded() {
+        // 1 MiB of zeros compresses to a few KB; a smaller limit stops it early
+        let bomb = deflate(&[0; MAX_PAYLOAD_SIZE]);
+        assert!(bomb.len() < 8 * 1024);
+        assert!(matches!(
+            inflate(&bomb, 1000),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+        assert_eq!(
+            inflate(&bomb, MAX_PAYLOAD_SIZE).map(|data| data.len()),
+            Ok(MAX_PAYLOAD_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_threshold_and_benefit() {
+        let compression = Compression::new().with_min_size(100);
+        assert_eq!(compression.compress(&[0; 99]), None);
+        assert_eq!(compression.compress(&random(&mut Rng::new(1), 1000)), None);
+
+        let payload = json(50);
+        let compressed = compression.compress(&payload).expect("JSON compresses");
+        assert_eq!(
+            compressed[..SIZE_PREFIX],
+            (payload.len() as u32).to_be_bytes()
+        );
+        assert!(compressed.len() < payload.len() / 3);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_compressed_frames_decode_transparently() {
+        let compression = Compression::new();
+        let messages = [
+            Message::request(1, &json(100)),
+            Message::response(2, 0, &json(10)),
+            Message::error(3, 0x01, &"bad request ".repeat(50)),
+            Message::request(4, b"small"),
+            Message::request(5, &json(100)).with_options(RequestOptions::new().with_budget_ms(100)),
+        ];
+        for message in &messages {
+            let bytes = compression.encode(message).unwrap();
+            let flagged = message.payload.len() >= Compression::DEFAULT_MIN_SIZE;
+            assert_eq!(bytes.len() < encode(message).unwrap().len(), flagged);
+            let length_offset = 8 + usize::from(message.status.is_some());
+            assert_eq!(bytes[length_offset] & 0x80 != 0, flagged);
+            assert_eq!(bytes[length_offset] & 0x40 != 0, message.options.is_some());
+
+            assert_eq!(decode(&bytes).as_ref(), Ok(message));
+            assert_eq!(read_message(&mut bytes.as_slice()).as_ref(), Ok(message));
+
+            let mut written = Vec::new();
+            compression.write_message(&mut written, message).unwrap();
+            assert_eq!(written, bytes);
+        }
+
+        let stream: Vec<u8> = messages
+            .iter()
+            .flat_map(|m| compression.encode(m).unwrap())
+            .collect();
+        let mut reader = FrameReader::new(stream.as_slice());
+        for message in &messages {
+            assert_eq!(reader.read_message().as_ref(), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_declared_size_is_enforced() {
+        let frame = |size: u32, stream: &[u8]| {
+            let mut payload = size.to_be_bytes().to_vec();
+            payload.extend_from_slice(stream);
+            let mut bytes = vec![0x54, 0x55, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01];
+            bytes.extend_from_slice(&(payload.len() as u32 | COMPRESSED).to_be_bytes());
+            bytes.extend(payload);
+            bytes
+        };
+        let stream = deflate(b"abcabcabcabc");
+
+        // Above MAX_PAYLOAD_SIZE: rejected before inflating
+        match decode(&frame(MAX_PAYLOAD_SIZE as u32 + 1, &stream)) {
+            Err(ProtocolError::PayloadTooLarge {
+                offset: 8, size, ..
+            }) => assert_eq!(size, MAX_PAYLOAD_SIZE + 1),
+            other => panic!("expected PayloadTooLarge, got {:?}", other),
+        }
+        // Inflating past the declared size, or short of it
+        for size in [11, 13] {
+            assert!(matches!(
+                decode(&frame(size, &stream)),
+                Err(ProtocolError::InvalidPayload(_))
+            ));
+        }
+        // No room for the size prefix
+        let mut bytes = frame(0, &[]);
+        bytes[11] = 2;
+        bytes.truncate(14);
+        assert!(matches!(
+            decode(&bytes),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+
+        assert_eq!(
+            decode(&frame(12, &stream)).unwrap().payload,
+            b"abcabcabcabc"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+//! Request deadlines and priorities
+//!
+//! A `Request` frame may carry an options block (SPEC.md Section 4.11)
+//! giving the time the sender is still willing to wait for the answer and
+//! how urgent the request is. The sender says so by setting bit 30 of the
+//! length field ([`OPTIONS`]); the five bytes after the length field are
+//! then the budget in milliseconds and the priority.
+//!
+//! The budget is relative, so the peers' clocks need not agree: the
+//! receiver turns it into a local deadline when the frame arrives. A
+//! [`Server`](crate::server::Server) answers a request whose deadline has
+//! passed with `DeadlineExceeded` instead of handling it, and starts
+//! higher-priority requests first when they queue for a worker.
+//!
+//! While a handler runs, its request's deadline is the thread's
+//! [`current`] deadline. A [`Client`](crate::client::Client) calling
+//! another service from the handler sends whatever is left of it as its own
+//! request's budget, so the whole chain of calls gives up together instead
+//! of piling up work nobody will wait for.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use protocol_name::deadline::RequestOptions;
+//! use protocol_name::{decode, encode, Message};
+//!
+//! let options = RequestOptions::new().with_budget(Duration::from_millis(250)).with_priority(200);
+//! let request = Message::request(1, b"hello").with_options(options);
+//! assert_eq!(decode(&encode(&request).unwrap()).unwrap().options, Some(options));
+//! ```
+
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::cell::Cell;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+/// Flag in the length field marking a frame that carries request options
+pub const OPTIONS: u32 = 0x4000_0000;
+
+/// Size of the options block following the length field
+pub const OPTIONS_LEN: usize = 5;
+
+/// Budget field value of a request without a deadline
+const NO_BUDGET: u32 = u32::MAX;
+
+/// Deadline and priority carried by a `Request`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestOptions {
+    budget_ms: Option<u32>,
+    priority: u8,
+}
+
+impl RequestOptions {
+    /// Priority of requests that carry no options
+    pub const NORMAL_PRIORITY: u8 = 128;
+
+    /// Options with no deadline and normal priority
+    pub const fn new() -> Self {
+        Self {
+            budget_ms: None,
+            priority: Self::NORMAL_PRIORITY,
+        }
+    }
+
+    /// Time the sender will wait for the answer, rounded down to whole
+    /// milliseconds
+    ///
+    /// Budgets too long for the wire are sent as the longest it can carry.
+    pub fn with_budget(self, budget: Duration) -> Self {
+        self.with_budget_ms(u32::try_from(budget.as_millis()).unwrap_or(u32::MAX))
+    }
+
+    /// Time the sender will wait for the answer, in milliseconds
+    pub fn with_budget_ms(mut self, budget_ms: u32) -> Self {
+        self.budget_ms = Some(budget_ms.min(NO_BUDGET - 1));
+        self
+    }
+
+    /// Scheduling priority: higher values are handled first
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Time the sender will wait for the answer, if limited
+    pub fn budget(&self) -> Option<Duration> {
+        self.budget_ms
+            .map(|ms| Duration::from_millis(u64::from(ms)))
+    }
+
+    /// [`budget`](Self::budget) in milliseconds, as sent
+    pub fn budget_ms(&self) -> Option<u32> {
+        self.budget_ms
+    }
+
+    /// Scheduling priority
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Whether the budget was used up before the request was sent
+    pub fn is_expired(&self) -> bool {
+        self.budget_ms == Some(0)
+    }
+
+    /// Local deadline of a request carrying these options that arrived at
+    /// `received`
+    #[cfg(feature = "std")]
+    pub fn deadline(&self, received: Instant) -> Option<Instant> {
+        self.budget().map(|budget| received + budget)
+    }
+
+    /// Encode the options block
+    pub fn to_bytes(&self) -> [u8; OPTIONS_LEN] {
+        let budget = self.budget_ms.unwrap_or(NO_BUDGET).to_be_bytes();
+        [budget[0], budget[1], budget[2], budget[3], self.priority]
+    }
+
+    /// Decode the options block
+    pub fn from_bytes(bytes: [u8; OPTIONS_LEN]) -> Self {
+        let budget = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        Self {
+            budget_ms: (budget != NO_BUDGET).then_some(budget),
+            priority: bytes[4],
+        }
+    }
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ============================================================================
+// Current Deadline
+// ============================================================================
+
+#[cfg(feature = "std")]
+thread_local! {
+    static CURRENT: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Deadline of the request being handled on this thread, if it has one
+#[cfg(feature = "std")]
+pub fn current() -> Option<Instant> {
+    CURRENT.with(Cell::get)
+}
+
+/// Time left before the [`current`] deadline, zero once it has passed
+#[cfg(feature = "std")]
+pub fn remaining() -> Option<Duration> {
+    current().map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Run `f` with `deadline` as the [`current`] deadline
+///
+/// A deadline already in force that is earlier still applies. The previous
+/// deadline is restored when `f` returns.
+#[cfg(feature = "std")]
+pub fn with_deadline<R>(deadline: Option<Instant>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Instant>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let previous = current();
+    let _restore = Restore(previous);
+    let effective = match (previous, deadline) {
+        (Some(previous), Some(deadline)) => Some(previous.min(deadline)),
+        (previous, deadline) => previous.or(deadline),
+    };
+    CURRENT.with(|current| current.set(effective));
+    f()
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_round_trip() {
+        let options = RequestOptions::new().with_budget_ms(1500).with_priority(7);
+        assert_eq!(options.to_bytes(), [0x00, 0x00, 0x05, 0xDC, 0x07]);
+        assert_eq!(RequestOptions::from_bytes(options.to_bytes()), options);
+
+        let unlimited = RequestOptions::new();
+        assert_eq!(unlimited.to_bytes(), [0xFF, 0xFF, 0xFF, 0xFF, 0x80]);
+        assert_eq!(RequestOptions::from_bytes(unlimited.to_bytes()), unlimited);
+    }
+
+    #[test]
+    fn test_budget_is_clamped_to_the_wire() {
+        let options = RequestOptions::new().with_budget(Duration::from_secs(u64::MAX));
+        assert_eq!(options.budget_ms(), Some(u32::MAX - 1));
+        assert_eq!(RequestOptions::from_bytes(options.to_bytes()), options);
+        assert!(RequestOptions::new()
+            .with_budget(Duration::from_micros(900))
+            .is_expired());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_nested_deadlines_keep_the_earliest() {
+        let now = Instant::now();
+        let (near, far) = (now + Duration::from_secs(1), now + Duration::from_secs(60));
+        assert_eq!(current(), None);
+        with_deadline(Some(near), || {
+            assert_eq!(current(), Some(near));
+            with_deadline(Some(far), || assert_eq!(current(), Some(near)));
+            with_deadline(None, || assert_eq!(current(), Some(near)));
+            assert!(remaining().unwrap() <= Duration::from_secs(1));
+        });
+        assert_eq!(current(), None);
+        with_deadline(Some(far), || {
+            with_deadline(Some(near), || assert_eq!(current(), Some(near)))
+        });
+    }
+}
@@ -0,0 +1,297 @@
+//! Credit-based flow control
+//!
+//! The peer that receives requests grants the sender a window of credit:
+//! a number of requests and a number of payload bytes (SPEC.md Section 4.5).
+//! Every request consumes one request and its payload length in bytes; the
+//! receiver hands credit back with `WindowUpdate` messages as it finishes
+//! requests. A sender without enough credit must wait.
+//!
+//! [`Credit`] is the sender's view and [`ReceiveWindow`] the receiver's.
+//! [`Client`](crate::client::Client) and [`Server`](crate::server::Server)
+//! use them to enforce the window on every connection.
+
+use alloc::format;
+
+use crate::{Message, MessageType, ProtocolError, MAX_PAYLOAD_SIZE};
+
+/// Size of a `WindowUpdate` payload: request count and byte count
+pub const WINDOW_UPDATE_SIZE: usize = 8;
+
+/// An amount of flow-control credit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Requests that may be outstanding
+    pub requests: u32,
+    /// Request payload bytes that may be outstanding
+    pub bytes: u32,
+}
+
+impl Window {
+    /// Window granted by [`Server`](crate::server::Server) unless configured
+    pub const DEFAULT: Window = Window {
+        requests: 32,
+        bytes: 4 * MAX_PAYLOAD_SIZE as u32,
+    };
+
+    /// Create a window
+    pub fn new(requests: u32, bytes: u32) -> Self {
+        Self { requests, bytes }
+    }
+
+    /// Read the credit carried by a `WindowUpdate` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        if message.message_type != MessageType::WindowUpdate {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "expected a window update, got {:?}",
+                message.message_type
+            )));
+        }
+        let payload: [u8; WINDOW_UPDATE_SIZE] =
+            message.payload.as_slice().try_into().map_err(|_| {
+                ProtocolError::InvalidPayload(format!(
+                    "window update payload must be {} bytes, got {}",
+                    WINDOW_UPDATE_SIZE,
+                    message.payload.len()
+                ))
+            })?;
+        Ok(Self {
+            requests: u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]),
+            bytes: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+        })
+    }
+
+    /// Build the `WindowUpdate` message granting this credit
+    pub fn to_message(self) -> Message {
+        Message::window_update(self.requests, self.bytes)
+    }
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What a sender does when its window is exhausted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for the receiver to return credit
+    #[default]
+    Block,
+    /// Return [`ProtocolError::WindowExhausted`] immediately
+    FailFast,
+}
+
+/// Sender-side credit
+///
+/// Starts empty: nothing may be sent until the receiver's initial grant
+/// arrives.
+#[derive(Debug, Clone, Default)]
+pub struct Credit {
+    requests: u64,
+    bytes: u64,
+    granted: bool,
+}
+
+impl Credit {
+    /// No credit, no grant received yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add credit from a `WindowUpdate`
+    pub fn grant(&mut self, window: Window) {
+        self.requests += u64::from(window.requests);
+        self.bytes += u64::from(window.bytes);
+        self.granted = true;
+    }
+
+    /// Whether any grant has been received
+    pub fn is_granted(&self) -> bool {
+        self.granted
+    }
+
+    /// Whether a request with this payload length may be sent now
+    pub fn covers(&self, payload_len: usize) -> bool {
+        self.requests >= 1 && self.bytes >= payload_len as u64
+    }
+
+    /// Spend credit for one request, failing if there is not enough
+    pub fn consume(&mut self, payload_len: usize) -> Result<(), ProtocolError> {
+        if !self.covers(payload_len) {
+            return Err(ProtocolError::WindowExhausted {
+                requests: saturate(self.requests),
+                bytes: saturate(self.bytes),
+            });
+        }
+        self.requests -= 1;
+        self.bytes -= payload_len as u64;
+        Ok(())
+    }
+
+    /// Credit currently available
+    pub fn available(&self) -> Window {
+        Window::new(saturate(self.requests), saturate(self.bytes))
+    }
+}
+
+/// Receiver-side window accounting
+///
+/// Tracks the sender's remaining credit as the receiver sees it, rejects
+/// requests that exceed it, and batches returned credit into `WindowUpdate`
+/// messages.
+#[derive(Debug, Clone)]
+pub struct ReceiveWindow {
+    window: Window,
+    /// Credit the sender still holds
+    remaining: Credit,
+    /// Credit from finished requests not yet returned
+    pending: Credit,
+}
+
+impl ReceiveWindow {
+    /// Account for a window of the given size
+    pub fn new(window: Window) -> Self {
+        Self {
+            window,
+            remaining: Credit::new(),
+            pending: Credit::new(),
+        }
+    }
+
+    /// Configured window size
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// The grant to send when the connection opens
+    pub fn initial_grant(&mut self) -> Message {
+        self.remaining.grant(self.window);
+        self.window.to_message()
+    }
+
+    /// Charge an incoming request against the sender's credit
+    ///
+    /// Fails with [`ProtocolError::WindowExhausted`] if the sender has
+    /// overrun its window.
+    pub fn admit(&mut self, payload_len: usize) -> Result<(), ProtocolError> {
+        self.remaining.consume(payload_len)
+    }
+
+    /// Record that a request has been answered, making its credit
+    /// returnable
+    pub fn complete(&mut self, payload_len: usize) {
+        self.pending
+            .grant(Window::new(1, saturate(payload_len as u64)));
+    }
+
+    /// A `WindowUpdate` returning pending credit, if one is due
+    ///
+    /// Credit is returned once half the window is pending, or as soon as the
+    /// sender may be unable to send even one maximum-size request. Send the
+    /// update before the response that triggered it, so a sender that has
+    /// read every response has also seen every update.
+    pub fn take_update(&mut self) -> Option<Message> {
+        let pending = self.pending.available();
+        if pending.requests == 0 && pending.bytes == 0 {
+            return None;
+        }
+        let remaining = self.remaining.available();
+        let due = u64::from(pending.requests) * 2 >= u64::from(self.window.requests)
+            || u64::from(pending.bytes) * 2 >= u64::from(self.window.bytes)
+            || remaining.requests == 0
+            || (remaining.bytes as usize) < MAX_PAYLOAD_SIZE;
+        if !due {
+            return None;
+        }
+        self.pending = Credit::new();
+        self.remaining.grant(pending);
+        Some(pending.to_message())
+    }
+}
+
+fn saturate(value: u64) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_update_round_trip() {
+        let window = Window::new(7, 70_000);
+        let message = window.to_message();
+        assert_eq!(message.message_type, MessageType::WindowUpdate);
+        assert_eq!(message.id, 0);
+        assert_eq!(message.status, None);
+        assert_eq!(Window::from_message(&message), Ok(window));
+
+        let mut bad = message.clone();
+        bad.payload.pop();
+        assert!(matches!(
+            Window::from_message(&bad),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn test_credit_consumption() {
+        let mut credit = Credit::new();
+        assert!(!credit.is_granted());
+        assert!(credit.consume(0).is_err());
+
+        credit.grant(Window::new(2, 10));
+        credit.consume(6).unwrap();
+        assert_eq!(
+            credit.consume(5),
+            Err(ProtocolError::WindowExhausted {
+                requests: 1,
+                bytes: 4
+            })
+        );
+        credit.consume(4).unwrap();
+        assert_eq!(credit.available(), Window::new(0, 0));
+    }
+
+    #[test]
+    fn test_receive_window_batches_updates() {
+        let mut rw = ReceiveWindow::new(Window::new(4, 4 * MAX_PAYLOAD_SIZE as u32));
+        rw.initial_grant();
+
+        rw.admit(10).unwrap();
+        rw.complete(10);
+        assert_eq!(rw.take_update(), None);
+
+        rw.admit(20).unwrap();
+        rw.complete(20);
+        let update = rw.take_update().unwrap();
+        assert_eq!(Window::from_message(&update), Ok(Window::new(2, 30)));
+        assert_eq!(rw.take_update(), None);
+    }
+
+    #[test]
+    fn test_receive_window_returns_credit_before_sender_stalls() {
+        // A small byte window: one large request leaves too little for another
+        let mut rw = ReceiveWindow::new(Window::new(100, MAX_PAYLOAD_SIZE as u32 + 100));
+        rw.initial_grant();
+        rw.admit(200).unwrap();
+        rw.complete(200);
+        assert!(rw.take_update().is_some());
+    }
+
+    #[test]
+    fn test_receive_window_rejects_overrun() {
+        let mut rw = ReceiveWindow::new(Window::new(1, 100));
+        rw.initial_grant();
+        rw.admit(50).unwrap();
+        assert!(matches!(
+            rw.admit(0),
+            Err(ProtocolError::WindowExhausted { .. })
+        ));
+    }
+}
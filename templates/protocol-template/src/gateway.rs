@@ -0,0 +1,698 @@
+//! HTTP/1.1 gateway
+//!
+//! Browsers and tools like curl cannot speak the binary frame format.
+//! [`Gateway`] accepts HTTP/1.1 requests and forwards each one to an
+//! upstream server as a `Request` frame:
+//!
+//! | HTTP | Protocol |
+//! |------|----------|
+//! | `POST /rpc/<id>` with a body | `Request` with ID `<id>` and the body as payload |
+//! | `200 OK` with the payload as body | `Response` with status 0 |
+//! | `500 Internal Server Error` with the payload as body | `Response` with any other status |
+//! | a status chosen by the error code, JSON body | `Error` |
+//!
+//! Responses carry the frame's status in an `X-Protocol-Status` header.
+//! Error frames become a JSON body such as
+//! `{"error":{"code":8,"name":"DeadlineExceeded","message":"..."}}`, sent
+//! with the HTTP status [`error_status`] gives the code. Failures of the
+//! gateway itself, such as an unreachable upstream, are answered with a
+//! `{"error":{"message":"..."}}` body.
+//!
+//! Each HTTP connection gets its own upstream connection, opened with its
+//! first request and kept while the HTTP connection is kept alive; an
+//! upstream connection that fails is replaced on the next request. Bodies
+//! must come with a `Content-Length` (chunked uploads are refused) and may
+//! be no larger than [`MAX_PAYLOAD_SIZE`]. A request the gateway rejects
+//! is answered and the connection closed, since its body is left unread.
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use std::net::{TcpListener, TcpStream};
+//!
+//! use protocol_name::gateway::Gateway;
+//!
+//! let gateway = Gateway::new(|| TcpStream::connect("127.0.0.1:9000"));
+//! gateway.serve(TcpListener::bind("127.0.0.1:8080").unwrap()).unwrap();
+//! // curl --data-binary hello http://127.0.0.1:8080/rpc/1
+//! ```
+
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+
+use crate::client::Client;
+use crate::interop::json_string;
+use crate::transport::{Listener, Transport};
+use crate::{ErrorCode, Message, MessageType, ProtocolError, MAX_PAYLOAD_SIZE};
+
+/// Longest request line and headers accepted, in bytes
+pub const MAX_HEAD_SIZE: usize = 8192;
+
+/// Path prefix of forwarded requests; the rest of the path is the ID
+pub const RPC_PATH: &str = "/rpc/";
+
+type Connect<T> = Arc<dyn Fn() -> io::Result<T> + Send + Sync>;
+
+/// An HTTP/1.1 front end for an upstream server
+pub struct Gateway<T> {
+    connect: Connect<T>,
+}
+
+impl<T> Clone for Gateway<T> {
+    fn clone(&self) -> Self {
+        Self {
+            connect: Arc::clone(&self.connect),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Gateway<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Gateway").finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport + Send + 'static> Gateway<T> {
+    /// Create a gateway opening upstream connections with `connect`
+    pub fn new<F>(connect: F) -> Self
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+    {
+        Self {
+            connect: Arc::new(connect),
+        }
+    }
+
+    /// Accept HTTP connections forever, serving each on its own thread
+    ///
+    /// Returns only if accepting fails.
+    pub fn serve<L: Listener>(&self, listener: L) -> io::Result<()> {
+        loop {
+            let stream = listener.accept_transport()?;
+            let gateway = self.clone();
+            thread::spawn(move || gateway.serve_connection(stream));
+        }
+    }
+
+    /// Serve HTTP requests on one connection until either side closes it
+    pub fn serve_connection<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        let mut upstream = None;
+        loop {
+            let (response, close) = match read_request(&mut stream)? {
+                Incoming::Closed => return Ok(()),
+                Incoming::Rejected(response) => (response, true),
+                Incoming::Request(request) => (
+                    self.forward(&mut upstream, request.id, &request.body),
+                    request.close,
+                ),
+            };
+            write_response(stream.get_mut(), &response, close)?;
+            if close {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send one request upstream and translate the reply
+    fn forward(&self, upstream: &mut Option<Client<T>>, id: u32, body: &[u8]) -> HttpResponse {
+        let client = match upstream {
+            Some(client) => client,
+            None => match (self.connect)() {
+                Ok(transport) => upstream.insert(Client::new(transport)),
+                Err(e) => {
+                    return HttpResponse::gateway_error(
+                        502,
+                        &format!("upstream unavailable: {}", e),
+                    )
+                }
+            },
+        };
+        match exchange(client, id, body) {
+            // A connection-level error: the upstream closes the connection
+            Ok(reply) if reply.id == 0 => {
+                *upstream = None;
+                HttpResponse::from_reply(&reply)
+            }
+            Ok(reply) => HttpResponse::from_reply(&reply),
+            // Refused before sending: the connection is still usable
+            Err(e @ ProtocolError::WindowExhausted { .. }) => {
+                HttpResponse::gateway_error(413, &e.to_string())
+            }
+            Err(e) => {
+                *upstream = None;
+                HttpResponse::gateway_error(502, &format!("upstream failed: {}", e))
+            }
+        }
+    }
+}
+
+fn exchange<T: Transport>(
+    client: &mut Client<T>,
+    id: u32,
+    body: &[u8],
+) -> Result<Message, ProtocolError> {
+    client.send(&Message::request(id, body))?;
+    let reply = client.recv()?;
+    if reply.id != id && !(reply.id == 0 && reply.message_type == MessageType::Error) {
+        return Err(ProtocolError::UnexpectedResponse {
+            expected: id,
+            actual: reply.id,
+        });
+    }
+    Ok(reply)
+}
+
+/// HTTP status answering an `Error` frame carrying `code`
+///
+/// Codes this implementation does not know are answered with
+/// `502 Bad Gateway`.
+pub fn error_status(code: Option<ErrorCode>) -> u16 {
+    match code {
+        Some(ErrorCode::InvalidFormat | ErrorCode::UnknownType) => 400,
+        Some(ErrorCode::UnknownMethod | ErrorCode::UnknownSession) => 404,
+        Some(ErrorCode::DuplicateId) => 409,
+        Some(ErrorCode::PayloadTooLarge) => 413,
+        Some(ErrorCode::FlowControl) => 503,
+        Some(ErrorCode::DeadlineExceeded) => 504,
+        None => 502,
+    }
+}
+
+// ============================================================================
+// HTTP Messages
+// ============================================================================
+
+/// A request to forward
+#[derive(Debug)]
+struct HttpRequest {
+    id: u32,
+    body: Vec<u8>,
+    /// Whether the client asked to close the connection after the response
+    close: bool,
+}
+
+/// Outcome of reading from a connection
+#[derive(Debug)]
+enum Incoming {
+    Request(HttpRequest),
+    /// Answer with this response and close the connection
+    Rejected(HttpResponse),
+    /// The client closed the connection between requests
+    Closed,
+}
+
+#[derive(Debug)]
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            content_type,
+            body,
+        }
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// A failure of the gateway itself
+    fn gateway_error(status: u16, message: &str) -> Self {
+        let body = format!("{{\"error\":{{\"message\":{}}}}}\n", json_string(message));
+        Self::new(status, "application/json", body.into_bytes())
+    }
+
+    /// Translate an upstream reply
+    fn from_reply(reply: &Message) -> Self {
+        let status = reply.status.unwrap_or(0);
+        match reply.message_type {
+            MessageType::Response => {
+                let http = if status == 0 { 200 } else { 500 };
+                Self::new(http, "application/octet-stream", reply.payload.clone())
+                    .with_header("X-Protocol-Status", status.to_string())
+            }
+            MessageType::Error => {
+                let code = ErrorCode::from_u8(status);
+                let mut body = format!("{{\"error\":{{\"code\":{}", status);
+                if let Some(code) = code {
+                    let _ = write!(body, ",\"name\":{}", json_string(&format!("{:?}", code)));
+                }
+                let message = String::from_utf8_lossy(&reply.payload);
+                let _ = writeln!(body, ",\"message\":{}}}}}", json_string(&message));
+                Self::new(error_status(code), "application/json", body.into_bytes())
+            }
+            other => Self::gateway_error(502, &format!("upstream answered with {:?}", other)),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+fn write_response<W: Write>(
+    writer: &mut W,
+    response: &HttpResponse,
+    close: bool,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    let _ = write!(
+        head,
+        "Content-Type: {}\r\nContent-Length: {}\r\n",
+        response.content_type,
+        response.body.len()
+    );
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    // One write, so the head and a small body share a packet
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&response.body);
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+// ============================================================================
+// Request Parsing
+// ============================================================================
+
+/// Read the next request, and its body if it is one the gateway forwards
+fn read_request<S: Read + Write>(stream: &mut BufReader<S>) -> io::Result<Incoming> {
+    let head = match read_head(stream)? {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(Incoming::Closed),
+        Err(rejection) => return Ok(Incoming::Rejected(rejection)),
+    };
+    let (id, length, expect_continue, close) = match parse_head(&head) {
+        Ok(parsed) => parsed,
+        Err(rejection) => return Ok(Incoming::Rejected(rejection)),
+    };
+    if expect_continue {
+        stream
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.get_mut().flush()?;
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok(Incoming::Request(HttpRequest { id, body, close }))
+}
+
+/// Read the request line and headers, up to the blank line ending them
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Result<Option<Vec<String>>, HttpResponse>> {
+    let mut lines = Vec::new();
+    let mut remaining = MAX_HEAD_SIZE;
+    loop {
+        let mut line = Vec::new();
+        let read = reader
+            .by_ref()
+            .take(remaining as u64)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 && lines.is_empty() {
+            return Ok(Ok(None));
+        }
+        if line.last() != Some(&b'\n') {
+            if remaining == read {
+                return Ok(Err(HttpResponse::gateway_error(
+                    431,
+                    "request head too large",
+                )));
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= read;
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            // Blank lines before the request line are ignored (RFC 9112)
+            if lines.is_empty() {
+                continue;
+            }
+            return Ok(Ok(Some(lines)));
+        }
+        match String::from_utf8(line) {
+            Ok(line) => lines.push(line),
+            Err(_) => {
+                return Ok(Err(HttpResponse::gateway_error(
+                    400,
+                    "request head is not UTF-8",
+                )))
+            }
+        }
+    }
+}
+
+/// Check a request head, returning the request ID, body length, whether
+/// the client expects `100 Continue` and whether to close afterwards
+fn parse_head(head: &[String]) -> Result<(u32, usize, bool, bool), HttpResponse> {
+    let bad_request = |message: &str| HttpResponse::gateway_error(400, message);
+    let mut parts = head[0].split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request("malformed request line"));
+    };
+    let mut close = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => {
+            return Err(HttpResponse::gateway_error(
+                505,
+                "unsupported HTTP version; use HTTP/1.1",
+            ))
+        }
+    };
+
+    let mut length = None;
+    let mut expect_continue = false;
+    for header in &head[1..] {
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request("malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let parsed = value
+                .parse::<usize>()
+                .ok()
+                .filter(|_| value.bytes().all(|b| b.is_ascii_digit()));
+            match (parsed, length) {
+                (Some(parsed), None) => length = Some(parsed),
+                (Some(parsed), Some(previous)) if parsed == previous => {}
+                _ => return Err(bad_request("invalid Content-Length")),
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(HttpResponse::gateway_error(
+                501,
+                "chunked bodies are not supported; send a Content-Length",
+            ));
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    close = true;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    close = false;
+                }
+            }
+        } else if name.eq_ignore_ascii_case("expect") {
+            if !value.eq_ignore_ascii_case("100-continue") {
+                return Err(HttpResponse::gateway_error(417, "unsupported expectation"));
+            }
+            expect_continue = true;
+        }
+    }
+
+    let path = target.split('?').next().unwrap_or_default();
+    let Some(id) = path.strip_prefix(RPC_PATH) else {
+        return Err(HttpResponse::gateway_error(404, "requests go to /rpc/<id>"));
+    };
+    if method != "POST" {
+        return Err(
+            HttpResponse::gateway_error(405, "use POST").with_header("Allow", "POST".to_string())
+        );
+    }
+    let id = Some(id)
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|id| id.parse::<u32>().ok())
+        .filter(|&id| id != 0);
+    let Some(id) = id else {
+        return Err(bad_request(
+            "request ID must be a number from 1 to 4294967295",
+        ));
+    };
+    let Some(length) = length else {
+        return Err(HttpResponse::gateway_error(
+            411,
+            "a Content-Length is required",
+        ));
+    };
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(HttpResponse::gateway_error(
+            413,
+            &format!(
+                "body of {} bytes exceeds the {} byte limit",
+                length, MAX_PAYLOAD_SIZE
+            ),
+        ));
+    }
+    Ok((id, length, expect_continue, close))
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::transport::{pipe, MemoryStream};
+
+    /// A gateway in front of `handler`, each upstream connection served
+    /// over an in-memory pipe
+    fn gateway<H>(handler: H) -> Gateway<MemoryStream>
+    where
+        H: Fn(Message) -> Message + Send + Sync + 'static,
+    {
+        let server = Server::new(handler);
+        Gateway::new(move || {
+            let (client, upstream) = pipe();
+            let server = server.clone();
+            thread::spawn(move || server.serve_connection(upstream));
+            Ok(client)
+        })
+    }
+
+    /// Send raw HTTP and return everything the gateway writes back before
+    /// closing the connection
+    fn exchange_http<T: Transport + Send + 'static>(gateway: &Gateway<T>, request: &str) -> String {
+        let (mut client, server) = pipe();
+        let gateway = gateway.clone();
+        let handle = thread::spawn(move || gateway.serve_connection(server));
+        client.write_all(request.as_bytes()).unwrap();
+        client.shutdown_write();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        handle.join().unwrap().unwrap();
+        response
+    }
+
+    fn echo_id(request: Message) -> Message {
+        let mut payload = format!("{}:", request.id).into_bytes();
+        payload.extend_from_slice(&request.payload);
+        Message::response(request.id, 0, &payload)
+    }
+
+    #[test]
+    fn test_post_is_forwarded_as_a_request() {
+        let response = exchange_http(
+            &gateway(echo_id),
+            "POST /rpc/42 HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nX-Protocol-Status: 0\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/octet-stream\r\n"));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\n42:hello"));
+    }
+
+    #[test]
+    fn test_keep_alive_reuses_the_connection() {
+        let response = exchange_http(
+            &gateway(echo_id),
+            "POST /rpc/1 HTTP/1.1\r\nContent-Length: 1\r\n\r\na\
+             POST /rpc/2?trace=1 HTTP/1.1\r\nContent-Length: 1\r\n\r\nb",
+        );
+        assert_eq!(
+            response.matches("HTTP/1.1 200 OK").count(),
+            2,
+            "{}",
+            response
+        );
+        assert!(response.contains("1:a") && response.ends_with("2:b"));
+        assert!(!response.contains("Connection: close"));
+    }
+
+    #[test]
+    fn test_failed_responses_and_error_frames() {
+        let gateway = gateway(|request: Message| match request.payload.as_slice() {
+            b"fail" => Message::response(request.id, 1, b"broken"),
+            _ => Message::error(
+                request.id,
+                ErrorCode::UnknownMethod as u8,
+                "no \"such\" method",
+            ),
+        });
+        let failed = exchange_http(
+            &gateway,
+            "POST /rpc/3 HTTP/1.1\r\nContent-Length: 4\r\n\r\nfail",
+        );
+        assert!(
+            failed.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            failed
+        );
+        assert!(failed.contains("\r\nX-Protocol-Status: 1\r\n") && failed.ends_with("broken"));
+
+        let error = exchange_http(
+            &gateway,
+            "POST /rpc/4 HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(error.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", error);
+        assert!(error.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(error.ends_with(
+            "\r\n\r\n{\"error\":{\"code\":6,\"name\":\"UnknownMethod\",\"message\":\"no \\\"such\\\" method\"}}\n"
+        ));
+    }
+
+    #[test]
+    fn test_rejected_requests_close_the_connection() {
+        let gateway = gateway(echo_id);
+        let cases = [
+            ("GET /rpc/1 HTTP/1.1\r\n\r\n", "405 Method Not Allowed"),
+            (
+                "POST /other HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+                "404 Not Found",
+            ),
+            (
+                "POST /rpc/0 HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                "POST /rpc/+7 HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                "POST /rpc/4294967296 HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+                "400 Bad Request",
+            ),
+            ("POST /rpc/1 HTTP/1.1\r\n\r\n", "411 Length Required"),
+            (
+                "POST /rpc/1 HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+                "400 Bad Request",
+            ),
+            (
+                "POST /rpc/1 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "501 Not Implemented",
+            ),
+            (
+                "POST /rpc/1 HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n",
+                "413 Payload Too Large",
+            ),
+            (
+                "POST /rpc/1 HTTP/2\r\n\r\n",
+                "505 HTTP Version Not Supported",
+            ),
+            ("POST /rpc/1\r\n\r\n", "400 Bad Request"),
+            (
+                "POST /rpc/1 HTTP/1.1\r\nno colon\r\n\r\n",
+                "400 Bad Request",
+            ),
+        ];
+        for (request, status) in cases {
+            let response = exchange_http(
+                &gateway,
+                &format!("{}POST /rpc/2 HTTP/1.1\r\n\r\n", request),
+            );
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{:?}: {}",
+                request,
+                response
+            );
+            assert_eq!(
+                response.matches("HTTP/1.1 ").count(),
+                1,
+                "{:?} kept the connection open",
+                request
+            );
+            assert!(response.contains("\r\nConnection: close\r\n"));
+        }
+        let allowed = exchange_http(&gateway, "GET /rpc/1 HTTP/1.1\r\n\r\n");
+        assert!(allowed.contains("\r\nAllow: POST\r\n"));
+        let oversized = exchange_http(
+            &gateway,
+            &format!(
+                "POST /rpc/1 HTTP/1.1\r\nX: {}\r\n\r\n",
+                "a".repeat(MAX_HEAD_SIZE)
+            ),
+        );
+        assert!(oversized.starts_with("HTTP/1.1 431 "), "{}", oversized);
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let response = exchange_http(
+            &gateway(echo_id),
+            "POST /rpc/5 HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("5:hi"));
+    }
+
+    #[test]
+    fn test_unreachable_upstream() {
+        let gateway: Gateway<MemoryStream> =
+            Gateway::new(|| Err(io::ErrorKind::ConnectionRefused.into()));
+        let response = exchange_http(
+            &gateway,
+            "POST /rpc/1 HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("{\"error\":{\"message\":\"upstream unavailable: "));
+    }
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(error_status(Some(ErrorCode::DeadlineExceeded)), 504);
+        assert_eq!(error_status(Some(ErrorCode::PayloadTooLarge)), 413);
+        assert_eq!(error_status(Some(ErrorCode::FlowControl)), 503);
+        assert_eq!(error_status(ErrorCode::from_u8(0x7F)), 502);
+    }
+}
@@ -0,0 +1,1427 @@
+//! Interface definitions and stub generation.
+//!
+//! A service contract is written once, in a small line-based IDL, and
+//! `protocol-idl` derives the code both sides need from it:
+//!
+//! - Rust record types with [`Encode`](crate::Encode) and
+//!   [`Decode`](crate::Decode) implementations
+//! - a Rust server trait per service, plus a function registering an
+//!   implementation on a [`Router`](crate::rpc::Router)
+//! - a Rust client per service, wrapping [`Client`](crate::client::Client)
+//! - a self-contained TypeScript client for Node.js
+//!
+//! Calls travel as method calls (SPEC.md Section 4.8) named
+//! `<service>.<method>`, with the service name in snake case, and arguments
+//! and results in the typed encoding (SPEC.md Section 3.4).
+//!
+//! ## IDL Format
+//!
+//! One declaration per line. `#` starts a comment, descriptions are quoted
+//! and optional. `method` lines belong to the `service` above them and
+//! `field` lines to the `record` above them.
+//!
+//! ```text
+//! service Calculator "Integer arithmetic"
+//! method add     Operands -> i64         "Sum of the operands"
+//! method history unit     -> list<Entry> "Calculations so far"
+//!
+//! record Operands "Two operands"
+//! field a i64 "Left operand"
+//! field b i64 "Right operand"
+//!
+//! record Entry
+//! field method   string
+//! field operands Operands
+//! field result   option<i64>
+//! ```
+//!
+//! Types are `bool`, `u8`, `u16`, `u32`, `u64`, `i8`, `i16`, `i32`, `i64`,
+//! `string`, `bytes`, `list<T>`, `option<T>` and record names. `unit`, for
+//! no arguments or no result, is allowed only in method signatures.
+
+use std::fmt::Write as _;
+
+use crate::codegen::{is_identifier, snake_case, tokenize, SpecError};
+use crate::compress::COMPRESSED;
+use crate::deadline::OPTIONS;
+use crate::rpc::RESERVED_PREFIX;
+use crate::{MessageType, MAGIC, MAX_PAYLOAD_SIZE, VERSION};
+
+/// Words that cannot name a field or method in the generated Rust or
+/// TypeScript
+const RESERVED_WORDS: &[&str] = &[
+    "as",
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "crate",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "dyn",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "extern",
+    "false",
+    "finally",
+    "fn",
+    "for",
+    "function",
+    "if",
+    "impl",
+    "import",
+    "in",
+    "instanceof",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "move",
+    "mut",
+    "new",
+    "null",
+    "pub",
+    "ref",
+    "return",
+    "self",
+    "static",
+    "struct",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "trait",
+    "true",
+    "try",
+    "type",
+    "typeof",
+    "unsafe",
+    "use",
+    "var",
+    "void",
+    "where",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Method names taken by the generated clients' own methods
+const CLIENT_METHODS: &[&str] = &["into_inner", "connection"];
+
+// ============================================================================
+// Types
+// ============================================================================
+
+/// A value type in a method signature or record field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdlType {
+    /// No value; method signatures only
+    Unit,
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    String,
+    Bytes,
+    List(Box<IdlType>),
+    Option(Box<IdlType>),
+    /// A record declared in the same file
+    Record(String),
+}
+
+/// One field of a record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDef {
+    pub name: String,
+    pub ty: IdlType,
+    pub description: Option<String>,
+}
+
+/// A record type, encoded as its fields in declaration order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordDef {
+    pub name: String,
+    pub description: Option<String>,
+    pub fields: Vec<FieldDef>,
+}
+
+/// One method of a service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodDef {
+    pub name: String,
+    pub request: IdlType,
+    pub response: IdlType,
+    pub description: Option<String>,
+}
+
+/// A named group of methods
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDef {
+    pub name: String,
+    pub description: Option<String>,
+    pub methods: Vec<MethodDef>,
+}
+
+impl ServiceDef {
+    /// Method name sent on the wire for `method`
+    pub fn wire_name(&self, method: &MethodDef) -> String {
+        format!("{}.{}", snake_case(&self.name), method.name)
+    }
+}
+
+/// A parsed interface definition
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Idl {
+    pub services: Vec<ServiceDef>,
+    pub records: Vec<RecordDef>,
+}
+
+// ============================================================================
+// Parsing
+// ============================================================================
+
+/// Declaration that `method` or `field` lines attach to
+enum Block {
+    None,
+    Service,
+    Record,
+}
+
+impl Idl {
+    /// Parse an IDL file
+    pub fn parse(source: &str) -> Result<Self, SpecError> {
+        let mut idl = Idl::default();
+        let mut block = Block::None;
+
+        for (index, raw) in source.lines().enumerate() {
+            let line_no = index + 1;
+            let tokens = tokenize(raw).map_err(|e| SpecError::new(line_no, e))?;
+            let Some((directive, args)) = tokens.split_first() else {
+                continue;
+            };
+            let error = |message: String| SpecError::new(line_no, message);
+
+            match directive.as_str() {
+                "service" | "record" => {
+                    let (name, description) = match args {
+                        [name] => (name, None),
+                        [name, description] => (name, Some(description.clone())),
+                        _ => {
+                            return Err(error(format!(
+                                "expected `{} NAME [\"description\"]`",
+                                directive
+                            )))
+                        }
+                    };
+                    if !is_type_name(name) {
+                        return Err(error(format!(
+                            "{} name must be CamelCase: {}",
+                            directive, name
+                        )));
+                    }
+                    if directive == "service" {
+                        idl.services.push(ServiceDef {
+                            name: name.clone(),
+                            description,
+                            methods: Vec::new(),
+                        });
+                        block = Block::Service;
+                    } else {
+                        idl.records.push(RecordDef {
+                            name: name.clone(),
+                            description,
+                            fields: Vec::new(),
+                        });
+                        block = Block::Record;
+                    }
+                }
+                "method" => {
+                    let (name, request, response, description) = match args {
+                        [name, request, arrow, response, rest @ ..]
+                            if arrow == "->" && rest.len() <= 1 =>
+                        {
+                            (name, request, response, rest.first().cloned())
+                        }
+                        _ => {
+                            return Err(error(
+                                "expected `method NAME REQUEST -> RESPONSE [\"description\"]`"
+                                    .into(),
+                            ))
+                        }
+                    };
+                    let (Block::Service, Some(service)) = (&block, idl.services.last_mut()) else {
+                        return Err(error(format!("method {} is outside a service", name)));
+                    };
+                    check_member_name(name).map_err(error)?;
+                    if CLIENT_METHODS.contains(&name.as_str()) {
+                        return Err(error(format!(
+                            "method name clashes with the generated client: {}",
+                            name
+                        )));
+                    }
+                    service.methods.push(MethodDef {
+                        name: name.clone(),
+                        request: parse_type(request, true).map_err(error)?,
+                        response: parse_type(response, true).map_err(error)?,
+                        description,
+                    });
+                }
+                "field" => {
+                    let (name, ty, description) = match args {
+                        [name, ty] => (name, ty, None),
+                        [name, ty, description] => (name, ty, Some(description.clone())),
+                        _ => {
+                            return Err(error(
+                                "expected `field NAME TYPE [\"description\"]`".into(),
+                            ))
+                        }
+                    };
+                    let (Block::Record, Some(record)) = (&block, idl.records.last_mut()) else {
+                        return Err(error(format!("field {} is outside a record", name)));
+                    };
+                    check_member_name(name).map_err(error)?;
+                    record.fields.push(FieldDef {
+                        name: name.clone(),
+                        ty: parse_type(ty, false).map_err(error)?,
+                        description,
+                    });
+                }
+                other => return Err(error(format!("unknown directive: {}", other))),
+            }
+        }
+
+        idl.validate()?;
+        Ok(idl)
+    }
+
+    /// Look up a record by name
+    pub fn record(&self, name: &str) -> Option<&RecordDef> {
+        self.records.iter().find(|r| r.name == name)
+    }
+
+    fn validate(&self) -> Result<(), SpecError> {
+        let fail = |message: String| Err(SpecError::new(0, message));
+
+        if self.services.is_empty() && self.records.is_empty() {
+            return fail("nothing declared".to_string());
+        }
+        // Generated client names must not collide either
+        let mut names: Vec<String> = Vec::new();
+        let generated = self
+            .services
+            .iter()
+            .flat_map(|s| [s.name.clone(), format!("{}Client", s.name)]);
+        for name in generated.chain(self.records.iter().map(|r| r.name.clone())) {
+            if names.contains(&name) {
+                return fail(format!("duplicate name: {}", name));
+            }
+            names.push(name);
+        }
+        for service in &self.services {
+            if format!("{}.", snake_case(&service.name)) == RESERVED_PREFIX {
+                return fail(format!("service name is reserved: {}", service.name));
+            }
+            if service.methods.is_empty() {
+                return fail(format!("service {} has no methods", service.name));
+            }
+            for (index, method) in service.methods.iter().enumerate() {
+                if service.methods[..index]
+                    .iter()
+                    .any(|m| m.name == method.name)
+                {
+                    return fail(format!(
+                        "duplicate method: {}.{}",
+                        service.name, method.name
+                    ));
+                }
+                self.check_type(&method.request)?;
+                self.check_type(&method.response)?;
+            }
+        }
+        for record in &self.records {
+            if record.fields.is_empty() {
+                return fail(format!("record {} has no fields", record.name));
+            }
+            for (index, field) in record.fields.iter().enumerate() {
+                if record.fields[..index].iter().any(|f| f.name == field.name) {
+                    return fail(format!("duplicate field: {}.{}", record.name, field.name));
+                }
+                self.check_type(&field.ty)?;
+            }
+            if self.contains(&record.name, &mut Vec::new(), &record.fields) {
+                return fail(format!("record {} contains itself", record.name));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, ty: &IdlType) -> Result<(), SpecError> {
+        match ty {
+            IdlType::Record(name) if self.record(name).is_none() => {
+                Err(SpecError::new(0, format!("unknown type: {}", name)))
+            }
+            IdlType::List(inner) | IdlType::Option(inner) => self.check_type(inner),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `fields` reach the record `target`, directly or through
+    /// other records
+    fn contains<'a>(
+        &'a self,
+        target: &str,
+        seen: &mut Vec<&'a str>,
+        fields: &'a [FieldDef],
+    ) -> bool {
+        fields.iter().any(|field| {
+            let mut ty = &field.ty;
+            while let IdlType::List(inner) | IdlType::Option(inner) = ty {
+                ty = inner;
+            }
+            let IdlType::Record(name) = ty else {
+                return false;
+            };
+            if name == target {
+                return true;
+            }
+            if seen.contains(&name.as_str()) {
+                return false;
+            }
+            seen.push(name);
+            self.record(name)
+                .is_some_and(|record| self.contains(target, seen, &record.fields))
+        })
+    }
+}
+
+fn is_type_name(name: &str) -> bool {
+    is_identifier(name) && name.starts_with(|c: char| c.is_ascii_uppercase()) && !name.contains('_')
+}
+
+fn check_member_name(name: &str) -> Result<(), String> {
+    if !is_identifier(name) || name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        return Err(format!("name must be snake_case: {}", name));
+    }
+    if RESERVED_WORDS.contains(&name) {
+        return Err(format!("name is a reserved word: {}", name));
+    }
+    Ok(())
+}
+
+fn parse_type(token: &str, allow_unit: bool) -> Result<IdlType, String> {
+    let wrapped = |prefix: &str| token.strip_prefix(prefix).and_then(|t| t.strip_suffix('>'));
+    let ty = match token {
+        "unit" if allow_unit => IdlType::Unit,
+        "unit" => return Err("unit is only allowed in method signatures".to_string()),
+        "bool" => IdlType::Bool,
+        "u8" => IdlType::U8,
+        "u16" => IdlType::U16,
+        "u32" => IdlType::U32,
+        "u64" => IdlType::U64,
+        "i8" => IdlType::I8,
+        "i16" => IdlType::I16,
+        "i32" => IdlType::I32,
+        "i64" => IdlType::I64,
+        "string" => IdlType::String,
+        "bytes" => IdlType::Bytes,
+        _ => {
+            if let Some(inner) = wrapped("list<") {
+                IdlType::List(Box::new(parse_type(inner, false)?))
+            } else if let Some(inner) = wrapped("option<") {
+                let inner = parse_type(inner, false)?;
+                // TypeScript's `null` cannot tell the two levels apart
+                if matches!(inner, IdlType::Option(_)) {
+                    return Err(format!("nested options are not supported: {}", token));
+                }
+                IdlType::Option(Box::new(inner))
+            } else if is_type_name(token) {
+                IdlType::Record(token.to_string())
+            } else {
+                return Err(format!("invalid type: {}", token));
+            }
+        }
+    };
+    Ok(ty)
+}
+
+// ============================================================================
+// Rust Generation
+// ============================================================================
+
+impl IdlType {
+    /// Rust type of a value
+    pub fn rust_type(&self) -> String {
+        match self {
+            Self::Unit => "()".to_string(),
+            Self::Bool => "bool".to_string(),
+            Self::U8 => "u8".to_string(),
+            Self::U16 => "u16".to_string(),
+            Self::U32 => "u32".to_string(),
+            Self::U64 => "u64".to_string(),
+            Self::I8 => "i8".to_string(),
+            Self::I16 => "i16".to_string(),
+            Self::I32 => "i32".to_string(),
+            Self::I64 => "i64".to_string(),
+            Self::String => "String".to_string(),
+            Self::Bytes => "Vec<u8>".to_string(),
+            Self::List(inner) => format!("Vec<{}>", inner.rust_type()),
+            Self::Option(inner) => format!("Option<{}>", inner.rust_type()),
+            Self::Record(name) => name.clone(),
+        }
+    }
+
+    /// Rust type a client method takes this value as
+    fn rust_arg_type(&self) -> String {
+        match self {
+            Self::String => "&str".to_string(),
+            Self::Bytes => "&[u8]".to_string(),
+            Self::List(inner) => format!("&[{}]", inner.rust_type()),
+            other => format!("&{}", other.rust_type()),
+        }
+    }
+}
+
+impl Idl {
+    /// Generate the Rust records, server traits and clients
+    pub fn generate_rust(&self, source_name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "//! Service stubs generated by `protocol-idl` from `{}`.",
+            source_name
+        );
+        out.push_str("//! Do not edit by hand; change the IDL file and regenerate.\n\n");
+        if !self.services.is_empty() {
+            out.push_str("use std::sync::Arc;\n\n");
+            out.push_str("use protocol_name::client::Client;\n");
+            out.push_str("use protocol_name::rpc::Router;\n");
+            out.push_str("use protocol_name::transport::Transport;\n");
+        }
+        if self.records.is_empty() {
+            out.push_str("use protocol_name::ProtocolError;\n");
+        } else {
+            out.push_str("use protocol_name::{Decode, Encode, ProtocolError};\n");
+        }
+
+        for record in &self.records {
+            out.push('\n');
+            doc(&mut out, "", record.description.as_deref());
+            out.push_str("#[derive(Debug, Clone, Default, PartialEq, Eq)]\n");
+            let _ = writeln!(out, "pub struct {} {{", record.name);
+            for field in &record.fields {
+                doc(&mut out, "    ", field.description.as_deref());
+                let _ = writeln!(out, "    pub {}: {},", field.name, field.ty.rust_type());
+            }
+            out.push_str("}\n\n");
+
+            let _ = writeln!(out, "impl Encode for {} {{", record.name);
+            out.push_str("    fn encode_payload(&self, out: &mut Vec<u8>) {\n");
+            for field in &record.fields {
+                let _ = writeln!(out, "        self.{}.encode_payload(out);", field.name);
+            }
+            out.push_str("    }\n}\n\n");
+
+            let _ = writeln!(out, "impl Decode for {} {{", record.name);
+            out.push_str(
+                "    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {\n",
+            );
+            out.push_str("        Ok(Self {\n");
+            for field in &record.fields {
+                let _ = writeln!(
+                    out,
+                    "            {}: Decode::decode_payload(input)?,",
+                    field.name
+                );
+            }
+            out.push_str("        })\n    }\n}\n");
+        }
+
+        for service in &self.services {
+            self.rust_server(&mut out, service);
+            self.rust_client(&mut out, service);
+        }
+        out
+    }
+
+    fn rust_server(&self, out: &mut String, service: &ServiceDef) {
+        let router_fn = format!("{}_router", snake_case(&service.name));
+        out.push('\n');
+        doc(out, "", service.description.as_deref());
+        if service.description.is_some() {
+            out.push_str("///\n");
+        }
+        let _ = writeln!(
+            out,
+            "/// Implement this and serve it with [`{}`].",
+            router_fn
+        );
+        let _ = writeln!(out, "pub trait {}: Send + Sync + 'static {{", service.name);
+        for method in &service.methods {
+            doc(out, "    ", method.description.as_deref());
+            let request = match method.request {
+                IdlType::Unit => String::new(),
+                ref ty => format!(", request: {}", ty.rust_type()),
+            };
+            let _ = writeln!(
+                out,
+                "    fn {}(&self{}) -> Result<{}, ProtocolError>;",
+                method.name,
+                request,
+                method.response.rust_type()
+            );
+        }
+        out.push_str("}\n\n");
+
+        let _ = writeln!(
+            out,
+            "/// Register the `{}` methods of `service` on `router`",
+            service.name
+        );
+        let _ = writeln!(
+            out,
+            "pub fn {}<S: {}>(router: Router, service: S) -> Router {{",
+            router_fn, service.name
+        );
+        out.push_str("    let service = Arc::new(service);\n");
+        for method in &service.methods {
+            let _ = writeln!(
+                out,
+                "    let {}_service = Arc::clone(&service);",
+                method.name
+            );
+        }
+        out.push_str("    router");
+        for method in &service.methods {
+            let (pattern, args) = match method.request {
+                IdlType::Unit => ("()".to_string(), ""),
+                ref ty => (format!("request: {}", ty.rust_type()), "request"),
+            };
+            let _ = write!(
+                out,
+                "\n        .route({:?}, move |{}| {}_service.{}({}))",
+                service.wire_name(method),
+                pattern,
+                method.name,
+                method.name,
+                args
+            );
+        }
+        out.push_str("\n}\n");
+    }
+
+    fn rust_client(&self, out: &mut String, service: &ServiceDef) {
+        let client = format!("{}Client", service.name);
+        out.push('\n');
+        let _ = writeln!(out, "/// Client for the `{}` service", service.name);
+        out.push_str("#[derive(Debug)]\n");
+        let _ = writeln!(out, "pub struct {}<T> {{", client);
+        out.push_str("    client: Client<T>,\n}\n\n");
+        let _ = writeln!(out, "impl<T: Transport> {}<T> {{", client);
+        out.push_str("    /// Call the service over `client`\n");
+        out.push_str(
+            "    pub fn new(client: Client<T>) -> Self {\n        Self { client }\n    }\n\n",
+        );
+        out.push_str("    /// Unwrap the underlying client\n");
+        out.push_str("    pub fn into_inner(self) -> Client<T> {\n        self.client\n    }\n");
+        for method in &service.methods {
+            out.push('\n');
+            doc(out, "    ", method.description.as_deref());
+            let (param, args) = match method.request {
+                IdlType::Unit => (String::new(), "&()"),
+                ref ty => (format!(", request: {}", ty.rust_arg_type()), "request"),
+            };
+            let _ = writeln!(
+                out,
+                "    pub fn {}(&mut self{}) -> Result<{}, ProtocolError> {{",
+                method.name,
+                param,
+                method.response.rust_type()
+            );
+            let _ = writeln!(
+                out,
+                "        self.client.call_method({:?}, {})",
+                service.wire_name(method),
+                args
+            );
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+    }
+}
+
+fn doc(out: &mut String, indent: &str, description: Option<&str>) {
+    if let Some(description) = description {
+        let _ = writeln!(out, "{}/// {}", indent, description);
+    }
+}
+
+// ============================================================================
+// TypeScript Generation
+// ============================================================================
+
+impl IdlType {
+    /// TypeScript type of a value
+    pub fn ts_type(&self) -> String {
+        match self {
+            Self::Unit => "void".to_string(),
+            Self::Bool => "boolean".to_string(),
+            Self::U8 | Self::U16 | Self::U32 | Self::I8 | Self::I16 | Self::I32 => {
+                "number".to_string()
+            }
+            Self::U64 | Self::I64 => "bigint".to_string(),
+            Self::String => "string".to_string(),
+            Self::Bytes => "Uint8Array".to_string(),
+            Self::List(inner) => format!("Array<{}>", inner.ts_type()),
+            Self::Option(inner) => format!("{} | null", inner.ts_type()),
+            Self::Record(name) => name.clone(),
+        }
+    }
+
+    /// Statement writing `value` to the Writer `w`; `depth` keeps closure
+    /// parameter names distinct
+    fn ts_write(&self, value: &str, depth: usize) -> String {
+        let item = format!("item{}", depth);
+        match self {
+            Self::Unit => String::new(),
+            Self::Bool => format!("w.bool({})", value),
+            Self::U8 => format!("w.u8({})", value),
+            Self::I8 => format!("w.i8({})", value),
+            Self::U16 => format!("w.uint({}, 16)", value),
+            Self::U32 => format!("w.uint({}, 32)", value),
+            Self::U64 => format!("w.uint({}, 64)", value),
+            Self::I16 => format!("w.int({}, 16)", value),
+            Self::I32 => format!("w.int({}, 32)", value),
+            Self::I64 => format!("w.int({}, 64)", value),
+            Self::String => format!("w.string({})", value),
+            Self::Bytes => format!("w.bytes({})", value),
+            Self::List(inner) => format!(
+                "w.list({}, ({}) => {})",
+                value,
+                item,
+                inner.ts_write(&item, depth + 1)
+            ),
+            Self::Option(inner) => format!(
+                "w.option({}, ({}) => {})",
+                value,
+                item,
+                inner.ts_write(&item, depth + 1)
+            ),
+            Self::Record(name) => format!("write{}(w, {})", name, value),
+        }
+    }
+
+    /// Expression reading a value from the Reader `r`
+    fn ts_read(&self) -> String {
+        match self {
+            Self::Unit => "undefined".to_string(),
+            Self::Bool => "r.bool()".to_string(),
+            Self::U8 => "r.u8()".to_string(),
+            Self::I8 => "r.i8()".to_string(),
+            Self::U16 => "Number(r.uint(16))".to_string(),
+            Self::U32 => "Number(r.uint(32))".to_string(),
+            Self::U64 => "r.uint(64)".to_string(),
+            Self::I16 => "Number(r.int(16))".to_string(),
+            Self::I32 => "Number(r.int(32))".to_string(),
+            Self::I64 => "r.int(64)".to_string(),
+            Self::String => "r.string()".to_string(),
+            Self::Bytes => "r.bytes()".to_string(),
+            Self::List(inner) => format!("r.list(() => {})", inner.ts_read()),
+            Self::Option(inner) => format!("r.option(() => {})", inner.ts_read()),
+            Self::Record(name) => format!("read{}(r)", name),
+        }
+    }
+}
+
+impl Idl {
+    /// Generate a TypeScript client for Node.js
+    ///
+    /// The output needs nothing but Node's `net`/`stream` types: it carries
+    /// its own framing, flow control and typed payload encoding.
+    pub fn generate_typescript(&self, source_name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "// Service client generated by `protocol-idl` from `{}`.",
+            source_name
+        );
+        out.push_str("// Do not edit by hand; change the IDL file and regenerate.\n\n");
+        out.push_str("import type { Duplex } from \"node:stream\";\n");
+
+        if !self.records.is_empty() {
+            section(&mut out, "Records");
+            for record in &self.records {
+                out.push('\n');
+                ts_doc(&mut out, "", record.description.as_deref());
+                let _ = writeln!(out, "export interface {} {{", record.name);
+                for field in &record.fields {
+                    ts_doc(&mut out, "  ", field.description.as_deref());
+                    let _ = writeln!(out, "  {}: {};", field.name, field.ty.ts_type());
+                }
+                out.push_str("}\n");
+            }
+        }
+
+        if !self.services.is_empty() {
+            section(&mut out, "Services");
+            for service in &self.services {
+                out.push('\n');
+                ts_doc(&mut out, "", service.description.as_deref());
+                let _ = writeln!(out, "export class {}Client {{", service.name);
+                out.push_str("  constructor(readonly connection: Connection) {}\n");
+                for method in &service.methods {
+                    out.push('\n');
+                    ts_doc(&mut out, "  ", method.description.as_deref());
+                    let (param, write) = match method.request {
+                        IdlType::Unit => (String::new(), "() => {}".to_string()),
+                        ref ty => (
+                            format!("request: {}", ty.ts_type()),
+                            format!("(w) => {}", ty.ts_write("request", 0)),
+                        ),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "  {}({}): Promise<{}> {{",
+                        camel_case(&method.name),
+                        param,
+                        method.response.ts_type()
+                    );
+                    let read = match method.response {
+                        IdlType::Unit => "() => undefined".to_string(),
+                        ref ty => format!("(r) => {}", ty.ts_read()),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "    return this.connection.call({:?}, {}, {});",
+                        service.wire_name(method),
+                        write,
+                        read
+                    );
+                    out.push_str("  }\n");
+                }
+                out.push_str("}\n");
+            }
+        }
+
+        // Only the directions a client needs, so nothing generated is unused
+        let mut written = Vec::new();
+        let mut read = Vec::new();
+        for method in self.services.iter().flat_map(|s| &s.methods) {
+            self.reachable(&method.request, &mut written);
+            self.reachable(&method.response, &mut read);
+        }
+        if !written.is_empty() || !read.is_empty() {
+            section(&mut out, "Record Encoding (SPEC.md Section 3.4)");
+        }
+        for record in &self.records {
+            if written.contains(&record.name.as_str()) {
+                let _ = write!(
+                    out,
+                    "\nfunction write{0}(w: Writer, value: {0}): void {{\n",
+                    record.name
+                );
+                for field in &record.fields {
+                    let _ = writeln!(
+                        out,
+                        "  {};",
+                        field.ty.ts_write(&format!("value.{}", field.name), 0)
+                    );
+                }
+                out.push_str("}\n");
+            }
+            if read.contains(&record.name.as_str()) {
+                let _ = write!(
+                    out,
+                    "\nfunction read{0}(r: Reader): {0} {{\n  return {{\n",
+                    record.name
+                );
+                for field in &record.fields {
+                    let _ = writeln!(out, "    {}: {},", field.name, field.ty.ts_read());
+                }
+                out.push_str("  };\n}\n");
+            }
+        }
+
+        section(&mut out, "Runtime");
+        out.push('\n');
+        let _ = writeln!(out, "const MAGIC = [{}];", hex_bytes(&MAGIC));
+        let _ = writeln!(out, "const VERSION = {};", VERSION);
+        let _ = writeln!(out, "const MAX_PAYLOAD_SIZE = {};", MAX_PAYLOAD_SIZE);
+        let _ = writeln!(out, "const COMPRESSED = 0x{:08X};", COMPRESSED);
+        let _ = writeln!(out, "const OPTIONS = 0x{:08X};", OPTIONS);
+        let known: Vec<MessageType> = (0..=u8::MAX)
+            .filter_map(|b| MessageType::try_from(b).ok())
+            .collect();
+        let _ = writeln!(
+            out,
+            "const KNOWN_TYPES = [{}];",
+            hex_bytes(&known.iter().map(|&t| t as u8).collect::<Vec<_>>())
+        );
+        let _ = writeln!(
+            out,
+            "const STATUS_TYPES = [{}];",
+            hex_bytes(
+                &known
+                    .iter()
+                    .filter(|t| t.has_status())
+                    .map(|&t| t as u8)
+                    .collect::<Vec<_>>()
+            )
+        );
+        for t in [
+            MessageType::Request,
+            MessageType::Response,
+            MessageType::WindowUpdate,
+            MessageType::Error,
+        ] {
+            let _ = writeln!(
+                out,
+                "const {} = 0x{:02X};",
+                snake_case(&format!("{:?}", t)).to_uppercase(),
+                t as u8
+            );
+        }
+        out.push_str(TS_RUNTIME);
+        out
+    }
+}
+
+impl Idl {
+    /// Add the records a value of type `ty` contains to `records`
+    fn reachable<'a>(&'a self, ty: &'a IdlType, records: &mut Vec<&'a str>) {
+        match ty {
+            IdlType::List(inner) | IdlType::Option(inner) => self.reachable(inner, records),
+            IdlType::Record(name) if !records.contains(&name.as_str()) => {
+                records.push(name);
+                for field in self.record(name).map_or(&[][..], |r| &r.fields) {
+                    self.reachable(&field.ty, records);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn section(out: &mut String, title: &str) {
+    let rule = "=".repeat(76);
+    let _ = write!(out, "\n// {}\n// {}\n// {}\n", rule, title, rule);
+}
+
+fn ts_doc(out: &mut String, indent: &str, description: Option<&str>) {
+    if let Some(description) = description {
+        let _ = writeln!(out, "{}/** {} */", indent, description);
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' && !out.is_empty() {
+            upper = true;
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("0x{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Framing, flow control and payload encoding shared by every generated
+/// TypeScript client
+const TS_RUNTIME: &str = r#"
+/** An error answer from the server, or a local protocol failure */
+export class RpcError extends Error {
+  constructor(
+    readonly code: number,
+    message: string,
+  ) {
+    super(message);
+    this.name = "RpcError";
+  }
+}
+
+function invalid(message: string): RpcError {
+  return new RpcError(0x01, message);
+}
+
+/** Writes values in the typed payload encoding */
+export class Writer {
+  private readonly out: number[] = [];
+
+  u8(value: number): void {
+    if (!Number.isInteger(value) || value < 0 || value > 0xff) throw new RangeError(`${value} out of range for u8`);
+    this.out.push(value);
+  }
+
+  i8(value: number): void {
+    if (!Number.isInteger(value) || value < -0x80 || value > 0x7f) throw new RangeError(`${value} out of range for i8`);
+    this.out.push(value & 0xff);
+  }
+
+  bool(value: boolean): void {
+    this.out.push(value ? 1 : 0);
+  }
+
+  uint(value: number | bigint, bits: number): void {
+    const v = BigInt(value);
+    if (v < 0n || v >> BigInt(bits) !== 0n) throw new RangeError(`${value} out of range for u${bits}`);
+    this.varint(v);
+  }
+
+  int(value: number | bigint, bits: number): void {
+    const v = BigInt(value);
+    const limit = 1n << BigInt(bits - 1);
+    if (v < -limit || v >= limit) throw new RangeError(`${value} out of range for i${bits}`);
+    this.varint(v >= 0n ? v << 1n : ((-v) << 1n) - 1n);
+  }
+
+  string(value: string): void {
+    this.bytes(new TextEncoder().encode(value));
+  }
+
+  bytes(value: Uint8Array): void {
+    this.varint(BigInt(value.length));
+    for (const byte of value) this.out.push(byte);
+  }
+
+  list<T>(values: readonly T[], write: (item: T) => void): void {
+    this.varint(BigInt(values.length));
+    values.forEach(write);
+  }
+
+  option<T>(value: T | null, write: (item: T) => void): void {
+    if (value === null) {
+      this.out.push(0);
+    } else {
+      this.out.push(1);
+      write(value);
+    }
+  }
+
+  finish(): Uint8Array {
+    return Uint8Array.from(this.out);
+  }
+
+  private varint(value: bigint): void {
+    while (value >= 0x80n) {
+      this.out.push(Number(value & 0x7fn) | 0x80);
+      value >>= 7n;
+    }
+    this.out.push(Number(value));
+  }
+}
+
+/** Reads values in the typed payload encoding */
+export class Reader {
+  private offset = 0;
+
+  constructor(private readonly input: Uint8Array) {}
+
+  u8(): number {
+    if (this.offset >= this.input.length) throw invalid("unexpected end of payload");
+    return this.input[this.offset++];
+  }
+
+  i8(): number {
+    return (this.u8() << 24) >> 24;
+  }
+
+  bool(): boolean {
+    const byte = this.u8();
+    if (byte > 1) throw invalid(`invalid bool: ${byte}`);
+    return byte === 1;
+  }
+
+  uint(bits: number): bigint {
+    const value = this.varint();
+    if (value >> BigInt(bits) !== 0n) throw invalid(`${value} out of range for u${bits}`);
+    return value;
+  }
+
+  int(bits: number): bigint {
+    const raw = this.varint();
+    const value = raw & 1n ? -(raw >> 1n) - 1n : raw >> 1n;
+    const limit = 1n << BigInt(bits - 1);
+    if (value < -limit || value >= limit) throw invalid(`${value} out of range for i${bits}`);
+    return value;
+  }
+
+  string(): string {
+    try {
+      return new TextDecoder("utf-8", { fatal: true }).decode(this.bytes());
+    } catch {
+      throw invalid("string is not UTF-8");
+    }
+  }
+
+  bytes(): Uint8Array {
+    const length = this.length();
+    const value = this.input.slice(this.offset, this.offset + length);
+    this.offset += length;
+    return value;
+  }
+
+  list<T>(read: () => T): T[] {
+    const count = this.length();
+    const items: T[] = [];
+    for (let i = 0; i < count; i++) items.push(read());
+    return items;
+  }
+
+  option<T>(read: () => T): T | null {
+    const tag = this.u8();
+    if (tag > 1) throw invalid(`invalid option tag: ${tag}`);
+    return tag === 1 ? read() : null;
+  }
+
+  /** Reject bytes left after the top-level value */
+  end(): void {
+    const left = this.input.length - this.offset;
+    if (left !== 0) throw invalid(`${left} trailing bytes`);
+  }
+
+  /** A length or count, which cannot exceed the bytes left */
+  private length(): number {
+    const value = this.varint();
+    if (value > BigInt(this.input.length - this.offset)) throw invalid("unexpected end of payload");
+    return Number(value);
+  }
+
+  private varint(): bigint {
+    let value = 0n;
+    for (let i = 0; i < 10; i++) {
+      const byte = this.u8();
+      const bits = BigInt(byte & 0x7f);
+      if (i === 9 && bits > 1n) throw invalid("varint overflows u64");
+      value |= bits << BigInt(7 * i);
+      if ((byte & 0x80) === 0) {
+        if (byte === 0 && i > 0) throw invalid("overlong varint");
+        return value;
+      }
+    }
+    throw invalid("varint overflows u64");
+  }
+}
+
+interface Frame {
+  type: number;
+  id: number;
+  status: number;
+  payload: Uint8Array;
+  size: number;
+}
+
+function encodeFrame(type: number, id: number, payload: Uint8Array): Uint8Array {
+  const frame = new Uint8Array(12 + payload.length);
+  const view = new DataView(frame.buffer);
+  frame.set(MAGIC, 0);
+  frame[2] = VERSION;
+  frame[3] = type;
+  view.setUint32(4, id);
+  view.setUint32(8, payload.length);
+  frame.set(payload, 12);
+  return frame;
+}
+
+/** Decode the frame at the start of `input`, or null if it is incomplete */
+function decodeFrame(input: Uint8Array): Frame | null {
+  if (input.length < 8) return null;
+  if (input[0] !== MAGIC[0] || input[1] !== MAGIC[1]) throw invalid("invalid magic");
+  if (input[2] !== VERSION) throw invalid(`unsupported version ${input[2]}`);
+  const type = input[3];
+  if (!KNOWN_TYPES.includes(type)) throw new RpcError(0x02, `unknown message type 0x${type.toString(16)}`);
+  const view = new DataView(input.buffer, input.byteOffset, input.length);
+  const id = view.getUint32(4);
+  const hasStatus = STATUS_TYPES.includes(type);
+  const header = hasStatus ? 13 : 12;
+  if (input.length < header) return null;
+  const status = hasStatus ? input[8] : 0;
+  const length = view.getUint32(header - 4);
+  if (length & COMPRESSED) throw invalid("compressed payloads are not supported");
+  if (length & OPTIONS) throw invalid("only requests may carry options");
+  if (length > MAX_PAYLOAD_SIZE) throw new RpcError(0x03, `payload of ${length} bytes is too large`);
+  if (input.length < header + length) return null;
+  return { type, id, status, payload: input.slice(header, header + length), size: header + length };
+}
+
+interface Call {
+  frame: Uint8Array;
+  payloadLength: number;
+  sent: boolean;
+  resolve: (payload: Uint8Array) => void;
+  reject: (error: Error) => void;
+}
+
+/**
+ * One connection to a server, shared by any number of service clients.
+ *
+ * Calls may overlap; they are sent as the server's flow-control window
+ * allows and answered in order. Frames other than answers, such as events,
+ * are ignored.
+ */
+export class Connection {
+  private input = new Uint8Array(0);
+  private readonly calls = new Map<number, Call>();
+  private readonly queue: number[] = [];
+  private requestCredit = 0;
+  private byteCredit = 0;
+  private granted = false;
+  private nextId = 1;
+  private failure: Error | null = null;
+
+  constructor(private readonly stream: Duplex) {
+    stream.on("data", (chunk: Uint8Array) => this.receive(chunk));
+    stream.on("error", (error: Error) => this.fail(error));
+    stream.on("close", () => this.fail(new RpcError(0, "connection closed")));
+  }
+
+  /** Call `method`, writing its arguments and reading its result */
+  call<T>(method: string, writeArgs: (w: Writer) => void, readResult: (r: Reader) => T): Promise<T> {
+    if (this.failure !== null) return Promise.reject(this.failure);
+    const w = new Writer();
+    w.string(method);
+    writeArgs(w);
+    const payload = w.finish();
+    if (payload.length > MAX_PAYLOAD_SIZE) {
+      return Promise.reject(new RpcError(0x03, `payload of ${payload.length} bytes is too large`));
+    }
+    const id = this.nextId;
+    this.nextId = id === 0xffffffff ? 1 : id + 1;
+    return new Promise<Uint8Array>((resolve, reject) => {
+      const frame = encodeFrame(REQUEST, id, payload);
+      this.calls.set(id, { frame, payloadLength: payload.length, sent: false, resolve, reject });
+      this.queue.push(id);
+      this.flush();
+    }).then((result) => {
+      const r = new Reader(result);
+      const value = readResult(r);
+      r.end();
+      return value;
+    });
+  }
+
+  /** Close the connection once pending writes finish */
+  close(): void {
+    this.stream.end();
+  }
+
+  /** Send queued calls while the window covers them */
+  private flush(): void {
+    while (this.queue.length > 0) {
+      const call = this.calls.get(this.queue[0])!;
+      if (this.requestCredit < 1 || this.byteCredit < call.payloadLength) break;
+      this.queue.shift();
+      this.requestCredit -= 1;
+      this.byteCredit -= call.payloadLength;
+      call.sent = true;
+      this.stream.write(call.frame);
+    }
+    // With nothing in flight no more credit is coming
+    const inFlight = this.calls.size - this.queue.length;
+    if (this.granted && inFlight === 0 && this.queue.length > 0) {
+      const id = this.queue.shift()!;
+      this.calls.get(id)!.reject(new RpcError(0x04, "request exceeds the flow-control window"));
+      this.calls.delete(id);
+      this.flush();
+    }
+  }
+
+  private receive(chunk: Uint8Array): void {
+    const input = new Uint8Array(this.input.length + chunk.length);
+    input.set(this.input, 0);
+    input.set(chunk, this.input.length);
+    this.input = input;
+    for (;;) {
+      let frame: Frame | null;
+      try {
+        frame = decodeFrame(this.input);
+      } catch (error) {
+        this.fail(error as Error);
+        this.stream.destroy();
+        return;
+      }
+      if (frame === null) return;
+      this.input = this.input.subarray(frame.size);
+      this.dispatch(frame);
+    }
+  }
+
+  private dispatch(frame: Frame): void {
+    if (frame.type === WINDOW_UPDATE) {
+      if (frame.payload.length !== 8) {
+        this.fail(invalid("window update payload must be 8 bytes"));
+        return;
+      }
+      const view = new DataView(frame.payload.buffer, frame.payload.byteOffset, 8);
+      this.requestCredit += view.getUint32(0);
+      this.byteCredit += view.getUint32(4);
+      this.granted = true;
+      this.flush();
+      return;
+    }
+    const message = () => new TextDecoder().decode(frame.payload);
+    if (frame.type === ERROR && frame.id === 0) {
+      this.fail(new RpcError(frame.status, message()));
+      return;
+    }
+    const call = this.calls.get(frame.id);
+    if (call === undefined || !call.sent) return;
+    if (frame.type === RESPONSE) {
+      this.calls.delete(frame.id);
+      call.resolve(frame.payload);
+    } else if (frame.type === ERROR) {
+      this.calls.delete(frame.id);
+      call.reject(new RpcError(frame.status, message()));
+    }
+    this.flush();
+  }
+
+  private fail(error: Error): void {
+    if (this.failure !== null) return;
+    this.failure = error;
+    for (const call of this.calls.values()) call.reject(error);
+    this.calls.clear();
+    this.queue.length = 0;
+  }
+}
+"#;
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALCULATOR: &str = r#"
+service Calculator "Integer arithmetic"
+method add     Operands -> i64         "Sum of the operands"
+method history unit     -> list<Entry>
+
+record Operands "Two operands"
+field a i64 "Left operand"
+field b i64
+
+record Entry
+field operands Operands
+field result   option<i64>
+"#;
+
+    #[test]
+    fn test_parse() {
+        let idl = Idl::parse(CALCULATOR).unwrap();
+        assert_eq!(idl.services.len(), 1);
+        let service = &idl.services[0];
+        assert_eq!(
+            service.methods[0].request,
+            IdlType::Record("Operands".to_string())
+        );
+        assert_eq!(service.methods[1].request, IdlType::Unit);
+        assert_eq!(
+            service.methods[1].response,
+            IdlType::List(Box::new(IdlType::Record("Entry".to_string())))
+        );
+        assert_eq!(service.wire_name(&service.methods[0]), "calculator.add");
+        assert_eq!(
+            idl.record("Entry").unwrap().fields[1].ty.rust_type(),
+            "Option<i64>"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = [
+            ("method add u32 -> u32", 1, "outside a service"),
+            ("record R\nfield x unit", 2, "unit is only allowed"),
+            ("record R\nfield type u8", 2, "reserved word"),
+            ("record R\nfield x option<option<u8>>", 2, "nested options"),
+            (
+                "service S\nmethod f Missing -> u8",
+                0,
+                "unknown type: Missing",
+            ),
+            ("record R\nfield r list<R>", 0, "contains itself"),
+            ("service Rpc\nmethod f u8 -> u8", 0, "reserved"),
+            (
+                "service S\nmethod f u8 -> u8\nmethod f u8 -> u8",
+                0,
+                "duplicate method",
+            ),
+            ("service S\nmethod into_inner u8 -> u8", 2, "clashes"),
+            ("record R", 0, "no fields"),
+            (
+                "service S\nmethod f u8 -> u8\nrecord SClient\nfield x u8",
+                0,
+                "duplicate name: SClient",
+            ),
+        ];
+        for (source, line, message) in cases {
+            let err = Idl::parse(source).unwrap_err();
+            assert_eq!(err.line, line, "{}", source);
+            assert!(err.message.contains(message), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn test_generate_rust() {
+        let rust = Idl::parse(CALCULATOR)
+            .unwrap()
+            .generate_rust("calculator.idl");
+        assert!(rust.contains(
+            "pub struct Operands {\n    /// Left operand\n    pub a: i64,\n    pub b: i64,\n}"
+        ));
+        assert!(rust.contains("    fn history(&self) -> Result<Vec<Entry>, ProtocolError>;"));
+        assert!(rust.contains(
+            ".route(\"calculator.add\", move |request: Operands| add_service.add(request))"
+        ));
+        assert!(rust.contains("self.client.call_method(\"calculator.history\", &())"));
+    }
+
+    #[test]
+    fn test_generate_typescript() {
+        let ts = Idl::parse(CALCULATOR)
+            .unwrap()
+            .generate_typescript("calculator.idl");
+        assert!(ts.contains(
+            "export interface Entry {\n  operands: Operands;\n  result: bigint | null;\n}"
+        ));
+        assert!(ts.contains("  history(): Promise<Array<Entry>> {"));
+        assert!(ts.contains("    result: r.option(() => r.int(64)),"));
+        // Entry is only ever read, Operands written and read
+        assert!(!ts.contains("function writeEntry"));
+        assert!(ts.contains("function writeOperands") && ts.contains("function readOperands"));
+        assert!(ts.contains("const STATUS_TYPES = [0x02, 0xFF];"));
+        assert!(ts.contains("const COMPRESSED = 0x80000000;"));
+        assert!(ts.contains("if (length & COMPRESSED) throw"));
+    }
+
+    #[test]
+    fn test_camel_case() {
+        assert_eq!(camel_case("list_all_items"), "listAllItems");
+        assert_eq!(camel_case("add"), "add");
+    }
+}
@@ -0,0 +1,1328 @@
+//! Cross-implementation test vectors
+//!
+//! Other implementations of the protocol (such as the TypeScript port) are
+//! checked against this one through a shared set of canonical cases.
+//! [`cases`] builds them from the reference codec: every message type,
+//! payload edge cases up to `MAX_PAYLOAD_SIZE`, and malformed frames.
+//! [`to_json`] exports them; `protocol-name vectors export --json` prints
+//! the same document, and `tests/vectors/interop.json` is a checked-in copy.
+//!
+//! A foreign implementation runs every case and writes its results as JSON
+//! (format below). [`verify`] compares them with the reference and reports
+//! each case that diverges; `protocol-name vectors verify RESULTS` does the
+//! same from the command line and fails if any case does.
+//!
+//! ## Vector format
+//!
+//! ```text
+//! {"protocol": "protocol-name", "protocol_version": 1, "max_payload_size": 1048576,
+//!  "cases": [
+//!   {"name": "...", "description": "...", "kind": "round_trip", "bytes": B, "message": M},
+//!   {"name": "...", "description": "...", "kind": "decode", "bytes": B, "message": M},
+//!   {"name": "...", "description": "...", "kind": "error", "bytes": B,
+//!    "error": "InvalidMagic", "error_code": "InvalidFormat"}
+//!  ]}
+//!
+//! M = {"type": "Request", "version": 1, "id": 1, "status": null, "payload": B}
+//!     plus, for a Request carrying options,
+//!     "options": {"budget_ms": 250, "priority": 128}   (budget_ms may be null)
+//! B = "5455..."                        hex string, or an array of parts:
+//!     ["5455...", {"fill": "5a", "count": 1048576}, ...]
+//! ```
+//!
+//! Bytes are decoded strictly: the buffer holds exactly one frame.
+//!
+//! - `round_trip`: `bytes` decodes to `message`, and `message` encodes to
+//!   exactly `bytes`.
+//! - `decode`: `bytes` decodes to `message`. Used where the encoding is not
+//!   canonical, such as a compressed payload.
+//! - `error`: decoding `bytes` fails. `error` names the [`ProtocolError`]
+//!   variant and `error_code` the code sent to the peer.
+//!
+//! ## Result format
+//!
+//! ```text
+//! {"results": [
+//!   {"name": "request_minimal", "decoded": M, "encoded": B},
+//!   {"name": "invalid_magic", "error": "InvalidMagic"}
+//! ]}
+//! ```
+//!
+//! `encoded` is required for `round_trip` cases only. Every case must have
+//! a result.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write as _};
+
+use crate::batch::{Batch, BatchResponse};
+use crate::compress::Compression;
+use crate::deadline::{RequestOptions, OPTIONS};
+use crate::pubsub::{Event, Publication};
+use crate::rpc::MethodCall;
+use crate::session::{Resume, SessionId};
+use crate::{
+    decode_strict, encode, ErrorCode, Message, MessageType, ProtocolError, MAX_PAYLOAD_SIZE,
+    VERSION,
+};
+
+/// Runs of at least this many equal bytes are exported as a fill
+const FILL_THRESHOLD: usize = 64;
+
+// ============================================================================
+// Cases
+// ============================================================================
+
+/// Expected result of a case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Decodes to this message, which encodes back to the same bytes
+    RoundTrip(Message),
+    /// Decodes to this message
+    Decode(Message),
+    /// Fails to decode
+    Error {
+        /// `ProtocolError` variant name
+        error: String,
+        /// Code sent to the peer
+        code: ErrorCode,
+    },
+}
+
+/// One canonical encode/decode case
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    /// Unique identifier
+    pub name: String,
+    /// One-line description
+    pub description: String,
+    /// Frame bytes
+    pub bytes: Vec<u8>,
+    /// Expected result
+    pub outcome: Outcome,
+}
+
+impl Case {
+    fn round_trip(name: &str, description: &str, message: Message) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            bytes: encode(&message).expect("reference cases encode"),
+            outcome: Outcome::RoundTrip(message),
+        }
+    }
+
+    fn decode(name: &str, description: &str, bytes: Vec<u8>, message: Message) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            bytes,
+            outcome: Outcome::Decode(message),
+        }
+    }
+
+    fn error(name: &str, description: &str, bytes: Vec<u8>, error: &str, code: ErrorCode) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            bytes,
+            outcome: Outcome::Error {
+                error: error.to_string(),
+                code,
+            },
+        }
+    }
+}
+
+/// Name of a `ProtocolError` variant, as used in vectors and results
+pub fn error_name(error: &ProtocolError) -> &'static str {
+    match error {
+        ProtocolError::InvalidMagic { .. } => "InvalidMagic",
+        ProtocolError::UnsupportedVersion { .. } => "UnsupportedVersion",
+        ProtocolError::UnknownType { .. } => "UnknownType",
+        ProtocolError::PayloadTooLarge { .. } => "PayloadTooLarge",
+        ProtocolError::UnexpectedFlag { .. } => "UnexpectedFlag",
+        ProtocolError::IncompleteMessage { .. } => "IncompleteMessage",
+        ProtocolError::InvalidPayload(_) => "InvalidPayload",
+        ProtocolError::TrailingBytes(_) => "TrailingBytes",
+        ProtocolError::WindowExhausted { .. } => "WindowExhausted",
+        ProtocolError::DuplicateId(_) => "DuplicateId",
+        ProtocolError::UnknownMethod(_) => "UnknownMethod",
+        ProtocolError::UnknownSession(_) => "UnknownSession",
+        ProtocolError::DeadlineExceeded(_) => "DeadlineExceeded",
+        ProtocolError::Rejected { .. } => "Rejected",
+        ProtocolError::UnexpectedResponse { .. } => "UnexpectedResponse",
+        ProtocolError::Tampered { .. } => "Tampered",
+        ProtocolError::Replayed { .. } => "Replayed",
+        ProtocolError::HandshakeFailed(_) => "HandshakeFailed",
+        ProtocolError::WebSocket(_) => "WebSocket",
+        #[cfg(feature = "std")]
+        ProtocolError::Io { .. } => "Io",
+    }
+}
+
+/// The canonical cases, built with the reference codec
+pub fn cases() -> Vec<Case> {
+    let mut cases = vec![
+        Case::round_trip(
+            "request_minimal",
+            "Request with an empty payload",
+            Message::request(1, b""),
+        ),
+        Case::round_trip(
+            "request_payload",
+            "Request carrying \"hello\"",
+            Message::request(2, b"hello"),
+        ),
+        Case::round_trip(
+            "request_id_zero",
+            "Request with ID 0",
+            Message::request(0, b"x"),
+        ),
+        Case::round_trip(
+            "request_id_max",
+            "Request with the largest ID",
+            Message::request(u32::MAX, b""),
+        ),
+        Case::round_trip(
+            "request_all_byte_values",
+            "Request carrying every byte value once",
+            Message::request(3, &(0..=255).collect::<Vec<u8>>()),
+        ),
+        Case::round_trip(
+            "request_utf8",
+            "Request carrying multi-byte UTF-8",
+            Message::request(4, "h\u{e9}llo w\u{f6}rld \u{2713} \u{1F980}".as_bytes()),
+        ),
+        Case::round_trip(
+            "request_max_payload",
+            "Request with a payload of exactly the maximum size",
+            Message::request(5, &vec![0x5A; MAX_PAYLOAD_SIZE]),
+        ),
+        Case::round_trip(
+            "response_success",
+            "Response with status 0",
+            Message::response(6, 0, b"ok"),
+        ),
+        Case::round_trip(
+            "response_empty",
+            "Response with an empty payload",
+            Message::response(7, 0, b""),
+        ),
+        Case::round_trip(
+            "response_status_max",
+            "Response with status 255",
+            Message::response(8, 255, b"no"),
+        ),
+        Case::round_trip(
+            "window_update",
+            "WindowUpdate granting 32 requests and 4 MiB",
+            Message::window_update(32, 4 * 1024 * 1024),
+        ),
+        Case::round_trip(
+            "batch",
+            "Batch 9 carrying request 10 (\"hi\") and request 11 (empty)",
+            {
+                let mut batch = Batch::new(9);
+                batch.push(10, b"hi");
+                batch.push(11, b"");
+                batch.to_message()
+            },
+        ),
+        Case::round_trip(
+            "batch_response",
+            "BatchResponse 9: success for request 10, DuplicateId for request 11",
+            BatchResponse::new(
+                9,
+                vec![
+                    Message::response(10, 0, b"hi"),
+                    Message::error(11, 0x05, ""),
+                ],
+            )
+            .to_message()
+            .expect("reference batch response encodes"),
+        ),
+        Case::round_trip(
+            "method_call",
+            "Request calling add(1, 300) with arguments (u32, u32)",
+            MethodCall::new("add", &(1u32, 300u32)).to_message(12),
+        ),
+        Case::round_trip(
+            "subscribe",
+            "Subscribe 13 to \"orders.>\"",
+            Message::subscribe(13, "orders.>"),
+        ),
+        Case::round_trip(
+            "unsubscribe",
+            "Unsubscribe 14 from subscription 13",
+            Message::unsubscribe(14, 13),
+        ),
+        Case::round_trip(
+            "publish",
+            "Publish 15 of \"hi\" to \"a.b\"",
+            Publication::new("a.b", b"hi").to_message(15),
+        ),
+        Case::round_trip(
+            "event",
+            "Event for subscription 13 after one dropped event",
+            Event {
+                subscription: 13,
+                dropped: 1,
+                topic: "a.b".to_string(),
+                data: b"hi".to_vec(),
+            }
+            .to_message(),
+        ),
+        Case::round_trip(
+            "request_options",
+            "Request 20 with a 250 ms budget and priority 200",
+            Message::request(20, b"hi")
+                .with_options(RequestOptions::new().with_budget_ms(250).with_priority(200)),
+        ),
+        Case::round_trip(
+            "request_options_no_budget",
+            "Request 21 with no deadline and the lowest priority",
+            Message::request(21, b"").with_options(RequestOptions::new().with_priority(0)),
+        ),
+        Case::round_trip(
+            "resume",
+            "Resume 19 of session 101112...1f, replaying requests 10 and 11",
+            Resume::new(
+                SessionId(core::array::from_fn(|i| 0x10 + i as u8)),
+                vec![10, 11],
+            )
+            .to_message(19),
+        ),
+    ];
+
+    for value in 0x01..=0xFF {
+        if let Some(code) = ErrorCode::from_u8(value) {
+            cases.push(Case::round_trip(
+                &format!("error_{}", snake_case(&format!("{:?}", code))),
+                &format!("Error with code {:?}", code),
+                Message::error(16, value, &format!("{:?}", code)),
+            ));
+        }
+    }
+
+    let abc = Message::request(17, b"abcabcabcabc");
+    let compressed = Compression::new()
+        .with_min_size(0)
+        .encode(&abc)
+        .expect("reference frame compresses");
+    cases.push(Case::decode(
+        "request_compressed",
+        "Request carrying \"abcabcabcabc\", compressed",
+        compressed.clone(),
+        abc,
+    ));
+    let large = Message::request(18, &b"0123456789".repeat(100));
+    cases.push(Case::decode(
+        "request_compressed_large",
+        "Request carrying 1000 bytes of digits, compressed",
+        Compression::new()
+            .encode(&large)
+            .expect("reference frame compresses"),
+        large,
+    ));
+
+    let urgent = Message::request(22, b"abcabcabcabc")
+        .with_options(RequestOptions::new().with_budget_ms(100));
+    cases.push(Case::decode(
+        "request_options_compressed",
+        "Request 22 with a 100 ms budget carrying \"abcabcabcabc\", compressed",
+        Compression::new()
+            .with_min_size(0)
+            .encode(&urgent)
+            .expect("reference frame compresses"),
+        urgent,
+    ));
+
+    let minimal = encode(&Message::request(1, b"")).expect("reference cases encode");
+    let with_payload = encode(&Message::request(2, b"hello")).expect("reference cases encode");
+    let response = encode(&Message::response(6, 0, b"ok")).expect("reference cases encode");
+    let with = |bytes: &[u8], at: usize, value: &[u8]| {
+        let mut bytes = bytes.to_vec();
+        bytes[at..at + value.len()].copy_from_slice(value);
+        bytes
+    };
+
+    cases.extend([
+        Case::error(
+            "empty_input",
+            "No bytes at all",
+            Vec::new(),
+            "IncompleteMessage",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "invalid_magic",
+            "Magic bytes inverted",
+            with(&minimal, 0, &[0xAB, 0xAA]),
+            "InvalidMagic",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "unsupported_version",
+            "Version 2",
+            with(&minimal, 2, &[VERSION + 1]),
+            "UnsupportedVersion",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "unknown_type",
+            "Unassigned message type 0x0B",
+            with(&minimal, 3, &[0x0B]),
+            "UnknownType",
+            ErrorCode::UnknownType,
+        ),
+        Case::error(
+            "incomplete_header",
+            "Input ends inside the ID field",
+            minimal[..6].to_vec(),
+            "IncompleteMessage",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "incomplete_status",
+            "Response ending before its status byte",
+            response[..8].to_vec(),
+            "IncompleteMessage",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "incomplete_payload",
+            "Length 5 followed by 3 payload bytes",
+            with_payload[..with_payload.len() - 2].to_vec(),
+            "IncompleteMessage",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "payload_too_large",
+            "Length one byte over the maximum",
+            with(&minimal, 8, &(MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes()),
+            "PayloadTooLarge",
+            ErrorCode::PayloadTooLarge,
+        ),
+        Case::error(
+            "trailing_bytes",
+            "A complete request followed by one more byte",
+            [&minimal[..], &[0x00]].concat(),
+            "TrailingBytes",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "compressed_too_large",
+            "Compressed payload declaring 2 MiB",
+            with(
+                &compressed,
+                12,
+                &(2 * MAX_PAYLOAD_SIZE as u32).to_be_bytes(),
+            ),
+            "PayloadTooLarge",
+            ErrorCode::PayloadTooLarge,
+        ),
+        Case::error(
+            "compressed_size_mismatch",
+            "Compressed payload declaring one byte more than it inflates to",
+            with(&compressed, 12, &13u32.to_be_bytes()),
+            "InvalidPayload",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "compressed_invalid_stream",
+            "Compressed payload holding a reserved DEFLATE block type",
+            with(&compressed, 16, &[0xFF]),
+            "InvalidPayload",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "options_on_response",
+            "Response whose length field flags request options",
+            with(&response, 9, &(OPTIONS | 2).to_be_bytes()),
+            "UnexpectedFlag",
+            ErrorCode::InvalidFormat,
+        ),
+        Case::error(
+            "incomplete_options",
+            "Request flagging options, ending after two option bytes",
+            [
+                &with(&minimal, 8, &OPTIONS.to_be_bytes())[..],
+                &[0x00, 0x00],
+            ]
+            .concat(),
+            "IncompleteMessage",
+            ErrorCode::InvalidFormat,
+        ),
+    ]);
+    cases
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
+
+// ============================================================================
+// Export
+// ============================================================================
+
+/// Render cases as the vector document, one case per line
+pub fn to_json(cases: &[Case]) -> String {
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{{\"protocol\":\"protocol-name\",\"protocol_version\":{},\"max_payload_size\":{},\"cases\":[",
+        VERSION, MAX_PAYLOAD_SIZE
+    );
+    for (i, case) in cases.iter().enumerate() {
+        out.push_str(if i == 0 { "\n" } else { ",\n" });
+        let _ = write!(
+            out,
+            "{{\"name\":{},\"description\":{},",
+            json_string(&case.name),
+            json_string(&case.description)
+        );
+        match &case.outcome {
+            Outcome::RoundTrip(message) | Outcome::Decode(message) => {
+                let kind = if matches!(case.outcome, Outcome::RoundTrip(_)) {
+                    "round_trip"
+                } else {
+                    "decode"
+                };
+                let _ = write!(
+                    out,
+                    "\"kind\":\"{}\",\"bytes\":{},\"message\":{}}}",
+                    kind,
+                    bytes_json(&case.bytes),
+                    message_json(message)
+                );
+            }
+            Outcome::Error { error, code } => {
+                let _ = write!(
+                    out,
+                    "\"kind\":\"error\",\"bytes\":{},\"error\":{},\"error_code\":\"{:?}\"}}",
+                    bytes_json(&case.bytes),
+                    json_string(error),
+                    code
+                );
+            }
+        }
+    }
+    out.push_str("\n]}\n");
+    out
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hex string, or parts with long runs written as fills
+fn bytes_json(bytes: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut literal = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..].iter().take_while(|&&b| b == bytes[i]).count();
+        if run >= FILL_THRESHOLD {
+            if literal < i {
+                parts.push(format!("\"{}\"", hex(&bytes[literal..i])));
+            }
+            parts.push(format!(
+                "{{\"fill\":\"{:02x}\",\"count\":{}}}",
+                bytes[i], run
+            ));
+            literal = i + run;
+        }
+        i += run;
+    }
+    if literal == 0 {
+        return format!("\"{}\"", hex(bytes));
+    }
+    if literal < bytes.len() {
+        parts.push(format!("\"{}\"", hex(&bytes[literal..])));
+    }
+    format!("[{}]", parts.join(","))
+}
+
+fn message_json(message: &Message) -> String {
+    let options = message.options.map_or(String::new(), |options| {
+        format!(
+            ",\"options\":{{\"budget_ms\":{},\"priority\":{}}}",
+            options
+                .budget_ms()
+                .map_or("null".to_string(), |ms| ms.to_string()),
+            options.priority()
+        )
+    });
+    format!(
+        "{{\"type\":\"{:?}\",\"version\":{},\"id\":{},\"status\":{}{},\"payload\":{}}}",
+        message.message_type,
+        message.version,
+        message.id,
+        message.status.map_or("null".to_string(), |s| s.to_string()),
+        options,
+        bytes_json(&message.payload)
+    )
+}
+
+// ============================================================================
+// Results
+// ============================================================================
+
+/// One implementation's result for one case
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaseResult {
+    /// Name of the case
+    pub name: String,
+    /// Message the bytes decoded to
+    pub decoded: Option<Message>,
+    /// Encoding of the expected message (round-trip cases)
+    pub encoded: Option<Vec<u8>>,
+    /// Error variant name, if decoding failed
+    pub error: Option<String>,
+}
+
+/// Results of running every case through the reference implementation
+pub fn reference_results(cases: &[Case]) -> Vec<CaseResult> {
+    cases
+        .iter()
+        .map(|case| {
+            let mut result = CaseResult {
+                name: case.name.clone(),
+                ..CaseResult::default()
+            };
+            match decode_strict(&case.bytes) {
+                Ok(message) => result.decoded = Some(message),
+                Err(e) => result.error = Some(error_name(&e).to_string()),
+            }
+            if let Outcome::RoundTrip(message) = &case.outcome {
+                result.encoded = encode(message).ok();
+            }
+            result
+        })
+        .collect()
+}
+
+/// Render results in the result format
+pub fn results_to_json(results: &[CaseResult]) -> String {
+    let lines: Vec<String> = results
+        .iter()
+        .map(|result| {
+            let mut fields = vec![format!("\"name\":{}", json_string(&result.name))];
+            if let Some(message) = &result.decoded {
+                fields.push(format!("\"decoded\":{}", message_json(message)));
+            }
+            if let Some(bytes) = &result.encoded {
+                fields.push(format!("\"encoded\":{}", bytes_json(bytes)));
+            }
+            if let Some(error) = &result.error {
+                fields.push(format!("\"error\":{}", json_string(error)));
+            }
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+    format!("{{\"results\":[\n{}\n]}}\n", lines.join(",\n"))
+}
+
+/// Parse a result document
+pub fn parse_results(json: &str) -> Result<Vec<CaseResult>, String> {
+    let document = Json::parse(json)?;
+    let results = document
+        .get("results")
+        .and_then(Json::as_array)
+        .ok_or("expected an object with a \"results\" array")?;
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let context = |e: String| format!("results[{}]: {}", i, e);
+            let name = value
+                .get("name")
+                .and_then(Json::as_str)
+                .ok_or_else(|| context("missing \"name\"".to_string()))?;
+            let context = |e: String| format!("result {:?}: {}", name, e);
+            Ok(CaseResult {
+                name: name.to_string(),
+                decoded: value
+                    .get("decoded")
+                    .map(parse_message)
+                    .transpose()
+                    .map_err(context)?,
+                encoded: value
+                    .get("encoded")
+                    .map(parse_bytes)
+                    .transpose()
+                    .map_err(context)?,
+                error: match value.get("error") {
+                    None | Some(Json::Null) => None,
+                    Some(error) => Some(
+                        error
+                            .as_str()
+                            .ok_or_else(|| context("\"error\" must be a string".into()))?
+                            .into(),
+                    ),
+                },
+            })
+        })
+        .collect()
+}
+
+fn parse_message(value: &Json) -> Result<Message, String> {
+    let type_name = value
+        .get("type")
+        .and_then(Json::as_str)
+        .ok_or("message needs a \"type\" string")?;
+    let message_type = (0..=u8::MAX)
+        .filter_map(|b| MessageType::try_from(b).ok())
+        .find(|t| format!("{:?}", t) == type_name)
+        .ok_or_else(|| format!("unknown message type {:?}", type_name))?;
+    let integer = |field: &str, max: u64| -> Result<Option<u64>, String> {
+        match value.get(field) {
+            None | Some(Json::Null) => Ok(None),
+            Some(Json::Number(n)) if *n <= max => Ok(Some(*n)),
+            Some(_) => Err(format!("\"{}\" must be an integer up to {}", field, max)),
+        }
+    };
+    Ok(Message {
+        version: integer("version", 0xFF)?.ok_or("message needs a \"version\"")? as u8,
+        message_type,
+        id: integer("id", u64::from(u32::MAX))?.ok_or("message needs an \"id\"")? as u32,
+        status: integer("status", 0xFF)?.map(|s| s as u8),
+        options: match value.get("options") {
+            None | Some(Json::Null) => None,
+            Some(options) => Some(parse_options(options)?),
+        },
+        payload: parse_bytes(value.get("payload").ok_or("message needs a \"payload\"")?)?,
+    })
+}
+
+fn parse_options(value: &Json) -> Result<RequestOptions, String> {
+    let options = RequestOptions::new();
+    let options = match value.get("budget_ms") {
+        None | Some(Json::Null) => options,
+        Some(Json::Number(ms)) if *ms < u64::from(u32::MAX) => options.with_budget_ms(*ms as u32),
+        Some(_) => {
+            return Err(format!(
+                "\"budget_ms\" must be null or an integer below {}",
+                u32::MAX
+            ))
+        }
+    };
+    match value.get("priority") {
+        Some(Json::Number(priority)) if *priority <= 0xFF => {
+            Ok(options.with_priority(*priority as u8))
+        }
+        _ => Err("options need a \"priority\" up to 255".to_string()),
+    }
+}
+
+fn parse_bytes(value: &Json) -> Result<Vec<u8>, String> {
+    fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+        if s.len() % 2 == 1 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("invalid hex string {:?}", s));
+        }
+        Ok((0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("hex digits"))
+            .collect())
+    }
+
+    match value {
+        Json::String(s) => parse_hex(s),
+        Json::Array(parts) => {
+            let mut bytes = Vec::new();
+            for part in parts {
+                match part {
+                    Json::String(s) => bytes.extend(parse_hex(s)?),
+                    Json::Object(_) => {
+                        let fill = part.get("fill").and_then(Json::as_str).map(parse_hex);
+                        let count = part.get("count").and_then(Json::as_u64);
+                        match (fill, count) {
+                            (Some(Ok(fill)), Some(count))
+                                if fill.len() == 1 && count <= MAX_PAYLOAD_SIZE as u64 * 2 =>
+                            {
+                                bytes.resize(bytes.len() + count as usize, fill[0]);
+                            }
+                            _ => {
+                                return Err(
+                                    "a fill needs one \"fill\" byte and a \"count\"".to_string()
+                                )
+                            }
+                        }
+                    }
+                    _ => return Err("byte parts must be hex strings or fills".to_string()),
+                }
+            }
+            Ok(bytes)
+        }
+        _ => Err("bytes must be a hex string or an array of parts".to_string()),
+    }
+}
+
+// ============================================================================
+// Verification
+// ============================================================================
+
+/// A case whose result diverges from the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// Name of the case
+    pub case: String,
+    /// What diverged
+    pub reason: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.case, self.reason)
+    }
+}
+
+/// Outcome of verifying a set of results
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Cases whose results match the reference
+    pub passed: usize,
+    /// Cases that diverge, missing or unknown
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// Whether every case matched
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Check a foreign implementation's results against the cases
+pub fn verify(cases: &[Case], results: &[CaseResult]) -> Report {
+    let mut report = Report::default();
+    for case in cases {
+        let failure = match results.iter().find(|r| r.name == case.name) {
+            None => Some("no result".to_string()),
+            Some(result) => check(case, result),
+        };
+        match failure {
+            None => report.passed += 1,
+            Some(reason) => report.failures.push(Failure {
+                case: case.name.clone(),
+                reason,
+            }),
+        }
+    }
+    for result in results {
+        if !cases.iter().any(|c| c.name == result.name) {
+            report.failures.push(Failure {
+                case: result.name.clone(),
+                reason: "result for an unknown case".to_string(),
+            });
+        }
+    }
+    report
+}
+
+/// Reason `result` diverges from `case`, if it does
+fn check(case: &Case, result: &CaseResult) -> Option<String> {
+    match &case.outcome {
+        Outcome::RoundTrip(expected) | Outcome::Decode(expected) => {
+            if let Some(error) = &result.error {
+                return Some(format!(
+                    "expected a {:?} message, got error {}",
+                    expected.message_type, error
+                ));
+            }
+            match &result.decoded {
+                None => return Some("no decoded message".to_string()),
+                Some(decoded) if decoded != expected => {
+                    return Some(describe_mismatch(expected, decoded))
+                }
+                Some(_) => {}
+            }
+            if matches!(case.outcome, Outcome::RoundTrip(_)) {
+                match &result.encoded {
+                    None => return Some("no encoding of the message".to_string()),
+                    Some(encoded) if *encoded != case.bytes => {
+                        let at = encoded.iter().zip(&case.bytes).position(|(a, b)| a != b);
+                        return Some(match at {
+                            Some(at) => format!(
+                                "encoding differs at byte {}: expected {:02x}, got {:02x}",
+                                at, case.bytes[at], encoded[at]
+                            ),
+                            None => format!(
+                                "encoding is {} bytes, expected {}",
+                                encoded.len(),
+                                case.bytes.len()
+                            ),
+                        });
+                    }
+                    Some(_) => {}
+                }
+            }
+            None
+        }
+        Outcome::Error { error, .. } => match (&result.error, &result.decoded) {
+            (Some(actual), _) if actual == error => None,
+            (Some(actual), _) => Some(format!("expected error {}, got {}", error, actual)),
+            (None, Some(message)) => Some(format!(
+                "expected error {}, got a {:?} message",
+                error, message.message_type
+            )),
+            (None, None) => Some(format!("expected error {}, got nothing", error)),
+        },
+    }
+}
+
+fn describe_mismatch(expected: &Message, actual: &Message) -> String {
+    let fields = [
+        (
+            "type",
+            format!("{:?}", expected.message_type),
+            format!("{:?}", actual.message_type),
+        ),
+        (
+            "version",
+            expected.version.to_string(),
+            actual.version.to_string(),
+        ),
+        ("id", expected.id.to_string(), actual.id.to_string()),
+        (
+            "status",
+            format!("{:?}", expected.status),
+            format!("{:?}", actual.status),
+        ),
+        (
+            "options",
+            format!("{:?}", expected.options),
+            format!("{:?}", actual.options),
+        ),
+    ];
+    if let Some((name, e, a)) = fields.iter().find(|(_, e, a)| e != a) {
+        return format!("decoded {} is {}, expected {}", name, a, e);
+    }
+    match expected
+        .payload
+        .iter()
+        .zip(&actual.payload)
+        .position(|(a, b)| a != b)
+    {
+        Some(at) => format!("decoded payload differs at byte {}", at),
+        None => format!(
+            "decoded payload is {} bytes, expected {}",
+            actual.payload.len(),
+            expected.payload.len()
+        ),
+    }
+}
+
+// ============================================================================
+// JSON
+// ============================================================================
+
+/// Minimal JSON value: enough to read result documents
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// Non-negative integers only
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            input: input.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.whitespace();
+        if parser.pos != parser.input.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    const MAX_DEPTH: usize = 32;
+
+    fn error(&self, what: &str) -> String {
+        format!("invalid JSON at byte {}: {}", self.pos, what)
+    }
+
+    fn whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.input[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", literal)))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > Self::MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.whitespace();
+        match self.input.get(self.pos) {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'0'..=b'9') => self.number(),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.whitespace();
+                if self.input.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.whitespace();
+                    match self.input.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error("expected , or ]")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.whitespace();
+                if self.input.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    if self.input.get(self.pos) != Some(&b'"') {
+                        return Err(self.error("expected a key"));
+                    }
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.value(depth + 1)?));
+                    self.whitespace();
+                    match self.input.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(self.error("expected , or }")),
+                    }
+                }
+            }
+            Some(b'-') => Err(self.error("negative numbers are not used")),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.input.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        if matches!(self.input.get(self.pos), Some(b'.' | b'e' | b'E')) {
+            return Err(self.error("only integers are used"));
+        }
+        core::str::from_utf8(&self.input[start..self.pos])
+            .expect("ASCII digits")
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("integer out of range"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1; // opening quote
+        let mut out = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return String::from_utf8(out).map_err(|_| self.error("string is not UTF-8"));
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.input.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let unit = self.hex4()?;
+                            let code = if (0xD800..0xDC00).contains(&unit) {
+                                self.pos += 1;
+                                self.expect("\\u")?;
+                                self.pos -= 1;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                unit
+                            };
+                            char::from_u32(code).ok_or_else(|| self.error("invalid \\u escape"))?
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0u8; 4];
+                    out.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                    self.pos += 1;
+                }
+                Some(&b) if b < 0x20 => return Err(self.error("control character in string")),
+                Some(&b) => {
+                    out.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    /// Read the 4 hex digits after `\u`, leaving `pos` on the last one
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .input
+            .get(self.pos + 1..self.pos + 5)
+            .and_then(|d| core::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("expected 4 hex digits"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_passes_its_own_cases() {
+        let cases = cases();
+        let report = verify(&cases, &reference_results(&cases));
+        assert!(report.is_success(), "{:?}", report.failures);
+        assert_eq!(report.passed, cases.len());
+
+        for case in &cases {
+            if let Outcome::Error { code, .. } = &case.outcome {
+                assert_eq!(
+                    decode_strict(&case.bytes).unwrap_err().error_code(),
+                    *code,
+                    "{}",
+                    case.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cases_cover_every_message_type_and_error_code() {
+        let cases = cases();
+        let decoded: Vec<&Message> = cases
+            .iter()
+            .filter_map(|c| match &c.outcome {
+                Outcome::RoundTrip(m) => Some(m),
+                _ => None,
+            })
+            .collect();
+        for t in (0..=u8::MAX).filter_map(|b| MessageType::try_from(b).ok()) {
+            assert!(decoded.iter().any(|m| m.message_type == t), "{:?}", t);
+        }
+        for code in (0..=u8::MAX).filter(|&b| ErrorCode::from_u8(b).is_some()) {
+            assert!(decoded
+                .iter()
+                .any(|m| m.message_type == MessageType::Error && m.status == Some(code)));
+        }
+        let mut names: Vec<&str> = cases.iter().map(|c| c.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), cases.len(), "case names are unique");
+    }
+
+    #[test]
+    fn test_results_survive_json() {
+        let cases = cases();
+        let results = reference_results(&cases);
+        assert_eq!(parse_results(&results_to_json(&results)).unwrap(), results);
+    }
+
+    #[test]
+    fn test_long_runs_export_as_fills() {
+        let mut bytes = vec![1, 2];
+        bytes.extend([0x5A; 100]);
+        bytes.push(3);
+        let json = bytes_json(&bytes);
+        assert_eq!(json, "[\"0102\",{\"fill\":\"5a\",\"count\":100},\"03\"]");
+        assert_eq!(parse_bytes(&Json::parse(&json).unwrap()).unwrap(), bytes);
+        assert_eq!(bytes_json(&[0x5A; 63]), format!("\"{}\"", "5a".repeat(63)));
+        assert_eq!(bytes_json(&[0; 64]), "[{\"fill\":\"00\",\"count\":64}]");
+    }
+
+    #[test]
+    fn test_divergence_is_reported_per_case() {
+        let cases = cases();
+        let mut results = reference_results(&cases);
+        let find = |results: &[CaseResult], name: &str| {
+            results.iter().position(|r| r.name == name).unwrap()
+        };
+
+        let i = find(&results, "response_success");
+        results[i].encoded.as_mut().unwrap()[8] = 1;
+        let i = find(&results, "window_update");
+        results[i].decoded.as_mut().unwrap().id = 7;
+        let i = find(&results, "trailing_bytes");
+        results[i].error = None;
+        results[i].decoded = Some(Message::request(1, b""));
+        let i = find(&results, "unknown_type");
+        results[i].error = Some("InvalidFormat".to_string());
+        let i = find(&results, "request_utf8");
+        results.remove(i);
+        results.push(CaseResult {
+            name: "extra".to_string(),
+            ..CaseResult::default()
+        });
+
+        let report = verify(&cases, &results);
+        assert_eq!(report.passed, cases.len() - 5);
+        let failures: Vec<String> = report.failures.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            failures,
+            [
+                "request_utf8: no result",
+                "response_success: encoding differs at byte 8: expected 00, got 01",
+                "window_update: decoded id is 7, expected 0",
+                "unknown_type: expected error UnknownType, got InvalidFormat",
+                "trailing_bytes: expected error TrailingBytes, got a Request message",
+                "extra: result for an unknown case",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_results_errors() {
+        assert!(parse_results("[]").unwrap_err().contains("\"results\""));
+        assert!(parse_results("{\"results\":[{}]}")
+            .unwrap_err()
+            .contains("results[0]"));
+        let bad_hex = "{\"results\":[{\"name\":\"a\",\"encoded\":\"abc\"}]}";
+        assert!(parse_results(bad_hex).unwrap_err().contains("invalid hex"));
+        let bad_type = "{\"results\":[{\"name\":\"a\",\"decoded\":{\"type\":\"Nope\",\"version\":1,\"id\":1,\"payload\":\"\"}}]}";
+        assert!(parse_results(bad_type)
+            .unwrap_err()
+            .contains("unknown message type"));
+        assert!(parse_results("{\"results\":[]} x")
+            .unwrap_err()
+            .contains("trailing"));
+        assert_eq!(parse_results("{\"results\":[]}"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_json_strings() {
+        let value =
+            Json::parse(r#"{"s": "a\"b\\c\n\u00e9\ud83e\udd80", "n": [1, true, null]}"#).unwrap();
+        assert_eq!(
+            value.get("s").and_then(Json::as_str),
+            Some("a\"b\\c\n\u{e9}\u{1F980}")
+        );
+        assert_eq!(
+            value.get("n"),
+            Some(&Json::Array(vec![
+                Json::Number(1),
+                Json::Bool(true),
+                Json::Null
+            ]))
+        );
+        assert!(Json::parse("\"\\ud83e\"").is_err());
+        assert!(Json::parse(&"[".repeat(100))
+            .unwrap_err()
+            .contains("deeply"));
+        assert_eq!(json_string("a\"\u{1}"), "\"a\\\"\\u0001\"");
+    }
+}
@@ -19,8 +19,70 @@
 //! let decoded = decode(&bytes).unwrap();
 //! assert_eq!(decoded.message_type(), MessageType::Request);
 //! ```
+//!
+//! ## Features
+//!
+//! `std` (default) adds everything that needs an operating system: reading
+//! and writing streams ([`read_message`], [`write_message`]), transports,
+//! clients and servers, the HTTP gateway, the code generators and the CLI.
+//! Without it the crate is `no_std` and needs only `alloc`: the codec,
+//! compression, the record-layer crypto, flow control, batching, typed
+//! payloads and method calls all build for bare-metal targets.
+//!
+//! ```text
+//! cargo build --lib --no-default-features --target x86_64-unknown-none
+//! cargo test --no-default-features
+//! ```
+//!
+//! The second command runs the unit tests and compliance vectors against
+//! the library built without `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
 
-use std::io::{self, Read, Write};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, IoSlice, Read, Write};
+
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+pub mod codegen;
+pub mod compress;
+pub mod crypto;
+pub mod deadline;
+pub mod flow;
+#[cfg(feature = "std")]
+pub mod gateway;
+#[cfg(feature = "std")]
+pub mod idl;
+pub mod interop;
+#[cfg(feature = "std")]
+pub mod observe;
+pub mod payload;
+#[cfg(feature = "std")]
+pub mod prop;
+pub mod pubsub;
+#[cfg(feature = "std")]
+pub mod reader;
+pub mod rng;
+pub mod rpc;
+#[cfg(feature = "std")]
+pub mod server;
+pub mod session;
+pub mod tlv;
+#[cfg(feature = "std")]
+pub mod transport;
+
+use deadline::{RequestOptions, OPTIONS, OPTIONS_LEN};
+pub use payload::{Decode, Encode};
+use session::SessionId;
 
 // ============================================================================
 // Constants
@@ -35,6 +97,10 @@ pub const VERSION: u8 = 1;
 /// Maximum payload size (1 MB default)
 pub const MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
 
+/// Largest possible header: magic, version, type, ID, length and request
+/// options (a Response or Error has a status byte instead of options)
+pub const MAX_HEADER_SIZE: usize = 17;
+
 // ============================================================================
 // Types
 // ============================================================================
@@ -47,10 +113,47 @@ pub enum MessageType {
     Request = 0x01,
     /// Server response
     Response = 0x02,
+    /// Flow control credit grant
+    WindowUpdate = 0x03,
+    /// Batch of requests
+    Batch = 0x04,
+    /// Per-item results of a batch
+    BatchResponse = 0x05,
+    /// Subscribe to a topic pattern
+    Subscribe = 0x06,
+    /// Cancel a subscription
+    Unsubscribe = 0x07,
+    /// Publish to a topic
+    Publish = 0x08,
+    /// Publication delivered to a subscriber
+    Event = 0x09,
+    /// Open or resume a session
+    Resume = 0x0A,
     /// Error message
     Error = 0xFF,
 }
 
+impl MessageType {
+    /// Whether frames of this type carry a status byte
+    pub fn has_status(self) -> bool {
+        matches!(self, MessageType::Response | MessageType::Error)
+    }
+
+    /// Whether frames of this type are requests: they consume flow-control
+    /// credit and are answered in order
+    pub fn is_request(self) -> bool {
+        matches!(
+            self,
+            MessageType::Request
+                | MessageType::Batch
+                | MessageType::Subscribe
+                | MessageType::Unsubscribe
+                | MessageType::Publish
+                | MessageType::Resume
+        )
+    }
+}
+
 impl TryFrom<u8> for MessageType {
     type Error = ProtocolError;
 
@@ -58,47 +161,428 @@ impl TryFrom<u8> for MessageType {
         match value {
             0x01 => Ok(MessageType::Request),
             0x02 => Ok(MessageType::Response),
+            0x03 => Ok(MessageType::WindowUpdate),
+            0x04 => Ok(MessageType::Batch),
+            0x05 => Ok(MessageType::BatchResponse),
+            0x06 => Ok(MessageType::Subscribe),
+            0x07 => Ok(MessageType::Unsubscribe),
+            0x08 => Ok(MessageType::Publish),
+            0x09 => Ok(MessageType::Event),
+            0x0A => Ok(MessageType::Resume),
             0xFF => Ok(Self::Error),
-            _ => Err(ProtocolError::UnknownType(value)),
+            _ => Err(ProtocolError::UnknownType {
+                offset: Field::Type.offset(),
+                actual: value,
+            }),
+        }
+    }
+}
+
+/// Application error codes carried in error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Invalid message format
+    InvalidFormat = 0x01,
+    /// Unknown message type
+    UnknownType = 0x02,
+    /// Payload too large
+    PayloadTooLarge = 0x03,
+    /// Flow control window exceeded
+    FlowControl = 0x04,
+    /// Request ID already in flight
+    DuplicateId = 0x05,
+    /// Unknown method
+    UnknownMethod = 0x06,
+    /// Unknown or expired session
+    UnknownSession = 0x07,
+    /// Request deadline exceeded
+    DeadlineExceeded = 0x08,
+}
+
+impl ErrorCode {
+    /// Look up an error code by its wire value
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(ErrorCode::InvalidFormat),
+            0x02 => Some(ErrorCode::UnknownType),
+            0x03 => Some(ErrorCode::PayloadTooLarge),
+            0x04 => Some(ErrorCode::FlowControl),
+            0x05 => Some(ErrorCode::DuplicateId),
+            0x06 => Some(ErrorCode::UnknownMethod),
+            0x07 => Some(ErrorCode::UnknownSession),
+            0x08 => Some(ErrorCode::DeadlineExceeded),
+            _ => None,
         }
     }
 }
 
+/// Header field, used to locate decode errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// Magic bytes (offset 0)
+    Magic,
+    /// Protocol version (offset 2)
+    Version,
+    /// Message type (offset 3)
+    Type,
+    /// Request/response ID (offset 4)
+    Id,
+    /// Status or error code (offset 8, Response/Error only)
+    Status,
+    /// Payload length (offset 8 or 9)
+    Length,
+    /// Request options (offset 12, Request only, when flagged in the length)
+    Options,
+    /// Payload (offset 12 or 13, or 17 after request options)
+    Payload,
+}
+
+impl Field {
+    /// Byte offset of the field within a frame. For `Length` and `Payload`
+    /// this is the offset in a Request frame; add one for Response and Error.
+    pub fn offset(self) -> usize {
+        match self {
+            Self::Magic => 0,
+            Self::Version => 2,
+            Self::Type => 3,
+            Self::Id => 4,
+            Self::Status | Self::Length => 8,
+            Self::Options | Self::Payload => 12,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Magic => "magic",
+            Self::Version => "version",
+            Self::Type => "type",
+            Self::Id => "id",
+            Self::Status => "status",
+            Self::Length => "length",
+            Self::Options => "options",
+            Self::Payload => "payload",
+        };
+        f.write_str(name)
+    }
+}
+
 /// Protocol error types
+///
+/// Decode errors carry the byte offset within the frame where the offending
+/// field starts and the expected versus actual contents.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ProtocolError {
     /// Invalid magic bytes
-    InvalidMagic([u8; 2]),
+    InvalidMagic {
+        /// Offset of the magic field
+        offset: usize,
+        /// Required magic bytes
+        expected: [u8; 2],
+        /// Bytes found
+        actual: [u8; 2],
+    },
     /// Unsupported protocol version
-    UnsupportedVersion(u8),
+    UnsupportedVersion {
+        /// Offset of the version field
+        offset: usize,
+        /// Supported version
+        expected: u8,
+        /// Version found
+        actual: u8,
+    },
     /// Unknown message type
-    UnknownType(u8),
+    UnknownType {
+        /// Offset of the type field
+        offset: usize,
+        /// Type byte found
+        actual: u8,
+    },
     /// Payload exceeds maximum size
-    PayloadTooLarge(usize),
-    /// Incomplete message (not enough bytes)
-    IncompleteMessage,
-    /// I/O error
-    Io(String),
+    PayloadTooLarge {
+        /// Offset of the length field
+        offset: usize,
+        /// Declared or actual payload size
+        size: usize,
+        /// Maximum allowed size
+        max: usize,
+    },
+    /// The length field sets a flag the message type cannot carry, such as
+    /// request options on a Response
+    UnexpectedFlag {
+        /// Offset of the length field
+        offset: usize,
+        /// Flag bit that was set
+        flag: u32,
+        /// Type of the frame carrying the flag
+        message_type: MessageType,
+    },
+    /// Incomplete message: the input ended inside a field
+    IncompleteMessage {
+        /// Offset of the field that could not be read
+        offset: usize,
+        /// Field being parsed
+        field: Field,
+        /// Bytes the field requires
+        expected: usize,
+        /// Bytes that were available
+        actual: usize,
+    },
+    /// Payload does not match its expected encoding (typed, compressed or
+    /// message-specific)
+    InvalidPayload(String),
+    /// Bytes left over after a complete message (strict decoding)
+    TrailingBytes(usize),
+    /// A request would exceed the flow-control window
+    WindowExhausted {
+        /// Request credit available
+        requests: u32,
+        /// Byte credit available
+        bytes: u32,
+    },
+    /// A request reused the ID of a request still in flight
+    DuplicateId(u32),
+    /// A method call named a method the server does not provide
+    UnknownMethod(String),
+    /// A `Resume` named a session the server does not know or has forgotten
+    UnknownSession(SessionId),
+    /// A request's deadline passed before it was answered
+    DeadlineExceeded(u32),
+    /// The peer answered a request with an error message
+    Rejected {
+        /// ID of the rejected request
+        id: u32,
+        /// Error code sent by the peer
+        code: u8,
+        /// Error text sent by the peer
+        message: String,
+    },
+    /// A response arrived for a different request than the one awaited
+    UnexpectedResponse {
+        /// ID of the outstanding request
+        expected: u32,
+        /// ID carried by the response
+        actual: u32,
+    },
+    /// A sealed record failed authentication, or arrived after records
+    /// that were removed ([`SecureStream`](transport::SecureStream))
+    Tampered {
+        /// Sequence number of the record that was expected next
+        sequence: u64,
+    },
+    /// A sealed record was received a second time
+    /// ([`SecureStream`](transport::SecureStream))
+    Replayed {
+        /// Sequence number carried by the repeated record
+        sequence: u64,
+        /// Sequence number that was expected next
+        expected: u64,
+    },
+    /// The encrypted-transport handshake failed, for example because the
+    /// peers hold different pre-shared keys
+    HandshakeFailed(String),
+    /// The WebSocket handshake failed or the peer broke RFC 6455
+    /// ([`WebSocketStream`](transport::WebSocketStream))
+    WebSocket(String),
+    /// I/O error, with the original error kind preserved
+    ///
+    /// When reading from a stream, `UnexpectedEof` is only reported if the
+    /// stream ended cleanly between messages; a stream ending inside a
+    /// message is reported as `IncompleteMessage`.
+    #[cfg(feature = "std")]
+    Io {
+        /// Kind of the underlying `io::Error`
+        kind: io::ErrorKind,
+        /// Description of the underlying error
+        message: String,
+    },
 }
 
-impl std::fmt::Display for ProtocolError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl ProtocolError {
+    /// Byte offset within the frame where decoding failed, if known
+    pub fn offset(&self) -> Option<usize> {
         match self {
-            Self::InvalidMagic(magic) => write!(f, "invalid magic: {:02x}{:02x}", magic[0], magic[1]),
-            Self::UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
-            Self::UnknownType(t) => write!(f, "unknown message type: {:02x}", t),
-            Self::PayloadTooLarge(size) => write!(f, "payload too large: {} bytes", size),
-            Self::IncompleteMessage => write!(f, "incomplete message"),
-            Self::Io(msg) => write!(f, "I/O error: {}", msg),
+            Self::InvalidMagic { offset, .. }
+            | Self::UnsupportedVersion { offset, .. }
+            | Self::UnknownType { offset, .. }
+            | Self::PayloadTooLarge { offset, .. }
+            | Self::UnexpectedFlag { offset, .. }
+            | Self::IncompleteMessage { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// Header field being parsed when decoding failed, if known
+    pub fn field(&self) -> Option<Field> {
+        match self {
+            Self::InvalidMagic { .. } => Some(Field::Magic),
+            Self::UnsupportedVersion { .. } => Some(Field::Version),
+            Self::UnknownType { .. } => Some(Field::Type),
+            Self::PayloadTooLarge { .. } | Self::UnexpectedFlag { .. } => Some(Field::Length),
+            Self::IncompleteMessage { field, .. } => Some(*field),
+            _ => None,
+        }
+    }
+
+    /// Whether the peer closed the stream cleanly between messages
+    #[cfg(feature = "std")]
+    pub fn is_clean_eof(&self) -> bool {
+        matches!(
+            self,
+            Self::Io {
+                kind: io::ErrorKind::UnexpectedEof,
+                ..
+            }
+        )
+    }
+
+    /// Whether the input ended partway through a message
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, Self::IncompleteMessage { .. })
+    }
+
+    /// Error code to send to the peer when this error ends its request
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::UnknownType { .. } => ErrorCode::UnknownType,
+            Self::PayloadTooLarge { .. } => ErrorCode::PayloadTooLarge,
+            Self::WindowExhausted { .. } => ErrorCode::FlowControl,
+            Self::DuplicateId(_) => ErrorCode::DuplicateId,
+            Self::UnknownMethod(_) => ErrorCode::UnknownMethod,
+            Self::UnknownSession(_) => ErrorCode::UnknownSession,
+            Self::DeadlineExceeded(_) => ErrorCode::DeadlineExceeded,
+            Self::Rejected { code, .. } => {
+                ErrorCode::from_u8(*code).unwrap_or(ErrorCode::InvalidFormat)
+            }
+            _ => ErrorCode::InvalidFormat,
+        }
+    }
+
+    /// Error for a stream that ended at a message boundary
+    #[cfg(feature = "std")]
+    pub(crate) fn closed() -> Self {
+        Self::Io {
+            kind: io::ErrorKind::UnexpectedEof,
+            message: "connection closed".into(),
         }
     }
 }
 
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMagic {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "invalid magic at byte {}: expected {:02x}{:02x}, got {:02x}{:02x}",
+                offset, expected[0], expected[1], actual[0], actual[1]
+            ),
+            Self::UnsupportedVersion {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "unsupported version at byte {}: expected {}, got {}",
+                offset, expected, actual
+            ),
+            Self::UnknownType { offset, actual } => {
+                write!(f, "unknown message type at byte {}: {:02x}", offset, actual)
+            }
+            Self::PayloadTooLarge { offset, size, max } => write!(
+                f,
+                "payload too large (length field at byte {}): {} bytes, maximum {}",
+                offset, size, max
+            ),
+            Self::UnexpectedFlag {
+                offset,
+                flag,
+                message_type,
+            } => write!(
+                f,
+                "unexpected flag (length field at byte {}): {:?} frames cannot set 0x{:08X}",
+                offset, message_type, flag
+            ),
+            Self::IncompleteMessage {
+                offset,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "incomplete message: {} field at byte {} needs {} bytes, got {}",
+                field, offset, expected, actual
+            ),
+            Self::InvalidPayload(msg) => write!(f, "invalid payload: {}", msg),
+            Self::TrailingBytes(n) => write!(f, "{} trailing bytes after message", n),
+            Self::WindowExhausted { requests, bytes } => write!(
+                f,
+                "flow control window exhausted: {} requests and {} bytes of credit available",
+                requests, bytes
+            ),
+            Self::DuplicateId(id) => write!(f, "request ID {} is already in flight", id),
+            Self::UnknownMethod(method) => write!(f, "unknown method {:?}", method),
+            Self::UnknownSession(session) => write!(f, "unknown or expired session {}", session),
+            Self::DeadlineExceeded(id) => write!(f, "request {} exceeded its deadline", id),
+            Self::Rejected { id, code, message } => {
+                write!(
+                    f,
+                    "request {} rejected with error code 0x{:02X}: {}",
+                    id, code, message
+                )
+            }
+            Self::UnexpectedResponse { expected, actual } => {
+                write!(
+                    f,
+                    "unexpected response: expected ID {}, got {}",
+                    expected, actual
+                )
+            }
+            Self::Tampered { sequence } => {
+                write!(
+                    f,
+                    "sealed record {} failed authentication or arrived out of order",
+                    sequence
+                )
+            }
+            Self::Replayed { sequence, expected } => {
+                write!(
+                    f,
+                    "sealed record {} replayed: expected record {}",
+                    sequence, expected
+                )
+            }
+            Self::HandshakeFailed(reason) => write!(f, "secure handshake failed: {}", reason),
+            Self::WebSocket(reason) => write!(f, "WebSocket error: {}", reason),
+            #[cfg(feature = "std")]
+            Self::Io { kind, message } => write!(f, "I/O error ({:?}): {}", kind, message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl std::error::Error for ProtocolError {}
 
+#[cfg(feature = "std")]
 impl From<io::Error> for ProtocolError {
     fn from(err: io::Error) -> Self {
-        ProtocolError::Io(err.to_string())
+        // Layers below the codec, such as the record layer, report protocol
+        // errors through `io::Error`
+        if let Some(inner) = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ProtocolError>())
+        {
+            return inner.clone();
+        }
+        ProtocolError::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
     }
 }
 
@@ -113,6 +597,8 @@ pub struct Message {
     pub id: u32,
     /// Response status (only for Response type)
     pub status: Option<u8>,
+    /// Deadline and priority (only for Request type, SPEC.md Section 4.11)
+    pub options: Option<RequestOptions>,
     /// Message payload
     pub payload: Vec<u8>,
 }
@@ -125,6 +611,7 @@ impl Message {
             message_type: MessageType::Request,
             id,
             status: None,
+            options: None,
             payload: payload.to_vec(),
         }
     }
@@ -136,6 +623,7 @@ impl Message {
             message_type: MessageType::Response,
             id,
             status: Some(status),
+            options: None,
             payload: payload.to_vec(),
         }
     }
@@ -147,10 +635,74 @@ impl Message {
             message_type: MessageType::Error,
             id,
             status: Some(error_code),
+            options: None,
             payload: message.as_bytes().to_vec(),
         }
     }
 
+    /// Create a window update granting flow-control credit (SPEC.md
+    /// Section 4.5)
+    pub fn window_update(requests: u32, bytes: u32) -> Self {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&requests.to_be_bytes());
+        payload.extend_from_slice(&bytes.to_be_bytes());
+        Self {
+            version: VERSION,
+            message_type: MessageType::WindowUpdate,
+            id: 0,
+            status: None,
+            options: None,
+            payload,
+        }
+    }
+
+    /// Create a subscription to a topic pattern (SPEC.md Section 4.7)
+    ///
+    /// The ID also identifies the subscription in the events it receives.
+    pub fn subscribe(id: u32, pattern: &str) -> Self {
+        Self {
+            version: VERSION,
+            message_type: MessageType::Subscribe,
+            id,
+            status: None,
+            options: None,
+            payload: pattern.as_bytes().to_vec(),
+        }
+    }
+
+    /// Cancel the subscription made by the Subscribe with ID `subscription`
+    pub fn unsubscribe(id: u32, subscription: u32) -> Self {
+        Self {
+            version: VERSION,
+            message_type: MessageType::Unsubscribe,
+            id,
+            status: None,
+            options: None,
+            payload: subscription.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Attach a deadline and priority to a request
+    pub fn with_options(mut self, options: RequestOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+
+    /// Create a request whose payload is a typed value
+    pub fn request_typed<T: Encode + ?Sized>(id: u32, value: &T) -> Self {
+        Self::request(id, &value.to_payload())
+    }
+
+    /// Create a response whose payload is a typed value
+    pub fn response_typed<T: Encode + ?Sized>(id: u32, status: u8, value: &T) -> Self {
+        Self::response(id, status, &value.to_payload())
+    }
+
+    /// Decode the payload as a typed value
+    pub fn payload_as<T: Decode>(&self) -> Result<T, ProtocolError> {
+        T::from_payload(&self.payload)
+    }
+
     /// Get the message type
     pub fn message_type(&self) -> MessageType {
         self.message_type
@@ -160,6 +712,12 @@ impl Message {
     pub fn is_success(&self) -> bool {
         self.message_type == MessageType::Response && self.status == Some(0)
     }
+
+    /// Size of the encoded frame in bytes
+    pub fn encoded_len(&self) -> usize {
+        let optional = usize::from(self.status.is_some()) + self.options.map_or(0, |_| OPTIONS_LEN);
+        MAX_HEADER_SIZE - OPTIONS_LEN + optional + self.payload.len()
+    }
 }
 
 // ============================================================================
@@ -168,39 +726,126 @@ impl Message {
 
 /// Encode a message to bytes
 pub fn encode(message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let mut buf = Vec::with_capacity(MAX_HEADER_SIZE + message.payload.len());
+    encode_into(message, &mut buf)?;
+    Ok(buf)
+}
+
+/// Encode a message by appending to an existing buffer
+///
+/// Reusing one buffer across messages avoids the per-message allocation
+/// made by [`encode`]. On error, `buf` is left unchanged.
+pub fn encode_into(message: &Message, buf: &mut Vec<u8>) -> Result<(), ProtocolError> {
+    let mut header = [0u8; MAX_HEADER_SIZE];
+    let header_len = encode_header(message, &mut header)?;
+
+    buf.reserve(header_len + message.payload.len());
+    buf.extend_from_slice(&header[..header_len]);
+    buf.extend_from_slice(&message.payload);
+    Ok(())
+}
+
+/// Encode the header (everything before the payload) into a fixed-size
+/// buffer, returning the number of bytes used
+pub fn encode_header(
+    message: &Message,
+    header: &mut [u8; MAX_HEADER_SIZE],
+) -> Result<usize, ProtocolError> {
     let payload_len = message.payload.len();
     if payload_len > MAX_PAYLOAD_SIZE {
-        return Err(ProtocolError::PayloadTooLarge(payload_len));
+        return Err(ProtocolError::PayloadTooLarge {
+            offset: Field::Length.offset() + usize::from(message.status.is_some()),
+            size: payload_len,
+            max: MAX_PAYLOAD_SIZE,
+        });
     }
 
-    let mut buf = Vec::with_capacity(11 + payload_len);
-
     // Header: magic (2) + version (1)
-    buf.extend_from_slice(&MAGIC);
-    buf.push(message.version);
+    header[0..2].copy_from_slice(&MAGIC);
+    header[2] = message.version;
 
     // Type (1)
-    buf.push(message.message_type as u8);
+    header[3] = message.message_type as u8;
 
     // ID (4, big-endian)
-    buf.extend_from_slice(&message.id.to_be_bytes());
+    header[4..8].copy_from_slice(&message.id.to_be_bytes());
 
     // Status (1, only for Response/Error)
+    let mut len = 8;
     if let Some(status) = message.status {
-        buf.push(status);
+        header[len] = status;
+        len += 1;
     }
 
-    // Payload length (4, big-endian) + payload
-    buf.extend_from_slice(&(payload_len as u32).to_be_bytes());
-    buf.extend_from_slice(&message.payload);
+    // Payload length (4, big-endian, bit 30 flags request options)
+    let flags = if message.options.is_some() {
+        OPTIONS
+    } else {
+        0
+    };
+    header[len..len + 4].copy_from_slice(&(payload_len as u32 | flags).to_be_bytes());
+    len += 4;
 
-    Ok(buf)
+    // Options (5, only for flagged Request)
+    if let Some(options) = message.options {
+        if message.message_type != MessageType::Request {
+            return Err(options_not_allowed(message.message_type, len - 4));
+        }
+        header[len..len + OPTIONS_LEN].copy_from_slice(&options.to_bytes());
+        len += OPTIONS_LEN;
+    }
+    Ok(len)
+}
+
+/// Error for request options on a frame that is not a Request, whose
+/// length field starts at `offset`
+fn options_not_allowed(message_type: MessageType, offset: usize) -> ProtocolError {
+    ProtocolError::UnexpectedFlag {
+        offset,
+        flag: OPTIONS,
+        message_type,
+    }
 }
 
 /// Write a message to a writer
+///
+/// The header and payload are handed to the writer as two slices with
+/// `write_vectored`, so the payload is never copied into an intermediate
+/// buffer.
+#[cfg(feature = "std")]
 pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), ProtocolError> {
-    let bytes = encode(message)?;
-    writer.write_all(&bytes)?;
+    let mut header = [0u8; MAX_HEADER_SIZE];
+    let header_len = encode_header(message, &mut header)?;
+    write_all_vectored(writer, &header[..header_len], &message.payload)?;
+    Ok(())
+}
+
+/// `write_all` for a header/payload pair, tolerating short vectored writes
+#[cfg(feature = "std")]
+pub(crate) fn write_all_vectored<W: Write>(
+    writer: &mut W,
+    mut header: &[u8],
+    mut payload: &[u8],
+) -> io::Result<()> {
+    while !header.is_empty() || !payload.is_empty() {
+        let slices = [IoSlice::new(header), IoSlice::new(payload)];
+        match writer.write_vectored(&slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole message",
+                ))
+            }
+            Ok(mut n) => {
+                let from_header = n.min(header.len());
+                header = &header[from_header..];
+                n -= from_header;
+                payload = &payload[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
     Ok(())
 }
 
@@ -209,122 +854,320 @@ pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(),
 // ============================================================================
 
 /// Decode a message from bytes
+///
+/// Bytes after the end of the message are ignored. Use [`decode_strict`]
+/// when the buffer must hold exactly one message, or [`decode_prefix`] to
+/// learn where the message ended.
 pub fn decode(bytes: &[u8]) -> Result<Message, ProtocolError> {
-    if bytes.len() < 8 {
-        return Err(ProtocolError::IncompleteMessage);
+    decode_prefix(bytes).map(|(message, _)| message)
+}
+
+/// Decode a buffer that must contain exactly one message
+pub fn decode_strict(bytes: &[u8]) -> Result<Message, ProtocolError> {
+    let (message, consumed) = decode_prefix(bytes)?;
+    if consumed != bytes.len() {
+        return Err(ProtocolError::TrailingBytes(bytes.len() - consumed));
     }
+    Ok(message)
+}
 
+/// Decode the message at the start of `bytes`, returning it together with
+/// the number of bytes it occupied
+pub fn decode_prefix(bytes: &[u8]) -> Result<(Message, usize), ProtocolError> {
     // Header: magic (2)
-    let magic = [bytes[0], bytes[1]];
+    let magic = take::<2>(bytes, Field::Magic, 0)?;
     if magic != MAGIC {
-        return Err(ProtocolError::InvalidMagic(magic));
+        return Err(ProtocolError::InvalidMagic {
+            offset: 0,
+            expected: MAGIC,
+            actual: magic,
+        });
     }
 
     // Version (1)
-    let version = bytes[2];
+    let [version] = take::<1>(bytes, Field::Version, 2)?;
     if version != VERSION {
-        return Err(ProtocolError::UnsupportedVersion(version));
+        return Err(ProtocolError::UnsupportedVersion {
+            offset: 2,
+            expected: VERSION,
+            actual: version,
+        });
     }
 
     // Type (1)
-    let message_type = MessageType::try_from(bytes[3])?;
+    let [type_byte] = take::<1>(bytes, Field::Type, 3)?;
+    let message_type = MessageType::try_from(type_byte)?;
 
     // ID (4, big-endian)
-    let id = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let id = u32::from_be_bytes(take::<4>(bytes, Field::Id, 4)?);
 
     // Parse based on message type
-    let (status, payload_offset) = match message_type {
-        MessageType::Request => (None, 8),
-        MessageType::Response | MessageType::Error => {
-            if bytes.len() < 9 {
-                return Err(ProtocolError::IncompleteMessage);
-            }
-            (Some(bytes[8]), 9)
-        }
+    let (status, payload_offset) = if message_type.has_status() {
+        let [status] = take::<1>(bytes, Field::Status, 8)?;
+        (Some(status), 9)
+    } else {
+        (None, 8)
     };
 
-    // Payload length (4) + payload
-    if bytes.len() < payload_offset + 4 {
-        return Err(ProtocolError::IncompleteMessage);
+    // Payload length (4, top bit flags compression, bit 30 options)
+    let length = u32::from_be_bytes(take::<4>(bytes, Field::Length, payload_offset)?);
+    let payload_len = (length & !(compress::COMPRESSED | OPTIONS)) as usize;
+
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::PayloadTooLarge {
+            offset: payload_offset,
+            size: payload_len,
+            max: MAX_PAYLOAD_SIZE,
+        });
     }
 
-    let payload_len = u32::from_be_bytes([
-        bytes[payload_offset],
-        bytes[payload_offset + 1],
-        bytes[payload_offset + 2],
-        bytes[payload_offset + 3],
-    ]) as usize;
+    // Options (5, only for flagged Request) + payload
+    let mut payload_start = payload_offset + 4;
+    let options = if length & OPTIONS != 0 {
+        if message_type != MessageType::Request {
+            return Err(options_not_allowed(message_type, payload_offset));
+        }
+        let options =
+            RequestOptions::from_bytes(take::<OPTIONS_LEN>(bytes, Field::Options, payload_start)?);
+        payload_start += OPTIONS_LEN;
+        Some(options)
+    } else {
+        None
+    };
+    let payload_end = payload_start + payload_len;
+    let payload =
+        bytes
+            .get(payload_start..payload_end)
+            .ok_or(ProtocolError::IncompleteMessage {
+                offset: payload_start,
+                field: Field::Payload,
+                expected: payload_len,
+                actual: bytes.len() - payload_start,
+            })?;
+    let payload = if length & compress::COMPRESSED != 0 {
+        compress::expand(payload, payload_offset)?
+    } else {
+        payload.to_vec()
+    };
 
-    if payload_len > MAX_PAYLOAD_SIZE {
-        return Err(ProtocolError::PayloadTooLarge(payload_len));
+    Ok((
+        Message {
+            version,
+            message_type,
+            id,
+            status,
+            options,
+            payload,
+        },
+        payload_end,
+    ))
+}
+
+/// Read a fixed-size field at `offset`, reporting a positional error if the
+/// input is too short
+fn take<const N: usize>(
+    bytes: &[u8],
+    field: Field,
+    offset: usize,
+) -> Result<[u8; N], ProtocolError> {
+    match bytes.get(offset..offset + N) {
+        Some(slice) => Ok(slice.try_into().expect("slice has length N")),
+        None => Err(ProtocolError::IncompleteMessage {
+            offset,
+            field,
+            expected: N,
+            actual: bytes.len().saturating_sub(offset),
+        }),
+    }
+}
+
+/// Iterate over a buffer of concatenated messages
+///
+/// Yields each message in order. A malformed or truncated frame is yielded
+/// as an error and ends the iteration, since the position of any following
+/// frame is unknown.
+pub fn decode_all(bytes: &[u8]) -> DecodeAll<'_> {
+    DecodeAll {
+        remaining: bytes,
+        failed: false,
     }
+}
+
+/// Iterator returned by [`decode_all`]
+#[derive(Debug, Clone)]
+pub struct DecodeAll<'a> {
+    remaining: &'a [u8],
+    failed: bool,
+}
 
-    let payload_start = payload_offset + 4;
-    if bytes.len() < payload_start + payload_len {
-        return Err(ProtocolError::IncompleteMessage);
+impl<'a> DecodeAll<'a> {
+    /// Bytes not yet decoded (after an error, starting at the bad frame)
+    pub fn remaining(&self) -> &'a [u8] {
+        self.remaining
     }
+}
 
-    let payload = bytes[payload_start..payload_start + payload_len].to_vec();
+impl Iterator for DecodeAll<'_> {
+    type Item = Result<Message, ProtocolError>;
 
-    Ok(Message {
-        version,
-        message_type,
-        id,
-        status,
-        payload,
-    })
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.remaining.is_empty() {
+            return None;
+        }
+        match decode_prefix(self.remaining) {
+            Ok((message, consumed)) => {
+                self.remaining = &self.remaining[consumed..];
+                Some(Ok(message))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
 /// Read a message from a reader
+///
+/// If the stream ends before the first byte of a message, the error is
+/// `Io` with kind `UnexpectedEof` (see [`ProtocolError::is_clean_eof`]). If
+/// it ends partway through, the error is `IncompleteMessage` locating the
+/// field that was cut off.
+#[cfg(feature = "std")]
 pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {
+    read_frame(reader).map(|(message, _)| message)
+}
+
+/// [`read_message`], also reporting whether the payload arrived compressed
+#[cfg(feature = "std")]
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<(Message, bool), ProtocolError> {
     // Read header
     let mut header = [0u8; 8];
-    reader.read_exact(&mut header)?;
+    read_field(reader, &mut header, Field::Magic, 0)?;
 
     // Validate and parse header
     let magic = [header[0], header[1]];
     if magic != MAGIC {
-        return Err(ProtocolError::InvalidMagic(magic));
+        return Err(ProtocolError::InvalidMagic {
+            offset: 0,
+            expected: MAGIC,
+            actual: magic,
+        });
     }
 
     let version = header[2];
     if version != VERSION {
-        return Err(ProtocolError::UnsupportedVersion(version));
+        return Err(ProtocolError::UnsupportedVersion {
+            offset: 2,
+            expected: VERSION,
+            actual: version,
+        });
     }
 
     let message_type = MessageType::try_from(header[3])?;
     let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
 
     // Read status if needed
-    let status = match message_type {
-        MessageType::Request => None,
-        MessageType::Response | MessageType::Error => {
-            let mut status_buf = [0u8; 1];
-            reader.read_exact(&mut status_buf)?;
-            Some(status_buf[0])
-        }
+    let (status, length_offset) = if message_type.has_status() {
+        let mut status_buf = [0u8; 1];
+        read_field(reader, &mut status_buf, Field::Status, 8)?;
+        (Some(status_buf[0]), 9)
+    } else {
+        (None, 8)
     };
 
-    // Read payload length
+    // Read payload length (top bit flags compression, bit 30 options)
     let mut len_buf = [0u8; 4];
-    reader.read_exact(&mut len_buf)?;
-    let payload_len = u32::from_be_bytes(len_buf) as usize;
+    read_field(reader, &mut len_buf, Field::Length, length_offset)?;
+    let length = u32::from_be_bytes(len_buf);
+    let payload_len = (length & !(compress::COMPRESSED | OPTIONS)) as usize;
 
     if payload_len > MAX_PAYLOAD_SIZE {
-        return Err(ProtocolError::PayloadTooLarge(payload_len));
+        return Err(ProtocolError::PayloadTooLarge {
+            offset: length_offset,
+            size: payload_len,
+            max: MAX_PAYLOAD_SIZE,
+        });
     }
 
+    // Read options if flagged
+    let mut payload_offset = length_offset + 4;
+    let options = if length & OPTIONS != 0 {
+        if message_type != MessageType::Request {
+            return Err(options_not_allowed(message_type, length_offset));
+        }
+        let mut options_buf = [0u8; OPTIONS_LEN];
+        read_field(reader, &mut options_buf, Field::Options, payload_offset)?;
+        payload_offset += OPTIONS_LEN;
+        Some(RequestOptions::from_bytes(options_buf))
+    } else {
+        None
+    };
+
     // Read payload
     let mut payload = vec![0u8; payload_len];
-    reader.read_exact(&mut payload)?;
+    read_field(reader, &mut payload, Field::Payload, payload_offset)?;
+    let compressed = length & compress::COMPRESSED != 0;
+    if compressed {
+        payload = compress::expand(&payload, length_offset)?;
+    }
 
-    Ok(Message {
+    let message = Message {
         version,
         message_type,
         id,
         status,
+        options,
         payload,
-    })
+    };
+    Ok((message, compressed))
+}
+
+/// `read_exact` that reports where a truncated stream ended
+///
+/// The header's first read distinguishes a clean end of stream (nothing
+/// read at offset 0) from truncation; the 8-byte fixed header is read as
+/// one field starting with `Magic`, so a cut inside it is attributed to the
+/// field containing the first missing byte.
+#[cfg(feature = "std")]
+fn read_field<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    field: Field,
+    offset: usize,
+) -> Result<(), ProtocolError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if offset == 0 && filled == 0 => return Err(ProtocolError::closed()),
+            Ok(0) => {
+                let (field, field_offset, expected) = if field == Field::Magic {
+                    // Locate the cut within the fixed 8-byte header
+                    [
+                        (Field::Magic, 0, 2),
+                        (Field::Version, 2, 1),
+                        (Field::Type, 3, 1),
+                        (Field::Id, 4, 4),
+                    ]
+                    .into_iter()
+                    .find(|&(_, start, len)| filled < start + len)
+                    .expect("header is 8 bytes")
+                } else {
+                    (field, offset, buf.len())
+                };
+                return Err(ProtocolError::IncompleteMessage {
+                    offset: field_offset,
+                    field,
+                    expected,
+                    actual: (offset + filled).saturating_sub(field_offset),
+                });
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
 }
 
 // ============================================================================
@@ -334,6 +1177,7 @@ pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec;
 
     #[test]
     fn test_encode_decode_request() {
@@ -359,17 +1203,211 @@ mod tests {
         assert!(decoded.is_success());
     }
 
+    #[test]
+    fn test_encode_into_appends() {
+        let mut buf = b"prefix".to_vec();
+        let message = Message::response(3, 0, b"data");
+        encode_into(&message, &mut buf).unwrap();
+
+        assert_eq!(&buf[..6], b"prefix");
+        assert_eq!(&buf[6..], encode(&message).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        for message in [
+            Message::request(1, b"abc"),
+            Message::response(1, 0, b""),
+            Message::error(1, 2, "x"),
+        ] {
+            assert_eq!(message.encoded_len(), encode(&message).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_request_options() {
+        let options = RequestOptions::new().with_budget_ms(250).with_priority(200);
+        let message = Message::request(1, b"hi").with_options(options);
+        let bytes = encode(&message).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x54, 0x55, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x40, 0x00, 0x00, 0x02, 0x00, 0x00,
+                0x00, 0xFA, 0xC8, b'h', b'i'
+            ]
+        );
+        assert_eq!(message.encoded_len(), bytes.len());
+        assert_eq!(decode_strict(&bytes), Ok(message));
+
+        let err = decode(&bytes[..14]).unwrap_err();
+        assert_eq!(
+            err,
+            ProtocolError::IncompleteMessage {
+                offset: 12,
+                field: Field::Options,
+                expected: OPTIONS_LEN,
+                actual: 2,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_message_with_options() {
+        let message =
+            Message::request(3, b"abc").with_options(RequestOptions::new().with_priority(1));
+        let bytes = encode(&message).unwrap();
+        assert_eq!(read_message(&mut &bytes[..]), Ok(message));
+        let err = read_message(&mut &bytes[..15]).unwrap_err();
+        assert_eq!(err.field(), Some(Field::Options));
+    }
+
+    #[test]
+    fn test_options_only_on_requests() {
+        let unexpected = ProtocolError::UnexpectedFlag {
+            offset: 9,
+            flag: OPTIONS,
+            message_type: MessageType::Response,
+        };
+        let mut response = Message::response(1, 0, b"");
+        response.options = Some(RequestOptions::new());
+        assert_eq!(encode(&response), Err(unexpected.clone()));
+
+        let mut bytes = encode(&Message::response(1, 0, b"")).unwrap();
+        bytes[9] |= 0x40;
+        assert_eq!(decode(&bytes), Err(unexpected.clone()));
+        assert_eq!(unexpected.field(), Some(Field::Length));
+        assert_eq!(unexpected.offset(), Some(9));
+
+        #[cfg(feature = "std")]
+        assert_eq!(read_message(&mut bytes.as_slice()), Err(unexpected));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_write_message_short_writes() {
+        // Accepts at most 3 bytes per call to exercise partial vectored writes
+        struct Trickle(Vec<u8>);
+
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let n = buf.len().min(3);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let message = Message::request(9, b"a longer payload");
+        let mut out = Trickle(Vec::new());
+        write_message(&mut out, &message).unwrap();
+        assert_eq!(out.0, encode(&message).unwrap());
+    }
+
+    #[test]
+    fn test_decode_prefix_reports_consumed() {
+        let mut bytes = encode(&Message::request(1, b"abc")).unwrap();
+        let frame_len = bytes.len();
+        bytes.extend_from_slice(b"garbage");
+
+        let (message, consumed) = decode_prefix(&bytes).unwrap();
+        assert_eq!(message.payload, b"abc");
+        assert_eq!(consumed, frame_len);
+        assert_eq!(decode_strict(&bytes), Err(ProtocolError::TrailingBytes(7)));
+        assert!(decode_strict(&bytes[..frame_len]).is_ok());
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let mut bytes = Vec::new();
+        encode_into(&Message::request(1, b"a"), &mut bytes).unwrap();
+        encode_into(&Message::response(1, 0, b"b"), &mut bytes).unwrap();
+        let ids: Vec<u32> = decode_all(&bytes).map(|m| m.unwrap().id).collect();
+        assert_eq!(ids, vec![1, 1]);
+
+        bytes.extend_from_slice(&[0x54, 0x55, 0x01]);
+        let mut frames = decode_all(&bytes);
+        assert!(frames.next().unwrap().is_ok());
+        assert!(frames.next().unwrap().is_ok());
+        assert!(matches!(
+            frames.next(),
+            Some(Err(ProtocolError::IncompleteMessage { .. }))
+        ));
+        assert_eq!(frames.remaining(), &[0x54, 0x55, 0x01]);
+        assert_eq!(frames.next(), None);
+    }
+
+    #[test]
+    fn test_incomplete_reports_field() {
+        let bytes = encode(&Message::response(1, 0, b"hello")).unwrap();
+
+        let err = decode(&bytes[..6]).unwrap_err();
+        assert_eq!(err.field(), Some(Field::Id));
+        assert_eq!(err.offset(), Some(4));
+
+        let err = decode(&bytes[..15]).unwrap_err();
+        assert_eq!(
+            err,
+            ProtocolError::IncompleteMessage {
+                offset: 13,
+                field: Field::Payload,
+                expected: 5,
+                actual: 2,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_read_message_eof_kinds() {
+        let bytes = encode(&Message::request(1, b"abc")).unwrap();
+
+        let err = read_message(&mut &bytes[..0]).unwrap_err();
+        assert!(err.is_clean_eof());
+
+        let err = read_message(&mut &bytes[..3]).unwrap_err();
+        assert!(err.is_truncated());
+        assert_eq!(err.field(), Some(Field::Type));
+
+        let err = read_message(&mut &bytes[..13]).unwrap_err();
+        assert_eq!(
+            err,
+            ProtocolError::IncompleteMessage {
+                offset: 12,
+                field: Field::Payload,
+                expected: 3,
+                actual: 1,
+            }
+        );
+    }
+
     #[test]
     fn test_invalid_magic() {
-        let bytes = [0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let bytes = [
+            0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
         let result = decode(&bytes);
-        assert!(matches!(result, Err(ProtocolError::InvalidMagic(_))));
+        assert!(matches!(
+            result,
+            Err(ProtocolError::InvalidMagic { offset: 0, .. })
+        ));
     }
 
     #[test]
     fn test_unknown_type() {
-        let bytes = [0x54, 0x55, 0x01, 0x99, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let bytes = [
+            0x54, 0x55, 0x01, 0x99, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
         let result = decode(&bytes);
-        assert!(matches!(result, Err(ProtocolError::UnknownType(0x99))));
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnknownType {
+                offset: 3,
+                actual: 0x99
+            })
+        ));
     }
 }
@@ -8,17 +8,49 @@
 //! # Encode a message to hex
 //! protocol-name encode --type request --id 1 --payload "hello"
 //!
+//! # Encode with the payload compressed (if that makes it smaller)
+//! protocol-name encode --id 1 --payload "hello hello hello" --compress
+//!
+//! # Encode a request with a 250 ms budget and a raised priority
+//! protocol-name encode --id 1 --payload "hello" --budget 250 --priority 200
+//!
 //! # Decode a hex message
 //! protocol-name decode 545501010000000100000005hello
 //!
+//! # Decode a message whose payload is TLV-encoded
+//! protocol-name decode <hex> --payload-format tlv
+//!
 //! # Validate a message
 //! protocol-name validate 545501010000000100000005hello
+//!
+//! # Serve echo requests over encrypted connections keyed by a file's contents
+//! protocol-name serve --tcp 127.0.0.1:9000 --psk-file secret.key
+//!
+//! # Serve echo requests to browsers over WebSocket (ws://127.0.0.1:9000/)
+//! protocol-name serve --tcp 127.0.0.1:9000 --websocket
+//!
+//! # Accept HTTP/1.1 on port 8080 and forward POST /rpc/<id> to a server
+//! protocol-name gateway --http 127.0.0.1:8080 --upstream 127.0.0.1:9000
+//!
+//! # Export the cross-implementation vectors, then check another
+//! # implementation's results against them
+//! protocol-name vectors export --json --out vectors.json
+//! protocol-name vectors verify results.json
 //! ```
 
 use std::env;
 use std::process;
 
-use protocol_name::{decode, encode, Message, MessageType, MAGIC, VERSION};
+use protocol_name::batch::{Batch, BatchResponse};
+use protocol_name::compress::{Compression, COMPRESSED};
+use protocol_name::deadline::{RequestOptions, OPTIONS};
+use protocol_name::gateway::Gateway;
+use protocol_name::interop;
+use protocol_name::pubsub::{Event, Publication};
+use protocol_name::server::Server;
+use protocol_name::session::{Resume, SessionId, Sessions};
+use protocol_name::tlv::Value;
+use protocol_name::{decode, decode_strict, encode, Message, MessageType, MAGIC, VERSION};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -32,6 +64,9 @@ fn main() {
         "encode" => cmd_encode(&args[2..]),
         "decode" => cmd_decode(&args[2..]),
         "validate" => cmd_validate(&args[2..]),
+        "serve" => cmd_serve(&args[2..]),
+        "gateway" => cmd_gateway(&args[2..]),
+        "vectors" => cmd_vectors(&args[2..]),
         "version" => {
             println!("Protocol Name v{}", env!("CARGO_PKG_VERSION"));
             println!("Protocol Version: {}", VERSION);
@@ -65,13 +100,36 @@ fn print_usage() {
     eprintln!("    encode      Encode a message to hex");
     eprintln!("    decode      Decode a hex message");
     eprintln!("    validate    Validate a hex message");
+    eprintln!(
+        "    serve       Run an echo server (--tcp ADDR | --unix PATH | --stdio) [--psk-file PATH]"
+    );
+    eprintln!("                [--websocket]");
+    eprintln!(
+        "    gateway     Forward HTTP POST /rpc/<id> to a server (--http ADDR --upstream ADDR)"
+    );
+    eprintln!("    vectors     Export interop vectors (export --json [--out FILE]) or");
+    eprintln!("                check another implementation's results (verify FILE)");
     eprintln!("    version     Show version info");
     eprintln!("    help        Show this message");
     eprintln!();
     eprintln!("EXAMPLES:");
     eprintln!("    protocol-name encode --type request --id 1 --payload hello");
+    eprintln!("    protocol-name encode --id 1 --payload 'hello hello hello' --compress");
+    eprintln!("    protocol-name encode --id 1 --payload hello --budget 250 --priority 200");
+    eprintln!("    protocol-name encode --type window-update --payload 32,4194304");
+    eprintln!("    protocol-name encode --type batch --id 1 --payload first,second");
+    eprintln!("    protocol-name encode --type subscribe --id 2 --payload 'orders.>'");
+    eprintln!("    protocol-name encode --type publish --id 3 --payload orders.eu=hello");
+    eprintln!("    protocol-name encode --type resume --id 4 --payload new");
     eprintln!("    protocol-name decode 545501010000000100000005hello");
+    eprintln!("    protocol-name decode <hex> --payload-format tlv");
     eprintln!("    protocol-name validate 545501010000000100000005hello");
+    eprintln!("    protocol-name serve --tcp 127.0.0.1:9000");
+    eprintln!("    protocol-name serve --tcp 127.0.0.1:9000 --psk-file secret.key");
+    eprintln!("    protocol-name serve --tcp 127.0.0.1:9000 --websocket");
+    eprintln!("    protocol-name gateway --http 127.0.0.1:8080 --upstream 127.0.0.1:9000");
+    eprintln!("    protocol-name vectors export --json --out vectors.json");
+    eprintln!("    protocol-name vectors verify results.json");
 }
 
 fn cmd_encode(args: &[String]) -> Result<(), String> {
@@ -79,6 +137,8 @@ fn cmd_encode(args: &[String]) -> Result<(), String> {
     let mut id: u32 = 1;
     let mut payload = Vec::new();
     let mut status: u8 = 0;
+    let mut compress = false;
+    let mut options: Option<RequestOptions> = None;
 
     let mut i = 0;
     while i < args.len() {
@@ -91,6 +151,12 @@ fn cmd_encode(args: &[String]) -> Result<(), String> {
                 msg_type = match args[i].as_str() {
                     "request" => MessageType::Request,
                     "response" => MessageType::Response,
+                    "window-update" => MessageType::WindowUpdate,
+                    "batch" => MessageType::Batch,
+                    "subscribe" => MessageType::Subscribe,
+                    "unsubscribe" => MessageType::Unsubscribe,
+                    "publish" => MessageType::Publish,
+                    "resume" => MessageType::Resume,
                     "error" => MessageType::Error,
                     t => return Err(format!("Unknown type: {}", t)),
                 };
@@ -116,6 +182,25 @@ fn cmd_encode(args: &[String]) -> Result<(), String> {
                 }
                 status = args[i].parse().map_err(|_| "Invalid status")?;
             }
+            "--compress" | "-z" => compress = true,
+            "--budget" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err("Missing value for --budget".to_string());
+                }
+                let budget_ms = args[i]
+                    .parse()
+                    .map_err(|_| "Invalid budget (milliseconds)")?;
+                options = Some(options.unwrap_or_default().with_budget_ms(budget_ms));
+            }
+            "--priority" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err("Missing value for --priority".to_string());
+                }
+                let priority = args[i].parse().map_err(|_| "Invalid priority (0-255)")?;
+                options = Some(options.unwrap_or_default().with_priority(priority));
+            }
             arg => {
                 return Err(format!("Unknown argument: {}", arg));
             }
@@ -123,13 +208,93 @@ fn cmd_encode(args: &[String]) -> Result<(), String> {
         i += 1;
     }
 
+    if options.is_some() && msg_type != MessageType::Request {
+        return Err("--budget and --priority apply to requests only".to_string());
+    }
+
     let message = match msg_type {
-        MessageType::Request => Message::request(id, &payload),
+        MessageType::Request => Message {
+            options,
+            ..Message::request(id, &payload)
+        },
         MessageType::Response => Message::response(id, status, &payload),
-        MessageType::Error => Message::error(id, status, std::str::from_utf8(&payload).unwrap_or("")),
+        MessageType::WindowUpdate => {
+            // Payload given as REQUESTS,BYTES
+            let text = String::from_utf8_lossy(&payload);
+            let (requests, bytes) = text
+                .split_once(',')
+                .ok_or("Window update payload must be REQUESTS,BYTES")?;
+            Message::window_update(
+                requests
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Invalid request credit")?,
+                bytes.trim().parse().map_err(|_| "Invalid byte credit")?,
+            )
+        }
+        MessageType::Batch => {
+            // Payload given as comma-separated request payloads, numbered
+            // from the batch's own ID
+            let mut batch = Batch::new(id);
+            for (item_id, item) in (id.wrapping_add(1)..).zip(payload.split(|&b| b == b',')) {
+                batch.push(item_id, item);
+            }
+            batch.to_message()
+        }
+        MessageType::Subscribe => Message::subscribe(id, &String::from_utf8_lossy(&payload)),
+        MessageType::Unsubscribe => {
+            // Payload given as the subscription's ID
+            let subscription = String::from_utf8_lossy(&payload)
+                .trim()
+                .parse()
+                .map_err(|_| "Invalid subscription ID")?;
+            Message::unsubscribe(id, subscription)
+        }
+        MessageType::Publish => {
+            // Payload given as TOPIC=DATA
+            let text = String::from_utf8_lossy(&payload);
+            let (topic, data) = text
+                .split_once('=')
+                .ok_or("Publish payload must be TOPIC=DATA")?;
+            Publication::new(topic, data.as_bytes()).to_message(id)
+        }
+        MessageType::Resume => {
+            // Payload given as SESSION[,ID...]: the session as 32 hex
+            // digits or "new", then the IDs replayed
+            let text = String::from_utf8_lossy(&payload);
+            let mut fields = text.split(',');
+            let session = match fields.next().unwrap_or("").trim() {
+                "new" => SessionId::NEW,
+                hex => SessionId::from_payload(&hex_to_bytes(hex)?).map_err(|e| e.to_string())?,
+            };
+            let replay = fields
+                .map(|field| {
+                    field
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid replayed ID: {}", field))
+                })
+                .collect::<Result<_, _>>()?;
+            Resume::new(session, replay).to_message(id)
+        }
+        MessageType::BatchResponse | MessageType::Event => {
+            return Err(format!(
+                "{:?} messages cannot be encoded from the command line",
+                msg_type
+            ))
+        }
+        MessageType::Error => {
+            Message::error(id, status, std::str::from_utf8(&payload).unwrap_or(""))
+        }
     };
 
-    let bytes = encode(&message).map_err(|e| e.to_string())?;
+    let bytes = if compress {
+        // Any size, but only if the payload shrinks
+        Compression::new().with_min_size(0).encode(&message)
+    } else {
+        encode(&message)
+    }
+    .map_err(|e| e.to_string())?;
 
     // Print as hex
     for byte in &bytes {
@@ -141,11 +306,34 @@ fn cmd_encode(args: &[String]) -> Result<(), String> {
 }
 
 fn cmd_decode(args: &[String]) -> Result<(), String> {
-    if args.is_empty() {
-        return Err("Missing hex input".to_string());
+    let mut hex = None;
+    let mut payload_format = "text";
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--payload-format" | "-f" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err("Missing value for --payload-format".to_string());
+                }
+                payload_format = match args[i].as_str() {
+                    f @ ("text" | "hex" | "tlv") => f,
+                    f => {
+                        return Err(format!(
+                            "Unknown payload format: {} (expected text, hex or tlv)",
+                            f
+                        ))
+                    }
+                };
+            }
+            arg if hex.is_none() => hex = Some(arg),
+            arg => return Err(format!("Unknown argument: {}", arg)),
+        }
+        i += 1;
     }
 
-    let hex = &args[0];
+    let hex = hex.ok_or("Missing hex input")?;
     let bytes = hex_to_bytes(hex)?;
     let message = decode(&bytes).map_err(|e| e.to_string())?;
 
@@ -155,7 +343,86 @@ fn cmd_decode(args: &[String]) -> Result<(), String> {
     if let Some(status) = message.status {
         println!("Status: {}", status);
     }
-    println!("Payload ({} bytes): {:?}", message.payload.len(), String::from_utf8_lossy(&message.payload));
+    if let Some(options) = message.options {
+        match options.budget_ms() {
+            Some(ms) => println!("Budget: {} ms", ms),
+            None => println!("Budget: none"),
+        }
+        println!("Priority: {}", options.priority());
+    }
+    let length_offset = 8 + usize::from(message.status.is_some());
+    let length = u32::from_be_bytes(
+        bytes[length_offset..length_offset + 4]
+            .try_into()
+            .expect("decoded frame has a length"),
+    );
+    if length & COMPRESSED != 0 {
+        println!(
+            "Compressed: {} bytes on the wire",
+            length & !(COMPRESSED | OPTIONS)
+        );
+    }
+    match payload_format {
+        "tlv" => {
+            let value = Value::from_bytes(&message.payload).map_err(|e| e.to_string())?;
+            println!("Payload ({} bytes, TLV):", message.payload.len());
+            println!("{}", value.pretty());
+        }
+        "hex" => {
+            let hex: String = message
+                .payload
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            println!("Payload ({} bytes): {}", message.payload.len(), hex);
+        }
+        _ => println!(
+            "Payload ({} bytes): {:?}",
+            message.payload.len(),
+            String::from_utf8_lossy(&message.payload)
+        ),
+    }
+    let items = match message.message_type {
+        MessageType::Batch => Batch::from_message(&message).map(|b| b.requests),
+        MessageType::BatchResponse => BatchResponse::from_message(&message).map(|b| b.results),
+        _ => Ok(Vec::new()),
+    };
+    match message.message_type {
+        MessageType::Publish => {
+            let publication = Publication::from_message(&message).map_err(|e| e.to_string())?;
+            println!("  Topic: {}", publication.topic);
+            println!("  Data: {:?}", String::from_utf8_lossy(&publication.data));
+        }
+        MessageType::Event => {
+            let event = Event::from_message(&message).map_err(|e| e.to_string())?;
+            println!("  Topic: {}", event.topic);
+            println!("  Dropped before: {}", event.dropped);
+            println!("  Data: {:?}", String::from_utf8_lossy(&event.data));
+        }
+        MessageType::Resume => {
+            let resume = Resume::from_message(&message).map_err(|e| e.to_string())?;
+            if resume.session.is_new() {
+                println!("  Session: new");
+            } else {
+                println!("  Session: {}", resume.session);
+            }
+            println!("  Replay: {:?}", resume.replay);
+        }
+        _ => {}
+    }
+    for item in items.map_err(|e| e.to_string())? {
+        let status = item
+            .status
+            .map(|s| format!(" status {}", s))
+            .unwrap_or_default();
+        println!(
+            "  {:?} {}{}: {:?}",
+            item.message_type,
+            item.id,
+            status,
+            String::from_utf8_lossy(&item.payload)
+        );
+    }
 
     Ok(())
 }
@@ -168,7 +435,7 @@ fn cmd_validate(args: &[String]) -> Result<(), String> {
     let hex = &args[0];
     let bytes = hex_to_bytes(hex)?;
 
-    match decode(&bytes) {
+    match decode_strict(&bytes) {
         Ok(_) => {
             println!("Valid message");
             Ok(())
@@ -180,8 +447,143 @@ fn cmd_validate(args: &[String]) -> Result<(), String> {
     }
 }
 
+/// Echo each request payload back in a success response
+fn echo(request: Message) -> Message {
+    Message::response(request.id, 0, &request.payload)
+}
+
+fn cmd_serve(args: &[String]) -> Result<(), String> {
+    // Sessions cost nothing until a client opens one
+    let mut server = Server::new(echo).with_sessions(Sessions::new());
+    let mut args = args.to_vec();
+    if let Some(i) = args.iter().position(|arg| arg == "--psk-file") {
+        let path = args.get(i + 1).ok_or("--psk-file requires a path")?.clone();
+        // The key is the file's bytes exactly, as SecureStream::connect
+        // callers pass them; a trailing newline is part of the key
+        let psk = std::fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+        server = server
+            .with_psk(&psk)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        args.drain(i..i + 2);
+    }
+    if let Some(i) = args.iter().position(|arg| arg == "--websocket") {
+        server = server.with_websocket(true);
+        args.remove(i);
+    }
+    match args.as_slice() {
+        [flag, addr] if flag == "--tcp" => {
+            let listener =
+                std::net::TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+            eprintln!(
+                "listening on {}",
+                listener.local_addr().map_err(|e| e.to_string())?
+            );
+            server.serve(listener).map_err(|e| e.to_string())
+        }
+        #[cfg(unix)]
+        [flag, path] if flag == "--unix" => {
+            let listener = std::os::unix::net::UnixListener::bind(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            eprintln!("listening on {}", path);
+            server.serve(listener).map_err(|e| e.to_string())
+        }
+        [flag] if flag == "--stdio" => server.serve_stdio().map_err(|e| e.to_string()),
+        _ => Err(concat!(
+            "Expected one of --tcp ADDR, --unix PATH or --stdio, ",
+            "optionally with --psk-file PATH and --websocket"
+        )
+        .to_string()),
+    }
+}
+
+fn cmd_gateway(args: &[String]) -> Result<(), String> {
+    let mut http = None;
+    let mut upstream = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--http" => http = Some(rest.next().ok_or("--http requires an address")?.clone()),
+            "--upstream" => {
+                upstream = Some(rest.next().ok_or("--upstream requires an address")?.clone())
+            }
+            arg => return Err(format!("Unknown option: {}", arg)),
+        }
+    }
+    let (Some(http), Some(upstream)) = (http, upstream) else {
+        return Err("Expected --http ADDR --upstream ADDR".to_string());
+    };
+    let listener = std::net::TcpListener::bind(&http).map_err(|e| format!("{}: {}", http, e))?;
+    eprintln!(
+        "listening on {}",
+        listener.local_addr().map_err(|e| e.to_string())?
+    );
+    let gateway = Gateway::new(move || {
+        let stream = std::net::TcpStream::connect(&upstream)?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    });
+    gateway.serve(listener).map_err(|e| e.to_string())
+}
+
+fn cmd_vectors(args: &[String]) -> Result<(), String> {
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            let mut out = None;
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    // JSON is the only format, and the default
+                    "--json" => {}
+                    "--out" | "-o" => out = Some(rest.next().ok_or("--out requires a path")?),
+                    arg => return Err(format!("Unknown option: {}", arg)),
+                }
+            }
+            let json = interop::to_json(&interop::cases());
+            match out {
+                Some(path) => std::fs::write(path, json).map_err(|e| format!("{}: {}", path, e)),
+                None => {
+                    print!("{}", json);
+                    Ok(())
+                }
+            }
+        }
+        Some("verify") => {
+            let [_, path] = args else {
+                return Err("Usage: protocol-name vectors verify RESULTS (- for stdin)".to_string());
+            };
+            let json = if path == "-" {
+                let mut json = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut json)
+                    .map_err(|e| e.to_string())?;
+                json
+            } else {
+                std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?
+            };
+            let results = interop::parse_results(&json)?;
+            let report = interop::verify(&interop::cases(), &results);
+            for failure in &report.failures {
+                println!("FAIL {}", failure);
+            }
+            println!("{} passed, {} failed", report.passed, report.failures.len());
+            if report.is_success() {
+                Ok(())
+            } else {
+                let total = report.passed + report.failures.len();
+                Err(format!(
+                    "{} of {} cases diverge from the reference",
+                    report.failures.len(),
+                    total
+                ))
+            }
+        }
+        _ => {
+            Err("Expected vectors export --json [--out FILE] or vectors verify RESULTS".to_string())
+        }
+    }
+}
+
 fn hex_to_bytes(hex: &str) -> Result<Vec<u8>, String> {
-    if hex.len() % 2 != 0 {
+    if hex.len() % 2 == 1 {
         return Err("Hex string must have even length".to_string());
     }
 
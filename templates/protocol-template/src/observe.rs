@@ -0,0 +1,459 @@
+//! Observability hooks
+//!
+//! An [`Observer`] attached to a [`Client`](crate::client::Client) or
+//! [`Server`](crate::server::Server) is told about every frame sent and
+//! received, every decode error, connection open/close (server side) and
+//! the latency of each request. All methods have empty defaults, so an
+//! observer implements only what it needs.
+//!
+//! [`Metrics`] is a ready-made observer keeping counters and a latency
+//! histogram, exportable as JSON or in the Prometheus text exposition
+//! format.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::sync::Arc;
+//!
+//! use protocol_name::observe::Metrics;
+//! use protocol_name::server::Server;
+//! use protocol_name::Message;
+//!
+//! let metrics = Arc::new(Metrics::new());
+//! let server = Server::new(|m: Message| Message::response(m.id, 0, b""))
+//!     .with_observer(metrics.clone());
+//!
+//! // ... serve connections, then expose:
+//! println!("{}", metrics.to_prometheus());
+//! ```
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{ErrorCode, Message, MessageType, ProtocolError};
+
+/// Receives protocol events
+///
+/// Called synchronously on the connection's thread, so implementations
+/// should be cheap and must not block.
+pub trait Observer: Send + Sync {
+    /// A connection was accepted
+    fn connection_opened(&self) {}
+
+    /// A connection ended, cleanly (`None`) or with an error
+    fn connection_closed(&self, _error: Option<&ProtocolError>) {}
+
+    /// A frame of `bytes` total length was written
+    fn frame_sent(&self, _message: &Message, _bytes: usize) {}
+
+    /// A frame of `bytes` total length was read
+    fn frame_received(&self, _message: &Message, _bytes: usize) {}
+
+    /// An incoming frame failed to decode
+    fn decode_error(&self, _error: &ProtocolError) {}
+
+    /// A request finished: on the client, from send until the reply arrived;
+    /// on the server, the time spent in the handler
+    fn request_completed(&self, _reply: &Message, _latency: Duration) {}
+}
+
+/// Upper bounds of the latency histogram buckets, in microseconds
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000, 1_000_000, 10_000_000,
+];
+
+/// Prefix for exported metric names
+const PREFIX: &str = "protocol_name";
+
+/// Counters per message type, indexed by [`type_index`]
+#[derive(Debug, Default)]
+struct PerType([AtomicU64; 11]);
+
+const TYPE_NAMES: [&str; 11] = [
+    "request",
+    "response",
+    "window_update",
+    "batch",
+    "batch_response",
+    "subscribe",
+    "unsubscribe",
+    "publish",
+    "event",
+    "resume",
+    "error",
+];
+
+fn type_index(message_type: MessageType) -> usize {
+    match message_type {
+        MessageType::Request => 0,
+        MessageType::Response => 1,
+        MessageType::WindowUpdate => 2,
+        MessageType::Batch => 3,
+        MessageType::BatchResponse => 4,
+        MessageType::Subscribe => 5,
+        MessageType::Unsubscribe => 6,
+        MessageType::Publish => 7,
+        MessageType::Event => 8,
+        MessageType::Resume => 9,
+        MessageType::Error => 10,
+    }
+}
+
+impl PerType {
+    fn add(&self, message_type: MessageType) {
+        self.0[type_index(message_type)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> [u64; 11] {
+        std::array::from_fn(|i| self.0[i].load(Ordering::Relaxed))
+    }
+}
+
+/// A latency histogram with fixed buckets
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative counts; the final slot is the overflow bucket
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let slot = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&le| us <= le)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[slot].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A point-in-time copy of [`Metrics`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Frames written, by type (request, response, window update, batch,
+    /// batch response, subscribe, unsubscribe, publish, event, error)
+    pub frames_sent: [u64; 11],
+    /// Frames read, by type (request, response, window update, batch,
+    /// batch response, subscribe, unsubscribe, publish, event, error)
+    pub frames_received: [u64; 11],
+    /// Total bytes written
+    pub bytes_sent: u64,
+    /// Total bytes read
+    pub bytes_received: u64,
+    /// Decode errors, by error code (invalid format, unknown type, payload too large)
+    pub decode_errors: [u64; 3],
+    /// Connections accepted
+    pub connections_opened: u64,
+    /// Connections ended
+    pub connections_closed: u64,
+    /// Cumulative request counts per bucket of [`LATENCY_BUCKETS_US`],
+    /// followed by the total
+    pub latency_buckets: Vec<u64>,
+    /// Sum of all request latencies in microseconds
+    pub latency_sum_us: u64,
+    /// Requests completed
+    pub requests: u64,
+}
+
+impl MetricsSnapshot {
+    /// Connections currently open
+    pub fn connections_active(&self) -> u64 {
+        self.connections_opened
+            .saturating_sub(self.connections_closed)
+    }
+}
+
+const ERROR_NAMES: [&str; 3] = ["invalid_format", "unknown_type", "payload_too_large"];
+
+/// Built-in observer collecting counters and a latency histogram
+///
+/// Share it between clients and servers with an `Arc`; all updates are
+/// lock-free.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    frames_sent: PerType,
+    frames_received: PerType,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    decode_errors: [AtomicU64; 3],
+    connections_opened: AtomicU64,
+    connections_closed: AtomicU64,
+    latency: Histogram,
+}
+
+impl Metrics {
+    /// Create an empty set of metrics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy the current values
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut cumulative = 0;
+        let latency_buckets = self
+            .latency
+            .buckets
+            .iter()
+            .map(|b| {
+                cumulative += b.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+        MetricsSnapshot {
+            frames_sent: self.frames_sent.load(),
+            frames_received: self.frames_received.load(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            decode_errors: [0, 1, 2].map(|i| self.decode_errors[i].load(Ordering::Relaxed)),
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+            latency_buckets,
+            latency_sum_us: self.latency.sum_us.load(Ordering::Relaxed),
+            requests: self.latency.count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Render as a JSON object
+    pub fn to_json(&self) -> String {
+        let s = self.snapshot();
+        let per_type = |counts: [u64; 11]| {
+            let fields: Vec<String> = TYPE_NAMES
+                .iter()
+                .zip(counts)
+                .map(|(n, c)| format!("\"{}\":{}", n, c))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        let errors: Vec<String> = ERROR_NAMES
+            .iter()
+            .zip(s.decode_errors)
+            .map(|(n, c)| format!("\"{}\":{}", n, c))
+            .collect();
+        let buckets: Vec<String> = bucket_bounds()
+            .zip(&s.latency_buckets)
+            .map(|(le, c)| {
+                format!(
+                    "{{\"le\":{},\"count\":{}}}",
+                    le.map_or("\"+Inf\"".to_string(), |v| v.to_string()),
+                    c
+                )
+            })
+            .collect();
+
+        format!(
+            concat!(
+                "{{\"frames_sent\":{},\"frames_received\":{},\"bytes_sent\":{},\"bytes_received\":{},",
+                "\"decode_errors\":{{{}}},\"connections\":{{\"opened\":{},\"closed\":{},\"active\":{}}},",
+                "\"request_latency\":{{\"count\":{},\"sum_seconds\":{},\"buckets\":[{}]}}}}"
+            ),
+            per_type(s.frames_sent),
+            per_type(s.frames_received),
+            s.bytes_sent,
+            s.bytes_received,
+            errors.join(","),
+            s.connections_opened,
+            s.connections_closed,
+            s.connections_active(),
+            s.requests,
+            seconds(s.latency_sum_us),
+            buckets.join(","),
+        )
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let s = self.snapshot();
+        let mut out = String::new();
+
+        for (name, help, counts) in [
+            ("frames_sent_total", "Frames written", s.frames_sent),
+            ("frames_received_total", "Frames read", s.frames_received),
+        ] {
+            header(&mut out, name, help, "counter");
+            for (t, c) in TYPE_NAMES.iter().zip(counts) {
+                let _ = writeln!(out, "{}_{}{{type=\"{}\"}} {}", PREFIX, name, t, c);
+            }
+        }
+        for (name, help, value) in [
+            ("bytes_sent_total", "Bytes written", s.bytes_sent),
+            ("bytes_received_total", "Bytes read", s.bytes_received),
+            (
+                "connections_opened_total",
+                "Connections accepted",
+                s.connections_opened,
+            ),
+            (
+                "connections_closed_total",
+                "Connections ended",
+                s.connections_closed,
+            ),
+        ] {
+            header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+        }
+
+        header(
+            &mut out,
+            "decode_errors_total",
+            "Frames that failed to decode",
+            "counter",
+        );
+        for (code, c) in ERROR_NAMES.iter().zip(s.decode_errors) {
+            let _ = writeln!(
+                out,
+                "{}_decode_errors_total{{code=\"{}\"}} {}",
+                PREFIX, code, c
+            );
+        }
+
+        header(
+            &mut out,
+            "connections_active",
+            "Connections currently open",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "{}_connections_active {}",
+            PREFIX,
+            s.connections_active()
+        );
+
+        let name = "request_duration_seconds";
+        header(&mut out, name, "Request latency", "histogram");
+        for (le, c) in bucket_bounds().zip(&s.latency_buckets) {
+            let le = le.map_or("+Inf".to_string(), |v| v.to_string());
+            let _ = writeln!(out, "{}_{}_bucket{{le=\"{}\"}} {}", PREFIX, name, le, c);
+        }
+        let _ = writeln!(out, "{}_{}_sum {}", PREFIX, name, seconds(s.latency_sum_us));
+        let _ = writeln!(out, "{}_{}_count {}", PREFIX, name, s.requests);
+        out
+    }
+}
+
+impl Observer for Metrics {
+    fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self, _error: Option<&ProtocolError>) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn frame_sent(&self, message: &Message, bytes: usize) {
+        self.frames_sent.add(message.message_type);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn frame_received(&self, message: &Message, bytes: usize) {
+        self.frames_received.add(message.message_type);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn decode_error(&self, error: &ProtocolError) {
+        let slot = match error.error_code() {
+            ErrorCode::UnknownType => 1,
+            ErrorCode::PayloadTooLarge => 2,
+            // Connection-, method-, session- and deadline-layer codes are
+            // never decode failures
+            ErrorCode::InvalidFormat
+            | ErrorCode::FlowControl
+            | ErrorCode::DuplicateId
+            | ErrorCode::UnknownMethod
+            | ErrorCode::UnknownSession
+            | ErrorCode::DeadlineExceeded => 0,
+        };
+        self.decode_errors[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn request_completed(&self, _reply: &Message, latency: Duration) {
+        self.latency.record(latency);
+    }
+}
+
+/// Bucket upper bounds in seconds, ending with `None` for `+Inf`
+fn bucket_bounds() -> impl Iterator<Item = Option<f64>> {
+    LATENCY_BUCKETS_US
+        .iter()
+        .map(|&us| Some(us as f64 / 1e6))
+        .chain(std::iter::once(None))
+}
+
+fn seconds(us: u64) -> f64 {
+    us as f64 / 1e6
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Metrics {
+        let metrics = Metrics::new();
+        let request = Message::request(1, b"ping");
+        let response = Message::response(1, 0, b"pong");
+        metrics.connection_opened();
+        metrics.frame_received(&request, request.encoded_len());
+        metrics.frame_sent(&response, response.encoded_len());
+        metrics.request_completed(&response, Duration::from_micros(300));
+        metrics.request_completed(&response, Duration::from_secs(60));
+        metrics.decode_error(&ProtocolError::UnknownType {
+            offset: 3,
+            actual: 7,
+        });
+        metrics
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let s = sample().snapshot();
+        assert_eq!(s.frames_received, [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(s.frames_sent, [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(s.bytes_sent, 17);
+        assert_eq!(s.decode_errors, [0, 1, 0]);
+        assert_eq!(s.connections_active(), 1);
+        assert_eq!(s.requests, 2);
+        // 300us lands in the 500us bucket; 60s only in +Inf
+        assert_eq!(s.latency_buckets[2], 0);
+        assert_eq!(s.latency_buckets[3], 1);
+        assert_eq!(s.latency_buckets[LATENCY_BUCKETS_US.len() - 1], 1);
+        assert_eq!(*s.latency_buckets.last().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let text = sample().to_prometheus();
+        assert!(text.contains("# TYPE protocol_name_frames_sent_total counter\n"));
+        assert!(text.contains("protocol_name_frames_sent_total{type=\"response\"} 1\n"));
+        assert!(text.contains("protocol_name_decode_errors_total{code=\"unknown_type\"} 1\n"));
+        assert!(text.contains("protocol_name_request_duration_seconds_bucket{le=\"0.0005\"} 1\n"));
+        assert!(text.contains("protocol_name_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("protocol_name_request_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let json = sample().to_json();
+        assert!(json.starts_with("{\"frames_sent\":{\"request\":0,\"response\":1,\"window_update\":0,\"batch\":0,\"batch_response\":0,\"subscribe\":0,\"unsubscribe\":0,\"publish\":0,\"event\":0,\"resume\":0,\"error\":0},"));
+        assert!(json.contains("\"connections\":{\"opened\":1,\"closed\":0,\"active\":1}"));
+        assert!(json.contains("{\"le\":0.0005,\"count\":1}"));
+        assert!(json.ends_with("{\"le\":\"+Inf\",\"count\":2}]}}"));
+        // Balanced braces and brackets
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+    }
+}
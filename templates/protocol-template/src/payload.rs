@@ -0,0 +1,421 @@
+//! Typed payloads
+//!
+//! `Encode` and `Decode` turn Rust values into message payloads and back
+//! using a compact binary encoding (SPEC.md Section 3.4):
+//!
+//! | Type | Encoding |
+//! |------|----------|
+//! | `u8`, `i8`, `bool` | 1 byte (`bool` is `0x00`/`0x01`) |
+//! | `u16`..`u64`, `usize` | unsigned LEB128 varint |
+//! | `i16`..`i64`, `isize` | zigzag, then LEB128 varint |
+//! | `String`, `&str` | varint byte length + UTF-8 bytes |
+//! | `Vec<T>`, `[T]` | varint element count + elements |
+//! | `Option<T>` | `0x00` for `None`, `0x01` + value for `Some` |
+//! | tuples, `()` | fields concatenated in order |
+//!
+//! Byte slices and `Vec<u8>` are therefore a varint length followed by the
+//! raw bytes. The encoding is not self-describing; both peers must agree on
+//! the type.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use protocol_name::Message;
+//!
+//! let request = Message::request_typed(1, &("add".to_string(), vec![1u32, 2, 3]));
+//! let (method, args): (String, Vec<u32>) = request.payload_as().unwrap();
+//! assert_eq!(method, "add");
+//! assert_eq!(args, vec![1, 2, 3]);
+//! ```
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::ProtocolError;
+
+/// Maximum encoded length of a LEB128 `u64`
+const MAX_VARINT_LEN: usize = 10;
+
+/// A value that can be written into a payload
+pub trait Encode {
+    /// Append the encoded value to `out`
+    fn encode_payload(&self, out: &mut Vec<u8>);
+
+    /// Encode into a fresh buffer
+    fn to_payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_payload(&mut out);
+        out
+    }
+}
+
+/// A value that can be read from a payload
+pub trait Decode: Sized {
+    /// Decode a value from the front of `input`, advancing it
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError>;
+
+    /// Decode a complete payload, rejecting trailing bytes
+    fn from_payload(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut input = bytes;
+        let value = Self::decode_payload(&mut input)?;
+        if !input.is_empty() {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "{} trailing bytes",
+                input.len()
+            )));
+        }
+        Ok(value)
+    }
+}
+
+// ============================================================================
+// Primitives
+// ============================================================================
+
+/// Append an unsigned LEB128 varint
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read an unsigned LEB128 varint, rejecting overlong encodings
+pub fn read_varint(input: &mut &[u8]) -> Result<u64, ProtocolError> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let byte = take_byte(input)?;
+        let bits = u64::from(byte & 0x7F);
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(invalid("varint overflows u64"));
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i > 0 {
+                return Err(invalid("overlong varint"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint overflows u64"))
+}
+
+fn invalid(msg: &str) -> ProtocolError {
+    ProtocolError::InvalidPayload(msg.to_string())
+}
+
+fn take_byte(input: &mut &[u8]) -> Result<u8, ProtocolError> {
+    let (&byte, rest) = input
+        .split_first()
+        .ok_or_else(|| invalid("unexpected end of payload"))?;
+    *input = rest;
+    Ok(byte)
+}
+
+fn take_bytes<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], ProtocolError> {
+    if input.len() < len {
+        return Err(invalid("unexpected end of payload"));
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn read_len(input: &mut &[u8]) -> Result<usize, ProtocolError> {
+    let len = read_varint(input)?;
+    usize::try_from(len).map_err(|_| invalid("length overflows usize"))
+}
+
+impl Encode for u8 {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        take_byte(input)
+    }
+}
+
+impl Encode for i8 {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl Decode for i8 {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        take_byte(input).map(|b| b as i8)
+    }
+}
+
+impl Encode for bool {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(*self));
+    }
+}
+
+impl Decode for bool {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        match take_byte(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(ProtocolError::InvalidPayload(format!(
+                "invalid bool: {:#04x}",
+                b
+            ))),
+        }
+    }
+}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_payload(&self, out: &mut Vec<u8>) {
+                write_varint(out, *self as u64);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+                let value = read_varint(input)?;
+                <$t>::try_from(value).map_err(|_| {
+                    ProtocolError::InvalidPayload(format!(
+                        "{} out of range for {}",
+                        value,
+                        stringify!($t)
+                    ))
+                })
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_signed {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode_payload(&self, out: &mut Vec<u8>) {
+                let v = *self as i64;
+                write_varint(out, ((v << 1) ^ (v >> 63)) as u64);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+                let raw = read_varint(input)?;
+                let value = ((raw >> 1) as i64) ^ -((raw & 1) as i64);
+                <$t>::try_from(value).map_err(|_| {
+                    ProtocolError::InvalidPayload(format!(
+                        "{} out of range for {}",
+                        value,
+                        stringify!($t)
+                    ))
+                })
+            }
+        }
+    )*};
+}
+
+impl_unsigned!(u16, u32, u64, usize);
+impl_signed!(i16, i32, i64, isize);
+
+// ============================================================================
+// Strings and Sequences
+// ============================================================================
+
+impl Encode for str {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.as_str().encode_payload(out);
+    }
+}
+
+impl Decode for String {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        let len = read_len(input)?;
+        let bytes = take_bytes(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.encode_payload(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_payload(out);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        let count = read_len(input)?;
+        // Elements such as `()` take no bytes, so the count cannot be checked
+        // against the input; a truncated sequence fails on its missing
+        // element. Only the preallocation is bounded by the input.
+        let mut items = Vec::with_capacity(count.min(input.len()));
+        for _ in 0..count {
+            items.push(T::decode_payload(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode_payload(out);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        match take_byte(input)? {
+            0 => Ok(None),
+            1 => T::decode_payload(input).map(Some),
+            b => Err(ProtocolError::InvalidPayload(format!(
+                "invalid option tag: {:#04x}",
+                b
+            ))),
+        }
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        (**self).encode_payload(out);
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for Box<T> {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        (**self).encode_payload(out);
+    }
+}
+
+impl<T: Decode> Decode for Box<T> {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        T::decode_payload(input).map(Box::new)
+    }
+}
+
+// ============================================================================
+// Tuples
+// ============================================================================
+
+impl Encode for () {
+    fn encode_payload(&self, _out: &mut Vec<u8>) {}
+}
+
+impl Decode for () {
+    fn decode_payload(_input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_payload(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_payload(out);)+
+            }
+        }
+
+        impl<$($name: Decode),+> Decode for ($($name,)+) {
+            fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+                Ok(($($name::decode_payload(input)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn roundtrip<T: Encode + Decode + PartialEq + std::fmt::Debug>(value: T) {
+        let bytes = value.to_payload();
+        assert_eq!(T::from_payload(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn test_varint_encoding() {
+        assert_eq!(0u32.to_payload(), vec![0x00]);
+        assert_eq!(127u32.to_payload(), vec![0x7F]);
+        assert_eq!(300u32.to_payload(), vec![0xAC, 0x02]);
+        assert_eq!(u64::MAX.to_payload().len(), MAX_VARINT_LEN);
+        assert_eq!((-1i32).to_payload(), vec![0x01]);
+        assert_eq!(1i32.to_payload(), vec![0x02]);
+    }
+
+    #[test]
+    fn test_roundtrip_values() {
+        roundtrip(u64::MAX);
+        roundtrip(i64::MIN);
+        roundtrip(-42i16);
+        roundtrip(true);
+        roundtrip("héllo".to_string());
+        roundtrip(vec![Some(1u8), None, Some(3)]);
+        roundtrip((7u32, "x".to_string(), vec![0xABu8; 3], Some(-1i64)));
+    }
+
+    #[test]
+    fn test_zero_sized_elements_roundtrip() {
+        assert_eq!(vec![(); 3].to_payload(), vec![0x03]);
+        roundtrip(vec![(); 3]);
+        roundtrip(vec![vec![(); 2], Vec::new()]);
+    }
+
+    #[test]
+    fn test_bytes_are_length_prefixed() {
+        assert_eq!(b"hi".as_slice().to_payload(), vec![0x02, b'h', b'i']);
+        assert_eq!("hi".to_payload(), vec![0x02, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(u8::from_payload(&[]).is_err());
+        assert!(u16::from_payload(&[0xFF, 0xFF, 0x7F]).is_err());
+        assert!(u32::from_payload(&[0x80, 0x00]).is_err());
+        assert!(u64::from_payload(&[0xFF; 11]).is_err());
+        assert!(bool::from_payload(&[2]).is_err());
+        assert!(String::from_payload(&[0x02, 0xFF, 0xFE]).is_err());
+        assert!(Vec::<u8>::from_payload(&[0x05, 1]).is_err());
+        assert!(u8::from_payload(&[1, 2]).is_err());
+    }
+}
@@ -0,0 +1,684 @@
+//! Property-based testing
+//!
+//! A small harness with no dependencies. A [`Strategy`] generates random
+//! values from a seeded [`Rng`] and proposes simpler versions of a value;
+//! [`Runner`] checks a property against many generated values. When one
+//! fails, the runner shrinks it to a minimal counterexample and reports it
+//! with the seed, so the run can be replayed exactly.
+//!
+//! ```rust
+//! use protocol_name::prop::{self, Runner};
+//! use protocol_name::{decode, encode};
+//!
+//! Runner::new().with_cases(64).check(&prop::messages(256), |message| {
+//!     let bytes = encode(message).map_err(|e| e.to_string())?;
+//!     let decoded = decode(&bytes).map_err(|e| e.to_string())?;
+//!     prop::ensure_eq(&decoded, message)
+//! });
+//! ```
+//!
+//! A panic inside the property counts as a failure, so "never panics" is a
+//! property too. `PROTOCOL_PROP_SEED` and `PROTOCOL_PROP_CASES` override
+//! the seed and case count of runners built with [`Runner::new`].
+
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use crate::compress::{Compression, COMPRESSED};
+use crate::deadline::{RequestOptions, OPTIONS};
+use crate::rng::Rng;
+use crate::{encode, Message, MessageType, MAX_PAYLOAD_SIZE, VERSION};
+
+/// Cases checked by [`Runner::new`] unless `PROTOCOL_PROP_CASES` is set
+pub const DEFAULT_CASES: u32 = 256;
+
+/// Seed used by [`Runner::new`] unless `PROTOCOL_PROP_SEED` is set
+pub const DEFAULT_SEED: u64 = 0x7475_756C_6265_6C74;
+
+/// Default limit on shrink candidates tried after a failure
+pub const DEFAULT_MAX_SHRINKS: u32 = 4096;
+
+// ============================================================================
+// Strategies
+// ============================================================================
+
+/// A source of random test values
+pub trait Strategy {
+    /// Type of the generated values
+    type Value: Clone + fmt::Debug;
+
+    /// Generate a value
+    fn generate(&self, rng: &mut Rng) -> Self::Value;
+
+    /// Simpler versions of `value`, most aggressive first
+    ///
+    /// Every candidate must be strictly simpler than `value`, or shrinking
+    /// would not terminate before the runner's limit.
+    fn shrink(&self, value: &Self::Value) -> Vec<Self::Value>;
+}
+
+/// Byte strings up to `max_len` long
+pub fn bytes(max_len: usize) -> Bytes {
+    Bytes { max_len }
+}
+
+/// Messages of every type with payloads up to `max_payload` bytes
+pub fn messages(max_payload: usize) -> Messages {
+    Messages {
+        payload: bytes(max_payload.min(MAX_PAYLOAD_SIZE)),
+    }
+}
+
+/// Encoded frames, some compressed, with random corruption applied
+///
+/// Most bytes a decoder sees from [`bytes`] fail at the magic. These get
+/// past it and exercise the later fields: a mutated type, length or
+/// compressed payload, truncation, trailing data, and frames back to back.
+pub fn frames(max_payload: usize) -> Frames {
+    Frames {
+        messages: messages(max_payload),
+    }
+}
+
+/// Strategy returned by [`bytes`]
+#[derive(Debug, Clone, Copy)]
+pub struct Bytes {
+    max_len: usize,
+}
+
+impl Strategy for Bytes {
+    type Value = Vec<u8>;
+
+    fn generate(&self, rng: &mut Rng) -> Vec<u8> {
+        // Favour short inputs, where edge cases are dense
+        let cap = match rng.below(4) {
+            0 => 8,
+            1 => 64,
+            2 => 1024,
+            _ => self.max_len,
+        };
+        let len = rng.range(0, cap.min(self.max_len));
+        let mut out = vec![0u8; len];
+        match rng.below(3) {
+            0 => rng.fill(&mut out),
+            1 => out.fill(rng.next_u64() as u8),
+            _ => {
+                // Bytes that mean something in a header
+                const INTERESTING: [u8; 8] = [0x00, 0x01, 0x02, 0x54, 0x55, 0x7F, 0x80, 0xFF];
+                for byte in &mut out {
+                    *byte = INTERESTING[rng.below(INTERESTING.len() as u64) as usize];
+                }
+            }
+        }
+        out
+    }
+
+    fn shrink(&self, value: &Vec<u8>) -> Vec<Vec<u8>> {
+        shrink_bytes(value)
+    }
+}
+
+/// Strategy returned by [`messages`]
+#[derive(Debug, Clone, Copy)]
+pub struct Messages {
+    payload: Bytes,
+}
+
+/// Every message type, simplest first
+const MESSAGE_TYPES: [MessageType; 11] = [
+    MessageType::Request,
+    MessageType::Response,
+    MessageType::WindowUpdate,
+    MessageType::Batch,
+    MessageType::BatchResponse,
+    MessageType::Subscribe,
+    MessageType::Unsubscribe,
+    MessageType::Publish,
+    MessageType::Event,
+    MessageType::Resume,
+    MessageType::Error,
+];
+
+impl Strategy for Messages {
+    type Value = Message;
+
+    fn generate(&self, rng: &mut Rng) -> Message {
+        let message_type = MESSAGE_TYPES[rng.below(MESSAGE_TYPES.len() as u64) as usize];
+        let id = match rng.below(4) {
+            0 => [0, 1, u32::MAX][rng.below(3) as usize],
+            1 => rng.below(256) as u32,
+            _ => rng.next_u64() as u32,
+        };
+        let status = message_type.has_status().then(|| match rng.below(2) {
+            0 => 0,
+            _ => rng.next_u64() as u8,
+        });
+        let options = (message_type == MessageType::Request && rng.chance(0.3)).then(|| {
+            let options = RequestOptions::new().with_priority(rng.next_u64() as u8);
+            match rng.below(3) {
+                0 => options,
+                1 => options.with_budget_ms(rng.below(2) as u32),
+                _ => options.with_budget_ms(rng.next_u64() as u32),
+            }
+        });
+        Message {
+            version: VERSION,
+            message_type,
+            id,
+            status,
+            options,
+            payload: self.payload.generate(rng),
+        }
+    }
+
+    fn shrink(&self, value: &Message) -> Vec<Message> {
+        let mut out: Vec<Message> = shrink_bytes(&value.payload)
+            .into_iter()
+            .map(|payload| Message {
+                payload,
+                ..value.clone()
+            })
+            .collect();
+        if value.options.is_some() {
+            out.push(Message {
+                options: None,
+                ..value.clone()
+            });
+        }
+        if value.id != 0 {
+            out.push(Message {
+                id: 0,
+                ..value.clone()
+            });
+            for id in [value.id / 2, value.id - 1] {
+                if id != 0 {
+                    out.push(Message {
+                        id,
+                        ..value.clone()
+                    });
+                }
+            }
+        }
+        if value.status.is_some_and(|status| status != 0) {
+            out.push(Message {
+                status: Some(0),
+                ..value.clone()
+            });
+        }
+        // Types earlier in the list count as simpler
+        let rank = MESSAGE_TYPES
+            .iter()
+            .position(|&t| t == value.message_type)
+            .unwrap_or(0);
+        for &message_type in &MESSAGE_TYPES[..rank] {
+            out.push(Message {
+                message_type,
+                status: message_type
+                    .has_status()
+                    .then_some(value.status.unwrap_or(0)),
+                options: value
+                    .options
+                    .filter(|_| message_type == MessageType::Request),
+                ..value.clone()
+            });
+        }
+        out
+    }
+}
+
+/// Strategy returned by [`frames`]
+#[derive(Debug, Clone, Copy)]
+pub struct Frames {
+    messages: Messages,
+}
+
+impl Strategy for Frames {
+    type Value = Vec<u8>;
+
+    fn generate(&self, rng: &mut Rng) -> Vec<u8> {
+        let message = self.messages.generate(rng);
+        let mut frame = if rng.chance(0.3) {
+            Compression::new().with_min_size(0).encode(&message)
+        } else {
+            encode(&message)
+        }
+        .expect("generated payloads fit in a frame");
+        let length_offset = if message.message_type.has_status() {
+            9
+        } else {
+            8
+        };
+
+        for _ in 0..rng.below(4) {
+            if frame.is_empty() {
+                break;
+            }
+            let at = rng.below(frame.len() as u64) as usize;
+            match rng.below(7) {
+                0 => frame[at] ^= 1 << rng.below(8),
+                1 => frame[at] = rng.next_u64() as u8,
+                2 => frame.truncate(at),
+                3 => {
+                    let mut tail = vec![0u8; rng.range(1, 16)];
+                    rng.fill(&mut tail);
+                    frame.extend_from_slice(&tail);
+                }
+                4 if frame.len() >= length_offset + 4 => {
+                    let actual = (frame.len() - length_offset - 4) as u32;
+                    let lengths = [
+                        0,
+                        actual.wrapping_sub(1),
+                        actual + 1,
+                        MAX_PAYLOAD_SIZE as u32,
+                        MAX_PAYLOAD_SIZE as u32 + 1,
+                        COMPRESSED | actual,
+                        COMPRESSED,
+                        OPTIONS | actual,
+                        u32::MAX,
+                    ];
+                    let length = lengths[rng.below(lengths.len() as u64) as usize];
+                    frame[length_offset..length_offset + 4].copy_from_slice(&length.to_be_bytes());
+                }
+                4 | 5 if frame.len() > 3 => frame[3] = rng.next_u64() as u8,
+                _ => frame.extend_from_within(..),
+            }
+        }
+        frame
+    }
+
+    fn shrink(&self, value: &Vec<u8>) -> Vec<Vec<u8>> {
+        shrink_bytes(value)
+    }
+}
+
+/// Shorter byte strings first, then ones with bytes zeroed
+fn shrink_bytes(value: &[u8]) -> Vec<Vec<u8>> {
+    let len = value.len();
+    if len == 0 {
+        return Vec::new();
+    }
+    let mut out = vec![Vec::new()];
+    // Remove chunks, halving their size down to single bytes
+    let mut chunk = len / 2;
+    while chunk > 0 {
+        let starts = (0..len).step_by(chunk).take(16);
+        for start in starts {
+            let end = (start + chunk).min(len);
+            let mut shorter = value[..start].to_vec();
+            shorter.extend_from_slice(&value[end..]);
+            out.push(shorter);
+        }
+        chunk /= 2;
+    }
+    for (i, &byte) in value.iter().enumerate().take(32) {
+        let mut smaller = [0, byte / 2, byte.saturating_sub(1)];
+        smaller.sort_unstable();
+        let mut last = None;
+        for candidate in smaller {
+            if candidate < byte && last != Some(candidate) {
+                let mut simpler = value.to_vec();
+                simpler[i] = candidate;
+                out.push(simpler);
+                last = Some(candidate);
+            }
+        }
+    }
+    out
+}
+
+// ============================================================================
+// Runner
+// ============================================================================
+
+/// Checks a property against generated values
+#[derive(Debug, Clone, Copy)]
+pub struct Runner {
+    cases: u32,
+    seed: u64,
+    max_shrinks: u32,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runner {
+    /// A runner with the default case count and seed, unless overridden by
+    /// `PROTOCOL_PROP_CASES` and `PROTOCOL_PROP_SEED`
+    pub fn new() -> Self {
+        let cases = env_number("PROTOCOL_PROP_CASES").map_or(DEFAULT_CASES, |cases| cases as u32);
+        let seed = env_number("PROTOCOL_PROP_SEED").unwrap_or(DEFAULT_SEED);
+        Self {
+            cases,
+            seed,
+            max_shrinks: DEFAULT_MAX_SHRINKS,
+        }
+    }
+
+    /// Set the number of generated cases
+    pub fn with_cases(mut self, cases: u32) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    /// Set the seed, to replay a reported failure
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Set how many shrink candidates to try after a failure
+    pub fn with_max_shrinks(mut self, max_shrinks: u32) -> Self {
+        self.max_shrinks = max_shrinks;
+        self
+    }
+
+    /// Number of cases generated per run
+    pub fn cases(&self) -> u32 {
+        self.cases
+    }
+
+    /// Seed of the generator
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Check `property` against generated values, returning the shrunk
+    /// counterexample if any fails
+    pub fn run<S, F>(&self, strategy: &S, property: F) -> Result<(), Failure<S::Value>>
+    where
+        S: Strategy,
+        F: Fn(&S::Value) -> Result<(), String>,
+    {
+        let mut rng = Rng::new(self.seed);
+        for case in 0..self.cases {
+            let original = strategy.generate(&mut rng);
+            let Err(message) = evaluate(&property, &original) else {
+                continue;
+            };
+
+            // Greedy shrinking: take the first simpler candidate that still
+            // fails, until none does or the budget runs out
+            let mut minimal = original.clone();
+            let mut message = message;
+            let mut shrinks = 0;
+            let mut tried = 0;
+            'shrink: while tried < self.max_shrinks {
+                for candidate in strategy.shrink(&minimal) {
+                    if tried == self.max_shrinks {
+                        break 'shrink;
+                    }
+                    tried += 1;
+                    if let Err(error) = evaluate(&property, &candidate) {
+                        minimal = candidate;
+                        message = error;
+                        shrinks += 1;
+                        continue 'shrink;
+                    }
+                }
+                break;
+            }
+            return Err(Failure {
+                seed: self.seed,
+                case,
+                original,
+                minimal,
+                shrinks,
+                message,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check `property`, panicking with a report of the minimal
+    /// counterexample if it fails
+    pub fn check<S, F>(&self, strategy: &S, property: F)
+    where
+        S: Strategy,
+        F: Fn(&S::Value) -> Result<(), String>,
+    {
+        if let Err(failure) = self.run(strategy, property) {
+            panic!("{failure}");
+        }
+    }
+}
+
+/// A property that failed, with the counterexample found
+#[derive(Debug, Clone)]
+pub struct Failure<T> {
+    /// Seed of the run
+    pub seed: u64,
+    /// Index of the failing case within the run
+    pub case: u32,
+    /// Value as generated
+    pub original: T,
+    /// Simplest failing value found by shrinking
+    pub minimal: T,
+    /// Number of successful shrink steps
+    pub shrinks: u32,
+    /// Why the property failed for `minimal`
+    pub message: String,
+}
+
+impl<T: fmt::Debug> fmt::Display for Failure<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "property failed at case {} (replay with PROTOCOL_PROP_SEED={:#x})",
+            self.case, self.seed
+        )?;
+        writeln!(
+            f,
+            "minimal input ({} shrinks): {:?}",
+            self.shrinks, self.minimal
+        )?;
+        write!(f, "error: {}", self.message)
+    }
+}
+
+/// `Ok` if the values are equal, otherwise an error showing both
+pub fn ensure_eq<T: PartialEq + fmt::Debug>(actual: &T, expected: &T) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {expected:?}, got {actual:?}"))
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    let value = std::env::var(name).ok()?;
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// ============================================================================
+// Panic Capture
+// ============================================================================
+
+thread_local! {
+    /// Set while a property runs: its panic message, once it has panicked
+    static CAPTURE: RefCell<Option<Option<String>>> = const { RefCell::new(None) };
+}
+
+/// Run the property, turning a panic into an error
+fn evaluate<T, F>(property: &F, value: &T) -> Result<(), String>
+where
+    F: Fn(&T) -> Result<(), String>,
+{
+    install_hook();
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(None));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| property(value)));
+    let captured = CAPTURE
+        .with(|capture| capture.borrow_mut().take())
+        .flatten();
+    match result {
+        Ok(result) => result,
+        Err(_) => Err(captured.unwrap_or_else(|| "panicked".to_string())),
+    }
+}
+
+/// Record panics inside a property instead of printing them, since
+/// shrinking may trigger the same panic many times
+fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let captured = CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+                Some(slot) => {
+                    let location = info
+                        .location()
+                        .map(|l| format!(" at {}:{}", l.file(), l.line()));
+                    let payload = info
+                        .payload()
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    *slot = Some(format!(
+                        "panicked{}: {payload}",
+                        location.unwrap_or_default()
+                    ));
+                    true
+                }
+                None => false,
+            });
+            if !captured {
+                previous(info);
+            }
+        }));
+    });
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passing_property() {
+        let runner = Runner::new().with_cases(100);
+        assert!(runner
+            .run(&bytes(64), |b| ensure_eq(&b.len().min(64), &b.len()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_shrinks_to_minimal_counterexample() {
+        // Fails for any input holding a byte >= 0x10: the minimal one is [0x10]
+        let failure = Runner::new()
+            .with_cases(500)
+            .run(&bytes(256), |b| match b.iter().any(|&x| x >= 0x10) {
+                true => Err("large byte".to_string()),
+                false => Ok(()),
+            })
+            .unwrap_err();
+        assert_eq!(failure.minimal, vec![0x10]);
+        assert!(failure.shrinks > 0);
+        assert_eq!(failure.message, "large byte");
+    }
+
+    #[test]
+    fn test_panics_are_failures() {
+        let failure = Runner::new()
+            .run(&bytes(32), |b| {
+                assert!(b.len() < 3, "too long");
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(failure.minimal, vec![0, 0, 0]);
+        assert!(failure.message.contains("too long"), "{}", failure.message);
+        assert!(failure.message.contains("prop.rs"), "{}", failure.message);
+    }
+
+    #[test]
+    fn test_same_seed_same_failure() {
+        let property = |m: &Message| match m.id >= 1000 {
+            true => Err(format!("id {}", m.id)),
+            false => Ok(()),
+        };
+        let a = Runner::new()
+            .with_seed(9)
+            .run(&messages(16), property)
+            .unwrap_err();
+        let b = Runner::new()
+            .with_seed(9)
+            .run(&messages(16), property)
+            .unwrap_err();
+        assert_eq!(
+            (a.case, &a.original, &a.minimal),
+            (b.case, &b.original, &b.minimal)
+        );
+        assert_eq!(a.minimal.id, 1000);
+        assert!(a.minimal.payload.is_empty());
+        assert_eq!(a.minimal.message_type, MessageType::Request);
+    }
+
+    #[test]
+    fn test_shrink_budget_is_respected() {
+        let failure = Runner::new()
+            .with_max_shrinks(0)
+            .run(&bytes(64), |_| Err("always".to_string()))
+            .unwrap_err();
+        assert_eq!(failure.shrinks, 0);
+        assert_eq!(failure.minimal, failure.original);
+    }
+
+    #[test]
+    fn test_message_shrinks_are_simpler() {
+        let strategy = messages(64);
+        let mut rng = Rng::new(3);
+        for _ in 0..50 {
+            let message = strategy.generate(&mut rng);
+            assert_eq!(message.status.is_some(), message.message_type.has_status());
+            for candidate in strategy.shrink(&message) {
+                assert_ne!(candidate, message);
+                assert_eq!(
+                    candidate.status.is_some(),
+                    candidate.message_type.has_status()
+                );
+                assert!(candidate.payload.len() <= message.payload.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_frames_reach_past_the_header() {
+        // Most generated frames keep a valid magic and version
+        let mut rng = Rng::new(5);
+        let valid = (0..200)
+            .filter(|_| {
+                frames(64)
+                    .generate(&mut rng)
+                    .starts_with(&[0x54, 0x55, VERSION])
+            })
+            .count();
+        assert!(valid > 150, "{valid}");
+    }
+
+    #[test]
+    fn test_failure_report() {
+        let failure = Failure {
+            seed: 0x2a,
+            case: 3,
+            original: vec![1u8, 2],
+            minimal: vec![1u8],
+            shrinks: 1,
+            message: "boom".to_string(),
+        };
+        let report = failure.to_string();
+        assert!(report.contains("PROTOCOL_PROP_SEED=0x2a"), "{report}");
+        assert!(report.contains("[1]"), "{report}");
+        assert!(report.ends_with("error: boom"), "{report}");
+    }
+}
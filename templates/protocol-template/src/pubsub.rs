@@ -0,0 +1,728 @@
+//! Publish/subscribe
+//!
+//! Clients subscribe to topic patterns and publish to topics; every
+//! publication is delivered as an `Event` to each subscription whose
+//! pattern matches (SPEC.md Section 4.7).
+//!
+//! Topics are dot-separated segments such as `orders.eu.created`. In a
+//! pattern, `*` matches exactly one segment and `>`, allowed only as the
+//! last segment, matches one or more:
+//!
+//! | Pattern | Matches | Does not match |
+//! |---------|---------|----------------|
+//! | `orders.eu.created` | `orders.eu.created` | `orders.eu` |
+//! | `orders.*.created` | `orders.us.created` | `orders.created` |
+//! | `orders.>` | `orders.eu`, `orders.eu.created` | `orders` |
+//!
+//! [`Broker`] routes publications in-process (it needs the `std` feature).
+//! Each [`Subscription`] has a bounded queue; a publisher never blocks on a
+//! slow subscriber. When a queue is full the [`DropPolicy`] decides which
+//! event is lost, and the next event delivered reports how many were
+//! dropped before it.
+//! [`Server`](crate::server::Server) connects its clients to a broker, so
+//! in-process and remote subscribers see the same events.
+//!
+//! ## Example
+//!
+//! ```rust
+//! # #[cfg(feature = "std")] {
+//! use protocol_name::pubsub::Broker;
+//!
+//! let broker = Broker::new();
+//! let subscription = broker.subscribe("orders.>").unwrap();
+//! assert_eq!(broker.publish("orders.eu.created", b"42").unwrap(), 1);
+//!
+//! let event = subscription.try_recv().unwrap();
+//! assert_eq!(event.topic, "orders.eu.created");
+//! assert_eq!(event.data, b"42");
+//! # }
+//! ```
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, VecDeque};
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::{Message, MessageType, ProtocolError, VERSION};
+
+/// Events each subscription buffers unless configured
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Longest topic or pattern, in bytes
+pub const MAX_TOPIC_LEN: usize = u16::MAX as usize;
+
+/// Check that `topic` is a valid publication topic: non-empty segments and
+/// no wildcards
+pub fn validate_topic(topic: &str) -> Result<(), ProtocolError> {
+    validate(topic, "topic")?;
+    if topic
+        .split('.')
+        .any(|segment| segment == "*" || segment == ">")
+    {
+        return Err(invalid(format!(
+            "wildcards are not allowed in a published topic: {:?}",
+            topic
+        )));
+    }
+    Ok(())
+}
+
+/// Check that `pattern` is a valid subscription pattern
+pub fn validate_pattern(pattern: &str) -> Result<(), ProtocolError> {
+    validate(pattern, "pattern")?;
+    let segments: Vec<_> = pattern.split('.').collect();
+    if segments[..segments.len() - 1].contains(&">") {
+        return Err(invalid(format!(
+            "'>' must be the last segment: {:?}",
+            pattern
+        )));
+    }
+    Ok(())
+}
+
+fn validate(text: &str, what: &str) -> Result<(), ProtocolError> {
+    if text.len() > MAX_TOPIC_LEN {
+        return Err(invalid(format!(
+            "{} of {} bytes exceeds {}",
+            what,
+            text.len(),
+            MAX_TOPIC_LEN
+        )));
+    }
+    if text.split('.').any(str::is_empty) {
+        return Err(invalid(format!(
+            "{} has an empty segment: {:?}",
+            what, text
+        )));
+    }
+    let wildcard_inside = text
+        .split('.')
+        .any(|segment| segment.len() > 1 && (segment.contains('*') || segment.contains('>')));
+    if wildcard_inside {
+        return Err(invalid(format!(
+            "wildcards must be whole segments: {:?}",
+            text
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the subscription `pattern`
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('.');
+    for segment in pattern.split('.') {
+        match segment {
+            ">" => return topic.next().is_some(),
+            "*" => {
+                if topic.next().is_none() {
+                    return false;
+                }
+            }
+            literal => {
+                if topic.next() != Some(literal) {
+                    return false;
+                }
+            }
+        }
+    }
+    topic.next().is_none()
+}
+
+fn invalid(message: String) -> ProtocolError {
+    ProtocolError::InvalidPayload(message)
+}
+
+/// The topic and data of a `Publish` message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publication {
+    /// Topic published to
+    pub topic: String,
+    /// Application data
+    pub data: Vec<u8>,
+}
+
+impl Publication {
+    /// Create a publication
+    pub fn new(topic: &str, data: &[u8]) -> Self {
+        Self {
+            topic: topic.to_string(),
+            data: data.to_vec(),
+        }
+    }
+
+    /// Read the publication carried by a `Publish` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        expect_type(message, MessageType::Publish)?;
+        let (topic, data) = split_topic(&message.payload)?;
+        validate_topic(&topic)?;
+        Ok(Self {
+            topic,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Build the `Publish` message with request ID `id`
+    pub fn to_message(&self, id: u32) -> Message {
+        let mut payload = Vec::with_capacity(2 + self.topic.len() + self.data.len());
+        put_topic(&mut payload, &self.topic);
+        payload.extend_from_slice(&self.data);
+        framed(MessageType::Publish, id, payload)
+    }
+}
+
+/// A publication delivered to one subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Subscription the event was delivered to. Over the wire this is the
+    /// ID of the Subscribe message.
+    pub subscription: u32,
+    /// Events dropped from the subscription's queue since the previous
+    /// event was delivered
+    pub dropped: u32,
+    /// Topic published to
+    pub topic: String,
+    /// Application data
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    /// Read the event carried by an `Event` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        expect_type(message, MessageType::Event)?;
+        let (dropped, rest) = message
+            .payload
+            .split_first_chunk::<4>()
+            .ok_or_else(|| invalid("event truncated in dropped count".to_string()))?;
+        let (topic, data) = split_topic(rest)?;
+        Ok(Self {
+            subscription: message.id,
+            dropped: u32::from_be_bytes(*dropped),
+            topic,
+            data: data.to_vec(),
+        })
+    }
+
+    /// Build the `Event` message carrying this event
+    pub fn to_message(&self) -> Message {
+        let mut payload = Vec::with_capacity(6 + self.topic.len() + self.data.len());
+        payload.extend_from_slice(&self.dropped.to_be_bytes());
+        put_topic(&mut payload, &self.topic);
+        payload.extend_from_slice(&self.data);
+        framed(MessageType::Event, self.subscription, payload)
+    }
+}
+
+fn expect_type(message: &Message, expected: MessageType) -> Result<(), ProtocolError> {
+    if message.message_type != expected {
+        return Err(invalid(format!(
+            "expected {:?}, got {:?}",
+            expected, message.message_type
+        )));
+    }
+    Ok(())
+}
+
+fn framed(message_type: MessageType, id: u32, payload: Vec<u8>) -> Message {
+    Message {
+        version: VERSION,
+        message_type,
+        id,
+        status: None,
+        options: None,
+        payload,
+    }
+}
+
+/// Append a topic with its 2-byte length; topics are validated to fit
+fn put_topic(payload: &mut Vec<u8>, topic: &str) {
+    let len = u16::try_from(topic.len()).unwrap_or(u16::MAX);
+    payload.extend_from_slice(&len.to_be_bytes());
+    payload.extend_from_slice(&topic.as_bytes()[..usize::from(len)]);
+}
+
+/// Split a length-prefixed topic from the data that follows it
+fn split_topic(bytes: &[u8]) -> Result<(String, &[u8]), ProtocolError> {
+    let (len, rest) = bytes
+        .split_first_chunk::<2>()
+        .ok_or_else(|| invalid("truncated topic length".to_string()))?;
+    let len = usize::from(u16::from_be_bytes(*len));
+    if rest.len() < len {
+        return Err(invalid(format!(
+            "topic of {} bytes, only {} remain",
+            len,
+            rest.len()
+        )));
+    }
+    let (topic, data) = rest.split_at(len);
+    let topic =
+        String::from_utf8(topic.to_vec()).map_err(|_| invalid("topic is not UTF-8".to_string()))?;
+    Ok((topic, data))
+}
+
+/// Which event a full subscription queue loses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued event to make room: subscribers see the
+    /// most recent events
+    #[default]
+    DropOldest,
+    /// Discard the event being published: subscribers see an unbroken
+    /// prefix
+    DropNewest,
+}
+
+#[cfg(feature = "std")]
+/// An in-process topic router
+///
+/// Clones share the same subscriptions. Queue settings apply to
+/// subscriptions made through the handle they were set on.
+#[derive(Clone)]
+pub struct Broker {
+    shared: Arc<Shared>,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Shared {
+    subscriptions: Mutex<BTreeMap<u32, Arc<Queue>>>,
+    next_id: AtomicU32,
+}
+
+#[cfg(feature = "std")]
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, Arc<Queue>>> {
+        self.subscriptions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("subscriptions", &self.subscriptions())
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Broker {
+    /// A broker with no subscriptions
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::default(),
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            policy: DropPolicy::default(),
+        }
+    }
+
+    /// Queue size and drop policy for new subscriptions
+    ///
+    /// A capacity of 0 is treated as 1.
+    pub fn with_queue(mut self, capacity: usize, policy: DropPolicy) -> Self {
+        self.capacity = capacity.max(1);
+        self.policy = policy;
+        self
+    }
+
+    /// Subscribe to topics matching `pattern`
+    pub fn subscribe(&self, pattern: &str) -> Result<Subscription, ProtocolError> {
+        validate_pattern(pattern)?;
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(Queue {
+            id,
+            pattern: pattern.to_string(),
+            capacity: self.capacity,
+            policy: self.policy,
+            state: Mutex::default(),
+            ready: Condvar::new(),
+        });
+        self.shared.lock().insert(id, Arc::clone(&queue));
+        Ok(Subscription {
+            queue,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    /// Deliver `data` to every subscription matching `topic`, returning how
+    /// many matched
+    ///
+    /// Never blocks on subscribers: full queues drop an event instead.
+    pub fn publish(&self, topic: &str, data: &[u8]) -> Result<usize, ProtocolError> {
+        validate_topic(topic)?;
+        let subscriptions = self.shared.lock();
+        let mut matched = 0;
+        for queue in subscriptions
+            .values()
+            .filter(|q| topic_matches(&q.pattern, topic))
+        {
+            queue.push(topic, data);
+            matched += 1;
+        }
+        Ok(matched)
+    }
+
+    /// Number of open subscriptions
+    pub fn subscriptions(&self) -> usize {
+        self.shared.lock().len()
+    }
+}
+
+#[cfg(feature = "std")]
+/// One subscriber's bounded event queue
+struct Queue {
+    id: u32,
+    pattern: String,
+    capacity: usize,
+    policy: DropPolicy,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<Publication>,
+    /// Dropped since the last event was taken
+    dropped: u32,
+    /// Dropped over the subscription's lifetime
+    dropped_total: u64,
+    closed: bool,
+}
+
+#[cfg(feature = "std")]
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, topic: &str, data: &[u8]) {
+        let mut state = self.lock();
+        if state.closed {
+            return;
+        }
+        if state.events.len() >= self.capacity {
+            state.dropped = state.dropped.saturating_add(1);
+            state.dropped_total += 1;
+            match self.policy {
+                DropPolicy::DropNewest => return,
+                DropPolicy::DropOldest => {
+                    state.events.pop_front();
+                }
+            }
+        }
+        state.events.push_back(Publication::new(topic, data));
+        self.ready.notify_one();
+    }
+
+    fn take(&self, state: &mut QueueState) -> Option<Event> {
+        let publication = state.events.pop_front()?;
+        Some(Event {
+            subscription: self.id,
+            dropped: std::mem::take(&mut state.dropped),
+            topic: publication.topic,
+            data: publication.data,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+/// A subscription made with [`Broker::subscribe`]
+///
+/// Dropping it unsubscribes. The receiving methods take `&self`, so a
+/// subscription can be shared between threads; [`close`](Self::close)
+/// wakes any thread waiting on it.
+pub struct Subscription {
+    queue: Arc<Queue>,
+    shared: Arc<Shared>,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("id", &self.queue.id)
+            .field("pattern", &self.queue.pattern)
+            .field("queued", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Subscription {
+    /// Broker-assigned ID, carried in this subscription's events
+    pub fn id(&self) -> u32 {
+        self.queue.id
+    }
+
+    /// Pattern subscribed to
+    pub fn pattern(&self) -> &str {
+        &self.queue.pattern
+    }
+
+    /// Wait for the next event; `None` once the subscription is closed
+    pub fn recv(&self) -> Option<Event> {
+        let mut state = self.queue.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(event) = self.queue.take(&mut state) {
+                return Some(event);
+            }
+            state = self
+                .queue
+                .ready
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(event) = self.queue.take(&mut state) {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self
+                .queue
+                .ready
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Take the next event if one is queued
+    pub fn try_recv(&self) -> Option<Event> {
+        let mut state = self.queue.lock();
+        if state.closed {
+            return None;
+        }
+        self.queue.take(&mut state)
+    }
+
+    /// Events queued and not yet received
+    pub fn len(&self) -> usize {
+        self.queue.lock().events.len()
+    }
+
+    /// Whether no events are queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Events lost to the drop policy over the subscription's lifetime
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().dropped_total
+    }
+
+    /// Whether the subscription has been closed
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().closed
+    }
+
+    /// Unsubscribe, discarding queued events and waking waiting receivers
+    pub fn close(&self) {
+        self.shared.lock().remove(&self.queue.id);
+        let mut state = self.queue.lock();
+        state.closed = true;
+        state.events.clear();
+        self.queue.ready.notify_all();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use std::thread;
+
+    #[test]
+    fn test_topic_matching() {
+        let cases = [
+            ("orders.eu.created", "orders.eu.created", true),
+            ("orders.eu.created", "orders.eu", false),
+            ("orders.*.created", "orders.us.created", true),
+            ("orders.*.created", "orders.created", false),
+            ("orders.*", "orders.eu.created", false),
+            ("orders.>", "orders.eu", true),
+            ("orders.>", "orders.eu.created", true),
+            ("orders.>", "orders", false),
+            ("*.>", "a.b.c", true),
+            (">", "anything.at.all", true),
+        ];
+        for (pattern, topic, expected) in cases {
+            assert_eq!(
+                topic_matches(pattern, topic),
+                expected,
+                "{} vs {}",
+                pattern,
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(validate_pattern("orders.*.>").is_ok());
+        assert!(validate_topic("orders.eu").is_ok());
+        for bad in [
+            "",
+            "orders.",
+            ".orders",
+            "orders..eu",
+            "orders.>.eu",
+            "orders.e*",
+        ] {
+            assert!(validate_pattern(bad).is_err(), "{:?}", bad);
+        }
+        assert!(validate_topic("orders.*").is_err());
+        assert!(validate_topic("orders.>").is_err());
+    }
+
+    #[test]
+    fn test_publication_and_event_round_trip() {
+        let publication = Publication::new("orders.eu", b"data");
+        let message = publication.to_message(7);
+        assert_eq!(
+            (message.message_type, message.id),
+            (MessageType::Publish, 7)
+        );
+        assert_eq!(&message.payload[..2], &[0, 9]);
+        assert_eq!(Publication::from_message(&message), Ok(publication));
+
+        let event = Event {
+            subscription: 3,
+            dropped: 2,
+            topic: "orders.eu".to_string(),
+            data: b"data".to_vec(),
+        };
+        let message = event.to_message();
+        assert_eq!((message.message_type, message.id), (MessageType::Event, 3));
+        assert_eq!(Event::from_message(&message), Ok(event));
+
+        let mut truncated = message.clone();
+        truncated.payload.truncate(7);
+        assert!(matches!(
+            Event::from_message(&truncated),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_broker_routes_by_pattern() {
+        let broker = Broker::new();
+        let all = broker.subscribe(">").unwrap();
+        let eu = broker.subscribe("orders.eu.*").unwrap();
+
+        assert_eq!(broker.publish("orders.eu.created", b"1").unwrap(), 2);
+        assert_eq!(broker.publish("orders.us.created", b"2").unwrap(), 1);
+        assert!(broker.publish("orders.*", b"").is_err());
+
+        assert_eq!(all.try_recv().unwrap().data, b"1");
+        assert_eq!(all.try_recv().unwrap().data, b"2");
+        assert_eq!(eu.try_recv().unwrap().topic, "orders.eu.created");
+        assert_eq!(eu.try_recv(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_drop_oldest_keeps_recent_events() {
+        let broker = Broker::new().with_queue(2, DropPolicy::DropOldest);
+        let subscription = broker.subscribe("t").unwrap();
+        for i in 0..5u8 {
+            broker.publish("t", &[i]).unwrap();
+        }
+        let first = subscription.try_recv().unwrap();
+        assert_eq!((first.data, first.dropped), (vec![3], 3));
+        let second = subscription.try_recv().unwrap();
+        assert_eq!((second.data, second.dropped), (vec![4], 0));
+        assert_eq!(subscription.dropped(), 3);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_drop_newest_keeps_first_events() {
+        let broker = Broker::new().with_queue(2, DropPolicy::DropNewest);
+        let subscription = broker.subscribe("t").unwrap();
+        for i in 0..5u8 {
+            broker.publish("t", &[i]).unwrap();
+        }
+        let first = subscription.try_recv().unwrap();
+        assert_eq!((first.data, first.dropped), (vec![0], 3));
+        assert_eq!(subscription.try_recv().unwrap().data, vec![1]);
+        assert_eq!(subscription.try_recv(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_close_unsubscribes_and_wakes_receivers() {
+        let broker = Broker::new();
+        let subscription = Arc::new(broker.subscribe("t").unwrap());
+        let waiter = {
+            let subscription = Arc::clone(&subscription);
+            thread::spawn(move || subscription.recv())
+        };
+        thread::sleep(Duration::from_millis(10));
+        subscription.close();
+        assert_eq!(waiter.join().unwrap(), None);
+        assert_eq!(broker.subscriptions(), 0);
+        assert_eq!(broker.publish("t", b"").unwrap(), 0);
+
+        drop(broker.subscribe("t").unwrap());
+        assert_eq!(broker.subscriptions(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_recv_timeout() {
+        let broker = Broker::new();
+        let subscription = broker.subscribe("t").unwrap();
+        assert_eq!(subscription.recv_timeout(Duration::from_millis(5)), None);
+        broker.publish("t", b"x").unwrap();
+        assert!(subscription
+            .recv_timeout(Duration::from_millis(5))
+            .is_some());
+    }
+}
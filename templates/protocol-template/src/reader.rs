@@ -0,0 +1,246 @@
+//! Buffered frame reading with resynchronization
+//!
+//! [`read_message`](crate::read_message) reads exactly one frame and leaves
+//! the stream wherever decoding stopped, so after corruption the only safe
+//! recovery is to drop the connection. That is fine for TCP, but serial lines
+//! and pipes have no connection to drop.
+//!
+//! [`FrameReader`] buffers the stream so it can look ahead. With resync
+//! enabled it recovers from corruption by scanning forward for the next
+//! `MAGIC` followed by a plausible header (supported version, known type,
+//! payload length within limits), discarding the bytes in between and
+//! reporting how many were skipped (SPEC.md Section 4.4).
+//!
+//! ## Example
+//!
+//! ```rust
+//! use protocol_name::reader::FrameReader;
+//! use protocol_name::{encode, Message};
+//!
+//! let mut stream = b"line noise".to_vec();
+//! stream.extend(encode(&Message::request(1, b"hello")).unwrap());
+//!
+//! let mut reader = FrameReader::new(stream.as_slice()).with_resync(true);
+//! let message = reader.read_message().unwrap();
+//! assert_eq!(message.payload, b"hello");
+//! assert_eq!(reader.last_skipped(), 10);
+//! ```
+
+use std::io::{self, Read};
+
+use crate::{decode_prefix, Field, Message, ProtocolError, MAGIC};
+
+/// Bytes requested from the underlying reader per fill
+const READ_CHUNK: usize = 8 * 1024;
+
+/// A buffered message reader that can resynchronize after corruption
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    resync: bool,
+    last_skipped: usize,
+    total_skipped: u64,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Wrap a reader. Resync is disabled by default.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            resync: false,
+            last_skipped: 0,
+            total_skipped: 0,
+        }
+    }
+
+    /// Enable or disable automatic resynchronization
+    ///
+    /// When enabled, [`read_message`](Self::read_message) skips over
+    /// corrupt data instead of returning a decode error. I/O errors and
+    /// end of stream are still returned.
+    pub fn with_resync(mut self, enabled: bool) -> Self {
+        self.resync = enabled;
+        self
+    }
+
+    /// Read the next message
+    ///
+    /// Without resync, a corrupt header is returned as an error and left in
+    /// the buffer; call [`resync`](Self::resync) to skip past it.
+    pub fn read_message(&mut self) -> Result<Message, ProtocolError> {
+        self.last_skipped = 0;
+        loop {
+            match decode_prefix(self.buffered()) {
+                Ok((message, consumed)) => {
+                    self.consume(consumed);
+                    return Ok(message);
+                }
+                Err(e @ ProtocolError::IncompleteMessage { .. }) => {
+                    if self.fill()? == 0 {
+                        return Err(self.eof_error(e));
+                    }
+                }
+                Err(_) if self.resync => {
+                    self.skip(1);
+                    self.scan_to_magic()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Skip forward to the next plausible frame header, returning the number
+    /// of bytes discarded
+    ///
+    /// Returns 0 if the buffer already starts at a plausible header.
+    pub fn resync(&mut self) -> Result<usize, ProtocolError> {
+        self.last_skipped = 0;
+        loop {
+            self.scan_to_magic()?;
+            match decode_prefix(self.buffered()) {
+                Ok(_) => return Ok(self.last_skipped),
+                // The whole header is buffered and valid; only payload is missing
+                Err(ProtocolError::IncompleteMessage {
+                    field: Field::Payload,
+                    ..
+                }) => return Ok(self.last_skipped),
+                Err(e @ ProtocolError::IncompleteMessage { .. }) => {
+                    if self.fill()? == 0 {
+                        return Err(self.eof_error(e));
+                    }
+                }
+                Err(_) => self.skip(1),
+            }
+        }
+    }
+
+    /// Bytes skipped while reading the most recent message (or by the most
+    /// recent [`resync`](Self::resync) call)
+    pub fn last_skipped(&self) -> usize {
+        self.last_skipped
+    }
+
+    /// Bytes skipped over the lifetime of this reader
+    pub fn total_skipped(&self) -> u64 {
+        self.total_skipped
+    }
+
+    /// Bytes read from the underlying reader but not yet decoded
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Get a reference to the underlying reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying reader
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Unwrap the underlying reader, discarding any buffered bytes
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Discard bytes until the buffer starts with `MAGIC`, reading more as
+    /// needed. A trailing partial match is kept.
+    fn scan_to_magic(&mut self) -> Result<(), ProtocolError> {
+        loop {
+            let data = self.buffered();
+            if let Some(at) = data.windows(MAGIC.len()).position(|w| w == MAGIC) {
+                self.skip(at);
+                return Ok(());
+            }
+            // Keep a final byte that could be the start of MAGIC
+            let keep = usize::from(data.last() == Some(&MAGIC[0]));
+            self.skip(data.len() - keep);
+            if self.fill()? == 0 {
+                return Err(ProtocolError::closed());
+            }
+        }
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.consume(n);
+        self.last_skipped += n;
+        self.total_skipped += n as u64;
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+        }
+    }
+
+    fn fill(&mut self) -> Result<usize, ProtocolError> {
+        if self.pos > 0 && self.pos >= self.buf.len() / 2 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        let start = self.buf.len();
+        self.buf.resize(start + READ_CHUNK, 0);
+        let result = loop {
+            match self.inner.read(&mut self.buf[start..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                other => break other,
+            }
+        };
+        let n = *result.as_ref().unwrap_or(&0);
+        self.buf.truncate(start + n);
+        Ok(result?)
+    }
+
+    /// Error for end of stream: clean if nothing is buffered, otherwise the
+    /// truncation reported by the decoder
+    fn eof_error(&self, truncated: ProtocolError) -> ProtocolError {
+        if self.buffered().is_empty() {
+            ProtocolError::closed()
+        } else {
+            truncated
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode;
+
+    #[test]
+    fn test_reads_back_to_back_frames() {
+        let mut stream = encode(&Message::request(1, b"a")).unwrap();
+        stream.extend(encode(&Message::response(1, 0, b"b")).unwrap());
+
+        let mut reader = FrameReader::new(stream.as_slice());
+        assert_eq!(reader.read_message().unwrap().id, 1);
+        assert_eq!(reader.read_message().unwrap().payload, b"b");
+        assert!(reader.read_message().unwrap_err().is_clean_eof());
+    }
+
+    #[test]
+    fn test_without_resync_reports_error() {
+        let mut stream = b"xx".to_vec();
+        stream.extend(encode(&Message::request(2, b"")).unwrap());
+
+        let mut reader = FrameReader::new(stream.as_slice());
+        assert!(matches!(
+            reader.read_message(),
+            Err(ProtocolError::InvalidMagic { .. })
+        ));
+        assert_eq!(reader.resync().unwrap(), 2);
+        assert_eq!(reader.read_message().unwrap().id, 2);
+    }
+}
@@ -0,0 +1,89 @@
+//! Deterministic pseudo-random numbers
+//!
+//! A small seeded generator (SplitMix64) for fault injection and tests. The
+//! same seed always produces the same sequence, on every platform, so a
+//! failing run can be reproduced from its seed alone. Not suitable for
+//! anything security-related.
+
+/// Seeded SplitMix64 generator
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from a seed
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Next 64 random bits
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..n`; returns 0 when `n` is 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        // Multiply-shift: bias is negligible for the ranges used here
+        ((u128::from(self.next_u64()) * u128::from(n)) >> 64) as u64
+    }
+
+    /// Uniform value in `low..=high`
+    pub fn range(&mut self, low: usize, high: usize) -> usize {
+        debug_assert!(low <= high);
+        low + self.below((high - low) as u64 + 1) as usize
+    }
+
+    /// True with probability `p` (clamped to 0.0..=1.0)
+    pub fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        // 53 random bits give a uniform f64 in [0, 1)
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// Fill a buffer with random bytes
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_sequence() {
+        // Reference values for SplitMix64 seeded with 0
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut rng = Rng::new(42);
+        for _ in 0..1000 {
+            assert!(rng.below(7) < 7);
+            assert!((3..=5).contains(&rng.range(3, 5)));
+        }
+        assert_eq!(rng.below(0), 0);
+        assert!(!rng.chance(0.0));
+        assert!(rng.chance(1.0));
+    }
+}
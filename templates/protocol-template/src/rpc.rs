@@ -0,0 +1,287 @@
+//! Method calls
+//!
+//! A method call is a `Request` whose payload starts with the method name
+//! as a typed `String`, followed by the typed arguments (SPEC.md Section
+//! 4.8). The response payload is the typed result.
+//!
+//! [`Router`] dispatches calls to functions registered by method name,
+//! decoding their arguments and encoding their results with the payload
+//! traits ([`Encode`], [`Decode`]). It is a server
+//! [`Handler`](crate::server::Handler), and [`Router::handle`] answers a
+//! request directly where there is no server. Calls to unregistered methods
+//! are answered with `UnknownMethod`, and the reserved method
+//! [`LIST_METHODS`] returns the names registered.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use protocol_name::rpc::{MethodCall, Router};
+//!
+//! let router = Router::new()
+//!     .route("add", |(a, b): (u32, u32)| Ok(a + b))
+//!     .route("greet", |name: String| Ok(format!("hello, {}", name)));
+//!
+//! let response = router.handle(MethodCall::new("add", &(2u32, 3u32)).to_message(1));
+//! assert_eq!(response.payload_as::<u32>().unwrap(), 5);
+//! ```
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "std")]
+use crate::server::Handler;
+use crate::{Decode, Encode, ErrorCode, Message, MessageType, ProtocolError};
+
+/// Reserved method answering with the registered method names, sorted, as
+/// a typed `Vec<String>`. It takes no arguments.
+pub const LIST_METHODS: &str = "rpc.methods";
+
+/// Prefix of method names reserved by the protocol
+pub const RESERVED_PREFIX: &str = "rpc.";
+
+/// A method name and its encoded arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodCall {
+    /// Method to call
+    pub method: String,
+    /// Typed encoding of the arguments
+    pub args: Vec<u8>,
+}
+
+impl MethodCall {
+    /// A call to `method` with typed arguments
+    pub fn new<A: Encode + ?Sized>(method: &str, args: &A) -> Self {
+        Self {
+            method: method.to_string(),
+            args: args.to_payload(),
+        }
+    }
+
+    /// Read the call carried by a `Request` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        if message.message_type != MessageType::Request {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "expected Request, got {:?}",
+                message.message_type
+            )));
+        }
+        let mut input = message.payload.as_slice();
+        let method = String::decode_payload(&mut input)?;
+        Ok(Self {
+            method,
+            args: input.to_vec(),
+        })
+    }
+
+    /// Build the `Request` message carrying this call
+    pub fn to_message(&self, id: u32) -> Message {
+        let mut payload = self.method.to_payload();
+        payload.extend_from_slice(&self.args);
+        Message::request(id, &payload)
+    }
+
+    /// Decode the arguments as a typed value
+    pub fn args_as<A: Decode>(&self) -> Result<A, ProtocolError> {
+        A::from_payload(&self.args)
+    }
+}
+
+/// Decodes the arguments, runs the method and encodes its result
+type Method = Box<dyn Fn(&[u8]) -> Result<Vec<u8>, ProtocolError> + Send + Sync>;
+
+/// Dispatches method calls to handlers registered by name
+#[derive(Default)]
+pub struct Router {
+    methods: BTreeMap<String, Method>,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("methods", &self.methods.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Router {
+    /// A router with no methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` for `method`
+    ///
+    /// Arguments that fail to decode as `A` are answered with
+    /// `InvalidFormat`; an error returned by the handler is answered with
+    /// its [`error_code`](ProtocolError::error_code) and text, or as given
+    /// by [`method_error`].
+    ///
+    /// # Panics
+    ///
+    /// If `method` is empty, reserved (starts with [`RESERVED_PREFIX`]) or
+    /// already registered.
+    pub fn route<A, R, F>(mut self, method: &str, handler: F) -> Self
+    where
+        A: Decode,
+        R: Encode,
+        F: Fn(A) -> Result<R, ProtocolError> + Send + Sync + 'static,
+    {
+        assert!(!method.is_empty(), "method name must not be empty");
+        assert!(
+            !method.starts_with(RESERVED_PREFIX),
+            "method names starting with {:?} are reserved",
+            RESERVED_PREFIX
+        );
+        assert!(
+            !self.methods.contains_key(method),
+            "method {:?} registered twice",
+            method
+        );
+        let method_fn: Method =
+            Box::new(move |args| Ok(handler(A::from_payload(args)?)?.to_payload()));
+        self.methods.insert(method.to_string(), method_fn);
+        self
+    }
+
+    /// Registered method names, sorted
+    pub fn methods(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(String::as_str)
+    }
+
+    /// Run one call, returning the encoded result
+    pub fn dispatch(&self, call: &MethodCall) -> Result<Vec<u8>, ProtocolError> {
+        if call.method == LIST_METHODS {
+            call.args_as::<()>()?;
+            return Ok(self.methods().collect::<Vec<_>>().to_payload());
+        }
+        match self.methods.get(&call.method) {
+            Some(method) => method(&call.args),
+            None => Err(ProtocolError::UnknownMethod(call.method.clone())),
+        }
+    }
+
+    /// Answer a `Request` carrying a method call with its `Response`, or
+    /// with an `Error` message if the call fails
+    ///
+    /// The router answers the same way as a server
+    /// [`Handler`](crate::server::Handler); this lets firmware without a
+    /// server dispatch the frames it reads itself.
+    pub fn handle(&self, request: Message) -> Message {
+        let id = request.id;
+        match MethodCall::from_message(&request).and_then(|call| self.dispatch(&call)) {
+            Ok(result) => Message::response(id, 0, &result),
+            Err(ProtocolError::Rejected { code, message, .. }) => {
+                Message::error(id, code, &message)
+            }
+            Err(e) => Message::error(id, e.error_code() as u8, &e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Handler for Router {
+    fn handle(&self, request: Message) -> Message {
+        Router::handle(self, request)
+    }
+}
+
+/// An error answering a call with `code` and `message` as given
+///
+/// Other errors returned by a handler are answered with their own code and
+/// text.
+pub fn method_error(code: ErrorCode, message: &str) -> ProtocolError {
+    ProtocolError::Rejected {
+        id: 0,
+        code: code as u8,
+        message: message.to_string(),
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn router() -> Router {
+        Router::new()
+            .route("add", |(a, b): (u32, u32)| Ok(a + b))
+            .route("div", |(a, b): (u32, u32)| {
+                a.checked_div(b)
+                    .ok_or_else(|| ProtocolError::InvalidPayload("division by zero".to_string()))
+            })
+    }
+
+    fn call<A: Encode + ?Sized>(method: &str, args: &A) -> Message {
+        router().handle(MethodCall::new(method, args).to_message(7))
+    }
+
+    #[test]
+    fn test_method_call_round_trip() {
+        let call = MethodCall::new("add", &(1u32, 300u32));
+        let message = call.to_message(3);
+        assert_eq!(message.payload, [0x03, b'a', b'd', b'd', 0x01, 0xAC, 0x02]);
+        assert_eq!(MethodCall::from_message(&message), Ok(call.clone()));
+        assert_eq!(call.args_as::<(u32, u32)>(), Ok((1, 300)));
+    }
+
+    #[test]
+    fn test_dispatches_by_name() {
+        let response = call("add", &(2u32, 3u32));
+        assert_eq!(response.message_type, MessageType::Response);
+        assert_eq!(response.id, 7);
+        assert_eq!(response.payload_as::<u32>(), Ok(5));
+    }
+
+    #[test]
+    fn test_unknown_method() {
+        let response = call("sub", &(2u32, 3u32));
+        assert_eq!(response.message_type, MessageType::Error);
+        assert_eq!(response.status, Some(ErrorCode::UnknownMethod as u8));
+        assert_eq!(response.payload, b"unknown method \"sub\"");
+    }
+
+    #[test]
+    fn test_bad_arguments_and_handler_errors() {
+        for response in [call("add", &"two"), call("div", &(1u32, 0u32))] {
+            assert_eq!(response.message_type, MessageType::Error);
+            assert_eq!(response.status, Some(ErrorCode::InvalidFormat as u8));
+        }
+
+        // Not a method call at all: the name does not decode
+        let response = router().handle(Message::request(1, &[0x05, b'a']));
+        assert_eq!(response.status, Some(ErrorCode::InvalidFormat as u8));
+    }
+
+    #[test]
+    fn test_handler_chooses_error_code() {
+        let router = Router::new().route("busy", |()| -> Result<(), _> {
+            Err(method_error(ErrorCode::FlowControl, "try again"))
+        });
+        let response = router.handle(MethodCall::new("busy", &()).to_message(1));
+        assert_eq!(response.status, Some(ErrorCode::FlowControl as u8));
+        assert_eq!(response.payload, b"try again");
+    }
+
+    #[test]
+    fn test_lists_methods() {
+        let response = call(LIST_METHODS, &());
+        assert_eq!(
+            response.payload_as::<Vec<String>>(),
+            Ok(vec!["add".to_string(), "div".to_string()])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved")]
+    fn test_reserved_names_cannot_be_registered() {
+        let _ = Router::new().route(LIST_METHODS, |()| Ok(()));
+    }
+}
@@ -0,0 +1,1605 @@
+//! Request/response server
+//!
+//! A [`Handler`] turns each request into a response. [`Server`] runs a
+//! handler over any [`Listener`] (one thread per connection), over a single
+//! [`Duplex`] transport, or over the process's own stdin/stdout.
+//!
+//! Requests are handled concurrently on a pool of worker threads shared by
+//! every connection ([`with_workers`](Server::with_workers)), but responses
+//! are written in request order (SPEC.md Section 4.2): a response that
+//! finishes early waits in a reorder buffer until every earlier response
+//! has been sent. The buffer holds at most
+//! [`with_reorder_limit`](Server::with_reorder_limit) responses; once it is
+//! full the server stops reading requests until the oldest one finishes.
+//!
+//! Requests waiting for a worker are started highest priority first, and
+//! in arrival order among equals (SPEC.md Section 4.11). A request whose
+//! budget runs out before it starts is answered with `DeadlineExceeded`
+//! instead of being handled; while it is handled, its deadline is the
+//! [`current`](crate::deadline::current) one, so a [`Client`] the handler
+//! uses passes the remaining budget on to the next service.
+//!
+//! A `Batch` is handled item by item on one thread and answered with a
+//! single `BatchResponse` (SPEC.md Section 4.6); a failing item does not
+//! affect the others.
+//!
+//! `Subscribe`, `Unsubscribe` and `Publish` are served from the server's
+//! [`Broker`] (SPEC.md Section 4.7), shared by every connection and by
+//! in-process subscribers. Each subscription forwards its events on its own
+//! thread; a connection's subscriptions end when it closes.
+//!
+//! A request reusing the ID of one still in flight is answered with a
+//! `DuplicateId` error. Frames that fail to decode are answered with an
+//! error message carrying the matching [`ErrorCode`] and ID 0, after which
+//! the connection is closed: the start of the next frame is unknown.
+//! Non-request messages are answered with `InvalidFormat` and the
+//! connection stays open.
+//!
+//! Each connection opens with a `WindowUpdate` granting the server's
+//! flow-control window (SPEC.md Section 4.5). A client that overruns it is
+//! sent a `FlowControl` error and disconnected.
+//!
+//! A server configured [`with_sessions`](Server::with_sessions) lets a
+//! connection's first request be a `Resume` that opens or resumes a
+//! session (SPEC.md Section 4.10). Requests on that connection are then
+//! recorded in the session, and a replayed request is answered with the
+//! response it already got instead of being handled again.
+//!
+//! Compressed requests (SPEC.md Section 4.9) are decompressed as they are
+//! read. Replies are compressed only if configured with
+//! [`with_compression`](Server::with_compression), and only on connections
+//! whose client has itself sent a compressed frame.
+//!
+//! A server configured [`with_psk`](Server::with_psk) runs the
+//! [`SecureStream`] handshake on each connection's own thread before
+//! reading any frame (SPEC.md Section 5.3); a failed handshake closes the
+//! connection.
+//!
+//! A server configured [`with_websocket`](Server::with_websocket) expects
+//! each connection to open with a WebSocket upgrade and carries frames in
+//! binary WebSocket messages ([`WebSocketStream`]), so browsers can connect
+//! directly. With a PSK as well, the encrypted handshake runs inside the
+//! WebSocket.
+//!
+//! [`Client`]: crate::client::Client
+//!
+//! ## Example
+//!
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//!
+//! use protocol_name::server::Server;
+//! use protocol_name::Message;
+//!
+//! let server = Server::new(|request: Message| Message::response(request.id, 0, &request.payload));
+//! server.serve(TcpListener::bind("127.0.0.1:9000").unwrap()).unwrap();
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+use crate::batch::{Batch, BatchResponse};
+use crate::compress::Compression;
+use crate::deadline::{self, RequestOptions};
+use crate::flow::{ReceiveWindow, Window};
+use crate::observe::Observer;
+use crate::pubsub::{Broker, Publication, Subscription};
+use crate::session::{Begin, Resume, Session, Sessions};
+use crate::transport::{check_psk, Duplex, Listener, SecureStream, Stdio, WebSocketStream};
+use crate::{
+    read_frame, write_message, ErrorCode, Message, MessageType, ProtocolError, MAX_PAYLOAD_SIZE,
+};
+
+/// Responses buffered per connection unless configured
+pub const DEFAULT_REORDER_LIMIT: usize = 31;
+
+/// Requests handled at once, across all connections, unless configured
+pub const DEFAULT_WORKERS: usize = 64;
+
+/// Produces a response for each request
+///
+/// Requests on the same connection may be handled concurrently.
+pub trait Handler: Send + Sync + 'static {
+    /// Handle one request. The returned message should be a response or
+    /// error carrying the request's ID.
+    fn handle(&self, request: Message) -> Message;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Message) -> Message + Send + Sync + 'static,
+{
+    fn handle(&self, request: Message) -> Message {
+        self(request)
+    }
+}
+
+/// A server running one handler for every connection
+pub struct Server<H> {
+    handler: Arc<H>,
+    observer: Option<Arc<dyn Observer>>,
+    window: Window,
+    reorder_limit: usize,
+    broker: Broker,
+    compression: Option<Compression>,
+    psk: Option<Arc<[u8]>>,
+    websocket: bool,
+    sessions: Option<Sessions>,
+    workers: Arc<Workers>,
+}
+
+impl<H> Clone for Server<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: Arc::clone(&self.handler),
+            observer: self.observer.clone(),
+            window: self.window,
+            reorder_limit: self.reorder_limit,
+            broker: self.broker.clone(),
+            compression: self.compression,
+            psk: self.psk.clone(),
+            websocket: self.websocket,
+            sessions: self.sessions.clone(),
+            workers: Arc::clone(&self.workers),
+        }
+    }
+}
+
+impl<H> fmt::Debug for Server<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("observed", &self.observer.is_some())
+            .field("window", &self.window)
+            .field("reorder_limit", &self.reorder_limit)
+            .field("broker", &self.broker)
+            .field("compression", &self.compression)
+            .field("encrypted", &self.psk.is_some())
+            .field("websocket", &self.websocket)
+            .field("sessions", &self.sessions)
+            .field("workers", &self.workers.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: Handler> Server<H> {
+    /// Create a server around a handler
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            observer: None,
+            window: Window::DEFAULT,
+            reorder_limit: DEFAULT_REORDER_LIMIT,
+            broker: Broker::new(),
+            compression: None,
+            psk: None,
+            websocket: false,
+            sessions: None,
+            workers: Arc::new(Workers::new(DEFAULT_WORKERS)),
+        }
+    }
+
+    /// Flow-control window granted to each client
+    pub fn with_window(mut self, window: Window) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Maximum number of finished responses held back per connection while
+    /// an earlier request is still being handled
+    ///
+    /// Up to `limit + 1` requests per connection are handled or wait for a
+    /// worker at once; 0 handles each connection's requests one at a time.
+    pub fn with_reorder_limit(mut self, limit: usize) -> Self {
+        self.reorder_limit = limit;
+        self
+    }
+
+    /// Maximum number of requests handled at once across all connections
+    /// (at least 1)
+    ///
+    /// Further requests wait, and the most urgent start first when a
+    /// worker is free. Clones made afterwards share the workers.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = Arc::new(Workers::new(workers.max(1)));
+        self
+    }
+
+    /// Serve publish/subscribe messages from `broker` instead of a broker
+    /// private to this server
+    pub fn with_broker(mut self, broker: Broker) -> Self {
+        self.broker = broker;
+        self
+    }
+
+    /// Broker serving this server's publish/subscribe messages
+    pub fn broker(&self) -> &Broker {
+        &self.broker
+    }
+
+    /// Let clients open and resume sessions kept in `sessions`
+    ///
+    /// Without sessions, a `Resume` is answered with `UnknownSession`.
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Compress outgoing payloads as `compression` decides
+    ///
+    /// A connection's replies stay uncompressed until its client sends a
+    /// compressed frame, so clients that cannot inflate still work.
+    /// Compressed requests are accepted either way; flow control counts
+    /// payloads at their uncompressed size.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Require every connection to complete a [`SecureStream`] handshake
+    /// with `psk`, and seal every frame in both directions
+    ///
+    /// Fails with [`ProtocolError::HandshakeFailed`] if `psk` is shorter
+    /// than [`MIN_PSK_LEN`](crate::transport::MIN_PSK_LEN).
+    pub fn with_psk(mut self, psk: &[u8]) -> Result<Self, ProtocolError> {
+        check_psk(psk)?;
+        self.psk = Some(psk.into());
+        Ok(self)
+    }
+
+    /// Expect every connection to open with a [`WebSocketStream`]
+    /// handshake, and carry frames in binary WebSocket messages
+    pub fn with_websocket(mut self, enabled: bool) -> Self {
+        self.websocket = enabled;
+        self
+    }
+
+    /// Report connections, frames, decode errors and handler latency to
+    /// `observer`
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Accept connections forever, serving each on its own thread
+    ///
+    /// Returns only if accepting fails. Errors on individual connections
+    /// end that connection without affecting the others.
+    pub fn serve<L: Listener>(&self, listener: L) -> io::Result<()> {
+        loop {
+            let transport = listener.accept_transport()?;
+            let server = self.clone();
+            thread::spawn(move || server.serve_connection(transport));
+        }
+    }
+
+    /// Serve requests over this process's stdin and stdout until stdin closes
+    pub fn serve_stdio(&self) -> Result<(), ProtocolError> {
+        self.serve_connection(Stdio::new())
+    }
+
+    /// Serve requests on one transport until the peer closes it
+    ///
+    /// A clean close between messages returns `Ok` once every outstanding
+    /// request has been answered. Decode and I/O errors are returned after
+    /// the peer has been notified where possible.
+    pub fn serve_connection<T: Duplex>(&self, transport: T) -> Result<(), ProtocolError> {
+        self.observe(|o| o.connection_opened());
+        let result = if self.websocket {
+            WebSocketStream::accept(transport).and_then(|websocket| self.secure_and_run(websocket))
+        } else {
+            self.secure_and_run(transport)
+        };
+        self.observe(|o| o.connection_closed(result.as_ref().err()));
+        result
+    }
+
+    fn secure_and_run<T: Duplex>(&self, transport: T) -> Result<(), ProtocolError> {
+        match &self.psk {
+            Some(psk) => {
+                SecureStream::accept(transport, psk).and_then(|secure| self.split_and_run(secure))
+            }
+            None => self.split_and_run(transport),
+        }
+    }
+
+    fn split_and_run<T: Duplex>(&self, transport: T) -> Result<(), ProtocolError> {
+        let (reader, writer) = transport.split()?;
+        self.run(reader, writer)
+    }
+
+    fn run<R: io::Read, W: Write + Send + 'static>(
+        &self,
+        mut reader: R,
+        writer: W,
+    ) -> Result<(), ProtocolError> {
+        let connection = Arc::new(Connection::new(
+            writer,
+            self.window,
+            self.observer.clone(),
+            self.compression,
+        ));
+        connection.grant()?;
+        let mut subscriptions = Subscriptions::default();
+        // Set by a `Resume` as the first request
+        let mut session: Option<Arc<Session>> = None;
+        loop {
+            let message = read_frame(&mut reader);
+            let received = Instant::now();
+            let message = match message {
+                Ok((message, compressed)) => {
+                    if compressed {
+                        connection
+                            .peer_compresses
+                            .store(true, atomic::Ordering::Relaxed);
+                    }
+                    message
+                }
+                Err(e) if e.is_clean_eof() => return connection.drain(),
+                Err(e @ ProtocolError::Io { .. }) => return Err(e),
+                Err(e) => {
+                    self.observe(|o| o.decode_error(&e));
+                    // Best effort: the peer may already be gone
+                    if let Ok(mut state) = connection.reserve(self.reorder_limit) {
+                        let seq = state.sequence();
+                        connection.finish(state, seq, Reply::new(rejection(0, &e)));
+                        let _ = connection.drain();
+                    }
+                    return Err(e);
+                }
+            };
+            self.observe(|o| o.frame_received(&message, message.encoded_len()));
+
+            let mut state = connection.reserve(self.reorder_limit)?;
+            match message.message_type {
+                MessageType::Request | MessageType::Batch => {
+                    let (id, payload_len) = (message.id, message.payload.len());
+                    if let Err(e) = state.window.admit(payload_len) {
+                        let seq = state.sequence();
+                        connection.finish(state, seq, Reply::new(rejection(id, &e)));
+                        let _ = connection.drain();
+                        return Err(e);
+                    }
+                    let seq = state.sequence();
+                    let job = match Job::new(message) {
+                        Ok(job) => job,
+                        Err(e) => {
+                            connection.finish(
+                                state,
+                                seq,
+                                Reply::new(rejection(id, &e)).returning(payload_len),
+                            );
+                            continue;
+                        }
+                    };
+                    if state.in_flight.contains(&id) {
+                        let e = ProtocolError::DuplicateId(id);
+                        connection.finish(
+                            state,
+                            seq,
+                            Reply::new(rejection(id, &e)).returning(payload_len),
+                        );
+                        continue;
+                    }
+                    let job = job.claim_ids(&mut state.in_flight);
+                    let deadline = job.options().deadline(received);
+                    let begun = session
+                        .as_ref()
+                        .map(|session| (Arc::clone(session), session.begin(job.id())));
+                    let answer = match &begun {
+                        Some((_, Begin::Cached(response))) => Some(response.clone()),
+                        Some((_, Begin::Running)) => None,
+                        // Already out of time: not worth a place in the queue
+                        Some((_, Begin::Run(_))) | None if job.options().is_expired() => {
+                            let expired = rejection(id, &ProtocolError::DeadlineExceeded(id));
+                            if let Some((session, Begin::Run(ticket))) = &begun {
+                                session.complete(id, *ticket, &expired);
+                            }
+                            Some(expired)
+                        }
+                        _ => None,
+                    };
+                    if let Some(answer) = answer {
+                        let reply = Reply::new(answer)
+                            .returning(payload_len)
+                            .releasing(job.claimed());
+                        connection.finish(state, seq, reply);
+                        continue;
+                    }
+                    drop(state);
+                    self.dispatch(&connection, seq, job, payload_len, begun, deadline);
+                }
+                MessageType::Resume => {
+                    let (id, payload_len) = (message.id, message.payload.len());
+                    if let Err(e) = state.window.admit(payload_len) {
+                        let seq = state.sequence();
+                        connection.finish(state, seq, Reply::new(rejection(id, &e)));
+                        let _ = connection.drain();
+                        return Err(e);
+                    }
+                    let seq = state.sequence();
+                    let reply = if seq == 0 {
+                        match self.resume(&message) {
+                            Ok(resumed) => {
+                                let reply = Message::response(id, 0, &resumed.id().0);
+                                session = Some(resumed);
+                                reply
+                            }
+                            Err(e) => rejection(id, &e),
+                        }
+                    } else {
+                        Message::error(
+                            id,
+                            ErrorCode::InvalidFormat as u8,
+                            "a session can only be resumed by a connection's first request",
+                        )
+                    };
+                    connection.finish(state, seq, Reply::new(reply).returning(payload_len));
+                }
+                MessageType::Subscribe | MessageType::Unsubscribe | MessageType::Publish => {
+                    let (id, payload_len) = (message.id, message.payload.len());
+                    if let Err(e) = state.window.admit(payload_len) {
+                        let seq = state.sequence();
+                        connection.finish(state, seq, Reply::new(rejection(id, &e)));
+                        let _ = connection.drain();
+                        return Err(e);
+                    }
+                    let seq = state.sequence();
+                    if !state.in_flight.insert(id) {
+                        let e = ProtocolError::DuplicateId(id);
+                        connection.finish(
+                            state,
+                            seq,
+                            Reply::new(rejection(id, &e)).returning(payload_len),
+                        );
+                        continue;
+                    }
+                    drop(state);
+                    let reply = self.pubsub(&connection, &mut subscriptions, message);
+                    let reply = Reply::new(reply).returning(payload_len).releasing(vec![id]);
+                    connection.finish(connection.lock(), seq, reply);
+                }
+                // Clients do not receive requests, so they grant no credit
+                MessageType::WindowUpdate => {}
+                other => {
+                    let seq = state.sequence();
+                    let error = Message::error(
+                        message.id,
+                        ErrorCode::InvalidFormat as u8,
+                        &format!("expected a request, got {:?}", other),
+                    );
+                    connection.finish(state, seq, Reply::new(error));
+                }
+            }
+        }
+    }
+
+    /// Queue one request or batch for a worker, queueing the reply at
+    /// `seq`
+    ///
+    /// In a session, the reply is recorded in it, or taken from it if the
+    /// request is a replay still being handled; waiting for that takes a
+    /// thread of its own rather than a worker.
+    fn dispatch<W: Write + Send + 'static>(
+        &self,
+        connection: &Arc<Connection<W>>,
+        seq: u64,
+        job: Job,
+        payload_len: usize,
+        begun: Option<(Arc<Session>, Begin)>,
+        deadline: Option<Instant>,
+    ) {
+        let server = self.clone();
+        let connection = Arc::clone(connection);
+        let (id, release, priority) = (job.id(), job.claimed(), job.options().priority());
+        let finish = move |message| {
+            let state = connection.lock();
+            connection.finish(
+                state,
+                seq,
+                Reply::new(message)
+                    .returning(payload_len)
+                    .releasing(release),
+            );
+        };
+        match begun {
+            Some((session, Begin::Run(ticket))) => self.workers.submit(priority, move || {
+                let message = server.execute(job, deadline);
+                session.complete(id, ticket, &message);
+                finish(message);
+            }),
+            Some((session, _)) => {
+                thread::spawn(move || finish(session.wait(id)));
+            }
+            None => self
+                .workers
+                .submit(priority, move || finish(server.execute(job, deadline))),
+        }
+    }
+
+    /// Answer one request or batch, unless its deadline has passed
+    fn execute(&self, job: Job, deadline: Option<Instant>) -> Message {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            let id = job.id();
+            return rejection(id, &ProtocolError::DeadlineExceeded(id));
+        }
+        deadline::with_deadline(deadline, || match job {
+            Job::Request(request) => self.handle(request),
+            Job::Batch { id, items } => {
+                let results = items
+                    .into_iter()
+                    .map(|item| match item {
+                        Ok(request) => self.handle(request),
+                        Err(rejected) => rejected,
+                    })
+                    .collect();
+                batch_reply(BatchResponse::new(id, results))
+            }
+        })
+    }
+
+    /// Open or resume the session a `Resume` names
+    fn resume(&self, message: &Message) -> Result<Arc<Session>, ProtocolError> {
+        let resume = Resume::from_message(message)?;
+        match &self.sessions {
+            Some(sessions) => sessions.resume(&resume),
+            None => Err(ProtocolError::UnknownSession(resume.session)),
+        }
+    }
+
+    /// Answer a publish/subscribe message
+    ///
+    /// Runs on the reader thread: none of them waits on anything but the
+    /// broker's locks.
+    fn pubsub<W: Write + Send + 'static>(
+        &self,
+        connection: &Arc<Connection<W>>,
+        subscriptions: &mut Subscriptions,
+        message: Message,
+    ) -> Message {
+        let id = message.id;
+        let started = Instant::now();
+        let reply = match message.message_type {
+            MessageType::Subscribe if subscriptions.0.contains_key(&id) => Message::error(
+                id,
+                ErrorCode::DuplicateId as u8,
+                &format!("subscription {} is already active", id),
+            ),
+            MessageType::Subscribe => {
+                let subscribed = std::str::from_utf8(&message.payload)
+                    .map_err(|_| ProtocolError::InvalidPayload("pattern is not UTF-8".to_string()))
+                    .and_then(|pattern| self.broker.subscribe(pattern));
+                match subscribed {
+                    Ok(subscription) => {
+                        let subscription = Arc::new(subscription);
+                        subscriptions.0.insert(id, Arc::clone(&subscription));
+                        spawn_forwarder(connection, id, subscription);
+                        Message::response(id, 0, &[])
+                    }
+                    Err(e) => rejection(id, &e),
+                }
+            }
+            MessageType::Unsubscribe => {
+                let subscription =
+                    <[u8; 4]>::try_from(message.payload.as_slice()).map(u32::from_be_bytes);
+                match subscription.map(|sub| (sub, subscriptions.0.remove(&sub))) {
+                    // Closed before the response is queued, so no event
+                    // for it can follow the response
+                    Ok((_, Some(subscription))) => {
+                        subscription.close();
+                        Message::response(id, 0, &[])
+                    }
+                    Ok((sub, None)) => Message::error(
+                        id,
+                        ErrorCode::InvalidFormat as u8,
+                        &format!("no active subscription {}", sub),
+                    ),
+                    Err(_) => Message::error(
+                        id,
+                        ErrorCode::InvalidFormat as u8,
+                        &format!(
+                            "unsubscribe payload must be 4 bytes, got {}",
+                            message.payload.len()
+                        ),
+                    ),
+                }
+            }
+            _ => match Publication::from_message(&message)
+                .and_then(|publication| self.broker.publish(&publication.topic, &publication.data))
+            {
+                Ok(matched) => Message::response(id, 0, &(matched as u32).to_be_bytes()),
+                Err(e) => rejection(id, &e),
+            },
+        };
+        self.observe(|o| o.request_completed(&reply, started.elapsed()));
+        reply
+    }
+
+    /// Run the handler on one request
+    fn handle(&self, request: Message) -> Message {
+        let id = request.id;
+        let started = Instant::now();
+        // A panicking handler must still fill its slot, or every later
+        // response on the connection would wait forever
+        let response = panic::catch_unwind(AssertUnwindSafe(|| self.handler.handle(request)))
+            .unwrap_or_else(|_| {
+                Message::error(id, ErrorCode::InvalidFormat as u8, "handler panicked")
+            });
+        self.observe(|o| o.request_completed(&response, started.elapsed()));
+        response
+    }
+
+    fn observe(&self, event: impl FnOnce(&dyn Observer)) {
+        if let Some(observer) = &self.observer {
+            event(observer.as_ref());
+        }
+    }
+}
+
+/// An error reply to the request `id`
+fn rejection(id: u32, error: &ProtocolError) -> Message {
+    Message::error(id, error.error_code() as u8, &error.to_string())
+}
+
+/// Encode a batch's results, or reject the batch if they do not fit in one
+/// frame
+fn batch_reply(response: BatchResponse) -> Message {
+    let len = response.payload_len();
+    if len > MAX_PAYLOAD_SIZE {
+        return Message::error(
+            response.id,
+            ErrorCode::PayloadTooLarge as u8,
+            &format!(
+                "batch results of {} bytes exceed the maximum of {}",
+                len, MAX_PAYLOAD_SIZE
+            ),
+        );
+    }
+    response
+        .to_message()
+        .unwrap_or_else(|e| rejection(response.id, &e))
+}
+
+/// Forward a subscription's events to the connection until either closes
+fn spawn_forwarder<W: Write + Send + 'static>(
+    connection: &Arc<Connection<W>>,
+    wire_id: u32,
+    subscription: Arc<Subscription>,
+) {
+    let connection = Arc::clone(connection);
+    thread::spawn(move || {
+        while let Some(mut event) = subscription.recv() {
+            event.subscription = wire_id;
+            if connection
+                .send_event(&subscription, &event.to_message())
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
+
+/// A connection's subscriptions, keyed by the ID of their Subscribe
+/// message. Dropping it closes them all, stopping their forwarders.
+#[derive(Default)]
+struct Subscriptions(HashMap<u32, Arc<Subscription>>);
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for subscription in self.0.values() {
+            subscription.close();
+        }
+    }
+}
+
+/// Work read from the connection
+enum Job {
+    Request(Message),
+    /// A batch's requests, or the rejection for each request whose ID was
+    /// already in flight
+    Batch {
+        id: u32,
+        items: Vec<Result<Message, Message>>,
+    },
+}
+
+impl Job {
+    fn new(message: Message) -> Result<Self, ProtocolError> {
+        if message.message_type != MessageType::Batch {
+            return Ok(Job::Request(message));
+        }
+        let batch = Batch::from_message(&message)?;
+        Ok(Job::Batch {
+            id: batch.id,
+            items: batch.requests.into_iter().map(Ok).collect(),
+        })
+    }
+
+    /// Deadline and priority; a batch carries none
+    fn options(&self) -> RequestOptions {
+        match self {
+            Job::Request(request) => request.options.unwrap_or_default(),
+            Job::Batch { .. } => RequestOptions::new(),
+        }
+    }
+
+    /// ID of the request or batch
+    fn id(&self) -> u32 {
+        match self {
+            Job::Request(request) => request.id,
+            Job::Batch { id, .. } => *id,
+        }
+    }
+
+    /// IDs marked in flight by [`claim_ids`](Self::claim_ids), freed when
+    /// the job is answered
+    fn claimed(&self) -> Vec<u32> {
+        match self {
+            Job::Request(request) => vec![request.id],
+            Job::Batch { id, items } => {
+                let requests = items
+                    .iter()
+                    .filter_map(|item| item.as_ref().ok().map(|request| request.id));
+                Some(*id).into_iter().chain(requests).collect()
+            }
+        }
+    }
+
+    /// Mark the job's IDs in flight, rejecting batch items whose ID already
+    /// is. The job's own ID must not be in flight.
+    fn claim_ids(self, in_flight: &mut HashSet<u32>) -> Self {
+        match self {
+            Job::Request(request) => {
+                in_flight.insert(request.id);
+                Job::Request(request)
+            }
+            Job::Batch { id, items } => {
+                in_flight.insert(id);
+                let items = items
+                    .into_iter()
+                    .map(|item| {
+                        item.and_then(|request| {
+                            if in_flight.insert(request.id) {
+                                Ok(request)
+                            } else {
+                                Err(rejection(
+                                    request.id,
+                                    &ProtocolError::DuplicateId(request.id),
+                                ))
+                            }
+                        })
+                    })
+                    .collect();
+                Job::Batch { id, items }
+            }
+        }
+    }
+}
+
+/// Worker threads shared by every connection of a server, and the work
+/// waiting for them
+///
+/// Workers are started as work arrives, up to the limit, and stop once
+/// nothing is waiting.
+struct Workers {
+    limit: usize,
+    state: Mutex<WorkQueue>,
+}
+
+#[derive(Default)]
+struct WorkQueue {
+    waiting: BinaryHeap<Work>,
+    /// Workers started and not yet stopped
+    running: usize,
+    /// Arrival order of the next work queued
+    next: u64,
+}
+
+/// A task waiting for a worker
+struct Work {
+    priority: u8,
+    arrival: u64,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+impl Workers {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(WorkQueue::default()),
+        }
+    }
+
+    /// Queue `task`, starting a worker if the limit allows
+    fn submit(self: &Arc<Self>, priority: u8, task: impl FnOnce() + Send + 'static) {
+        let mut state = self.lock();
+        let arrival = state.next;
+        state.next += 1;
+        state.waiting.push(Work {
+            priority,
+            arrival,
+            task: Box::new(task),
+        });
+        if state.running < self.limit {
+            state.running += 1;
+            let workers = Arc::clone(self);
+            thread::spawn(move || workers.work());
+        }
+    }
+
+    /// Run waiting tasks, most urgent first, until none is left
+    fn work(&self) {
+        loop {
+            let work = {
+                let mut state = self.lock();
+                match state.waiting.pop() {
+                    Some(work) => work,
+                    None => {
+                        state.running -= 1;
+                        return;
+                    }
+                }
+            };
+            (work.task)();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, WorkQueue> {
+        // Tasks run outside the lock, so it cannot be poisoned mid-update
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Ord for Work {
+    /// Higher priority first, then earlier arrival
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.arrival.cmp(&self.arrival))
+    }
+}
+
+impl PartialOrd for Work {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Work {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Work {}
+
+/// A message waiting in the reorder buffer
+#[derive(Debug)]
+struct Reply {
+    message: Message,
+    /// Payload length of the request answered, whose credit is returned
+    /// when the reply is sent
+    credit: Option<usize>,
+    /// In-flight IDs freed when the reply is sent
+    release: Vec<u32>,
+}
+
+impl Reply {
+    fn new(message: Message) -> Self {
+        Self {
+            message,
+            credit: None,
+            release: Vec::new(),
+        }
+    }
+
+    fn returning(mut self, payload_len: usize) -> Self {
+        self.credit = Some(payload_len);
+        self
+    }
+
+    fn releasing(mut self, ids: Vec<u32>) -> Self {
+        self.release = ids;
+        self
+    }
+}
+
+/// Write side of one connection, shared by the reader and handler threads
+struct Connection<W> {
+    state: Mutex<ConnectionState<W>>,
+    /// Signalled whenever a reply is written
+    written: Condvar,
+    observer: Option<Arc<dyn Observer>>,
+    compression: Option<Compression>,
+    /// Set once the client sends a compressed frame; until then replies
+    /// are not compressed
+    peer_compresses: AtomicBool,
+}
+
+struct ConnectionState<W> {
+    writer: W,
+    window: ReceiveWindow,
+    /// IDs of requests whose response has not been sent
+    in_flight: HashSet<u32>,
+    /// Replies finished ahead of an earlier one, keyed by sequence number
+    reorder: BTreeMap<u64, Reply>,
+    /// Sequence number of the next reply to write
+    next_write: u64,
+    /// Sequence number for the next frame read
+    next_seq: u64,
+    /// The first write error; later replies are discarded
+    error: Option<ProtocolError>,
+}
+
+impl<W> ConnectionState<W> {
+    /// Allocate the position of the next reply in the output
+    fn sequence(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Replies allocated but not yet written
+    fn pending(&self) -> u64 {
+        self.next_seq - self.next_write
+    }
+}
+
+impl<W: Write> Connection<W> {
+    fn new(
+        writer: W,
+        window: Window,
+        observer: Option<Arc<dyn Observer>>,
+        compression: Option<Compression>,
+    ) -> Self {
+        Self {
+            state: Mutex::new(ConnectionState {
+                writer,
+                window: ReceiveWindow::new(window),
+                in_flight: HashSet::new(),
+                reorder: BTreeMap::new(),
+                next_write: 0,
+                next_seq: 0,
+                error: None,
+            }),
+            written: Condvar::new(),
+            observer,
+            compression,
+            peer_compresses: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ConnectionState<W>> {
+        // Replies are written whole under the lock, so a panic cannot leave
+        // the state half-updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send the initial window grant
+    fn grant(&self) -> Result<(), ProtocolError> {
+        let mut state = self.lock();
+        let grant = state.window.initial_grant();
+        self.send(&mut state.writer, &grant)
+    }
+
+    /// Wait for room in the reorder buffer
+    ///
+    /// Fails with the write error if the connection can no longer be
+    /// written to.
+    fn reserve(&self, limit: usize) -> Result<MutexGuard<'_, ConnectionState<W>>, ProtocolError> {
+        let mut state = self.lock();
+        loop {
+            if let Some(e) = &state.error {
+                return Err(e.clone());
+            }
+            if state.pending() <= limit as u64 {
+                return Ok(state);
+            }
+            state = self.written.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Wait until every reply has been written
+    fn drain(&self) -> Result<(), ProtocolError> {
+        let mut state = self.lock();
+        while state.error.is_none() && state.pending() > 0 {
+            state = self.written.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.error.clone().map_or(Ok(()), Err)
+    }
+
+    /// Queue the reply at `seq` and write every reply now in order
+    ///
+    /// Credit returned by a reply is granted in a `WindowUpdate` sent just
+    /// before it.
+    fn finish(&self, mut state: MutexGuard<'_, ConnectionState<W>>, seq: u64, reply: Reply) {
+        state.reorder.insert(seq, reply);
+        let state = &mut *state;
+        while let Some(reply) = state.reorder.remove(&state.next_write) {
+            state.next_write += 1;
+            for id in &reply.release {
+                state.in_flight.remove(id);
+            }
+            if state.error.is_some() {
+                continue;
+            }
+            if let Some(payload_len) = reply.credit {
+                state.window.complete(payload_len);
+            }
+            let update = state.window.take_update();
+            let result = update
+                .iter()
+                .chain(Some(&reply.message))
+                .try_for_each(|message| self.send(&mut state.writer, message));
+            if let Err(e) = result {
+                state.error = Some(e);
+            }
+        }
+        self.written.notify_all();
+    }
+
+    /// Write an event outside the reply order
+    ///
+    /// Checked under the lock, so an event is never written once its
+    /// subscription has been closed. Fails if the connection can no longer
+    /// be written to.
+    fn send_event(
+        &self,
+        subscription: &Subscription,
+        event: &Message,
+    ) -> Result<(), ProtocolError> {
+        let mut state = self.lock();
+        if let Some(e) = &state.error {
+            return Err(e.clone());
+        }
+        if subscription.is_closed() {
+            return Ok(());
+        }
+        if let Err(e) = self.send(&mut state.writer, event) {
+            state.error = Some(e.clone());
+            self.written.notify_all();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn send(&self, writer: &mut W, message: &Message) -> Result<(), ProtocolError> {
+        match &self.compression {
+            Some(compression) if self.peer_compresses.load(atomic::Ordering::Relaxed) => {
+                compression.write_message(writer, message)?
+            }
+            _ => write_message(writer, message)?,
+        }
+        writer.flush()?;
+        if let Some(observer) = &self.observer {
+            observer.frame_sent(message, message.encoded_len());
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionId;
+    use crate::{decode_all, encode};
+    use std::time::Duration;
+
+    /// Feeds fixed input and collects the server's output
+    struct Session {
+        input: io::Cursor<Vec<u8>>,
+        output: Output,
+    }
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for Session {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl io::Write for Session {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Duplex for Session {
+        type Reader = io::Cursor<Vec<u8>>;
+        type Writer = Output;
+
+        fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+            Ok((self.input, self.output))
+        }
+    }
+
+    fn echo(m: Message) -> Message {
+        Message::response(m.id, 0, &m.payload)
+    }
+
+    fn run(input: Vec<u8>) -> (Result<(), ProtocolError>, Vec<Message>) {
+        let (result, replies) = run_with(Server::new(echo), input);
+        let replies = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        (result, replies)
+    }
+
+    fn run_with<H: Handler>(
+        server: Server<H>,
+        input: Vec<u8>,
+    ) -> (Result<(), ProtocolError>, Vec<Message>) {
+        let output = Output::default();
+        let session = Session {
+            input: io::Cursor::new(input),
+            output: output.clone(),
+        };
+        let result = server.serve_connection(session);
+        let output = output.0.lock().unwrap();
+        let replies = decode_all(&output).map(Result::unwrap).collect();
+        (result, replies)
+    }
+
+    #[test]
+    fn test_answers_each_request() {
+        let mut input = encode(&Message::request(1, b"a")).unwrap();
+        input.extend(encode(&Message::request(2, b"b")).unwrap());
+
+        let (result, replies) = run(input);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            replies,
+            vec![Message::response(1, 0, b"a"), Message::response(2, 0, b"b")]
+        );
+    }
+
+    #[test]
+    fn test_rejects_non_request() {
+        let (result, replies) = run(encode(&Message::response(4, 0, b"")).unwrap());
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies[0].message_type, MessageType::Error);
+        assert_eq!(replies[0].id, 4);
+        assert_eq!(replies[0].status, Some(ErrorCode::InvalidFormat as u8));
+    }
+
+    #[test]
+    fn test_reports_decode_error_and_closes() {
+        let mut input = encode(&Message::request(1, b"")).unwrap();
+        input[3] = 0x42;
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+
+        let (result, replies) = run(input);
+        assert!(matches!(result, Err(ProtocolError::UnknownType { .. })));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].id, 0);
+        assert_eq!(replies[0].status, Some(ErrorCode::UnknownType as u8));
+    }
+
+    #[test]
+    fn test_responses_leave_in_request_order() {
+        // Later requests finish first
+        let server = Server::new(|m: Message| {
+            thread::sleep(Duration::from_millis(u64::from(8 - m.id) * 5));
+            echo(m)
+        });
+        let mut input = Vec::new();
+        for id in 1..=8 {
+            input.extend(encode(&Message::request(id, &[id as u8])).unwrap());
+        }
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let ids: Vec<_> = replies
+            .iter()
+            .filter(|m| m.message_type == MessageType::Response)
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rejects_duplicate_in_flight_id() {
+        let server = Server::new(|m: Message| {
+            thread::sleep(Duration::from_millis(50));
+            echo(m)
+        });
+        let mut input = encode(&Message::request(1, b"first")).unwrap();
+        input.extend(encode(&Message::request(1, b"again")).unwrap());
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        assert_eq!(replies[0], Message::response(1, 0, b"first"));
+        assert_eq!(
+            (replies[1].message_type, replies[1].id),
+            (MessageType::Error, 1)
+        );
+        assert_eq!(replies[1].status, Some(ErrorCode::DuplicateId as u8));
+    }
+
+    #[test]
+    fn test_answers_batch_with_per_item_results() {
+        let server = Server::new(|m: Message| match m.payload.as_slice() {
+            b"fail" => Message::error(m.id, ErrorCode::InvalidFormat as u8, "failed"),
+            _ => echo(m),
+        });
+        let mut batch = Batch::new(1);
+        batch.push(2, b"a");
+        batch.push(3, b"fail");
+        batch.push(2, b"again");
+        batch.push(4, b"");
+
+        let (result, replies) = run_with(server, encode(&batch.to_message()).unwrap());
+        assert_eq!(result, Ok(()));
+        let reply = replies
+            .iter()
+            .find(|m| m.message_type == MessageType::BatchResponse)
+            .unwrap();
+        let response = BatchResponse::from_message(reply).unwrap();
+        assert_eq!(response.id, 1);
+        let results: Vec<_> = response
+            .results
+            .iter()
+            .map(|m| (m.message_type, m.id, m.status))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                (MessageType::Response, 2, Some(0)),
+                (MessageType::Error, 3, Some(ErrorCode::InvalidFormat as u8)),
+                (MessageType::Error, 2, Some(ErrorCode::DuplicateId as u8)),
+                (MessageType::Response, 4, Some(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed_batch() {
+        let mut message = Batch::new(1).to_message();
+        message.payload.push(0);
+        let mut input = encode(&message).unwrap();
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+
+        let (result, replies) = run(input);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            (replies[0].message_type, replies[0].id),
+            (MessageType::Error, 1)
+        );
+        assert_eq!(replies[0].status, Some(ErrorCode::InvalidFormat as u8));
+        assert_eq!(replies[1], Message::response(2, 0, b""));
+    }
+
+    #[test]
+    fn test_grants_window_and_returns_credit() {
+        let mut input = Vec::new();
+        for id in 1..=4 {
+            input.extend(encode(&Message::request(id, b"abc")).unwrap());
+        }
+
+        // Handling one request at a time makes the update schedule exact
+        let server = Server::new(echo)
+            .with_window(Window::new(4, 1 << 30))
+            .with_reorder_limit(0);
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let kinds: Vec<_> = replies.iter().map(|m| (m.message_type, m.id)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (MessageType::WindowUpdate, 0),
+                (MessageType::Response, 1),
+                (MessageType::WindowUpdate, 0),
+                (MessageType::Response, 2),
+                (MessageType::Response, 3),
+                (MessageType::WindowUpdate, 0),
+                (MessageType::Response, 4),
+            ]
+        );
+        assert_eq!(
+            Window::from_message(&replies[0]),
+            Ok(Window::new(4, 1 << 30))
+        );
+        assert_eq!(Window::from_message(&replies[2]), Ok(Window::new(2, 6)));
+    }
+
+    #[test]
+    fn test_rejects_window_overrun() {
+        // Credit for the first request is returned before the second is
+        // read, but no amount of credit covers a payload larger than the window
+        let mut input = encode(&Message::request(1, b"12345")).unwrap();
+        input.extend(encode(&Message::request(2, b"123456")).unwrap());
+
+        let server = Server::new(echo)
+            .with_window(Window::new(8, 5))
+            .with_reorder_limit(0);
+        let (result, replies) = run_with(server, input);
+        assert!(matches!(result, Err(ProtocolError::WindowExhausted { .. })));
+        let last = replies.last().unwrap();
+        assert_eq!((last.message_type, last.id), (MessageType::Error, 2));
+        assert_eq!(last.status, Some(ErrorCode::FlowControl as u8));
+    }
+
+    #[test]
+    fn test_publish_reaches_in_process_subscribers() {
+        let broker = Broker::new();
+        let subscription = broker.subscribe("orders.*").unwrap();
+        let mut input = encode(&Publication::new("orders.eu", b"42").to_message(1)).unwrap();
+        input.extend(encode(&Publication::new("billing.eu", b"7").to_message(2)).unwrap());
+        let (result, replies) = run_with(Server::new(echo).with_broker(broker), input);
+        assert_eq!(result, Ok(()));
+
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                Message::response(1, 0, &1u32.to_be_bytes()),
+                Message::response(2, 0, &0u32.to_be_bytes())
+            ]
+        );
+        let event = subscription.try_recv().unwrap();
+        assert_eq!(
+            (event.topic.as_str(), event.data.as_slice()),
+            ("orders.eu", &b"42"[..])
+        );
+        assert!(subscription.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_invalid_subscriptions() {
+        let mut input = Vec::new();
+        for message in [
+            Message::subscribe(1, "orders.>.eu"),
+            Message::unsubscribe(2, 9),
+            Message::request(3, b"still open"),
+        ] {
+            input.extend(encode(&message).unwrap());
+        }
+        let server = Server::new(echo);
+        let broker = server.broker().clone();
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0].message_type, MessageType::Error);
+        assert_eq!(replies[1].message_type, MessageType::Error);
+        assert_eq!(replies[2], Message::response(3, 0, b"still open"));
+        assert_eq!(broker.subscriptions(), 0);
+    }
+
+    #[test]
+    fn test_subscriptions_end_with_the_connection() {
+        let server = Server::new(echo);
+        let broker = server.broker().clone();
+        let (result, replies) =
+            run_with(server, encode(&Message::subscribe(1, "orders.>")).unwrap());
+        assert_eq!(result, Ok(()));
+        assert!(replies.contains(&Message::response(1, 0, b"")));
+        assert_eq!(broker.subscriptions(), 0);
+    }
+
+    #[test]
+    fn test_handler_panic_is_answered() {
+        let server = Server::new(|m: Message| {
+            if m.id == 1 {
+                panic!("boom");
+            }
+            echo(m)
+        });
+        let mut input = encode(&Message::request(1, b"")).unwrap();
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let kinds: Vec<_> = replies
+            .iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .map(|m| (m.message_type, m.id))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(MessageType::Error, 1), (MessageType::Response, 2)]
+        );
+    }
+
+    fn resume_reply(replies: &[Message]) -> SessionId {
+        let reply = &replies[0];
+        assert_eq!(reply.message_type, MessageType::Response, "{:?}", reply);
+        SessionId::from_payload(&reply.payload).unwrap()
+    }
+
+    #[test]
+    fn test_replays_are_answered_once() {
+        let handled = Arc::new(Mutex::new(0));
+        let count = Arc::clone(&handled);
+        let sessions = Sessions::new();
+        let server = Server::new(move |m: Message| {
+            *count.lock().unwrap() += 1;
+            Message::response(m.id, 0, &[*count.lock().unwrap()])
+        })
+        .with_sessions(sessions.clone());
+
+        let mut input = encode(&Resume::open().to_message(1)).unwrap();
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+        let (result, replies) = run_with(server.clone(), input);
+        assert_eq!(result, Ok(()));
+        let session = resume_reply(&replies[1..]);
+        assert_eq!(replies[2], Message::response(2, 0, &[1]));
+
+        // Request 2 is replayed, request 3 is new
+        let mut input = encode(&Resume::new(session, vec![2]).to_message(3)).unwrap();
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+        input.extend(encode(&Message::request(4, b"")).unwrap());
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        assert_eq!(resume_reply(&replies), session);
+        assert_eq!(
+            replies[1..],
+            [Message::response(2, 0, &[1]), Message::response(4, 0, &[2])]
+        );
+        assert_eq!(*handled.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_resume_must_come_first() {
+        let server = Server::new(echo).with_sessions(Sessions::new());
+        let mut input = encode(&Message::request(1, b"")).unwrap();
+        input.extend(encode(&Resume::open().to_message(2)).unwrap());
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let error = replies.iter().find(|m| m.id == 2).unwrap();
+        assert_eq!(error.status, Some(ErrorCode::InvalidFormat as u8));
+    }
+
+    #[test]
+    fn test_resume_without_sessions() {
+        let (result, replies) = run(encode(&Resume::open().to_message(1)).unwrap());
+        assert_eq!(result, Ok(()));
+        assert_eq!(replies[0].message_type, MessageType::Error);
+        assert_eq!(replies[0].status, Some(ErrorCode::UnknownSession as u8));
+    }
+
+    fn with_options(id: u32, options: RequestOptions) -> Vec<u8> {
+        encode(&Message::request(id, &[id as u8]).with_options(options)).unwrap()
+    }
+
+    #[test]
+    fn test_expired_requests_are_not_handled() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&handled);
+        let server = Server::new(move |m: Message| {
+            seen.lock().unwrap().push(m.id);
+            if m.id == 1 {
+                thread::sleep(Duration::from_millis(50));
+            }
+            echo(m)
+        })
+        .with_workers(1);
+        let mut input = with_options(1, RequestOptions::new().with_budget_ms(60_000));
+        // Spent before it arrived, and spent while waiting for the worker
+        input.extend(with_options(2, RequestOptions::new().with_budget_ms(0)));
+        input.extend(with_options(3, RequestOptions::new().with_budget_ms(10)));
+        input.extend(with_options(4, RequestOptions::new()));
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let replies: Vec<_> = replies
+            .into_iter()
+            .filter(|m| m.message_type != MessageType::WindowUpdate)
+            .collect();
+        assert_eq!(
+            replies.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+        for expired in &replies[1..3] {
+            assert_eq!(expired.message_type, MessageType::Error);
+            assert_eq!(expired.status, Some(ErrorCode::DeadlineExceeded as u8));
+        }
+        assert!(replies[0].is_success() && replies[3].is_success());
+        assert_eq!(*handled.lock().unwrap(), vec![1, 4]);
+    }
+
+    #[test]
+    fn test_waiting_requests_start_by_priority() {
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&started);
+        let server = Server::new(move |m: Message| {
+            seen.lock().unwrap().push(m.id);
+            if m.id == 1 {
+                thread::sleep(Duration::from_millis(50));
+            }
+            echo(m)
+        })
+        .with_workers(1);
+        let mut input = encode(&Message::request(1, b"")).unwrap();
+        input.extend(with_options(2, RequestOptions::new().with_priority(10)));
+        input.extend(with_options(3, RequestOptions::new().with_priority(200)));
+        input.extend(encode(&Message::request(4, b"")).unwrap());
+        input.extend(with_options(5, RequestOptions::new().with_priority(200)));
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        // Request 1 may or may not have started before the rest arrived
+        let started: Vec<_> = started
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .filter(|&id| id != 1)
+            .collect();
+        assert_eq!(started, vec![3, 5, 4, 2]);
+        // Responses still leave in request order
+        let ids: Vec<_> = replies
+            .iter()
+            .filter(|m| m.is_success())
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_handlers_run_under_the_request_deadline() {
+        let server = Server::new(|m: Message| {
+            let remaining = deadline::remaining().map_or(u32::MAX, |left| left.as_millis() as u32);
+            Message::response(m.id, 0, &remaining.to_be_bytes())
+        });
+        let mut input = with_options(1, RequestOptions::new().with_budget_ms(5_000));
+        input.extend(encode(&Message::request(2, b"")).unwrap());
+
+        let (result, replies) = run_with(server, input);
+        assert_eq!(result, Ok(()));
+        let remaining: Vec<_> = replies
+            .iter()
+            .filter(|m| m.is_success())
+            .map(|m| u32::from_be_bytes(m.payload[..].try_into().unwrap()))
+            .collect();
+        assert!(
+            remaining[0] <= 5_000 && remaining[0] > 4_000,
+            "{:?}",
+            remaining
+        );
+        assert_eq!(remaining[1], u32::MAX);
+    }
+}
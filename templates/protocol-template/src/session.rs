@@ -0,0 +1,579 @@
+//! Resumable sessions
+//!
+//! A session outlives the connections that carry it (SPEC.md Section
+//! 4.10). The client opens one with a `Resume` naming no session and gets
+//! back a [`SessionId`]. When the connection drops, the client connects
+//! again, sends a `Resume` naming the session and listing the IDs of the
+//! requests it never got an answer to, and sends those requests again.
+//!
+//! The server remembers its most recent responses in each session.
+//! A replayed request that was already handled is answered from that
+//! cache, one still running is answered when it finishes, and one that
+//! never arrived is handled now. Either way, each request runs at most
+//! once.
+//!
+//! [`Resume`] encodes and decodes the handshake. [`Sessions`] is the
+//! server's table of sessions (it needs the `std` feature); pass it to
+//! [`Server::with_sessions`](crate::server::Server::with_sessions).
+
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::{Message, MessageType, ProtocolError, VERSION};
+
+/// Length of a session ID, in bytes
+pub const SESSION_ID_LEN: usize = 16;
+
+/// Sessions a [`Sessions`] table keeps unless configured
+pub const DEFAULT_SESSION_LIMIT: usize = 256;
+
+/// Responses each session remembers unless configured
+///
+/// Twice the default flow-control window's request credit: every request a
+/// client can have unanswered is still remembered.
+pub const DEFAULT_CACHE_SIZE: usize = 64;
+
+/// Identifies a session across connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(pub [u8; SESSION_ID_LEN]);
+
+impl SessionId {
+    /// The all-zero ID a `Resume` carries to open a new session
+    pub const NEW: SessionId = SessionId([0; SESSION_ID_LEN]);
+
+    /// Whether this is [`NEW`](Self::NEW) rather than an existing session
+    pub fn is_new(&self) -> bool {
+        *self == Self::NEW
+    }
+
+    /// Read the session ID answering a `Resume`
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtocolError> {
+        <[u8; SESSION_ID_LEN]>::try_from(payload)
+            .map(SessionId)
+            .map_err(|_| {
+                ProtocolError::InvalidPayload(format!(
+                    "session ID of {} bytes, expected {}",
+                    payload.len(),
+                    SESSION_ID_LEN
+                ))
+            })
+    }
+
+    /// A fresh ID that is not [`NEW`](Self::NEW)
+    #[cfg(feature = "std")]
+    fn generate() -> Self {
+        loop {
+            let random = crate::transport::session_random();
+            let mut id = [0; SESSION_ID_LEN];
+            id.copy_from_slice(&random[..SESSION_ID_LEN]);
+            if id != Self::NEW.0 {
+                return SessionId(id);
+            }
+        }
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// The session and replayed request IDs of a `Resume` message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resume {
+    /// Session to resume, or [`SessionId::NEW`] to open one
+    pub session: SessionId,
+    /// IDs of the requests the client will send again, in the order it
+    /// sent them
+    pub replay: Vec<u32>,
+}
+
+impl Resume {
+    /// A `Resume` opening a new session
+    pub fn open() -> Self {
+        Self::new(SessionId::NEW, Vec::new())
+    }
+
+    /// A `Resume` of `session`, announcing the requests in `replay`
+    pub fn new(session: SessionId, replay: Vec<u32>) -> Self {
+        Self { session, replay }
+    }
+
+    /// Read the handshake carried by a `Resume` message
+    pub fn from_message(message: &Message) -> Result<Self, ProtocolError> {
+        if message.message_type != MessageType::Resume {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "expected Resume, got {:?}",
+                message.message_type
+            )));
+        }
+        let (session, replay) = message
+            .payload
+            .split_first_chunk::<SESSION_ID_LEN>()
+            .ok_or_else(|| {
+                ProtocolError::InvalidPayload("resume truncated in session ID".to_string())
+            })?;
+        if replay.len() % 4 != 0 {
+            return Err(ProtocolError::InvalidPayload(format!(
+                "resume replay list of {} bytes is not a multiple of 4",
+                replay.len()
+            )));
+        }
+        Ok(Self {
+            session: SessionId(*session),
+            replay: replay
+                .chunks_exact(4)
+                .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+                .collect(),
+        })
+    }
+
+    /// Build the `Resume` message with request ID `id`
+    pub fn to_message(&self, id: u32) -> Message {
+        let mut payload = Vec::with_capacity(SESSION_ID_LEN + 4 * self.replay.len());
+        payload.extend_from_slice(&self.session.0);
+        for replayed in &self.replay {
+            payload.extend_from_slice(&replayed.to_be_bytes());
+        }
+        Message {
+            version: VERSION,
+            message_type: MessageType::Resume,
+            id,
+            status: None,
+            options: None,
+            payload,
+        }
+    }
+}
+
+// ============================================================================
+// Server Sessions
+// ============================================================================
+
+/// A server's sessions, shared by every connection
+///
+/// Holds at most [`with_limit`](Self::with_limit) sessions; opening one
+/// more forgets the session resumed least recently, and resuming a
+/// forgotten session fails with `UnknownSession`. Each session remembers
+/// the responses to its [`with_cache_size`](Self::with_cache_size) most
+/// recent requests. A cache smaller than the flow-control window's request
+/// credit can forget a response the client never received, and the
+/// request then runs again when it is replayed.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct Sessions {
+    table: Arc<Mutex<Table>>,
+    limit: usize,
+    cache_size: usize,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Table {
+    /// Each session and when it was last opened or resumed
+    sessions: HashMap<SessionId, (Arc<Session>, u64)>,
+    clock: u64,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("sessions", &self.len())
+            .field("limit", &self.limit)
+            .field("cache_size", &self.cache_size)
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Sessions {
+    /// An empty table
+    pub fn new() -> Self {
+        Self {
+            table: Arc::default(),
+            limit: DEFAULT_SESSION_LIMIT,
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+
+    /// Most sessions kept at once
+    ///
+    /// A limit of 0 is treated as 1.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit.max(1);
+        self
+    }
+
+    /// Responses remembered per session
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    /// Sessions currently kept
+    pub fn len(&self) -> usize {
+        self.lock().sessions.len()
+    }
+
+    /// Whether no session is kept
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Open or resume the session a `Resume` names
+    pub(crate) fn resume(&self, resume: &Resume) -> Result<Arc<Session>, ProtocolError> {
+        let mut table = self.lock();
+        table.clock += 1;
+        let now = table.clock;
+        if !resume.session.is_new() {
+            let (session, used) = table
+                .sessions
+                .get_mut(&resume.session)
+                .ok_or(ProtocolError::UnknownSession(resume.session))?;
+            *used = now;
+            session.expect_replays(&resume.replay);
+            return Ok(Arc::clone(session));
+        }
+
+        if table.sessions.len() >= self.limit {
+            let oldest = table
+                .sessions
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                table.sessions.remove(&oldest);
+            }
+        }
+        let id = loop {
+            let id = SessionId::generate();
+            if !table.sessions.contains_key(&id) {
+                break id;
+            }
+        };
+        let session = Arc::new(Session::new(id, self.cache_size));
+        table.sessions.insert(id, (Arc::clone(&session), now));
+        Ok(session)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Table> {
+        self.table.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One session's response cache
+#[cfg(feature = "std")]
+pub(crate) struct Session {
+    id: SessionId,
+    cache_size: usize,
+    cache: Mutex<Cache>,
+    /// Signalled whenever a response is stored
+    stored: Condvar,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u32, Entry>,
+    /// Request IDs in the order they were first handled
+    order: VecDeque<u32>,
+    /// IDs announced by the last `Resume` and not yet replayed
+    replays: HashSet<u32>,
+    /// Ticket for the next request handled
+    next_ticket: u64,
+}
+
+#[cfg(feature = "std")]
+enum Entry {
+    /// Being handled under this ticket
+    Running(u64),
+    Done(Message),
+}
+
+/// How to answer a request in a session
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub(crate) enum Begin {
+    /// Handle it, then [`complete`](Session::complete) it with this ticket
+    Run(u64),
+    /// A replay of a request still being handled: [`wait`](Session::wait)
+    /// for its response
+    Running,
+    /// A replay of a request already answered with this response
+    Cached(Message),
+}
+
+#[cfg(feature = "std")]
+impl Session {
+    fn new(id: SessionId, cache_size: usize) -> Self {
+        Self {
+            id,
+            cache_size,
+            cache: Mutex::default(),
+            stored: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Forget every response but those the client will replay
+    ///
+    /// The client lists every request it has no answer to, so the others
+    /// were received.
+    fn expect_replays(&self, replay: &[u32]) {
+        let mut cache = self.lock();
+        let cache = &mut *cache;
+        cache.replays = replay.iter().copied().collect();
+        let replays = &cache.replays;
+        cache.entries.retain(|id, _| replays.contains(id));
+        let entries = &cache.entries;
+        cache.order.retain(|id| entries.contains_key(id));
+    }
+
+    /// Decide how to answer the request `id`
+    ///
+    /// Only a request announced as a replay is answered from the cache;
+    /// any other replaces what is cached under its ID.
+    pub(crate) fn begin(&self, id: u32) -> Begin {
+        let mut cache = self.lock();
+        if cache.replays.remove(&id) {
+            match cache.entries.get(&id) {
+                Some(Entry::Done(response)) => return Begin::Cached(response.clone()),
+                Some(Entry::Running(_)) => return Begin::Running,
+                // Never arrived
+                None => {}
+            }
+        }
+        let ticket = cache.next_ticket;
+        cache.next_ticket += 1;
+        if cache.entries.insert(id, Entry::Running(ticket)).is_some() {
+            cache.order.retain(|&cached| cached != id);
+        }
+        cache.order.push_back(id);
+        Begin::Run(ticket)
+    }
+
+    /// Remember the response to the request `id` begun with `ticket`
+    pub(crate) fn complete(&self, id: u32, ticket: u64, response: &Message) {
+        let mut cache = self.lock();
+        // A later request may have taken over the ID
+        if let Some(entry) = cache.entries.get_mut(&id) {
+            if matches!(entry, Entry::Running(running) if *running == ticket) {
+                *entry = Entry::Done(response.clone());
+            }
+        }
+        cache.evict(self.cache_size);
+        self.stored.notify_all();
+    }
+
+    /// Wait for the response to the request `id`, still being handled
+    pub(crate) fn wait(&self, id: u32) -> Message {
+        let mut cache = self.lock();
+        loop {
+            match cache.entries.get(&id) {
+                Some(Entry::Done(response)) => return response.clone(),
+                Some(Entry::Running(_)) => {
+                    cache = self.stored.wait(cache).unwrap_or_else(|e| e.into_inner())
+                }
+                // Forgotten by a later resume that did not list it
+                None => {
+                    return Message::error(
+                        id,
+                        crate::ErrorCode::UnknownSession as u8,
+                        &format!("response to request {} was discarded", id),
+                    )
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Cache {
+    /// Forget the oldest answered requests beyond `size`; requests still
+    /// running are kept
+    fn evict(&mut self, size: usize) {
+        let mut excess = self.entries.len().saturating_sub(size);
+        let mut index = 0;
+        while excess > 0 && index < self.order.len() {
+            let id = self.order[index];
+            if matches!(self.entries.get(&id), Some(Entry::Done(_))) {
+                self.entries.remove(&id);
+                self.order.remove(index);
+                excess -= 1;
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_resume_round_trip() {
+        let resume = Resume::new(SessionId([7; SESSION_ID_LEN]), vec![3, 0x0102_0304]);
+        let message = resume.to_message(9);
+        assert_eq!((message.message_type, message.id), (MessageType::Resume, 9));
+        assert_eq!(&message.payload[SESSION_ID_LEN..], [0, 0, 0, 3, 1, 2, 3, 4]);
+        assert_eq!(Resume::from_message(&message), Ok(resume));
+
+        let open = Resume::open().to_message(1);
+        assert_eq!(open.payload, [0; SESSION_ID_LEN]);
+        assert!(Resume::from_message(&open).unwrap().session.is_new());
+    }
+
+    #[test]
+    fn test_resume_rejects_malformed_payloads() {
+        let mut message = Resume::open().to_message(1);
+        message.payload.push(0);
+        assert!(matches!(
+            Resume::from_message(&message),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+        message.payload.truncate(SESSION_ID_LEN - 1);
+        assert!(matches!(
+            Resume::from_message(&message),
+            Err(ProtocolError::InvalidPayload(_))
+        ));
+        assert!(Resume::from_message(&Message::request(1, &[0; SESSION_ID_LEN])).is_err());
+    }
+
+    #[test]
+    fn test_session_id_display() {
+        let mut id = SessionId::NEW;
+        id.0[0] = 0xAB;
+        id.0[15] = 0x01;
+        assert_eq!(id.to_string(), "ab000000000000000000000000000001");
+        assert_eq!(SessionId::from_payload(&id.0), Ok(id));
+        assert!(SessionId::from_payload(&[0; 4]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_replays_are_answered_from_the_cache() {
+        let sessions = Sessions::new();
+        let session = sessions.resume(&Resume::open()).unwrap();
+        assert!(!session.id().is_new());
+
+        let Begin::Run(ticket) = session.begin(1) else {
+            panic!("first request should run")
+        };
+        session.complete(1, ticket, &Message::response(1, 0, b"once"));
+        let Begin::Run(_) = session.begin(2) else {
+            panic!("second request should run")
+        };
+
+        let resumed = sessions
+            .resume(&Resume::new(session.id(), vec![1, 2, 3]))
+            .unwrap();
+        assert!(Arc::ptr_eq(&session, &resumed));
+        assert!(matches!(session.begin(1), Begin::Cached(m) if m.payload == b"once"));
+        assert!(matches!(session.begin(2), Begin::Running));
+        // Announced but never received: handled now
+        assert!(matches!(session.begin(3), Begin::Run(_)));
+        // Replayed once only: the same ID again is a new request
+        assert!(matches!(session.begin(1), Begin::Run(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_unlisted_responses_are_forgotten_on_resume() {
+        let sessions = Sessions::new();
+        let session = sessions.resume(&Resume::open()).unwrap();
+        for id in 1..=2 {
+            let Begin::Run(ticket) = session.begin(id) else {
+                panic!("new request should run")
+            };
+            session.complete(id, ticket, &Message::response(id, 0, b""));
+        }
+        sessions
+            .resume(&Resume::new(session.id(), vec![2]))
+            .unwrap();
+        assert_eq!(session.lock().entries.len(), 1);
+        assert!(matches!(session.begin(2), Begin::Cached(_)));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_waits_for_running_replay() {
+        let session = Arc::new(Session::new(SessionId([1; SESSION_ID_LEN]), 4));
+        let Begin::Run(ticket) = session.begin(5) else {
+            panic!("new request should run")
+        };
+        let waiter = {
+            let session = Arc::clone(&session);
+            std::thread::spawn(move || session.wait(5))
+        };
+        session.complete(5, ticket, &Message::response(5, 0, b"done"));
+        assert_eq!(waiter.join().unwrap().payload, b"done");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_cache_is_bounded_and_keeps_running_requests() {
+        let session = Session::new(SessionId([1; SESSION_ID_LEN]), 2);
+        let Begin::Run(running) = session.begin(1) else {
+            panic!("new request should run")
+        };
+        for id in 2..=4 {
+            let Begin::Run(ticket) = session.begin(id) else {
+                panic!("new request should run")
+            };
+            session.complete(id, ticket, &Message::response(id, 0, b""));
+        }
+        let cache = session.lock();
+        let mut ids: Vec<_> = cache.entries.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 4]);
+        assert!(matches!(cache.entries[&1], Entry::Running(ticket) if ticket == running));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_unknown_sessions_and_session_limit() {
+        let sessions = Sessions::new().with_limit(2);
+        let missing = SessionId([9; SESSION_ID_LEN]);
+        assert_eq!(
+            sessions.resume(&Resume::new(missing, Vec::new())).err(),
+            Some(ProtocolError::UnknownSession(missing))
+        );
+
+        let first = sessions.resume(&Resume::open()).unwrap().id();
+        let second = sessions.resume(&Resume::open()).unwrap().id();
+        sessions.resume(&Resume::new(first, Vec::new())).unwrap();
+        sessions.resume(&Resume::open()).unwrap();
+        assert_eq!(sessions.len(), 2);
+        // The session resumed least recently was forgotten
+        assert!(sessions.resume(&Resume::new(first, Vec::new())).is_ok());
+        assert!(sessions.resume(&Resume::new(second, Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,531 @@
+//! Self-describing TLV payloads
+//!
+//! Where the typed encoding in [`crate::payload`] needs both peers to agree
+//! on a schema, a [`Value`] carries its own structure, so generic tools (the
+//! CLI, gateways, debuggers) can inspect payloads without knowing their type.
+//!
+//! Every value is encoded as tag–length–value (SPEC.md Section 3.5):
+//!
+//! ```text
+//! +-------+----------------+-----------------+
+//! | Tag   | Length         | Value           |
+//! | 1 byte| LEB128 varint  | Length bytes    |
+//! +-------+----------------+-----------------+
+//! ```
+//!
+//! | Tag | Type | Value bytes |
+//! |-----|------|-------------|
+//! | 0x00 | Null | empty |
+//! | 0x01 | Bool | 1 byte, 0x00 or 0x01 |
+//! | 0x02 | Int | zigzag LEB128 varint (i64) |
+//! | 0x03 | Float | 8 bytes, IEEE 754 big-endian |
+//! | 0x04 | Bytes | raw bytes |
+//! | 0x05 | String | UTF-8 bytes |
+//! | 0x06 | List | concatenated TLV elements |
+//! | 0x07 | Map | concatenated (String key TLV, value TLV) pairs |
+//!
+//! ## Example
+//!
+//! ```rust
+//! use protocol_name::tlv::Value;
+//!
+//! let value = Value::Map(vec![
+//!     ("name".to_string(), Value::from("probe")),
+//!     ("port".to_string(), Value::Int(8080)),
+//! ]);
+//! let bytes = value.to_bytes();
+//! assert_eq!(Value::from_bytes(&bytes).unwrap(), value);
+//! ```
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::payload::{read_varint, write_varint, Decode, Encode};
+use crate::{ProtocolError, MAX_PAYLOAD_SIZE};
+
+/// Tag byte for [`Value::Null`]
+pub const TAG_NULL: u8 = 0x00;
+/// Tag byte for [`Value::Bool`]
+pub const TAG_BOOL: u8 = 0x01;
+/// Tag byte for [`Value::Int`]
+pub const TAG_INT: u8 = 0x02;
+/// Tag byte for [`Value::Float`]
+pub const TAG_FLOAT: u8 = 0x03;
+/// Tag byte for [`Value::Bytes`]
+pub const TAG_BYTES: u8 = 0x04;
+/// Tag byte for [`Value::String`]
+pub const TAG_STRING: u8 = 0x05;
+/// Tag byte for [`Value::List`]
+pub const TAG_LIST: u8 = 0x06;
+/// Tag byte for [`Value::Map`]
+pub const TAG_MAP: u8 = 0x07;
+
+/// Default maximum nesting depth of lists and maps
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// A self-describing payload value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Absent value
+    Null,
+    /// Boolean
+    Bool(bool),
+    /// Signed integer
+    Int(i64),
+    /// 64-bit float
+    Float(f64),
+    /// Raw bytes
+    Bytes(Vec<u8>),
+    /// UTF-8 string
+    String(String),
+    /// Ordered list
+    List(Vec<Value>),
+    /// String-keyed map, in insertion order
+    Map(Vec<(String, Value)>),
+}
+
+/// Limits applied while decoding untrusted TLV data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum nesting depth of lists and maps
+    pub max_depth: usize,
+    /// Maximum total encoded size in bytes
+    pub max_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_size: MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> ProtocolError {
+    ProtocolError::InvalidPayload(msg.into())
+}
+
+// ============================================================================
+// Encoding
+// ============================================================================
+
+impl Value {
+    /// Tag byte for this value
+    pub fn tag(&self) -> u8 {
+        match self {
+            Self::Null => TAG_NULL,
+            Self::Bool(_) => TAG_BOOL,
+            Self::Int(_) => TAG_INT,
+            Self::Float(_) => TAG_FLOAT,
+            Self::Bytes(_) => TAG_BYTES,
+            Self::String(_) => TAG_STRING,
+            Self::List(_) => TAG_LIST,
+            Self::Map(_) => TAG_MAP,
+        }
+    }
+
+    /// Append the TLV encoding of this value to `out`
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(self.tag());
+        let mut body = Vec::new();
+        match self {
+            Self::Null => {}
+            Self::Bool(b) => body.push(u8::from(*b)),
+            Self::Int(i) => write_varint(&mut body, ((i << 1) ^ (i >> 63)) as u64),
+            Self::Float(f) => body.extend_from_slice(&f.to_be_bytes()),
+            Self::Bytes(bytes) => body.extend_from_slice(bytes),
+            Self::String(s) => body.extend_from_slice(s.as_bytes()),
+            Self::List(items) => items.iter().for_each(|item| item.write_to(&mut body)),
+            Self::Map(entries) => {
+                for (key, value) in entries {
+                    Value::String(key.clone()).write_to(&mut body);
+                    value.write_to(&mut body);
+                }
+            }
+        }
+        write_varint(out, body.len() as u64);
+        out.extend_from_slice(&body);
+    }
+
+    /// Encode into a fresh buffer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out);
+        out
+    }
+}
+
+// ============================================================================
+// Decoding
+// ============================================================================
+
+impl Value {
+    /// Decode a single value occupying all of `bytes`, with default limits
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_bytes_with_limits(bytes, Limits::default())
+    }
+
+    /// Decode a single value occupying all of `bytes`
+    pub fn from_bytes_with_limits(bytes: &[u8], limits: Limits) -> Result<Self, ProtocolError> {
+        if bytes.len() > limits.max_size {
+            return Err(invalid(format!(
+                "TLV data exceeds size limit ({} > {})",
+                bytes.len(),
+                limits.max_size
+            )));
+        }
+        let mut input = bytes;
+        let value = Self::read_from(&mut input, limits.max_depth)?;
+        if !input.is_empty() {
+            return Err(invalid(format!(
+                "{} trailing bytes after TLV value",
+                input.len()
+            )));
+        }
+        Ok(value)
+    }
+
+    /// Decode one value from the front of `input`. `depth` is the remaining
+    /// nesting budget for lists and maps.
+    fn read_from(input: &mut &[u8], depth: usize) -> Result<Self, ProtocolError> {
+        let (&tag, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid("unexpected end of TLV data"))?;
+        *input = rest;
+
+        let len = usize::try_from(read_varint(input)?)
+            .map_err(|_| invalid("TLV length overflows usize"))?;
+        if len > input.len() {
+            return Err(invalid(format!(
+                "TLV length {} exceeds remaining {} bytes",
+                len,
+                input.len()
+            )));
+        }
+        let (mut body, rest) = input.split_at(len);
+        *input = rest;
+
+        let expect_len = |expected: usize| {
+            if len == expected {
+                Ok(())
+            } else {
+                Err(invalid(format!(
+                    "tag {:#04x} requires length {}, got {}",
+                    tag, expected, len
+                )))
+            }
+        };
+
+        match tag {
+            TAG_NULL => expect_len(0).map(|_| Self::Null),
+            TAG_BOOL => {
+                expect_len(1)?;
+                match body[0] {
+                    0 => Ok(Self::Bool(false)),
+                    1 => Ok(Self::Bool(true)),
+                    b => Err(invalid(format!("invalid bool: {:#04x}", b))),
+                }
+            }
+            TAG_INT => {
+                let raw = read_varint(&mut body)?;
+                if !body.is_empty() {
+                    return Err(invalid("trailing bytes in integer"));
+                }
+                Ok(Self::Int(((raw >> 1) as i64) ^ -((raw & 1) as i64)))
+            }
+            TAG_FLOAT => {
+                expect_len(8)?;
+                let bits: [u8; 8] = body.try_into().expect("length checked");
+                Ok(Self::Float(f64::from_be_bytes(bits)))
+            }
+            TAG_BYTES => Ok(Self::Bytes(body.to_vec())),
+            TAG_STRING => String::from_utf8(body.to_vec())
+                .map(Self::String)
+                .map_err(|_| invalid("string is not valid UTF-8")),
+            TAG_LIST | TAG_MAP => {
+                let depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid("TLV nesting exceeds depth limit"))?;
+                if tag == TAG_LIST {
+                    let mut items = Vec::new();
+                    while !body.is_empty() {
+                        items.push(Self::read_from(&mut body, depth)?);
+                    }
+                    Ok(Self::List(items))
+                } else {
+                    let mut entries = Vec::new();
+                    while !body.is_empty() {
+                        let key = match Self::read_from(&mut body, depth)? {
+                            Self::String(key) => key,
+                            other => {
+                                return Err(invalid(format!(
+                                    "map key must be a string, got tag {:#04x}",
+                                    other.tag()
+                                )))
+                            }
+                        };
+                        if body.is_empty() {
+                            return Err(invalid(format!("map key {:?} has no value", key)));
+                        }
+                        entries.push((key, Self::read_from(&mut body, depth)?));
+                    }
+                    Ok(Self::Map(entries))
+                }
+            }
+            other => Err(invalid(format!("unknown TLV tag: {:#04x}", other))),
+        }
+    }
+}
+
+impl Encode for Value {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.write_to(out);
+    }
+}
+
+impl Decode for Value {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        Self::read_from(input, DEFAULT_MAX_DEPTH)
+    }
+}
+
+// ============================================================================
+// Accessors and Conversions
+// ============================================================================
+
+impl Value {
+    /// Look up a key in a map value
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// The value as a string slice, if it is a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, if it is an integer
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Multi-line, indented rendering for humans
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize) {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Self::List(items) if !items.is_empty() => {
+                out.push_str("[\n");
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(&pad);
+                    item.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Self::Map(entries) if !entries.is_empty() => {
+                out.push_str("{\n");
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    out.push_str(&format!("{}{:?}: ", pad, key));
+                    value.write_pretty(out, indent + 1);
+                    out.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+            other => out.push_str(&other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Compact single-line rendering (JSON-like; bytes shown as hex)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => write!(f, "{:?}", x),
+            Self::Bytes(bytes) => {
+                write!(f, "0x")?;
+                bytes.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            Self::String(s) => write!(f, "{:?}", s),
+            Self::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Self::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Self::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Self::Float(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Self::List(items)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Self::Null, Into::into)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn sample() -> Value {
+        Value::Map(vec![
+            ("ok".to_string(), Value::Bool(true)),
+            ("n".to_string(), Value::Int(-300)),
+            ("pi".to_string(), Value::Float(3.5)),
+            ("raw".to_string(), Value::Bytes(vec![0xDE, 0xAD])),
+            (
+                "tags".to_string(),
+                Value::List(vec![Value::from("a"), Value::Null]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let value = sample();
+        assert_eq!(Value::from_bytes(&value.to_bytes()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_exact_encoding() {
+        assert_eq!(Value::Null.to_bytes(), vec![0x00, 0x00]);
+        assert_eq!(Value::Int(-1).to_bytes(), vec![0x02, 0x01, 0x01]);
+        assert_eq!(
+            Value::List(vec![Value::Bool(true)]).to_bytes(),
+            vec![0x06, 0x03, 0x01, 0x01, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut value = Value::Null;
+        for _ in 0..5 {
+            value = Value::List(vec![value]);
+        }
+        let bytes = value.to_bytes();
+        let tight = Limits {
+            max_depth: 4,
+            ..Limits::default()
+        };
+        assert!(Value::from_bytes_with_limits(&bytes, tight).is_err());
+        assert!(Value::from_bytes_with_limits(
+            &bytes,
+            Limits {
+                max_depth: 5,
+                ..tight
+            }
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let bytes = Value::Bytes(vec![0; 100]).to_bytes();
+        let limits = Limits {
+            max_size: 50,
+            ..Limits::default()
+        };
+        assert!(Value::from_bytes_with_limits(&bytes, limits).is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(Value::from_bytes(&[0x05, 0x03, b'a']).is_err()); // short body
+        assert!(Value::from_bytes(&[0x09, 0x00]).is_err()); // unknown tag
+        assert!(Value::from_bytes(&[0x01, 0x01, 0x02]).is_err()); // bad bool
+        assert!(Value::from_bytes(&[0x07, 0x02, 0x02, 0x00]).is_err()); // non-string key
+        assert!(Value::from_bytes(&[0x00, 0x00, 0x00]).is_err()); // trailing
+    }
+
+    #[test]
+    fn test_pretty() {
+        let pretty = sample().pretty();
+        assert!(pretty.starts_with("{\n  \"ok\": true,\n"));
+        assert!(pretty.contains("  \"raw\": 0xdead,\n"));
+        assert!(pretty.contains("  \"tags\": [\n    \"a\",\n    null\n  ]\n}"));
+        assert_eq!(Value::List(vec![]).pretty(), "[]");
+    }
+}
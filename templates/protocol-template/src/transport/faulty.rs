@@ -0,0 +1,286 @@
+//! Fault injection
+//!
+//! [`FaultyTransport`] wraps another transport and misbehaves on purpose:
+//! short reads and writes at random boundaries, delays, and dropped,
+//! duplicated or corrupted bytes. All randomness comes from a seeded
+//! [`Rng`], so a given seed and sequence of calls always produces the same
+//! faults.
+//!
+//! Byte faults are applied on the write side. A write that drops bytes
+//! still reports them as written, as a lossy link would.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::io::{Read, Write};
+//! use protocol_name::transport::{pipe, FaultyTransport};
+//!
+//! let (a, mut b) = pipe();
+//! let mut a = FaultyTransport::new(a, 7).with_split_writes(true);
+//! a.write_all(b"hello").unwrap();
+//!
+//! let mut buf = [0u8; 5];
+//! b.read_exact(&mut buf).unwrap();
+//! assert_eq!(&buf, b"hello");
+//! ```
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use super::Duplex;
+use crate::rng::Rng;
+
+/// Counts of faults injected so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Writes that accepted fewer bytes than offered
+    pub short_writes: u64,
+    /// Reads that returned fewer bytes than available
+    pub short_reads: u64,
+    /// Bytes silently discarded
+    pub dropped: u64,
+    /// Bytes written twice
+    pub duplicated: u64,
+    /// Bytes altered before writing
+    pub corrupted: u64,
+}
+
+/// A transport wrapper that injects deterministic faults
+#[derive(Debug)]
+pub struct FaultyTransport<T> {
+    inner: T,
+    rng: Rng,
+    split_writes: bool,
+    split_reads: bool,
+    max_delay: Duration,
+    drop_rate: f64,
+    duplicate_rate: f64,
+    corrupt_rate: f64,
+    stats: FaultStats,
+}
+
+impl<T> FaultyTransport<T> {
+    /// Wrap a transport. No faults are enabled by default.
+    pub fn new(inner: T, seed: u64) -> Self {
+        Self {
+            inner,
+            rng: Rng::new(seed),
+            split_writes: false,
+            split_reads: false,
+            max_delay: Duration::ZERO,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            corrupt_rate: 0.0,
+            stats: FaultStats::default(),
+        }
+    }
+
+    /// Accept a random, non-empty prefix of each write
+    pub fn with_split_writes(mut self, enabled: bool) -> Self {
+        self.split_writes = enabled;
+        self
+    }
+
+    /// Return a random, non-empty prefix of each read
+    pub fn with_split_reads(mut self, enabled: bool) -> Self {
+        self.split_reads = enabled;
+        self
+    }
+
+    /// Sleep for a random duration up to `max` before each write
+    pub fn with_delay(mut self, max: Duration) -> Self {
+        self.max_delay = max;
+        self
+    }
+
+    /// Probability that each written byte is dropped
+    pub fn with_drop_rate(mut self, p: f64) -> Self {
+        self.drop_rate = p;
+        self
+    }
+
+    /// Probability that each written byte is sent twice
+    pub fn with_duplicate_rate(mut self, p: f64) -> Self {
+        self.duplicate_rate = p;
+        self
+    }
+
+    /// Probability that each written byte is flipped to a different value
+    pub fn with_corrupt_rate(mut self, p: f64) -> Self {
+        self.corrupt_rate = p;
+        self
+    }
+
+    /// Faults injected so far
+    pub fn stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    /// Get a reference to the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the wrapped transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the wrapped transport
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn delay(&mut self) {
+        if !self.max_delay.is_zero() {
+            let nanos = self.rng.below(self.max_delay.as_nanos() as u64 + 1);
+            thread::sleep(Duration::from_nanos(nanos));
+        }
+    }
+}
+
+impl<T: Duplex> Duplex for FaultyTransport<T> {
+    type Reader = FaultyTransport<T::Reader>;
+    type Writer = FaultyTransport<T::Writer>;
+
+    /// Split into halves with the same faults. The writing half draws from
+    /// a generator derived from the seed, and each half counts its own
+    /// [`stats`](Self::stats).
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let mut rng = self.rng.clone();
+        let writer_rng = Rng::new(rng.next_u64());
+        let template = self.wrap((), Rng::new(0));
+        let (reader, writer) = self.inner.split()?;
+        Ok((
+            template.wrap(reader, rng),
+            template.wrap(writer, writer_rng),
+        ))
+    }
+}
+
+impl<T> FaultyTransport<T> {
+    /// A fresh wrapper around `inner` with this configuration
+    fn wrap<U>(&self, inner: U, rng: Rng) -> FaultyTransport<U> {
+        FaultyTransport {
+            inner,
+            rng,
+            split_writes: self.split_writes,
+            split_reads: self.split_reads,
+            max_delay: self.max_delay,
+            drop_rate: self.drop_rate,
+            duplicate_rate: self.duplicate_rate,
+            corrupt_rate: self.corrupt_rate,
+            stats: FaultStats::default(),
+        }
+    }
+}
+
+impl<T: Read> Read for FaultyTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.split_reads || buf.len() <= 1 {
+            return self.inner.read(buf);
+        }
+        let len = self.rng.range(1, buf.len());
+        if len < buf.len() {
+            self.stats.short_reads += 1;
+        }
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<T: Write> Write for FaultyTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.inner.write(buf);
+        }
+        self.delay();
+
+        let len = if self.split_writes {
+            self.rng.range(1, buf.len())
+        } else {
+            buf.len()
+        };
+        if len < buf.len() {
+            self.stats.short_writes += 1;
+        }
+
+        let mut out = Vec::with_capacity(len);
+        for &byte in &buf[..len] {
+            if self.rng.chance(self.drop_rate) {
+                self.stats.dropped += 1;
+                continue;
+            }
+            let mut byte = byte;
+            if self.rng.chance(self.corrupt_rate) {
+                byte ^= self.rng.range(1, 255) as u8;
+                self.stats.corrupted += 1;
+            }
+            out.push(byte);
+            if self.rng.chance(self.duplicate_rate) {
+                out.push(byte);
+                self.stats.duplicated += 1;
+            }
+        }
+        self.inner.write_all(&out)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(seed: u64, input: &[u8]) -> (Vec<u8>, FaultStats) {
+        let mut t = FaultyTransport::new(Vec::new(), seed)
+            .with_split_writes(true)
+            .with_drop_rate(0.05)
+            .with_duplicate_rate(0.05)
+            .with_corrupt_rate(0.05);
+        t.write_all(input).unwrap();
+        let stats = t.stats();
+        (t.into_inner(), stats)
+    }
+
+    #[test]
+    fn test_same_seed_same_faults() {
+        let input: Vec<u8> = (0..=255).collect();
+        assert_eq!(run(1, &input), run(1, &input));
+        assert_ne!(run(1, &input).0, run(2, &input).0);
+    }
+
+    #[test]
+    fn test_no_faults_by_default() {
+        let mut t = FaultyTransport::new(Vec::new(), 0);
+        t.write_all(b"intact").unwrap();
+        assert_eq!(t.get_ref(), b"intact");
+        assert_eq!(t.stats(), FaultStats::default());
+    }
+
+    #[test]
+    fn test_byte_faults_are_counted() {
+        let input = vec![0u8; 4096];
+        let (output, stats) = run(3, &input);
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.corrupted > 0);
+        assert!(stats.short_writes > 0);
+        assert_eq!(output.len() as u64, 4096 - stats.dropped + stats.duplicated);
+    }
+
+    #[test]
+    fn test_split_reads() {
+        let data = vec![9u8; 100];
+        let mut t = FaultyTransport::new(data.as_slice(), 5).with_split_reads(true);
+        let mut out = Vec::new();
+        t.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert!(t.stats().short_reads > 0);
+    }
+}
@@ -0,0 +1,260 @@
+//! In-memory duplex pipe
+//!
+//! [`pipe`] returns two connected [`MemoryStream`]s: bytes written to one are
+//! read from the other. Reads block until data arrives, the peer is dropped
+//! (end of stream) or the read timeout expires, so protocol logic can be
+//! tested across threads without sockets.
+//!
+//! A stream can be [split](super::Duplex::split) into a read-only and a
+//! write-only half; each half closes only its own direction when dropped.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// One direction of the pipe
+#[derive(Debug, Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    data: VecDeque<u8>,
+    /// The writing end was dropped
+    writer_closed: bool,
+    /// The reading end was dropped
+    reader_closed: bool,
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, ChannelState> {
+        // A panicking peer cannot leave the queue inconsistent
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One end of an in-memory duplex pipe, or one half of an end
+#[derive(Debug)]
+pub struct MemoryStream {
+    incoming: Option<Arc<Channel>>,
+    outgoing: Option<Arc<Channel>>,
+    read_timeout: Option<Duration>,
+}
+
+/// Create a connected pair of in-memory streams
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let a_to_b = Arc::new(Channel::default());
+    let b_to_a = Arc::new(Channel::default());
+    let a = MemoryStream {
+        incoming: Some(Arc::clone(&b_to_a)),
+        outgoing: Some(Arc::clone(&a_to_b)),
+        read_timeout: None,
+    };
+    let b = MemoryStream {
+        incoming: Some(a_to_b),
+        outgoing: Some(b_to_a),
+        read_timeout: None,
+    };
+    (a, b)
+}
+
+impl MemoryStream {
+    /// Limit how long a read waits for data; `None` waits forever
+    ///
+    /// A read that times out fails with `io::ErrorKind::TimedOut`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Bytes written by the peer and not yet read
+    pub fn available(&self) -> usize {
+        self.incoming.as_ref().map_or(0, |c| c.lock().data.len())
+    }
+
+    /// Close the writing direction, so the peer reads end of stream, while
+    /// still reading what the peer sends
+    pub fn shutdown_write(&mut self) {
+        if let Some(outgoing) = self.outgoing.take() {
+            outgoing.lock().writer_closed = true;
+            outgoing.ready.notify_all();
+        }
+    }
+}
+
+impl super::Duplex for MemoryStream {
+    type Reader = MemoryStream;
+    type Writer = MemoryStream;
+
+    fn split(mut self) -> io::Result<(MemoryStream, MemoryStream)> {
+        let writer = MemoryStream {
+            incoming: None,
+            outgoing: self.outgoing.take(),
+            read_timeout: None,
+        };
+        let reader = MemoryStream {
+            incoming: self.incoming.take(),
+            outgoing: None,
+            read_timeout: self.read_timeout,
+        };
+        Ok((reader, writer))
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} half of a split stream", what),
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let incoming = self
+            .incoming
+            .as_ref()
+            .ok_or_else(|| unsupported("write-only"))?;
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        let mut state = incoming.lock();
+        while state.data.is_empty() && !state.writer_closed {
+            state = match deadline {
+                None => incoming
+                    .ready
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                    }
+                    incoming
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+
+        let n = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let outgoing = self
+            .outgoing
+            .as_ref()
+            .ok_or_else(|| unsupported("read-only"))?;
+        let mut state = outgoing.lock();
+        if state.reader_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer closed"));
+        }
+        state.data.extend(buf);
+        outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        self.shutdown_write();
+        if let Some(incoming) = &self.incoming {
+            incoming.lock().reader_closed = true;
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_bytes_cross_between_ends() {
+        let (mut a, mut b) = pipe();
+        a.write_all(b"ping").unwrap();
+        b.write_all(b"pong").unwrap();
+
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        a.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn test_read_blocks_until_write() {
+        let (mut a, mut b) = pipe();
+        let reader = thread::spawn(move || {
+            let mut buf = [0u8; 3];
+            b.read_exact(&mut buf).unwrap();
+            buf
+        });
+        a.write_all(b"abc").unwrap();
+        assert_eq!(&reader.join().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_drop_signals_eof_and_broken_pipe() {
+        let (mut a, b) = pipe();
+        a.write_all(b"x").unwrap();
+        drop(b);
+        assert_eq!(a.write(b"y").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        let (a, mut b) = pipe();
+        drop(a);
+        assert_eq!(b.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_split_halves_close_independently() {
+        use crate::transport::Duplex;
+
+        let (a, mut b) = pipe();
+        let (mut reader, mut writer) = a.split().unwrap();
+        writer.write_all(b"hi").unwrap();
+        drop(writer);
+
+        let mut buf = Vec::new();
+        b.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hi");
+
+        // The reading half is still open
+        b.write_all(b"back").unwrap();
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"back");
+        assert_eq!(
+            reader.write(b"x").unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let (_a, mut b) = pipe();
+        b.set_read_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(
+            b.read(&mut [0u8; 1]).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Transports
+//!
+//! The codec works over any `Read + Write` byte stream. This module names
+//! that contract ([`Transport`]) and provides the streams the client and
+//! server run over:
+//!
+//! | Transport | Client side | Server side |
+//! |-----------|-------------|-------------|
+//! | TCP | `TcpStream` | `TcpListener` |
+//! | Unix domain socket | `UnixStream` | `UnixListener` |
+//! | stdio (LSP-style) | [`ChildProcess`] | [`Stdio`] |
+//! | in-memory | [`pipe`] | [`pipe`] |
+//!
+//! [`SecureStream`] wraps any of them in authenticated encryption keyed by
+//! a pre-shared key, for links where TLS is not practical.
+//! [`WebSocketStream`] carries the frames in WebSocket messages, for
+//! browsers.
+//! [`FaultyTransport`] wraps any of them to inject reproducible faults in
+//! tests.
+//!
+//! Any type implementing [`Listener`] can be served by
+//! [`Server::serve`](crate::server::Server::serve). The server reads
+//! requests and writes responses from different threads, so served
+//! transports implement [`Duplex`].
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+mod faulty;
+mod memory;
+mod secure;
+mod stdio;
+mod websocket;
+
+pub use faulty::{FaultStats, FaultyTransport};
+pub use memory::{pipe, MemoryStream};
+pub(crate) use secure::{check_psk, session_random};
+pub use secure::{SecureReader, SecureStream, SecureWriter, MAX_RECORD_PLAINTEXT, MIN_PSK_LEN};
+pub use stdio::{ChildProcess, Stdio};
+pub use websocket::{WebSocketReader, WebSocketStream, WebSocketWriter};
+
+/// A bidirectional byte stream carrying framed messages
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write + ?Sized> Transport for T {}
+
+/// A transport that can be split into halves usable from different threads
+pub trait Duplex: Transport {
+    /// Reading half
+    type Reader: Read + Send + 'static;
+    /// Writing half
+    type Writer: Write + Send + 'static;
+
+    /// Split into reading and writing halves
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl Duplex for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn split(self) -> io::Result<(TcpStream, TcpStream)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+}
+
+#[cfg(unix)]
+impl Duplex for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+
+    fn split(self) -> io::Result<(UnixStream, UnixStream)> {
+        let writer = self.try_clone()?;
+        Ok((self, writer))
+    }
+}
+
+/// A source of incoming connections
+pub trait Listener {
+    /// Stream type of an accepted connection
+    type Stream: Duplex + Send + 'static;
+
+    /// Block until the next connection arrives
+    fn accept_transport(&self) -> io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_transport(&self) -> io::Result<TcpStream> {
+        let (stream, _) = self.accept()?;
+        // Requests are small and latency-sensitive
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_transport(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
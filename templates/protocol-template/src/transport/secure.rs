@@ -0,0 +1,831 @@
+//! Authenticated encryption over any transport
+//!
+//! [`SecureStream`] wraps a transport for deployments where TLS is not
+//! practical, such as raw pipes between processes (SPEC.md Section 5.3).
+//! Both ends hold the same pre-shared key (PSK). A three-message handshake
+//! exchanges fresh random values, derives per-session keys and nonces from
+//! them with HKDF-SHA-256, and proves that both sides hold the PSK without
+//! revealing it. From then on every flushed write travels as one sealed
+//! record:
+//!
+//! ```text
+//! Length (4, BE) | Sequence (8, BE) | Ciphertext | Tag (16)
+//! ```
+//!
+//! Records are encrypted with ChaCha20-Poly1305 under a key for each
+//! direction, with the nonce derived from the sequence number and the
+//! record header as associated data. The receiver accepts only the next
+//! sequence number in order, so a modified, reordered or removed record is
+//! reported as [`ProtocolError::Tampered`] and a repeated one as
+//! [`ProtocolError::Replayed`]. Either error ends the session.
+//!
+//! Writes are buffered until [`flush`](Write::flush), which seals them into
+//! a record; the client and server flush after every frame. Unflushed data
+//! is discarded when the stream is dropped.
+//!
+//! A PSK authenticates both ends but provides no forward secrecy: anyone
+//! who later learns it can decrypt recorded sessions. Cutting the
+//! connection exactly between records looks like the peer closing it.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::io::{Read, Write};
+//! use std::thread;
+//! use protocol_name::transport::{pipe, SecureStream};
+//!
+//! let psk = b"correct horse battery staple";
+//! let (a, b) = pipe();
+//! let server = thread::spawn(move || {
+//!     let mut stream = SecureStream::accept(b, psk).unwrap();
+//!     let mut buf = [0u8; 5];
+//!     stream.read_exact(&mut buf).unwrap();
+//!     buf
+//! });
+//!
+//! let mut stream = SecureStream::connect(a, psk).unwrap();
+//! stream.write_all(b"hello").unwrap();
+//! stream.flush().unwrap();
+//! assert_eq!(&server.join().unwrap(), b"hello");
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use super::Duplex;
+use crate::crypto::{
+    constant_time_eq, hkdf_expand, hkdf_extract, hmac_sha256, sha256, ChaCha20Poly1305, NONCE_LEN,
+    TAG_LEN,
+};
+use crate::{ProtocolError, MAGIC, MAX_HEADER_SIZE, MAX_PAYLOAD_SIZE, VERSION};
+
+/// Shortest accepted pre-shared key, in bytes
+pub const MIN_PSK_LEN: usize = 16;
+
+/// Largest plaintext carried by one record: a whole frame
+pub const MAX_RECORD_PLAINTEXT: usize = MAX_HEADER_SIZE + MAX_PAYLOAD_SIZE;
+
+/// Handshake message types, outside the frame type space (SPEC.md
+/// Section 6.2)
+const CLIENT_HELLO: u8 = 0xF1;
+const SERVER_HELLO: u8 = 0xF2;
+const CLIENT_FINISHED: u8 = 0xF3;
+
+/// Frame type a peer without encryption answers a handshake with
+const ERROR_TYPE: u8 = 0xFF;
+
+const RANDOM_LEN: usize = 32;
+const CONFIRM_LEN: usize = 32;
+const PREFIX_LEN: usize = 4;
+const SEQUENCE_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 4 + SEQUENCE_LEN;
+
+// ============================================================================
+// Handshake
+// ============================================================================
+
+/// Keys and nonces for one direction of a session
+struct DirectionKeys {
+    cipher: ChaCha20Poly1305,
+    iv: [u8; NONCE_LEN],
+}
+
+/// Everything derived from the PSK and both random values
+struct KeySchedule {
+    client_to_server: DirectionKeys,
+    server_to_client: DirectionKeys,
+    client_confirm: [u8; CONFIRM_LEN],
+    server_confirm: [u8; CONFIRM_LEN],
+}
+
+impl KeySchedule {
+    /// Derive the session keys; `transcript` is the client hello followed
+    /// by the server hello up to its confirmation
+    fn derive(psk: &[u8], client_random: &[u8], server_random: &[u8], transcript: &[u8]) -> Self {
+        let mut salt = [0u8; 2 * RANDOM_LEN];
+        salt[..RANDOM_LEN].copy_from_slice(client_random);
+        salt[RANDOM_LEN..].copy_from_slice(server_random);
+        let prk = hkdf_extract(&salt, psk);
+
+        let expand = |label: &str, out: &mut [u8]| {
+            hkdf_expand(
+                &prk,
+                format!("protocol-name v{} {}", VERSION, label).as_bytes(),
+                out,
+            )
+        };
+        let direction = |label: &str| {
+            let mut key = [0u8; 32];
+            let mut iv = [0u8; NONCE_LEN];
+            expand(&format!("{} key", label), &mut key);
+            expand(&format!("{} iv", label), &mut iv);
+            DirectionKeys {
+                cipher: ChaCha20Poly1305::new(key),
+                iv,
+            }
+        };
+        let confirm = |label: &str| {
+            let mut key = [0u8; 32];
+            expand(&format!("{} finished", label), &mut key);
+            hmac_sha256(&key, &[&sha256(transcript)])
+        };
+
+        Self {
+            client_to_server: direction("c2s"),
+            server_to_client: direction("s2c"),
+            client_confirm: confirm("client"),
+            server_confirm: confirm("server"),
+        }
+    }
+}
+
+fn handshake_failed(reason: impl Into<String>) -> ProtocolError {
+    ProtocolError::HandshakeFailed(reason.into())
+}
+
+fn prefix(kind: u8) -> [u8; PREFIX_LEN] {
+    [MAGIC[0], MAGIC[1], VERSION, kind]
+}
+
+/// Read one handshake message of type `kind` into `buf`
+fn read_handshake<R: Read>(reader: &mut R, kind: u8, buf: &mut [u8]) -> Result<(), ProtocolError> {
+    let closed = |e: io::Error| match e.kind() {
+        io::ErrorKind::UnexpectedEof => handshake_failed("connection closed during the handshake"),
+        _ => ProtocolError::from(e),
+    };
+    reader.read_exact(&mut buf[..PREFIX_LEN]).map_err(closed)?;
+    if buf[..PREFIX_LEN] != prefix(kind) {
+        if buf[..3] == prefix(kind)[..3] && buf[3] == ERROR_TYPE {
+            return Err(handshake_failed(
+                "peer answered with an error frame; is encryption enabled on both ends?",
+            ));
+        }
+        return Err(handshake_failed(format!(
+            "expected handshake message {:02X}, got bytes {:02X?}",
+            kind,
+            &buf[..PREFIX_LEN]
+        )));
+    }
+    reader.read_exact(&mut buf[PREFIX_LEN..]).map_err(closed)
+}
+
+/// 32 fresh random bytes
+///
+/// Read from the operating system where it offers `/dev/urandom`; elsewhere
+/// mixed from std's randomly keyed hasher, the clock and a counter. The
+/// values need to be unique rather than secret: the PSK keeps sessions
+/// confidential, the randoms keep their keys distinct.
+pub(crate) fn session_random() -> [u8; RANDOM_LEN] {
+    let mut random = [0u8; RANDOM_LEN];
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut random))
+        .is_ok()
+    {
+        return random;
+    }
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut seed = Vec::new();
+    for _ in 0..4 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Ok(elapsed) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(elapsed.as_nanos());
+        }
+        seed.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    sha256(&seed)
+}
+
+pub(crate) fn check_psk(psk: &[u8]) -> Result<(), ProtocolError> {
+    if psk.len() < MIN_PSK_LEN {
+        return Err(handshake_failed(format!(
+            "pre-shared key must be at least {} bytes, got {}",
+            MIN_PSK_LEN,
+            psk.len()
+        )));
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Records
+// ============================================================================
+
+/// Nonce of the record with sequence number `sequence`
+fn nonce(iv: &[u8; NONCE_LEN], sequence: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = *iv;
+    for (n, s) in nonce[NONCE_LEN - SEQUENCE_LEN..]
+        .iter_mut()
+        .zip(sequence.to_be_bytes())
+    {
+        *n ^= s;
+    }
+    nonce
+}
+
+/// Report a record error through `io::Error`, to be unwrapped by
+/// `ProtocolError::from`
+fn record_error(error: ProtocolError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Sending half of a session: buffers writes and seals them on flush
+struct Sealer {
+    cipher: ChaCha20Poly1305,
+    iv: [u8; NONCE_LEN],
+    sequence: u64,
+    pending: Vec<u8>,
+}
+
+impl Sealer {
+    fn new(keys: DirectionKeys) -> Self {
+        Self {
+            cipher: keys.cipher,
+            iv: keys.iv,
+            sequence: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn write<W: Write>(&mut self, writer: &mut W, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.len() == MAX_RECORD_PLAINTEXT {
+            self.seal(writer)?;
+        }
+        let n = buf.len().min(MAX_RECORD_PLAINTEXT - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.seal(writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Write the pending bytes as one record
+    fn seal<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let length = (SEQUENCE_LEN + self.pending.len() + TAG_LEN) as u32;
+        let mut record = Vec::with_capacity(4 + length as usize);
+        record.extend_from_slice(&length.to_be_bytes());
+        record.extend_from_slice(&self.sequence.to_be_bytes());
+        let sealed = self
+            .cipher
+            .seal(&nonce(&self.iv, self.sequence), &record, &self.pending);
+        record.extend_from_slice(&sealed);
+        writer.write_all(&record)?;
+
+        self.sequence = self.sequence.checked_add(1).ok_or_else(|| {
+            io::Error::other("record sequence numbers exhausted; start a new session")
+        })?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Receiving half of a session: opens records in sequence
+struct Opener {
+    cipher: ChaCha20Poly1305,
+    iv: [u8; NONCE_LEN],
+    sequence: u64,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl Opener {
+    fn new(keys: DirectionKeys) -> Self {
+        Self {
+            cipher: keys.cipher,
+            iv: keys.iv,
+            sequence: 0,
+            plaintext: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn read<R: Read>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if !self.open(reader)? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.plaintext.len() - self.position);
+        buf[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+
+    /// Read and open the next record; `false` at a clean end of stream
+    fn open<R: Read>(&mut self, reader: &mut R) -> io::Result<bool> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        if !fill(reader, &mut header)? {
+            return Ok(false);
+        }
+        let tampered = || {
+            record_error(ProtocolError::Tampered {
+                sequence: self.sequence,
+            })
+        };
+
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if !(SEQUENCE_LEN + TAG_LEN..=SEQUENCE_LEN + MAX_RECORD_PLAINTEXT + TAG_LEN)
+            .contains(&length)
+        {
+            return Err(tampered());
+        }
+        let sequence = u64::from_be_bytes(header[4..].try_into().expect("8-byte sequence"));
+        if sequence < self.sequence {
+            return Err(record_error(ProtocolError::Replayed {
+                sequence,
+                expected: self.sequence,
+            }));
+        }
+        if sequence > self.sequence {
+            // Earlier records were removed
+            return Err(tampered());
+        }
+
+        let mut sealed = vec![0u8; length - SEQUENCE_LEN];
+        if !fill(reader, &mut sealed)? {
+            return Err(truncated());
+        }
+        let plaintext = self
+            .cipher
+            .open(&nonce(&self.iv, sequence), &header, &sealed)
+            .ok_or_else(tampered)?;
+
+        self.sequence += 1;
+        self.plaintext = plaintext;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "stream ended inside a sealed record",
+    )
+}
+
+/// Fill `buf`; `false` if the stream ended before its first byte
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(truncated()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+// ============================================================================
+// Streams
+// ============================================================================
+
+/// A transport carrying sealed records after a PSK handshake
+pub struct SecureStream<T> {
+    inner: T,
+    opener: Opener,
+    sealer: Sealer,
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for SecureStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureStream")
+            .field("inner", &self.inner)
+            .field("sent", &self.sealer.sequence)
+            .field("received", &self.opener.sequence)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Read + Write> SecureStream<T> {
+    /// Run the client side of the handshake over `inner`
+    ///
+    /// Fails with [`ProtocolError::HandshakeFailed`] if the server does not
+    /// hold the same `psk`, or if `psk` is shorter than [`MIN_PSK_LEN`].
+    pub fn connect(mut inner: T, psk: &[u8]) -> Result<Self, ProtocolError> {
+        check_psk(psk)?;
+        let client_random = session_random();
+        let mut client_hello = prefix(CLIENT_HELLO).to_vec();
+        client_hello.extend_from_slice(&client_random);
+        inner.write_all(&client_hello)?;
+        inner.flush()?;
+
+        let mut server_hello = [0u8; PREFIX_LEN + RANDOM_LEN + CONFIRM_LEN];
+        read_handshake(&mut inner, SERVER_HELLO, &mut server_hello)?;
+        let (server_part, server_confirm) = server_hello.split_at(PREFIX_LEN + RANDOM_LEN);
+        let transcript = [client_hello.as_slice(), server_part].concat();
+        let keys =
+            KeySchedule::derive(psk, &client_random, &server_part[PREFIX_LEN..], &transcript);
+        if !constant_time_eq(server_confirm, &keys.server_confirm) {
+            return Err(handshake_failed(
+                "server does not hold the same pre-shared key",
+            ));
+        }
+
+        let mut finished = prefix(CLIENT_FINISHED).to_vec();
+        finished.extend_from_slice(&keys.client_confirm);
+        inner.write_all(&finished)?;
+        inner.flush()?;
+
+        Ok(Self {
+            inner,
+            opener: Opener::new(keys.server_to_client),
+            sealer: Sealer::new(keys.client_to_server),
+        })
+    }
+
+    /// Run the server side of the handshake over `inner`
+    ///
+    /// Fails with [`ProtocolError::HandshakeFailed`] if the client does not
+    /// hold the same `psk`, or if `psk` is shorter than [`MIN_PSK_LEN`].
+    pub fn accept(mut inner: T, psk: &[u8]) -> Result<Self, ProtocolError> {
+        check_psk(psk)?;
+        let mut client_hello = [0u8; PREFIX_LEN + RANDOM_LEN];
+        read_handshake(&mut inner, CLIENT_HELLO, &mut client_hello)?;
+
+        let server_random = session_random();
+        let mut server_hello = prefix(SERVER_HELLO).to_vec();
+        server_hello.extend_from_slice(&server_random);
+        let transcript = [client_hello.as_slice(), &server_hello].concat();
+        let keys = KeySchedule::derive(
+            psk,
+            &client_hello[PREFIX_LEN..],
+            &server_random,
+            &transcript,
+        );
+        server_hello.extend_from_slice(&keys.server_confirm);
+        inner.write_all(&server_hello)?;
+        inner.flush()?;
+
+        let mut finished = [0u8; PREFIX_LEN + CONFIRM_LEN];
+        read_handshake(&mut inner, CLIENT_FINISHED, &mut finished)?;
+        if !constant_time_eq(&finished[PREFIX_LEN..], &keys.client_confirm) {
+            return Err(handshake_failed(
+                "client does not hold the same pre-shared key",
+            ));
+        }
+
+        Ok(Self {
+            inner,
+            opener: Opener::new(keys.client_to_server),
+            sealer: Sealer::new(keys.server_to_client),
+        })
+    }
+}
+
+impl<T> SecureStream<T> {
+    /// Get a reference to the underlying transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying transport
+    ///
+    /// Bytes written or read directly bypass the record layer, and are
+    /// rejected by the peer.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read> Read for SecureStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.opener.read(&mut self.inner, buf)
+    }
+}
+
+impl<T: Write> Write for SecureStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sealer.write(&mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sealer.flush(&mut self.inner)
+    }
+}
+
+impl<T: Duplex> Duplex for SecureStream<T> {
+    type Reader = SecureReader<T::Reader>;
+    type Writer = SecureWriter<T::Writer>;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let (reader, writer) = self.inner.split()?;
+        Ok((
+            SecureReader {
+                inner: reader,
+                opener: self.opener,
+            },
+            SecureWriter {
+                inner: writer,
+                sealer: self.sealer,
+            },
+        ))
+    }
+}
+
+/// Reading half of a split [`SecureStream`]
+pub struct SecureReader<R> {
+    inner: R,
+    opener: Opener,
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for SecureReader<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureReader")
+            .field("inner", &self.inner)
+            .field("received", &self.opener.sequence)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> Read for SecureReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.opener.read(&mut self.inner, buf)
+    }
+}
+
+/// Writing half of a split [`SecureStream`]
+pub struct SecureWriter<W> {
+    inner: W,
+    sealer: Sealer,
+}
+
+impl<W: std::fmt::Debug> std::fmt::Debug for SecureWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureWriter")
+            .field("inner", &self.inner)
+            .field("sent", &self.sealer.sequence)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> Write for SecureWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sealer.write(&mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sealer.flush(&mut self.inner)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::transport::{pipe, MemoryStream};
+    use crate::{read_message, write_message, Message};
+
+    const PSK: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn connected() -> (SecureStream<MemoryStream>, SecureStream<MemoryStream>) {
+        let (a, b) = pipe();
+        let server = thread::spawn(move || SecureStream::accept(b, PSK).unwrap());
+        let client = SecureStream::connect(a, PSK).unwrap();
+        (client, server.join().unwrap())
+    }
+
+    fn send(stream: &mut SecureStream<MemoryStream>, data: &[u8]) {
+        stream.write_all(data).unwrap();
+        stream.flush().unwrap();
+    }
+
+    /// Take the next raw record off the wire on the receiving side
+    fn intercept(receiver: &mut SecureStream<MemoryStream>) -> Vec<u8> {
+        let mut length = [0u8; 4];
+        receiver.get_mut().read_exact(&mut length).unwrap();
+        let mut rest = vec![0u8; u32::from_be_bytes(length) as usize];
+        receiver.get_mut().read_exact(&mut rest).unwrap();
+        [length.to_vec(), rest].concat()
+    }
+
+    fn record_error(result: io::Result<usize>) -> ProtocolError {
+        ProtocolError::from(result.unwrap_err())
+    }
+
+    #[test]
+    fn test_frames_round_trip_both_ways() {
+        let (mut client, mut server) = connected();
+        let request = Message::request(1, b"hello");
+        write_message(&mut client, &request).unwrap();
+        client.flush().unwrap();
+        assert_eq!(read_message(&mut server).unwrap(), request);
+
+        let response = Message::response(1, 0, &[7u8; 100_000]);
+        write_message(&mut server, &response).unwrap();
+        server.flush().unwrap();
+        assert_eq!(read_message(&mut client).unwrap(), response);
+    }
+
+    #[test]
+    fn test_wire_carries_no_plaintext() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"attack at dawn");
+        let record = intercept(&mut server);
+        assert_eq!(record.len(), RECORD_HEADER_LEN + 14 + TAG_LEN);
+        assert_eq!(
+            record[..RECORD_HEADER_LEN],
+            [0, 0, 0, 38, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(!record.windows(6).any(|w| w == b"attack"));
+    }
+
+    #[test]
+    fn test_sessions_use_fresh_keys() {
+        let (mut first, mut first_server) = connected();
+        let (mut second, mut second_server) = connected();
+        send(&mut first, b"same plaintext");
+        send(&mut second, b"same plaintext");
+        assert_ne!(intercept(&mut first_server), intercept(&mut second_server));
+    }
+
+    #[test]
+    fn test_wrong_psk_fails_both_sides() {
+        let (a, b) = pipe();
+        let server =
+            thread::spawn(move || SecureStream::accept(b, b"a different key, same length...!"));
+        let client = SecureStream::connect(a, PSK);
+        assert!(
+            matches!(&client, Err(ProtocolError::HandshakeFailed(m)) if m.contains("server")),
+            "{:?}",
+            client
+        );
+        // The client gives up without finishing, so the server sees the close
+        drop(client);
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(ProtocolError::HandshakeFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_plain_peer_is_reported() {
+        let (a, mut b) = pipe();
+        let peer = thread::spawn(move || {
+            let mut hello = [0u8; PREFIX_LEN + RANDOM_LEN];
+            b.read_exact(&mut hello).unwrap();
+            let rejection = Message::error(0, 0x02, "unknown message type");
+            write_message(&mut b, &rejection).unwrap();
+        });
+        let err = SecureStream::connect(a, PSK).unwrap_err();
+        peer.join().unwrap();
+        assert!(
+            matches!(&err, ProtocolError::HandshakeFailed(m) if m.contains("error frame")),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_tampered_record_is_rejected() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"transfer 10");
+        let mut record = intercept(&mut server);
+        let last = record.len() - TAG_LEN - 1;
+        record[last] ^= 0x01;
+        client.get_mut().write_all(&record).unwrap();
+        assert_eq!(
+            record_error(server.read(&mut [0u8; 64])),
+            ProtocolError::Tampered { sequence: 0 }
+        );
+    }
+
+    #[test]
+    fn test_tampered_header_is_rejected() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"transfer 10");
+        let mut record = intercept(&mut server);
+        record[3] -= 1; // shorten the record by one byte
+        client
+            .get_mut()
+            .write_all(&record[..record.len() - 1])
+            .unwrap();
+        assert_eq!(
+            record_error(server.read(&mut [0u8; 64])),
+            ProtocolError::Tampered { sequence: 0 }
+        );
+    }
+
+    #[test]
+    fn test_replayed_record_is_rejected() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"transfer 10");
+        let record = intercept(&mut server);
+        client.get_mut().write_all(&record).unwrap();
+        client.get_mut().write_all(&record).unwrap();
+
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"transfer 10");
+        assert_eq!(
+            record_error(server.read(&mut buf)),
+            ProtocolError::Replayed {
+                sequence: 0,
+                expected: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_dropped_record_is_rejected() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"first");
+        send(&mut client, b"second");
+        intercept(&mut server);
+        assert_eq!(
+            record_error(server.read(&mut [0u8; 64])),
+            ProtocolError::Tampered { sequence: 0 }
+        );
+    }
+
+    #[test]
+    fn test_record_from_other_direction_is_rejected() {
+        let (mut client, mut server) = connected();
+        send(&mut server, b"reflected");
+        let record = intercept(&mut client);
+        // Reflect the server's record back at it
+        client.get_mut().write_all(&record).unwrap();
+        assert_eq!(
+            record_error(server.read(&mut [0u8; 64])),
+            ProtocolError::Tampered { sequence: 0 }
+        );
+    }
+
+    #[test]
+    fn test_cut_record_is_not_a_clean_close() {
+        let (mut client, mut server) = connected();
+        send(&mut client, b"partial");
+        let record = intercept(&mut server);
+        client.get_mut().write_all(&record[..10]).unwrap();
+        drop(client);
+        let err = read_message(&mut server).unwrap_err();
+        assert!(!err.is_clean_eof(), "{:?}", err);
+        assert!(matches!(
+            err,
+            ProtocolError::Io {
+                kind: io::ErrorKind::ConnectionAborted,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_close_between_records_is_clean() {
+        let (client, mut server) = connected();
+        drop(client);
+        assert!(read_message(&mut server).unwrap_err().is_clean_eof());
+    }
+
+    #[test]
+    fn test_split_halves_continue_the_session() {
+        let (mut client, server) = connected();
+        let (mut reader, mut writer) = server.split().unwrap();
+        send(&mut client, b"ping");
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf).unwrap();
+        writer.write_all(b"pong").unwrap();
+        writer.flush().unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn test_nonce_xors_sequence_into_iv() {
+        let iv = [0xAA; NONCE_LEN];
+        assert_eq!(nonce(&iv, 0), iv);
+        assert_eq!(nonce(&iv, 0x0102)[10..], [0xAB, 0xA8]);
+        assert_eq!(nonce(&iv, 0x0102)[..4], [0xAA; 4]);
+    }
+
+    #[test]
+    fn test_short_psk_is_refused() {
+        let (a, b) = pipe();
+        let err = SecureStream::connect(a, b"short").unwrap_err();
+        assert!(
+            matches!(&err, ProtocolError::HandshakeFailed(m) if m.contains("at least 16 bytes")),
+            "{}",
+            err
+        );
+        assert!(matches!(
+            SecureStream::accept(b, b"short"),
+            Err(ProtocolError::HandshakeFailed(_))
+        ));
+    }
+}
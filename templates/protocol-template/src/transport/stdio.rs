@@ -0,0 +1,149 @@
+//! stdin/stdout transports
+//!
+//! Tools that run as child processes speak the protocol over their standard
+//! streams, LSP-style: the parent writes frames to the child's stdin and
+//! reads frames from its stdout. stderr is left alone for logging.
+
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio as ProcessStdio};
+
+/// The current process's stdin and stdout, as seen by a child tool
+///
+/// Every write is flushed explicitly, so each frame is delivered as soon as
+/// it is written and never depends on stdout's line buffering.
+#[derive(Debug)]
+pub struct Stdio {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl Stdio {
+    /// Use this process's stdin and stdout
+    pub fn new() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Duplex for Stdio {
+    type Reader = Stdio;
+    type Writer = Stdio;
+
+    /// Both halves share the process-wide handles; use one for reading and
+    /// the other for writing
+    fn split(self) -> io::Result<(Stdio, Stdio)> {
+        Ok((self, Stdio::new()))
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.lock().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = self.stdout.lock();
+        let n = out.write(buf)?;
+        out.flush()?;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let mut out = self.stdout.lock();
+        let n = out.write_vectored(bufs)?;
+        out.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+/// A spawned child process reached through its stdin and stdout
+///
+/// Dropping the transport closes the child's stdin and kills the child if
+/// it has not exited; use [`close`](Self::close) to wait for a graceful exit.
+#[derive(Debug)]
+pub struct ChildProcess {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl ChildProcess {
+    /// Spawn `command` with piped stdin/stdout (stderr is inherited)
+    pub fn spawn(mut command: Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(ProcessStdio::piped())
+            .stdout(ProcessStdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self {
+            child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+
+    /// OS process ID of the child
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Close the child's stdin (signalling end of input) and wait for it to
+    /// exit
+    pub fn close(mut self) -> io::Result<ExitStatus> {
+        self.stdin.take();
+        self.child.wait()
+    }
+}
+
+impl Read for ChildProcess {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for ChildProcess {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin_mut()?.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.stdin_mut()?.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin_mut()?.flush()
+    }
+}
+
+impl ChildProcess {
+    fn stdin_mut(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "child stdin closed"))
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        self.stdin.take();
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
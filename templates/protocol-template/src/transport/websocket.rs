@@ -0,0 +1,1090 @@
+//! WebSocket transport (RFC 6455)
+//!
+//! [`WebSocketStream`] carries the frame stream inside binary WebSocket
+//! messages, so a browser can talk to a server directly (SPEC.md Section
+//! 4.1). [`connect`](WebSocketStream::connect) and
+//! [`accept`](WebSocketStream::accept) run the HTTP/1.1 upgrade handshake,
+//! in which the server proves it understood the request by hashing the
+//! client's random key with SHA-1.
+//!
+//! Writes are buffered until [`flush`](Write::flush), which sends them as
+//! one binary message; the client and server flush after every frame, so
+//! each protocol frame arrives in a message of its own. Reads return the
+//! payload of the binary messages received as one continuous stream,
+//! however the sender fragmented them.
+//!
+//! As RFC 6455 requires, the client masks every frame it sends with a fresh
+//! unpredictable key and the server sends its frames unmasked. A frame
+//! masked the wrong way, a text message or any other violation is reported
+//! as [`ProtocolError::WebSocket`]. Pings are answered with pongs as they
+//! are read, and a close frame is answered with one and then reads as the
+//! end of the stream. [`ping`](WebSocketStream::ping) and
+//! [`close`](WebSocketStream::close) send those frames.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use std::io::{Read, Write};
+//! use std::thread;
+//! use protocol_name::transport::{pipe, WebSocketStream};
+//!
+//! let (a, b) = pipe();
+//! let server = thread::spawn(move || {
+//!     let mut stream = WebSocketStream::accept(b).unwrap();
+//!     let mut buf = [0u8; 5];
+//!     stream.read_exact(&mut buf).unwrap();
+//!     buf
+//! });
+//!
+//! let mut stream = WebSocketStream::connect(a, "localhost", "/").unwrap();
+//! stream.write_all(b"hello").unwrap();
+//! stream.flush().unwrap();
+//! assert_eq!(&server.join().unwrap(), b"hello");
+//! ```
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{session_random, Duplex};
+use crate::crypto::{chacha20_block, sha1, KEY_LEN, NONCE_LEN};
+use crate::ProtocolError;
+
+/// Appended to the client's key before hashing it (RFC 6455 Section 1.3)
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake request or response accepted, in bytes
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+/// Longest payload of a control frame (RFC 6455 Section 5.5)
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const RESERVED: u8 = 0x70;
+const MASKED: u8 = 0x80;
+
+/// Close status of an orderly shutdown (RFC 6455 Section 7.4.1)
+const NORMAL_CLOSURE: u16 = 1000;
+
+/// `Sec-WebSocket-Accept` value answering the client key `key`
+fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(&[key.as_bytes(), GUID.as_bytes()].concat()))
+}
+
+fn violation(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        ProtocolError::WebSocket(reason.to_string()),
+    )
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "stream ended inside a WebSocket frame",
+    )
+}
+
+// ============================================================================
+// Base64 (RFC 4648 Section 4)
+// ============================================================================
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, &b| n << 8 | u32::from(b)) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let padding = bytes.iter().rev().take_while(|&&b| b == b'=').count();
+    if !bytes.len().is_multiple_of(4) || padding > 2 {
+        return None;
+    }
+    let mut out = Vec::new();
+    for chunk in bytes[..bytes.len() - padding].chunks(4) {
+        let mut n = 0u32;
+        for &b in chunk {
+            n = n << 6 | BASE64.iter().position(|&c| c == b)? as u32;
+        }
+        n <<= 6 * (4 - chunk.len());
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+// ============================================================================
+// Handshake (RFC 6455 Section 4)
+// ============================================================================
+
+/// Read an HTTP head up to its blank line, a byte at a time so that no
+/// byte of the first frame is consumed
+fn read_head<R: Read>(reader: &mut R) -> Result<Vec<String>, ProtocolError> {
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HANDSHAKE_SIZE {
+            return Err(ProtocolError::WebSocket("handshake too large".to_string()));
+        }
+        match reader.read_exact(&mut byte) {
+            Ok(()) => head.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(ProtocolError::WebSocket(
+                    "connection closed during the handshake".to_string(),
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let head = String::from_utf8(head)
+        .map_err(|_| ProtocolError::WebSocket("handshake is not UTF-8".to_string()))?;
+    Ok(head
+        .split("\r\n")
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Value of the header `name`, if present
+fn header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head[1..].iter().find_map(|line| {
+        let (n, value) = line.split_once(':')?;
+        n.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Whether the comma-separated header `name` lists `token`
+fn has_token(head: &[String], name: &str, token: &str) -> bool {
+    header(head, name).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// Check an upgrade request, returning the client's key, or the status
+/// line and headers to refuse it with and the reason
+fn check_upgrade(head: &[String]) -> Result<&str, (&'static str, String)> {
+    let refuse = |reason: &str| ("400 Bad Request", reason.to_string());
+    let mut request_line = head[0].split(' ');
+    if request_line.next() != Some("GET") || request_line.nth(1) != Some("HTTP/1.1") {
+        return Err(refuse("expected a GET request over HTTP/1.1"));
+    }
+    if !has_token(head, "Upgrade", "websocket") || !has_token(head, "Connection", "Upgrade") {
+        return Err(refuse("not a WebSocket upgrade"));
+    }
+    if header(head, "Sec-WebSocket-Version") != Some("13") {
+        return Err((
+            "426 Upgrade Required\r\nSec-WebSocket-Version: 13",
+            "unsupported WebSocket version".to_string(),
+        ));
+    }
+    match header(head, "Sec-WebSocket-Key") {
+        Some(key) if base64_decode(key).is_some_and(|nonce| nonce.len() == 16) => Ok(key),
+        _ => Err(refuse("missing or malformed Sec-WebSocket-Key")),
+    }
+}
+
+// ============================================================================
+// Framing (RFC 6455 Section 5)
+// ============================================================================
+
+/// Unpredictable masking keys, drawn from a ChaCha20 keystream under a
+/// random key
+struct Masks {
+    key: [u8; KEY_LEN],
+    counter: u32,
+    block: [u8; 64],
+    used: usize,
+}
+
+impl Masks {
+    fn new() -> Self {
+        Self {
+            key: session_random(),
+            counter: 0,
+            block: [0; 64],
+            used: 64,
+        }
+    }
+
+    fn next(&mut self) -> [u8; 4] {
+        if self.used == self.block.len() {
+            if self.counter == u32::MAX {
+                *self = Self::new();
+            }
+            self.block = chacha20_block(&self.key, self.counter, &[0; NONCE_LEN]);
+            self.counter += 1;
+            self.used = 0;
+        }
+        let mask = [
+            self.block[self.used],
+            self.block[self.used + 1],
+            self.block[self.used + 2],
+            self.block[self.used + 3],
+        ];
+        self.used += 4;
+        mask
+    }
+}
+
+/// XOR `data`, starting `offset` bytes into a frame payload, with `mask`
+fn apply_mask(mask: [u8; 4], offset: u64, data: &mut [u8]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[((offset + i as u64) % 4) as usize];
+    }
+}
+
+/// Frames outgoing messages, masking them on the client side
+struct Sender {
+    /// Present on the client side
+    masks: Option<Masks>,
+    /// Set once a close frame has been sent
+    closed: bool,
+}
+
+impl Sender {
+    fn new(client: bool) -> Self {
+        Self {
+            masks: client.then(Masks::new),
+            closed: false,
+        }
+    }
+
+    /// Send one unfragmented frame
+    fn send<W: Write>(&mut self, writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "WebSocket already closed",
+            ));
+        }
+        let masked = if self.masks.is_some() { MASKED } else { 0 };
+        let mut frame = Vec::with_capacity(14 + payload.len());
+        frame.push(FIN | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(masked | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(masked | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(masked | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = frame.len();
+        frame.extend_from_slice(payload);
+        if let Some(masks) = &mut self.masks {
+            let mask = masks.next();
+            frame.splice(start..start, mask);
+            apply_mask(mask, 0, &mut frame[start + 4..]);
+        }
+        self.closed = opcode == CLOSE;
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    /// Answer a control frame read by a [`Receiver`]
+    fn answer<W: Write>(&mut self, writer: &mut W, control: Control) -> io::Result<()> {
+        match control {
+            // No pongs once closing
+            Control::Ping(payload) if !self.closed => self.send(writer, PONG, &payload),
+            // Echo the status code (RFC 6455 Section 5.5.1)
+            Control::Close(payload) if !self.closed => {
+                self.send(writer, CLOSE, &payload[..payload.len().min(2)])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn send_message<W: Write>(&mut self, writer: &mut W, pending: &mut Vec<u8>) -> io::Result<()> {
+        if !pending.is_empty() {
+            self.send(writer, BINARY, pending)?;
+            pending.clear();
+        }
+        writer.flush()
+    }
+}
+
+/// A control frame to answer
+enum Control {
+    Ping(Vec<u8>),
+    Close(Vec<u8>),
+}
+
+/// What a read produced
+enum Received {
+    /// Payload bytes, or 0 at the end of the stream
+    Data(usize),
+    Control(Control),
+}
+
+/// Parses incoming frames
+struct Receiver {
+    /// Whether frames must be masked: true on the server side
+    expect_masked: bool,
+    /// Payload bytes left in the current data frame
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    /// Payload bytes of the current data frame already read
+    offset: u64,
+    /// Whether a fragmented message is in progress
+    fragmented: bool,
+    /// Set once a close frame has been received
+    closed: bool,
+}
+
+impl Receiver {
+    fn new(expect_masked: bool) -> Self {
+        Self {
+            expect_masked,
+            remaining: 0,
+            mask: None,
+            offset: 0,
+            fragmented: false,
+            closed: false,
+        }
+    }
+
+    fn read<R: Read>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<Received> {
+        if buf.is_empty() || self.closed {
+            return Ok(Received::Data(0));
+        }
+        while self.remaining == 0 {
+            let mut first = [0u8];
+            // The connection closing between frames ends the stream
+            if reader.read(&mut first)? == 0 {
+                return Ok(Received::Data(0));
+            }
+            if let Some(control) = self.read_header(reader, first[0])? {
+                return Ok(Received::Control(control));
+            }
+        }
+        let want = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = reader.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(truncated());
+        }
+        if let Some(mask) = self.mask {
+            apply_mask(mask, self.offset, &mut buf[..n]);
+        }
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(Received::Data(n))
+    }
+
+    /// Read the rest of a frame header, and the payload of a control frame
+    fn read_header<R: Read>(&mut self, reader: &mut R, first: u8) -> io::Result<Option<Control>> {
+        let (fin, opcode) = (first & FIN != 0, first & 0x0F);
+        if first & RESERVED != 0 {
+            return Err(violation("reserved bits set without an extension"));
+        }
+        let mut second = [0u8];
+        read_in_frame(reader, &mut second)?;
+        if (second[0] & MASKED != 0) != self.expect_masked {
+            return Err(violation(if self.expect_masked {
+                "client frame not masked"
+            } else {
+                "server frame masked"
+            }));
+        }
+        let length = match second[0] & 0x7F {
+            126 => {
+                let mut length = [0u8; 2];
+                read_in_frame(reader, &mut length)?;
+                u64::from(u16::from_be_bytes(length))
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                read_in_frame(reader, &mut length)?;
+                let length = u64::from_be_bytes(length);
+                if length >> 63 != 0 {
+                    return Err(violation("frame length out of range"));
+                }
+                length
+            }
+            length => u64::from(length),
+        };
+        let mask = if self.expect_masked {
+            let mut mask = [0u8; 4];
+            read_in_frame(reader, &mut mask)?;
+            Some(mask)
+        } else {
+            None
+        };
+
+        match opcode {
+            BINARY | CONTINUATION => {
+                if (opcode == CONTINUATION) != self.fragmented {
+                    return Err(violation(if self.fragmented {
+                        "new message inside a fragmented one"
+                    } else {
+                        "continuation frame outside a message"
+                    }));
+                }
+                self.fragmented = !fin;
+                self.remaining = length;
+                self.mask = mask;
+                self.offset = 0;
+                Ok(None)
+            }
+            TEXT => Err(violation("text message; frames travel in binary messages")),
+            CLOSE | PING | PONG => {
+                if !fin || length > MAX_CONTROL_PAYLOAD as u64 {
+                    return Err(violation(
+                        "control frame fragmented or longer than 125 bytes",
+                    ));
+                }
+                let mut payload = vec![0u8; length as usize];
+                read_in_frame(reader, &mut payload)?;
+                if let Some(mask) = mask {
+                    apply_mask(mask, 0, &mut payload);
+                }
+                match opcode {
+                    PING => Ok(Some(Control::Ping(payload))),
+                    CLOSE => {
+                        self.closed = true;
+                        Ok(Some(Control::Close(payload)))
+                    }
+                    _ => Ok(None),
+                }
+            }
+            _ => Err(violation("unknown opcode")),
+        }
+    }
+}
+
+fn read_in_frame<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(),
+        _ => e,
+    })
+}
+
+// ============================================================================
+// Streams
+// ============================================================================
+
+/// A transport carrying the frame stream in WebSocket binary messages
+pub struct WebSocketStream<T> {
+    inner: T,
+    receiver: Receiver,
+    sender: Sender,
+    /// Written bytes not yet sent
+    pending: Vec<u8>,
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for WebSocketStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketStream")
+            .field("inner", &self.inner)
+            .field("client", &self.sender.masks.is_some())
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Read + Write> WebSocketStream<T> {
+    /// Run the client side of the handshake over `inner`, asking the
+    /// server `host` to upgrade the request for `path`
+    ///
+    /// Fails with [`ProtocolError::WebSocket`] if the server refuses or
+    /// answers the key wrongly.
+    pub fn connect(inner: T, host: &str, path: &str) -> Result<Self, ProtocolError> {
+        let nonce = session_random();
+        Self::connect_with_key(inner, host, path, &base64_encode(&nonce[..16]))
+    }
+
+    fn connect_with_key(
+        mut inner: T,
+        host: &str,
+        path: &str,
+        key: &str,
+    ) -> Result<Self, ProtocolError> {
+        let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", path, host);
+        let _ = write!(
+            request,
+            concat!(
+                "Upgrade: websocket\r\nConnection: Upgrade\r\n",
+                "Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            ),
+            key
+        );
+        inner.write_all(request.as_bytes())?;
+        inner.flush()?;
+
+        let head = read_head(&mut inner)?;
+        let mut status_line = head[0].split(' ');
+        if status_line.next() != Some("HTTP/1.1") || status_line.next() != Some("101") {
+            return Err(ProtocolError::WebSocket(format!(
+                "server refused the upgrade: {}",
+                head[0]
+            )));
+        }
+        if !has_token(&head, "Upgrade", "websocket") || !has_token(&head, "Connection", "Upgrade") {
+            return Err(ProtocolError::WebSocket(
+                "server did not switch to WebSocket".to_string(),
+            ));
+        }
+        if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(key).as_str()) {
+            return Err(ProtocolError::WebSocket(
+                "server answered with the wrong accept key".to_string(),
+            ));
+        }
+        Ok(Self::new(inner, true))
+    }
+
+    /// Run the server side of the handshake over `inner`
+    ///
+    /// A request that is not a valid upgrade is refused with an HTTP error
+    /// and reported as [`ProtocolError::WebSocket`].
+    pub fn accept(mut inner: T) -> Result<Self, ProtocolError> {
+        let head = read_head(&mut inner)?;
+        let response = match check_upgrade(&head) {
+            Ok(key) => format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            ),
+            Err((status, reason)) => {
+                // Best effort: the connection is given up either way
+                let refusal = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = inner
+                    .write_all(refusal.as_bytes())
+                    .and_then(|()| inner.flush());
+                return Err(ProtocolError::WebSocket(reason));
+            }
+        };
+        inner.write_all(response.as_bytes())?;
+        inner.flush()?;
+        Ok(Self::new(inner, false))
+    }
+
+    fn new(inner: T, client: bool) -> Self {
+        Self {
+            inner,
+            receiver: Receiver::new(!client),
+            sender: Sender::new(client),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Send a ping carrying `payload`; the peer answers with a pong
+    ///
+    /// Fails if `payload` is longer than 125 bytes.
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ping payload longer than 125 bytes",
+            ));
+        }
+        self.sender.send(&mut self.inner, PING, payload)
+    }
+
+    /// Send the buffered bytes, then a close frame
+    ///
+    /// Reads continue until the peer's answering close frame, which ends
+    /// the stream; writes fail from now on.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.sender
+            .send_message(&mut self.inner, &mut self.pending)?;
+        self.sender
+            .send(&mut self.inner, CLOSE, &NORMAL_CLOSURE.to_be_bytes())
+    }
+}
+
+impl<T> WebSocketStream<T> {
+    /// Get a reference to the underlying transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying transport
+    ///
+    /// Bytes written or read directly bypass the WebSocket framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read + Write> Read for WebSocketStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.receiver.read(&mut self.inner, buf)? {
+                Received::Data(n) => return Ok(n),
+                Received::Control(control) => self.sender.answer(&mut self.inner, control)?,
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for WebSocketStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sender.send_message(&mut self.inner, &mut self.pending)
+    }
+}
+
+impl<T: Duplex> Duplex for WebSocketStream<T> {
+    type Reader = WebSocketReader<T::Reader, T::Writer>;
+    type Writer = WebSocketWriter<T::Writer>;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let (reader, writer) = self.inner.split()?;
+        // The reading half answers pings and closes through the writer
+        let shared = Arc::new(Mutex::new(Shared {
+            writer,
+            sender: self.sender,
+        }));
+        Ok((
+            WebSocketReader {
+                inner: reader,
+                receiver: self.receiver,
+                shared: Arc::clone(&shared),
+            },
+            WebSocketWriter {
+                shared,
+                pending: self.pending,
+            },
+        ))
+    }
+}
+
+/// The writing side shared by the halves of a split stream
+struct Shared<W> {
+    writer: W,
+    sender: Sender,
+}
+
+fn lock<W>(shared: &Mutex<Shared<W>>) -> MutexGuard<'_, Shared<W>> {
+    // A panicking holder cannot leave a frame half-built: frames are
+    // written with a single call
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reading half of a split [`WebSocketStream`]
+pub struct WebSocketReader<R, W> {
+    inner: R,
+    receiver: Receiver,
+    shared: Arc<Mutex<Shared<W>>>,
+}
+
+impl<R: std::fmt::Debug, W> std::fmt::Debug for WebSocketReader<R, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketReader")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read, W: Write> Read for WebSocketReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.receiver.read(&mut self.inner, buf)? {
+                Received::Data(n) => return Ok(n),
+                Received::Control(control) => {
+                    let shared = &mut *lock(&self.shared);
+                    shared.sender.answer(&mut shared.writer, control)?;
+                }
+            }
+        }
+    }
+}
+
+/// Writing half of a split [`WebSocketStream`]
+pub struct WebSocketWriter<W> {
+    shared: Arc<Mutex<Shared<W>>>,
+    pending: Vec<u8>,
+}
+
+impl<W> std::fmt::Debug for WebSocketWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocketWriter")
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> Write for WebSocketWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let shared = &mut *lock(&self.shared);
+        shared
+            .sender
+            .send_message(&mut shared.writer, &mut self.pending)
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use crate::transport::{pipe, MemoryStream};
+    use crate::{read_message, write_message, Message};
+
+    /// The client handshake of RFC 6455 Section 1.2
+    const SAMPLE_REQUEST: &str = "GET /chat HTTP/1.1\r\n\
+        Host: server.example.com\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Origin: http://example.com\r\n\
+        Sec-WebSocket-Protocol: chat, superchat\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    /// The server's answer, without choosing a subprotocol
+    const SAMPLE_RESPONSE: &str = "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+    fn connected() -> (WebSocketStream<MemoryStream>, WebSocketStream<MemoryStream>) {
+        let (a, b) = pipe();
+        let server = thread::spawn(move || WebSocketStream::accept(b).unwrap());
+        let client = WebSocketStream::connect(a, "localhost", "/").unwrap();
+        (client, server.join().unwrap())
+    }
+
+    fn read_raw(stream: &mut MemoryStream, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        stream.read_exact(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn websocket_error(error: io::Error) -> String {
+        match ProtocolError::from(error) {
+            ProtocolError::WebSocket(reason) => reason,
+            other => panic!("expected a WebSocket error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_accept_key_rfc6455() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_base64_rfc4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (plain, encoded) in vectors {
+            assert_eq!(base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(
+            base64_decode("dGhlIHNhbXBsZSBub25jZQ==").unwrap(),
+            b"the sample nonce"
+        );
+        for bad in ["Zg=", "Zg===", "Z=g=", "Zm9v!A==", "===="] {
+            assert_eq!(base64_decode(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_server_answers_the_rfc_sample_handshake_on_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = WebSocketStream::accept(listener.accept().unwrap().0).unwrap();
+            let mut hello = [0u8; 5];
+            stream.read_exact(&mut hello).unwrap();
+            stream.write_all(&hello).unwrap();
+            stream.flush().unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(SAMPLE_REQUEST.as_bytes()).unwrap();
+        let mut response = vec![0u8; SAMPLE_RESPONSE.len()];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), SAMPLE_RESPONSE);
+
+        // RFC 6455 Section 5.7: a masked "Hello", here in a binary frame
+        client
+            .write_all(&[
+                0x82, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ])
+            .unwrap();
+        let mut echoed = [0u8; 7];
+        client.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"\x82\x05Hello");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_client_completes_the_rfc_sample_handshake_on_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let head = read_head(&mut stream).unwrap();
+            assert_eq!(head[0], "GET /chat HTTP/1.1");
+            assert_eq!(header(&head, "Host"), Some("server.example.com"));
+            assert_eq!(header(&head, "Sec-WebSocket-Version"), Some("13"));
+            assert_eq!(check_upgrade(&head).unwrap(), "dGhlIHNhbXBsZSBub25jZQ==");
+            stream.write_all(SAMPLE_RESPONSE.as_bytes()).unwrap();
+
+            // Unmasked from the server, masked from the client
+            stream.write_all(&[0x82, 0x05]).unwrap();
+            stream.write_all(b"Hello").unwrap();
+            let mut frame = [0u8; 11];
+            stream.read_exact(&mut frame).unwrap();
+            assert_eq!(frame[..2], [0x82, 0x85]);
+            let mask = [frame[2], frame[3], frame[4], frame[5]];
+            let mut payload = frame[6..].to_vec();
+            apply_mask(mask, 0, &mut payload);
+            assert_eq!(payload, b"Hello");
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = WebSocketStream::connect_with_key(
+            stream,
+            "server.example.com",
+            "/chat",
+            "dGhlIHNhbXBsZSBub25jZQ==",
+        )
+        .unwrap();
+        let mut hello = [0u8; 5];
+        client.read_exact(&mut hello).unwrap();
+        client.write_all(&hello).unwrap();
+        client.flush().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_frames_round_trip_both_ways() {
+        let (mut client, mut server) = connected();
+        let request = Message::request(1, b"hello");
+        write_message(&mut client, &request).unwrap();
+        client.flush().unwrap();
+        assert_eq!(read_message(&mut server).unwrap(), request);
+
+        let response = Message::response(1, 0, &[7u8; 100_000]);
+        write_message(&mut server, &response).unwrap();
+        server.flush().unwrap();
+        assert_eq!(read_message(&mut client).unwrap(), response);
+    }
+
+    #[test]
+    fn test_payload_lengths_rfc6455() {
+        let (mut client, mut server) = connected();
+        // RFC 6455 Section 5.7: 256 bytes and 64 KiB in unmasked binary frames
+        let cases = [
+            (256, vec![0x82, 0x7E, 0x01, 0x00]),
+            (65536, vec![0x82, 0x7F, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ];
+        for (len, header) in cases {
+            server.write_all(&vec![0xAB; len]).unwrap();
+            server.flush().unwrap();
+            assert_eq!(read_raw(client.get_mut(), header.len()), header);
+            assert_eq!(read_raw(client.get_mut(), len), vec![0xAB; len]);
+        }
+    }
+
+    #[test]
+    fn test_client_frames_are_masked_with_fresh_keys() {
+        let (mut client, mut server) = connected();
+        for _ in 0..2 {
+            client.write_all(b"attack at dawn").unwrap();
+            client.flush().unwrap();
+        }
+        let first = read_raw(server.get_mut(), 2 + 4 + 14);
+        let second = read_raw(server.get_mut(), 2 + 4 + 14);
+        assert_eq!(first[..2], [0x82, 0x80 | 14]);
+        assert_ne!(first[2..6], second[2..6]);
+        assert!(!first.windows(6).any(|w| w == b"attack"));
+    }
+
+    #[test]
+    fn test_fragments_and_pings_between_them() {
+        let (a, mut raw) = pipe();
+        let server = thread::spawn(move || WebSocketStream::accept(a).unwrap());
+        raw.write_all(SAMPLE_REQUEST.as_bytes()).unwrap();
+        let mut server = {
+            read_raw(&mut raw, SAMPLE_RESPONSE.len());
+            server.join().unwrap()
+        };
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let masked = |first: u8, payload: &[u8]| {
+            let mut frame = vec![first, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            let mut payload = payload.to_vec();
+            apply_mask(mask, 0, &mut payload);
+            frame.extend(payload);
+            frame
+        };
+        // "Hel", a ping, then "lo" (RFC 6455 Section 5.7, in binary)
+        raw.write_all(&masked(BINARY, b"Hel")).unwrap();
+        raw.write_all(&masked(FIN | PING, b"Hello")).unwrap();
+        raw.write_all(&masked(FIN | CONTINUATION, b"lo")).unwrap();
+        let mut hello = [0u8; 5];
+        server.read_exact(&mut hello).unwrap();
+        assert_eq!(&hello, b"Hello");
+        assert_eq!(read_raw(&mut raw, 7), b"\x8a\x05Hello");
+
+        // Closing: the status is echoed and the stream ends
+        raw.write_all(&masked(FIN | CLOSE, &NORMAL_CLOSURE.to_be_bytes()))
+            .unwrap();
+        assert_eq!(server.read(&mut hello).unwrap(), 0);
+        assert_eq!(read_raw(&mut raw, 4), [0x88, 0x02, 0x03, 0xE8]);
+        assert_eq!(
+            server
+                .write_all(b"late")
+                .and_then(|()| server.flush())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn test_ping_and_close_from_the_client() {
+        let (mut client, server) = connected();
+        let (mut reader, mut writer) = server.split().unwrap();
+        let echo = thread::spawn(move || {
+            let message = read_message(&mut reader).unwrap();
+            write_message(&mut writer, &message).unwrap();
+            writer.flush().unwrap();
+            // The close frame ends the stream, and is answered
+            assert!(read_message(&mut reader).unwrap_err().is_clean_eof());
+        });
+        client.ping(b"are you there").unwrap();
+        write_message(&mut client, &Message::request(9, b"hi")).unwrap();
+        client.flush().unwrap();
+        // The pong is absorbed while reading
+        assert_eq!(
+            read_message(&mut client).unwrap(),
+            Message::request(9, b"hi")
+        );
+        client.close().unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        echo.join().unwrap();
+        assert!(client.ping(&[0u8; 126]).is_err());
+    }
+
+    #[test]
+    fn test_violations_are_reported() {
+        let cases: [(&[u8], &str); 6] = [
+            (
+                &[0x82, 0x05, b'H', b'e', b'l', b'l', b'o'],
+                "client frame not masked",
+            ),
+            (&[0x81, 0x80, 0, 0, 0, 0], "text message"),
+            (&[0xC2, 0x80, 0, 0, 0, 0], "reserved bits"),
+            (&[0x80, 0x80, 0, 0, 0, 0], "continuation frame outside"),
+            (&[0x09, 0x80, 0, 0, 0, 0], "control frame"),
+            (&[0x83, 0x80, 0, 0, 0, 0], "unknown opcode"),
+        ];
+        for (frame, reason) in cases {
+            let (a, mut raw) = pipe();
+            let server = thread::spawn(move || WebSocketStream::accept(a).unwrap());
+            raw.write_all(SAMPLE_REQUEST.as_bytes()).unwrap();
+            raw.write_all(frame).unwrap();
+            let mut server = server.join().unwrap();
+            let error = websocket_error(server.read(&mut [0u8; 8]).unwrap_err());
+            assert!(error.contains(reason), "{:?}: {}", frame, error);
+        }
+
+        // A client refuses masked frames from the server
+        let (mut client, mut server) = connected();
+        server
+            .get_mut()
+            .write_all(&[0x82, 0x81, 0, 0, 0, 0, 0])
+            .unwrap();
+        assert!(websocket_error(client.read(&mut [0u8; 8]).unwrap_err())
+            .contains("server frame masked"));
+    }
+
+    #[test]
+    fn test_bad_upgrades_are_refused() {
+        let cases = [
+            ("POST / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n", "400 Bad Request"),
+            ("GET / HTTP/1.1\r\nHost: x\r\n\r\n", "400 Bad Request"),
+            (
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\n\r\n",
+                "426 Upgrade Required\r\nSec-WebSocket-Version: 13",
+            ),
+            (
+                "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                 Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n",
+                "400 Bad Request",
+            ),
+        ];
+        for (request, status) in cases {
+            let (a, mut raw) = pipe();
+            let server = thread::spawn(move || WebSocketStream::accept(a));
+            raw.write_all(request.as_bytes()).unwrap();
+            assert!(matches!(
+                server.join().unwrap(),
+                Err(ProtocolError::WebSocket(_))
+            ));
+            let mut response = String::new();
+            raw.read_to_string(&mut response).unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}\r\n", status)),
+                "{}",
+                response
+            );
+        }
+
+        // A client refuses a server that answers the key wrongly
+        let (a, mut raw) = pipe();
+        let client = thread::spawn(move || WebSocketStream::connect(a, "localhost", "/"));
+        read_head(&mut raw).unwrap();
+        raw.write_all(SAMPLE_RESPONSE.as_bytes()).unwrap();
+        let error = client.join().unwrap().unwrap_err();
+        assert!(
+            matches!(&error, ProtocolError::WebSocket(m) if m.contains("accept key")),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Spec-file consistency tests
+//!
+//! `protocol.spec` is the single source of truth for the wire format. These
+//! tests fail whenever the reference codec, SPEC.md or the generated codec
+//! drift away from it.
+
+use protocol_name::codegen::{Expected, ProtocolSpec};
+use protocol_name::deadline::{self, RequestOptions};
+use protocol_name::{
+    compress, decode, encode, interop, ErrorCode, Message, MessageType, ProtocolError,
+};
+
+#[path = "generated/codec.rs"]
+#[allow(dead_code)]
+#[rustfmt::skip]
+mod generated;
+
+const SPEC_SOURCE: &str = include_str!("../protocol.spec");
+
+fn spec() -> ProtocolSpec {
+    ProtocolSpec::parse(SPEC_SOURCE).expect("protocol.spec should parse")
+}
+
+fn error_variant(err: &ProtocolError) -> &'static str {
+    match err {
+        ProtocolError::InvalidMagic { .. } => "InvalidMagic",
+        ProtocolError::UnsupportedVersion { .. } => "UnsupportedVersion",
+        ProtocolError::UnknownType { .. } => "UnknownType",
+        ProtocolError::PayloadTooLarge { .. } => "PayloadTooLarge",
+        ProtocolError::UnexpectedFlag { .. } => "UnexpectedFlag",
+        ProtocolError::IncompleteMessage { .. } => "IncompleteMessage",
+        ProtocolError::InvalidPayload(_) => "InvalidPayload",
+        ProtocolError::TrailingBytes(_) => "TrailingBytes",
+        ProtocolError::WindowExhausted { .. } => "WindowExhausted",
+        ProtocolError::DuplicateId(_) => "DuplicateId",
+        ProtocolError::UnknownMethod(_) => "UnknownMethod",
+        ProtocolError::UnknownSession(_) => "UnknownSession",
+        ProtocolError::DeadlineExceeded(_) => "DeadlineExceeded",
+        ProtocolError::Rejected { .. } => "Rejected",
+        ProtocolError::UnexpectedResponse { .. } => "UnexpectedResponse",
+        ProtocolError::Tampered { .. } => "Tampered",
+        ProtocolError::Replayed { .. } => "Replayed",
+        ProtocolError::HandshakeFailed(_) => "HandshakeFailed",
+        ProtocolError::WebSocket(_) => "WebSocket",
+        ProtocolError::Io { .. } => "Io",
+    }
+}
+
+/// The options block's budget as sent, `0xFFFFFFFF` meaning no deadline
+fn budget_field(options: &RequestOptions) -> u32 {
+    options.budget_ms().unwrap_or(u32::MAX)
+}
+
+// ============================================================================
+// Spec vs. Reference Implementation
+// ============================================================================
+
+#[test]
+fn spec_constants_match_reference() {
+    let spec = spec();
+    assert_eq!(spec.magic, protocol_name::MAGIC);
+    assert_eq!(spec.version, protocol_name::VERSION);
+    assert_eq!(spec.max_payload, protocol_name::MAX_PAYLOAD_SIZE);
+    assert_eq!(spec.max_header_size(), protocol_name::MAX_HEADER_SIZE);
+
+    for t in &spec.message_types {
+        let parsed = MessageType::try_from(t.value).expect("spec type should be known");
+        assert_eq!(format!("{:?}", parsed), t.name);
+    }
+
+    for e in &spec.error_codes {
+        let code = ErrorCode::from_u8(e.code).expect("spec error code should be known");
+        assert_eq!(format!("{:?}", code), e.name);
+    }
+
+    for flag in &spec.flags {
+        let reference = match flag.name.as_str() {
+            "compressed" => compress::COMPRESSED,
+            "options" => deadline::OPTIONS,
+            other => panic!("spec flag `{}` has no counterpart in the reference", other),
+        };
+        assert_eq!(flag.mask(), u64::from(reference), "{}", flag.name);
+    }
+}
+
+#[test]
+fn spec_vectors_decode_with_reference() {
+    for vector in spec().vectors() {
+        match (&vector.expected, decode(&vector.bytes)) {
+            (
+                Expected::Message {
+                    message_type,
+                    fields,
+                    payload,
+                },
+                Ok(message),
+            ) => {
+                assert_eq!(
+                    &format!("{:?}", message.message_type),
+                    message_type,
+                    "{}",
+                    vector.name
+                );
+                for (name, value) in fields {
+                    let actual = match name.as_str() {
+                        "id" => Some(u64::from(message.id)),
+                        "status" => message.status.map(u64::from),
+                        "budget" => message.options.map(|o| u64::from(budget_field(&o))),
+                        "priority" => message.options.map(|o| u64::from(o.priority())),
+                        other => panic!("spec field `{}` has no counterpart in Message", other),
+                    };
+                    assert_eq!(&actual, value, "{}: field {}", vector.name, name);
+                }
+                assert_eq!(&message.payload, payload, "{}", vector.name);
+                assert_eq!(encode(&message).unwrap(), vector.bytes, "{}", vector.name);
+            }
+            (Expected::Error(variant), Err(err)) => {
+                assert_eq!(error_variant(&err), variant, "{}", vector.name);
+            }
+            (expected, actual) => {
+                panic!("{}: expected {:?}, got {:?}", vector.name, expected, actual)
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Generated Artifacts Are Current
+// ============================================================================
+
+#[test]
+fn spec_md_wire_format_is_current() {
+    let spec_md = include_str!("../SPEC.md");
+    let regenerated = spec()
+        .splice_wire_format(spec_md)
+        .expect("SPEC.md should have markers");
+    assert!(
+        regenerated == spec_md,
+        "SPEC.md is out of date; run `cargo run --bin protocol-gen -- protocol.spec --spec-md SPEC.md`"
+    );
+}
+
+#[test]
+fn generated_codec_is_current() {
+    let checked_in = include_str!("generated/codec.rs");
+    assert!(
+        spec().generate_codec("protocol.spec") == checked_in,
+        "tests/generated/codec.rs is out of date; regenerate it with protocol-gen --out-dir"
+    );
+}
+
+// ============================================================================
+// Generated Codec vs. Reference Implementation
+// ============================================================================
+
+#[test]
+fn generated_codec_agrees_with_reference() {
+    let messages = [
+        Message::request(1, b""),
+        Message::request(0xDEAD_BEEF, b"hello"),
+        Message::response(7, 0, b"ok"),
+        Message::error(9, 0x03, "too large"),
+        Message::request(20, b"hi")
+            .with_options(RequestOptions::new().with_budget_ms(250).with_priority(200)),
+        Message::request(21, b"").with_options(RequestOptions::new().with_priority(0)),
+    ];
+
+    for message in &messages {
+        let bytes = encode(message).unwrap();
+        let decoded = generated::decode(&bytes).expect("generated codec should decode");
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.status, message.status);
+        assert_eq!(decoded.budget, message.options.as_ref().map(budget_field));
+        assert_eq!(decoded.priority, message.options.map(|o| o.priority()));
+        assert_eq!(decoded.payload, message.payload);
+        assert_eq!(generated::encode(&decoded).unwrap(), bytes);
+
+        let streamed = generated::read_message(&mut bytes.as_slice()).unwrap();
+        assert_eq!(streamed, decoded);
+    }
+
+    for vector in spec().vectors() {
+        let reference = decode(&vector.bytes).map_err(|e| error_variant(&e).to_string());
+        let generated = generated::decode(&vector.bytes).map_err(|e| format!("{:?}", e));
+        assert_eq!(reference.is_ok(), generated.is_ok(), "{}", vector.name);
+    }
+
+    // The generated codec frames compressed payloads without inflating them:
+    // it reports the original size and leaves a stream at the next frame
+    let cases = interop::cases();
+    for name in [
+        "request_compressed",
+        "request_compressed_large",
+        "request_options_compressed",
+    ] {
+        let case = cases.iter().find(|c| c.name == name).unwrap();
+        let original = decode(&case.bytes).unwrap().payload.len();
+        let unsupported = Err(generated::ProtocolError::CompressedPayload(original));
+        assert_eq!(generated::decode(&case.bytes), unsupported, "{}", name);
+
+        let next = encode(&Message::request(1, b"next")).unwrap();
+        let stream = [case.bytes.as_slice(), &next].concat();
+        let mut reader = stream.as_slice();
+        assert_eq!(
+            generated::read_message(&mut reader),
+            unsupported,
+            "{}",
+            name
+        );
+        assert_eq!(
+            generated::read_message(&mut reader).unwrap().payload,
+            b"next"
+        );
+    }
+}
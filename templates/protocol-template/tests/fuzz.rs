@@ -0,0 +1,96 @@
+//! Fuzz targets without cargo-fuzz
+//!
+//! Replays every input checked in under `fuzz/corpus/<target>/` and
+//! `fuzz/crashers/<target>/`, then runs each target on seeded random
+//! frames and bytes. The ignored tests are a longer offline campaign:
+//!
+//! ```text
+//! PROTOCOL_PROP_CASES=1000000 cargo test --release --test fuzz -- --ignored
+//! ```
+
+#[path = "../fuzz/targets.rs"]
+mod targets;
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use protocol_name::prop::{self, Runner};
+
+/// Inputs checked in for `target`, crashers first
+fn inputs(target: &str) -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz");
+    let mut paths = Vec::new();
+    for dir in ["crashers", "corpus"] {
+        let Ok(entries) = fs::read_dir(root.join(dir).join(target)) else {
+            continue;
+        };
+        let mut found: Vec<PathBuf> = entries.map(|entry| entry.unwrap().path()).collect();
+        found.sort();
+        paths.extend(found);
+    }
+    paths
+}
+
+fn replay(target: &str, run: fn(&[u8])) {
+    let paths = inputs(target);
+    assert!(!paths.is_empty(), "no inputs for {target}");
+    let failed: Vec<String> = paths
+        .iter()
+        .filter(|path| {
+            let data = fs::read(path).unwrap();
+            panic::catch_unwind(AssertUnwindSafe(|| run(&data))).is_err()
+        })
+        .map(|path| path.display().to_string())
+        .collect();
+    assert!(failed.is_empty(), "{target} fails on: {failed:?}");
+}
+
+fn campaign(runner: Runner, run: fn(&[u8])) {
+    let target = |data: &Vec<u8>| {
+        run(data);
+        Ok(())
+    };
+    runner.check(&prop::frames(4096), target);
+    runner.check(&prop::bytes(4096), target);
+}
+
+// ============================================================================
+// Checked-in Inputs
+// ============================================================================
+
+#[test]
+fn decode_corpus() {
+    replay("decode", targets::decode);
+}
+
+#[test]
+fn read_message_corpus() {
+    replay("read_message", targets::read_message);
+}
+
+// ============================================================================
+// Generated Inputs
+// ============================================================================
+
+#[test]
+fn decode_generated() {
+    campaign(Runner::new().with_cases(2000), targets::decode);
+}
+
+#[test]
+fn read_message_generated() {
+    campaign(Runner::new().with_cases(2000), targets::read_message);
+}
+
+#[test]
+#[ignore = "long-running; run with --ignored"]
+fn decode_campaign() {
+    campaign(Runner::new(), targets::decode);
+}
+
+#[test]
+#[ignore = "long-running; run with --ignored"]
+fn read_message_campaign() {
+    campaign(Runner::new(), targets::read_message);
+}
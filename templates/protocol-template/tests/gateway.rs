@@ -0,0 +1,238 @@
+//! HTTP gateway tests
+//!
+//! A gateway and the server behind it run on loopback, and a minimal
+//! HTTP/1.1 client talks to the gateway the way curl would. The last test
+//! runs the `gateway` and `serve` CLI commands end to end.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+use protocol_name::gateway::Gateway;
+use protocol_name::server::Server;
+use protocol_name::{ErrorCode, Message};
+
+/// A reply read off the gateway connection
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Reply {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').unwrap();
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    let mut reply = Reply {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    let length = reply
+        .header("Content-Length")
+        .map_or(0, |length| length.parse().unwrap());
+    reply.body = vec![0; length];
+    reader.read_exact(&mut reply.body).unwrap();
+    reply
+}
+
+fn post(reader: &mut BufReader<TcpStream>, id: u32, body: &[u8], headers: &str) -> Reply {
+    let head = format!(
+        "POST /rpc/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n{}\r\n",
+        id,
+        body.len(),
+        headers
+    );
+    let stream = reader.get_mut();
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+    read_reply(reader)
+}
+
+fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+    BufReader::new(TcpStream::connect(addr).unwrap())
+}
+
+/// Start a server running `handler` and a gateway in front of it
+fn gateway_for<H>(handler: H) -> SocketAddr
+where
+    H: Fn(Message) -> Message + Send + Sync + 'static,
+{
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    thread::spawn(move || Server::new(handler).serve(upstream));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Gateway::new(move || TcpStream::connect(upstream_addr)).serve(listener));
+    addr
+}
+
+fn service(request: Message) -> Message {
+    match request.payload.as_slice() {
+        b"fail" => Message::response(request.id, 1, b"it broke"),
+        b"late" => Message::error(request.id, ErrorCode::DeadlineExceeded as u8, "too late"),
+        payload => Message::response(request.id, 0, payload),
+    }
+}
+
+#[test]
+fn requests_and_replies_cross_the_gateway() {
+    let addr = gateway_for(service);
+    let mut http = connect(addr);
+
+    let ok = post(&mut http, 1, b"hello", "");
+    assert_eq!(ok.status, 200);
+    assert_eq!(ok.header("X-Protocol-Status"), Some("0"));
+    assert_eq!(ok.header("Content-Type"), Some("application/octet-stream"));
+    assert_eq!(ok.body, b"hello");
+
+    // The same HTTP connection, and the same upstream connection
+    let failed = post(&mut http, 2, b"fail", "");
+    assert_eq!(failed.status, 500);
+    assert_eq!(failed.header("X-Protocol-Status"), Some("1"));
+    assert_eq!(failed.body, b"it broke");
+
+    let late = post(&mut http, 3, b"late", "");
+    assert_eq!(late.status, 504);
+    assert_eq!(late.header("Content-Type"), Some("application/json"));
+    assert_eq!(
+        String::from_utf8(late.body).unwrap(),
+        "{\"error\":{\"code\":8,\"name\":\"DeadlineExceeded\",\"message\":\"too late\"}}\n"
+    );
+
+    // Binary bodies large enough for curl to ask before sending them
+    let large: Vec<u8> = (0..70_000u32).map(|i| i as u8).collect();
+    let head = format!(
+        "POST /rpc/4 HTTP/1.1\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+        large.len()
+    );
+    http.get_mut().write_all(head.as_bytes()).unwrap();
+    assert_eq!(read_reply(&mut http).status, 100);
+    http.get_mut().write_all(&large).unwrap();
+    let echoed = read_reply(&mut http);
+    assert_eq!(echoed.status, 200);
+    assert_eq!(echoed.body, large);
+
+    let closing = post(&mut http, 5, b"bye", "Connection: close\r\n");
+    assert_eq!(closing.header("Connection"), Some("close"));
+    let mut rest = Vec::new();
+    assert_eq!(http.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn connections_are_served_concurrently() {
+    let addr = gateway_for(service);
+    let handles: Vec<_> = (0..8u32)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut http = connect(addr);
+                for id in 1..=20 {
+                    let body = format!("{}-{}", i, id);
+                    let reply = post(&mut http, id, body.as_bytes(), "");
+                    assert_eq!(reply.status, 200);
+                    assert_eq!(reply.body, body.as_bytes());
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn ids_can_be_reused_once_answered() {
+    let addr = gateway_for(service);
+    let mut http = connect(addr);
+    assert_eq!(post(&mut http, 9, b"a", "").status, 200);
+    assert_eq!(post(&mut http, 9, b"b", "").status, 200);
+}
+
+#[test]
+fn unreachable_upstream_is_a_bad_gateway() {
+    // Bind then drop, so nothing listens on the port
+    let upstream_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Gateway::new(move || TcpStream::connect(upstream_addr)).serve(listener));
+
+    let reply = post(&mut connect(addr), 1, b"hello", "");
+    assert_eq!(reply.status, 502);
+    let body = String::from_utf8(reply.body).unwrap();
+    assert!(
+        body.starts_with("{\"error\":{\"message\":\"upstream unavailable: "),
+        "{}",
+        body
+    );
+}
+
+/// A CLI child process, killed when dropped
+struct Cli(Child);
+
+impl Cli {
+    /// Spawn `protocol-name args` and wait for the address it listens on
+    fn listen(args: &[&str]) -> (Self, String) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_protocol-name"))
+            .args(args)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stderr.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let addr = line
+            .trim()
+            .strip_prefix("listening on ")
+            .unwrap()
+            .to_string();
+        (Self(child), addr)
+    }
+}
+
+impl Drop for Cli {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn cli_gateway_in_front_of_cli_server() {
+    let (_server, upstream) = Cli::listen(&["serve", "--tcp", "127.0.0.1:0"]);
+    let (_gateway, addr) =
+        Cli::listen(&["gateway", "--http", "127.0.0.1:0", "--upstream", &upstream]);
+
+    let mut http = connect(addr.parse().unwrap());
+    let reply = post(&mut http, 7, b"echo me", "");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"echo me");
+
+    http.get_mut().write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(read_reply(&mut http).status, 404);
+}
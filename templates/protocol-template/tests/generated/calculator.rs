@@ -0,0 +1,206 @@
+//! Service stubs generated by `protocol-idl` from `calculator.idl`.
+//! Do not edit by hand; change the IDL file and regenerate.
+
+use std::sync::Arc;
+
+use protocol_name::client::Client;
+use protocol_name::rpc::Router;
+use protocol_name::transport::Transport;
+use protocol_name::{Decode, Encode, ProtocolError};
+
+/// Two operands
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Operands {
+    /// Left operand
+    pub a: i64,
+    /// Right operand
+    pub b: i64,
+}
+
+impl Encode for Operands {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.a.encode_payload(out);
+        self.b.encode_payload(out);
+    }
+}
+
+impl Decode for Operands {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            a: Decode::decode_payload(input)?,
+            b: Decode::decode_payload(input)?,
+        })
+    }
+}
+
+/// A past calculation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub method: String,
+    pub operands: Operands,
+    /// Absent if the calculation failed
+    pub result: Option<i64>,
+}
+
+impl Encode for Entry {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.method.encode_payload(out);
+        self.operands.encode_payload(out);
+        self.result.encode_payload(out);
+    }
+}
+
+impl Decode for Entry {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            method: Decode::decode_payload(input)?,
+            operands: Decode::decode_payload(input)?,
+            result: Decode::decode_payload(input)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Note {
+    pub title: String,
+    pub body: Vec<u8>,
+    pub tags: Vec<String>,
+    pub pinned: bool,
+    pub rank: u8,
+}
+
+impl Encode for Note {
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        self.title.encode_payload(out);
+        self.body.encode_payload(out);
+        self.tags.encode_payload(out);
+        self.pinned.encode_payload(out);
+        self.rank.encode_payload(out);
+    }
+}
+
+impl Decode for Note {
+    fn decode_payload(input: &mut &[u8]) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            title: Decode::decode_payload(input)?,
+            body: Decode::decode_payload(input)?,
+            tags: Decode::decode_payload(input)?,
+            pinned: Decode::decode_payload(input)?,
+            rank: Decode::decode_payload(input)?,
+        })
+    }
+}
+
+/// Integer arithmetic with a history
+///
+/// Implement this and serve it with [`calculator_router`].
+pub trait Calculator: Send + Sync + 'static {
+    /// Sum of the operands
+    fn add(&self, request: Operands) -> Result<i64, ProtocolError>;
+    /// Quotient of the operands; fails on division by zero
+    fn divide(&self, request: Operands) -> Result<i64, ProtocolError>;
+    /// Calculations so far, oldest first
+    fn history(&self) -> Result<Vec<Entry>, ProtocolError>;
+    /// Forget the history
+    fn clear(&self) -> Result<(), ProtocolError>;
+}
+
+/// Register the `Calculator` methods of `service` on `router`
+pub fn calculator_router<S: Calculator>(router: Router, service: S) -> Router {
+    let service = Arc::new(service);
+    let add_service = Arc::clone(&service);
+    let divide_service = Arc::clone(&service);
+    let history_service = Arc::clone(&service);
+    let clear_service = Arc::clone(&service);
+    router
+        .route("calculator.add", move |request: Operands| add_service.add(request))
+        .route("calculator.divide", move |request: Operands| divide_service.divide(request))
+        .route("calculator.history", move |()| history_service.history())
+        .route("calculator.clear", move |()| clear_service.clear())
+}
+
+/// Client for the `Calculator` service
+#[derive(Debug)]
+pub struct CalculatorClient<T> {
+    client: Client<T>,
+}
+
+impl<T: Transport> CalculatorClient<T> {
+    /// Call the service over `client`
+    pub fn new(client: Client<T>) -> Self {
+        Self { client }
+    }
+
+    /// Unwrap the underlying client
+    pub fn into_inner(self) -> Client<T> {
+        self.client
+    }
+
+    /// Sum of the operands
+    pub fn add(&mut self, request: &Operands) -> Result<i64, ProtocolError> {
+        self.client.call_method("calculator.add", request)
+    }
+
+    /// Quotient of the operands; fails on division by zero
+    pub fn divide(&mut self, request: &Operands) -> Result<i64, ProtocolError> {
+        self.client.call_method("calculator.divide", request)
+    }
+
+    /// Calculations so far, oldest first
+    pub fn history(&mut self) -> Result<Vec<Entry>, ProtocolError> {
+        self.client.call_method("calculator.history", &())
+    }
+
+    /// Forget the history
+    pub fn clear(&mut self) -> Result<(), ProtocolError> {
+        self.client.call_method("calculator.clear", &())
+    }
+}
+
+/// Labelled notes
+///
+/// Implement this and serve it with [`notes_router`].
+pub trait Notes: Send + Sync + 'static {
+    /// Store a note, returning its number
+    fn put(&self, request: Note) -> Result<u32, ProtocolError>;
+    /// Notes carrying a tag
+    fn find_tag(&self, request: String) -> Result<Vec<Note>, ProtocolError>;
+}
+
+/// Register the `Notes` methods of `service` on `router`
+pub fn notes_router<S: Notes>(router: Router, service: S) -> Router {
+    let service = Arc::new(service);
+    let put_service = Arc::clone(&service);
+    let find_tag_service = Arc::clone(&service);
+    router
+        .route("notes.put", move |request: Note| put_service.put(request))
+        .route("notes.find_tag", move |request: String| find_tag_service.find_tag(request))
+}
+
+/// Client for the `Notes` service
+#[derive(Debug)]
+pub struct NotesClient<T> {
+    client: Client<T>,
+}
+
+impl<T: Transport> NotesClient<T> {
+    /// Call the service over `client`
+    pub fn new(client: Client<T>) -> Self {
+        Self { client }
+    }
+
+    /// Unwrap the underlying client
+    pub fn into_inner(self) -> Client<T> {
+        self.client
+    }
+
+    /// Store a note, returning its number
+    pub fn put(&mut self, request: &Note) -> Result<u32, ProtocolError> {
+        self.client.call_method("notes.put", request)
+    }
+
+    /// Notes carrying a tag
+    pub fn find_tag(&mut self, request: &str) -> Result<Vec<Note>, ProtocolError> {
+        self.client.call_method("notes.find_tag", request)
+    }
+}
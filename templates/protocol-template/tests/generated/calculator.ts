@@ -0,0 +1,490 @@
+// Service client generated by `protocol-idl` from `calculator.idl`.
+// Do not edit by hand; change the IDL file and regenerate.
+
+import type { Duplex } from "node:stream";
+
+// ============================================================================
+// Records
+// ============================================================================
+
+/** Two operands */
+export interface Operands {
+  /** Left operand */
+  a: bigint;
+  /** Right operand */
+  b: bigint;
+}
+
+/** A past calculation */
+export interface Entry {
+  method: string;
+  operands: Operands;
+  /** Absent if the calculation failed */
+  result: bigint | null;
+}
+
+export interface Note {
+  title: string;
+  body: Uint8Array;
+  tags: Array<string>;
+  pinned: boolean;
+  rank: number;
+}
+
+// ============================================================================
+// Services
+// ============================================================================
+
+/** Integer arithmetic with a history */
+export class CalculatorClient {
+  constructor(readonly connection: Connection) {}
+
+  /** Sum of the operands */
+  add(request: Operands): Promise<bigint> {
+    return this.connection.call("calculator.add", (w) => writeOperands(w, request), (r) => r.int(64));
+  }
+
+  /** Quotient of the operands; fails on division by zero */
+  divide(request: Operands): Promise<bigint> {
+    return this.connection.call("calculator.divide", (w) => writeOperands(w, request), (r) => r.int(64));
+  }
+
+  /** Calculations so far, oldest first */
+  history(): Promise<Array<Entry>> {
+    return this.connection.call("calculator.history", () => {}, (r) => r.list(() => readEntry(r)));
+  }
+
+  /** Forget the history */
+  clear(): Promise<void> {
+    return this.connection.call("calculator.clear", () => {}, () => undefined);
+  }
+}
+
+/** Labelled notes */
+export class NotesClient {
+  constructor(readonly connection: Connection) {}
+
+  /** Store a note, returning its number */
+  put(request: Note): Promise<number> {
+    return this.connection.call("notes.put", (w) => writeNote(w, request), (r) => Number(r.uint(32)));
+  }
+
+  /** Notes carrying a tag */
+  findTag(request: string): Promise<Array<Note>> {
+    return this.connection.call("notes.find_tag", (w) => w.string(request), (r) => r.list(() => readNote(r)));
+  }
+}
+
+// ============================================================================
+// Record Encoding (SPEC.md Section 3.4)
+// ============================================================================
+
+function writeOperands(w: Writer, value: Operands): void {
+  w.int(value.a, 64);
+  w.int(value.b, 64);
+}
+
+function readOperands(r: Reader): Operands {
+  return {
+    a: r.int(64),
+    b: r.int(64),
+  };
+}
+
+function readEntry(r: Reader): Entry {
+  return {
+    method: r.string(),
+    operands: readOperands(r),
+    result: r.option(() => r.int(64)),
+  };
+}
+
+function writeNote(w: Writer, value: Note): void {
+  w.string(value.title);
+  w.bytes(value.body);
+  w.list(value.tags, (item0) => w.string(item0));
+  w.bool(value.pinned);
+  w.u8(value.rank);
+}
+
+function readNote(r: Reader): Note {
+  return {
+    title: r.string(),
+    body: r.bytes(),
+    tags: r.list(() => r.string()),
+    pinned: r.bool(),
+    rank: r.u8(),
+  };
+}
+
+// ============================================================================
+// Runtime
+// ============================================================================
+
+const MAGIC = [0x54, 0x55];
+const VERSION = 1;
+const MAX_PAYLOAD_SIZE = 1048576;
+const COMPRESSED = 0x80000000;
+const OPTIONS = 0x40000000;
+const KNOWN_TYPES = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0xFF];
+const STATUS_TYPES = [0x02, 0xFF];
+const REQUEST = 0x01;
+const RESPONSE = 0x02;
+const WINDOW_UPDATE = 0x03;
+const ERROR = 0xFF;
+
+/** An error answer from the server, or a local protocol failure */
+export class RpcError extends Error {
+  constructor(
+    readonly code: number,
+    message: string,
+  ) {
+    super(message);
+    this.name = "RpcError";
+  }
+}
+
+function invalid(message: string): RpcError {
+  return new RpcError(0x01, message);
+}
+
+/** Writes values in the typed payload encoding */
+export class Writer {
+  private readonly out: number[] = [];
+
+  u8(value: number): void {
+    if (!Number.isInteger(value) || value < 0 || value > 0xff) throw new RangeError(`${value} out of range for u8`);
+    this.out.push(value);
+  }
+
+  i8(value: number): void {
+    if (!Number.isInteger(value) || value < -0x80 || value > 0x7f) throw new RangeError(`${value} out of range for i8`);
+    this.out.push(value & 0xff);
+  }
+
+  bool(value: boolean): void {
+    this.out.push(value ? 1 : 0);
+  }
+
+  uint(value: number | bigint, bits: number): void {
+    const v = BigInt(value);
+    if (v < 0n || v >> BigInt(bits) !== 0n) throw new RangeError(`${value} out of range for u${bits}`);
+    this.varint(v);
+  }
+
+  int(value: number | bigint, bits: number): void {
+    const v = BigInt(value);
+    const limit = 1n << BigInt(bits - 1);
+    if (v < -limit || v >= limit) throw new RangeError(`${value} out of range for i${bits}`);
+    this.varint(v >= 0n ? v << 1n : ((-v) << 1n) - 1n);
+  }
+
+  string(value: string): void {
+    this.bytes(new TextEncoder().encode(value));
+  }
+
+  bytes(value: Uint8Array): void {
+    this.varint(BigInt(value.length));
+    for (const byte of value) this.out.push(byte);
+  }
+
+  list<T>(values: readonly T[], write: (item: T) => void): void {
+    this.varint(BigInt(values.length));
+    values.forEach(write);
+  }
+
+  option<T>(value: T | null, write: (item: T) => void): void {
+    if (value === null) {
+      this.out.push(0);
+    } else {
+      this.out.push(1);
+      write(value);
+    }
+  }
+
+  finish(): Uint8Array {
+    return Uint8Array.from(this.out);
+  }
+
+  private varint(value: bigint): void {
+    while (value >= 0x80n) {
+      this.out.push(Number(value & 0x7fn) | 0x80);
+      value >>= 7n;
+    }
+    this.out.push(Number(value));
+  }
+}
+
+/** Reads values in the typed payload encoding */
+export class Reader {
+  private offset = 0;
+
+  constructor(private readonly input: Uint8Array) {}
+
+  u8(): number {
+    if (this.offset >= this.input.length) throw invalid("unexpected end of payload");
+    return this.input[this.offset++];
+  }
+
+  i8(): number {
+    return (this.u8() << 24) >> 24;
+  }
+
+  bool(): boolean {
+    const byte = this.u8();
+    if (byte > 1) throw invalid(`invalid bool: ${byte}`);
+    return byte === 1;
+  }
+
+  uint(bits: number): bigint {
+    const value = this.varint();
+    if (value >> BigInt(bits) !== 0n) throw invalid(`${value} out of range for u${bits}`);
+    return value;
+  }
+
+  int(bits: number): bigint {
+    const raw = this.varint();
+    const value = raw & 1n ? -(raw >> 1n) - 1n : raw >> 1n;
+    const limit = 1n << BigInt(bits - 1);
+    if (value < -limit || value >= limit) throw invalid(`${value} out of range for i${bits}`);
+    return value;
+  }
+
+  string(): string {
+    try {
+      return new TextDecoder("utf-8", { fatal: true }).decode(this.bytes());
+    } catch {
+      throw invalid("string is not UTF-8");
+    }
+  }
+
+  bytes(): Uint8Array {
+    const length = this.length();
+    const value = this.input.slice(this.offset, this.offset + length);
+    this.offset += length;
+    return value;
+  }
+
+  list<T>(read: () => T): T[] {
+    const count = this.length();
+    const items: T[] = [];
+    for (let i = 0; i < count; i++) items.push(read());
+    return items;
+  }
+
+  option<T>(read: () => T): T | null {
+    const tag = this.u8();
+    if (tag > 1) throw invalid(`invalid option tag: ${tag}`);
+    return tag === 1 ? read() : null;
+  }
+
+  /** Reject bytes left after the top-level value */
+  end(): void {
+    const left = this.input.length - this.offset;
+    if (left !== 0) throw invalid(`${left} trailing bytes`);
+  }
+
+  /** A length or count, which cannot exceed the bytes left */
+  private length(): number {
+    const value = this.varint();
+    if (value > BigInt(this.input.length - this.offset)) throw invalid("unexpected end of payload");
+    return Number(value);
+  }
+
+  private varint(): bigint {
+    let value = 0n;
+    for (let i = 0; i < 10; i++) {
+      const byte = this.u8();
+      const bits = BigInt(byte & 0x7f);
+      if (i === 9 && bits > 1n) throw invalid("varint overflows u64");
+      value |= bits << BigInt(7 * i);
+      if ((byte & 0x80) === 0) {
+        if (byte === 0 && i > 0) throw invalid("overlong varint");
+        return value;
+      }
+    }
+    throw invalid("varint overflows u64");
+  }
+}
+
+interface Frame {
+  type: number;
+  id: number;
+  status: number;
+  payload: Uint8Array;
+  size: number;
+}
+
+function encodeFrame(type: number, id: number, payload: Uint8Array): Uint8Array {
+  const frame = new Uint8Array(12 + payload.length);
+  const view = new DataView(frame.buffer);
+  frame.set(MAGIC, 0);
+  frame[2] = VERSION;
+  frame[3] = type;
+  view.setUint32(4, id);
+  view.setUint32(8, payload.length);
+  frame.set(payload, 12);
+  return frame;
+}
+
+/** Decode the frame at the start of `input`, or null if it is incomplete */
+function decodeFrame(input: Uint8Array): Frame | null {
+  if (input.length < 8) return null;
+  if (input[0] !== MAGIC[0] || input[1] !== MAGIC[1]) throw invalid("invalid magic");
+  if (input[2] !== VERSION) throw invalid(`unsupported version ${input[2]}`);
+  const type = input[3];
+  if (!KNOWN_TYPES.includes(type)) throw new RpcError(0x02, `unknown message type 0x${type.toString(16)}`);
+  const view = new DataView(input.buffer, input.byteOffset, input.length);
+  const id = view.getUint32(4);
+  const hasStatus = STATUS_TYPES.includes(type);
+  const header = hasStatus ? 13 : 12;
+  if (input.length < header) return null;
+  const status = hasStatus ? input[8] : 0;
+  const length = view.getUint32(header - 4);
+  if (length & COMPRESSED) throw invalid("compressed payloads are not supported");
+  if (length & OPTIONS) throw invalid("only requests may carry options");
+  if (length > MAX_PAYLOAD_SIZE) throw new RpcError(0x03, `payload of ${length} bytes is too large`);
+  if (input.length < header + length) return null;
+  return { type, id, status, payload: input.slice(header, header + length), size: header + length };
+}
+
+interface Call {
+  frame: Uint8Array;
+  payloadLength: number;
+  sent: boolean;
+  resolve: (payload: Uint8Array) => void;
+  reject: (error: Error) => void;
+}
+
+/**
+ * One connection to a server, shared by any number of service clients.
+ *
+ * Calls may overlap; they are sent as the server's flow-control window
+ * allows and answered in order. Frames other than answers, such as events,
+ * are ignored.
+ */
+export class Connection {
+  private input = new Uint8Array(0);
+  private readonly calls = new Map<number, Call>();
+  private readonly queue: number[] = [];
+  private requestCredit = 0;
+  private byteCredit = 0;
+  private granted = false;
+  private nextId = 1;
+  private failure: Error | null = null;
+
+  constructor(private readonly stream: Duplex) {
+    stream.on("data", (chunk: Uint8Array) => this.receive(chunk));
+    stream.on("error", (error: Error) => this.fail(error));
+    stream.on("close", () => this.fail(new RpcError(0, "connection closed")));
+  }
+
+  /** Call `method`, writing its arguments and reading its result */
+  call<T>(method: string, writeArgs: (w: Writer) => void, readResult: (r: Reader) => T): Promise<T> {
+    if (this.failure !== null) return Promise.reject(this.failure);
+    const w = new Writer();
+    w.string(method);
+    writeArgs(w);
+    const payload = w.finish();
+    if (payload.length > MAX_PAYLOAD_SIZE) {
+      return Promise.reject(new RpcError(0x03, `payload of ${payload.length} bytes is too large`));
+    }
+    const id = this.nextId;
+    this.nextId = id === 0xffffffff ? 1 : id + 1;
+    return new Promise<Uint8Array>((resolve, reject) => {
+      const frame = encodeFrame(REQUEST, id, payload);
+      this.calls.set(id, { frame, payloadLength: payload.length, sent: false, resolve, reject });
+      this.queue.push(id);
+      this.flush();
+    }).then((result) => {
+      const r = new Reader(result);
+      const value = readResult(r);
+      r.end();
+      return value;
+    });
+  }
+
+  /** Close the connection once pending writes finish */
+  close(): void {
+    this.stream.end();
+  }
+
+  /** Send queued calls while the window covers them */
+  private flush(): void {
+    while (this.queue.length > 0) {
+      const call = this.calls.get(this.queue[0])!;
+      if (this.requestCredit < 1 || this.byteCredit < call.payloadLength) break;
+      this.queue.shift();
+      this.requestCredit -= 1;
+      this.byteCredit -= call.payloadLength;
+      call.sent = true;
+      this.stream.write(call.frame);
+    }
+    // With nothing in flight no more credit is coming
+    const inFlight = this.calls.size - this.queue.length;
+    if (this.granted && inFlight === 0 && this.queue.length > 0) {
+      const id = this.queue.shift()!;
+      this.calls.get(id)!.reject(new RpcError(0x04, "request exceeds the flow-control window"));
+      this.calls.delete(id);
+      this.flush();
+    }
+  }
+
+  private receive(chunk: Uint8Array): void {
+    const input = new Uint8Array(this.input.length + chunk.length);
+    input.set(this.input, 0);
+    input.set(chunk, this.input.length);
+    this.input = input;
+    for (;;) {
+      let frame: Frame | null;
+      try {
+        frame = decodeFrame(this.input);
+      } catch (error) {
+        this.fail(error as Error);
+        this.stream.destroy();
+        return;
+      }
+      if (frame === null) return;
+      this.input = this.input.subarray(frame.size);
+      this.dispatch(frame);
+    }
+  }
+
+  private dispatch(frame: Frame): void {
+    if (frame.type === WINDOW_UPDATE) {
+      if (frame.payload.length !== 8) {
+        this.fail(invalid("window update payload must be 8 bytes"));
+        return;
+      }
+      const view = new DataView(frame.payload.buffer, frame.payload.byteOffset, 8);
+      this.requestCredit += view.getUint32(0);
+      this.byteCredit += view.getUint32(4);
+      this.granted = true;
+      this.flush();
+      return;
+    }
+    const message = () => new TextDecoder().decode(frame.payload);
+    if (frame.type === ERROR && frame.id === 0) {
+      this.fail(new RpcError(frame.status, message()));
+      return;
+    }
+    const call = this.calls.get(frame.id);
+    if (call === undefined || !call.sent) return;
+    if (frame.type === RESPONSE) {
+      this.calls.delete(frame.id);
+      call.resolve(frame.payload);
+    } else if (frame.type === ERROR) {
+      this.calls.delete(frame.id);
+      call.reject(new RpcError(frame.status, message()));
+    }
+    this.flush();
+  }
+
+  private fail(error: Error): void {
+    if (this.failure !== null) return;
+    this.failure = error;
+    for (const call of this.calls.values()) call.reject(error);
+    this.calls.clear();
+    this.queue.length = 0;
+  }
+}
@@ -0,0 +1,370 @@
+//! # Protocol Name
+//!
+//! Codec generated by `protocol-gen` from `protocol.spec`.
+//! Do not edit by hand; change the spec file and regenerate.
+
+use std::io::{self, Read, Write};
+
+/// Protocol magic bytes
+pub const MAGIC: [u8; 2] = [0x54, 0x55];
+
+/// Current protocol version
+pub const VERSION: u8 = 1;
+
+/// Maximum payload size
+pub const MAX_PAYLOAD_SIZE: usize = 1048576;
+
+/// Length field flag: Payload is compressed (Section 4.9)
+pub const COMPRESSED: u32 = 0x80000000;
+
+/// Length field flag: Request options follow the length (Section 4.11)
+pub const OPTIONS: u32 = 0x40000000;
+
+/// Message type identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// Client request
+    Request = 0x01,
+    /// Server response
+    Response = 0x02,
+    /// Flow control credit grant
+    WindowUpdate = 0x03,
+    /// Batch of requests
+    Batch = 0x04,
+    /// Per-item results of a batch
+    BatchResponse = 0x05,
+    /// Subscribe to a topic pattern
+    Subscribe = 0x06,
+    /// Cancel a subscription
+    Unsubscribe = 0x07,
+    /// Publish to a topic
+    Publish = 0x08,
+    /// Publication delivered to a subscriber
+    Event = 0x09,
+    /// Open or resume a session
+    Resume = 0x0A,
+    /// Error message
+    Error = 0xFF,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        match value {
+            0x01 => Ok(MessageType::Request),
+            0x02 => Ok(MessageType::Response),
+            0x03 => Ok(MessageType::WindowUpdate),
+            0x04 => Ok(MessageType::Batch),
+            0x05 => Ok(MessageType::BatchResponse),
+            0x06 => Ok(MessageType::Subscribe),
+            0x07 => Ok(MessageType::Unsubscribe),
+            0x08 => Ok(MessageType::Publish),
+            0x09 => Ok(MessageType::Event),
+            0x0A => Ok(MessageType::Resume),
+            0xFF => Ok(MessageType::Error),
+            _ => Err(ProtocolError::UnknownType(value)),
+        }
+    }
+}
+
+/// Application error codes carried in error messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Invalid message format
+    InvalidFormat = 0x01,
+    /// Unknown message type
+    UnknownType = 0x02,
+    /// Payload too large
+    PayloadTooLarge = 0x03,
+    /// Flow control window exceeded
+    FlowControl = 0x04,
+    /// Request ID already in flight
+    DuplicateId = 0x05,
+    /// Unknown method
+    UnknownMethod = 0x06,
+    /// Unknown or expired session
+    UnknownSession = 0x07,
+    /// Request deadline exceeded
+    DeadlineExceeded = 0x08,
+}
+
+impl ErrorCode {
+    /// Look up an error code by its wire value
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(ErrorCode::InvalidFormat),
+            0x02 => Some(ErrorCode::UnknownType),
+            0x03 => Some(ErrorCode::PayloadTooLarge),
+            0x04 => Some(ErrorCode::FlowControl),
+            0x05 => Some(ErrorCode::DuplicateId),
+            0x06 => Some(ErrorCode::UnknownMethod),
+            0x07 => Some(ErrorCode::UnknownSession),
+            0x08 => Some(ErrorCode::DeadlineExceeded),
+            _ => None,
+        }
+    }
+}
+
+/// Protocol error types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Invalid magic bytes
+    InvalidMagic([u8; 2]),
+    /// Unsupported protocol version
+    UnsupportedVersion(u8),
+    /// Unknown message type
+    UnknownType(u8),
+    /// Payload exceeds maximum size
+    PayloadTooLarge(usize),
+    /// Incomplete message (not enough bytes)
+    IncompleteMessage,
+    /// Malformed payload
+    InvalidPayload(String),
+    /// The length field sets the named flag, which this message type
+    /// cannot carry
+    UnexpectedFlag(&'static str, MessageType),
+    /// Compressed payload of the given original size, which this codec
+    /// does not inflate
+    CompressedPayload(usize),
+    /// I/O error
+    Io(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic(magic) => {
+                write!(f, "invalid magic: ")?;
+                magic.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            Self::UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
+            Self::UnknownType(t) => write!(f, "unknown message type: {:02x}", t),
+            Self::PayloadTooLarge(size) => write!(f, "payload too large: {} bytes", size),
+            Self::IncompleteMessage => write!(f, "incomplete message"),
+            Self::InvalidPayload(msg) => write!(f, "invalid payload: {}", msg),
+            Self::UnexpectedFlag(flag, message_type) => {
+                write!(f, "{:?} frames cannot carry `{}`", message_type, flag)
+            }
+            Self::CompressedPayload(size) => {
+                write!(f, "compressed payload not supported ({} bytes inflated)", size)
+            }
+            Self::Io(msg) => write!(f, "I/O error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err.to_string())
+    }
+}
+
+/// A protocol message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// Protocol version
+    pub version: u8,
+    /// Message type
+    pub message_type: MessageType,
+    /// Request/response ID
+    pub id: u32,
+    /// Response status or error code
+    pub status: Option<u8>,
+    /// Request budget in milliseconds (0xFFFFFFFF = no deadline)
+    pub budget: Option<u32>,
+    /// Request priority, higher first (128 when absent)
+    pub priority: Option<u8>,
+    /// Message payload
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// Get the message type
+    pub fn message_type(&self) -> MessageType {
+        self.message_type
+    }
+}
+
+/// Encode a message to bytes
+pub fn encode(message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let payload_len = message.payload.len();
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::PayloadTooLarge(payload_len));
+    }
+
+    let mut buf = Vec::with_capacity(17 + payload_len);
+    let has_options = matches!(message.message_type, MessageType::Request) && (message.budget.is_some() || message.priority.is_some());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(message.version);
+    buf.push(message.message_type as u8);
+    buf.extend_from_slice(&message.id.to_be_bytes());
+    if matches!(message.message_type, MessageType::Response | MessageType::Error) {
+        buf.extend_from_slice(&message.status.unwrap_or_default().to_be_bytes());
+    }
+    let mut length = payload_len as u32;
+    if has_options {
+        length |= OPTIONS;
+    }
+    buf.extend_from_slice(&length.to_be_bytes());
+    if has_options {
+        buf.extend_from_slice(&message.budget.unwrap_or_default().to_be_bytes());
+    }
+    if has_options {
+        buf.extend_from_slice(&message.priority.unwrap_or_default().to_be_bytes());
+    }
+    buf.extend_from_slice(&message.payload);
+
+    Ok(buf)
+}
+
+/// Write a message to a writer
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), ProtocolError> {
+    let bytes = encode(message)?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn take<const N: usize>(bytes: &[u8], offset: &mut usize) -> Result<[u8; N], ProtocolError> {
+    let slice = bytes.get(*offset..*offset + N).ok_or(ProtocolError::IncompleteMessage)?;
+    *offset += N;
+    Ok(slice.try_into().expect("slice has length N"))
+}
+
+/// Decode a message from bytes
+pub fn decode(bytes: &[u8]) -> Result<Message, ProtocolError> {
+    let mut offset = 0;
+    let magic = take::<2>(bytes, &mut offset)?;
+    if magic != MAGIC {
+        return Err(ProtocolError::InvalidMagic(magic));
+    }
+    let [version] = take::<1>(bytes, &mut offset)?;
+    if version != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    let [type_byte] = take::<1>(bytes, &mut offset)?;
+    let message_type = MessageType::try_from(type_byte)?;
+    let id = u32::from_be_bytes(take::<4>(bytes, &mut offset)?);
+    let status = if matches!(message_type, MessageType::Response | MessageType::Error) {
+        Some(u8::from_be_bytes(take::<1>(bytes, &mut offset)?))
+    } else {
+        None
+    };
+    let length = u32::from_be_bytes(take::<4>(bytes, &mut offset)?);
+    let payload_len = (length & !(COMPRESSED | OPTIONS)) as usize;
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::PayloadTooLarge(payload_len));
+    }
+    if length & OPTIONS != 0 && !matches!(message_type, MessageType::Request) {
+        return Err(ProtocolError::UnexpectedFlag("options", message_type));
+    }
+    let budget = if length & OPTIONS != 0 {
+        Some(u32::from_be_bytes(take::<4>(bytes, &mut offset)?))
+    } else {
+        None
+    };
+    let priority = if length & OPTIONS != 0 {
+        Some(u8::from_be_bytes(take::<1>(bytes, &mut offset)?))
+    } else {
+        None
+    };
+    let payload = bytes
+        .get(offset..offset + payload_len)
+        .ok_or(ProtocolError::IncompleteMessage)?
+        .to_vec();
+
+    if length & COMPRESSED != 0 {
+        return Err(compressed_payload(&payload));
+    }
+
+    Ok(Message {
+        version,
+        message_type,
+        id,
+        status,
+        budget,
+        priority,
+        payload,
+    })
+}
+
+fn read_exact<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], ProtocolError> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read a message from a reader
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, ProtocolError> {
+    let magic = read_exact::<_, 2>(reader)?;
+    if magic != MAGIC {
+        return Err(ProtocolError::InvalidMagic(magic));
+    }
+    let [version] = read_exact::<_, 1>(reader)?;
+    if version != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    let [type_byte] = read_exact::<_, 1>(reader)?;
+    let message_type = MessageType::try_from(type_byte)?;
+    let id = u32::from_be_bytes(read_exact::<_, 4>(reader)?);
+    let status = if matches!(message_type, MessageType::Response | MessageType::Error) {
+        Some(u8::from_be_bytes(read_exact::<_, 1>(reader)?))
+    } else {
+        None
+    };
+    let length = u32::from_be_bytes(read_exact::<_, 4>(reader)?);
+    let payload_len = (length & !(COMPRESSED | OPTIONS)) as usize;
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(ProtocolError::PayloadTooLarge(payload_len));
+    }
+    if length & OPTIONS != 0 && !matches!(message_type, MessageType::Request) {
+        return Err(ProtocolError::UnexpectedFlag("options", message_type));
+    }
+    let budget = if length & OPTIONS != 0 {
+        Some(u32::from_be_bytes(read_exact::<_, 4>(reader)?))
+    } else {
+        None
+    };
+    let priority = if length & OPTIONS != 0 {
+        Some(u8::from_be_bytes(read_exact::<_, 1>(reader)?))
+    } else {
+        None
+    };
+    let mut payload = vec![0u8; payload_len];
+    reader.read_exact(&mut payload)?;
+
+    if length & COMPRESSED != 0 {
+        return Err(compressed_payload(&payload));
+    }
+
+    Ok(Message {
+        version,
+        message_type,
+        id,
+        status,
+        budget,
+        priority,
+        payload,
+    })
+}
+
+/// Error for a compressed payload, which this codec frames but does not
+/// inflate
+fn compressed_payload(payload: &[u8]) -> ProtocolError {
+    let Some(prefix) = payload.get(..4) else {
+        return ProtocolError::InvalidPayload(format!(
+            "compressed payload of {} bytes has no size prefix",
+            payload.len()
+        ));
+    };
+    let size = u32::from_be_bytes(prefix.try_into().expect("prefix has 4 bytes")) as usize;
+    if size > MAX_PAYLOAD_SIZE {
+        ProtocolError::PayloadTooLarge(size)
+    } else {
+        ProtocolError::CompressedPayload(size)
+    }
+}
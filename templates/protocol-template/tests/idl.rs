@@ -0,0 +1,189 @@
+//! Generated service stub tests
+//!
+//! `tests/idl/calculator.idl` is compiled by `protocol-idl` into the Rust
+//! stubs and TypeScript client under `tests/generated/`. These tests fail
+//! when either drifts from the IDL, and run the Rust stubs end to end.
+
+use std::sync::Mutex;
+use std::thread;
+
+use protocol_name::client::Client;
+use protocol_name::idl::Idl;
+use protocol_name::rpc::Router;
+use protocol_name::server::Server;
+use protocol_name::transport::{pipe, MemoryStream};
+use protocol_name::{ErrorCode, ProtocolError};
+
+#[path = "generated/calculator.rs"]
+#[rustfmt::skip]
+mod calculator;
+
+use calculator::{Calculator, CalculatorClient, Entry, Note, Notes, NotesClient, Operands};
+
+const IDL_SOURCE: &str = include_str!("idl/calculator.idl");
+
+fn idl() -> Idl {
+    Idl::parse(IDL_SOURCE).expect("calculator.idl should parse")
+}
+
+// ============================================================================
+// Generated Artifacts Are Current
+// ============================================================================
+
+#[test]
+fn generated_stubs_are_current() {
+    let regenerate = "regenerate them with protocol-idl tests/idl/calculator.idl \
+                      --rust tests/generated/calculator.rs --ts tests/generated/calculator.ts";
+    assert!(
+        idl().generate_rust("calculator.idl") == include_str!("generated/calculator.rs"),
+        "tests/generated/calculator.rs is out of date; {}",
+        regenerate
+    );
+    assert!(
+        idl().generate_typescript("calculator.idl") == include_str!("generated/calculator.ts"),
+        "tests/generated/calculator.ts is out of date; {}",
+        regenerate
+    );
+}
+
+// ============================================================================
+// Stubs End to End
+// ============================================================================
+
+#[derive(Default)]
+struct Arithmetic {
+    history: Mutex<Vec<Entry>>,
+}
+
+impl Arithmetic {
+    fn record(&self, method: &str, operands: Operands, result: Option<i64>) {
+        self.history.lock().unwrap().push(Entry {
+            method: method.to_string(),
+            operands,
+            result,
+        });
+    }
+}
+
+impl Calculator for Arithmetic {
+    fn add(&self, request: Operands) -> Result<i64, ProtocolError> {
+        let sum = request.a + request.b;
+        self.record("add", request, Some(sum));
+        Ok(sum)
+    }
+
+    fn divide(&self, request: Operands) -> Result<i64, ProtocolError> {
+        let quotient = request.a.checked_div(request.b);
+        self.record("divide", request, quotient);
+        quotient.ok_or_else(|| ProtocolError::InvalidPayload("division by zero".to_string()))
+    }
+
+    fn history(&self) -> Result<Vec<Entry>, ProtocolError> {
+        Ok(self.history.lock().unwrap().clone())
+    }
+
+    fn clear(&self) -> Result<(), ProtocolError> {
+        self.history.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Notebook {
+    notes: Mutex<Vec<Note>>,
+}
+
+impl Notes for Notebook {
+    fn put(&self, request: Note) -> Result<u32, ProtocolError> {
+        let mut notes = self.notes.lock().unwrap();
+        notes.push(request);
+        Ok(notes.len() as u32)
+    }
+
+    fn find_tag(&self, request: String) -> Result<Vec<Note>, ProtocolError> {
+        let notes = self.notes.lock().unwrap();
+        Ok(notes
+            .iter()
+            .filter(|n| n.tags.contains(&request))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Serve both services on one connection
+fn connect() -> Client<MemoryStream> {
+    let router = calculator::calculator_router(Router::new(), Arithmetic::default());
+    let router = calculator::notes_router(router, Notebook::default());
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || Server::new(router).serve_connection(server_end));
+    Client::new(client_end)
+}
+
+#[test]
+fn calculator_stubs_round_trip() {
+    let mut calculator = CalculatorClient::new(connect());
+    assert_eq!(calculator.add(&Operands { a: 40, b: 2 }), Ok(42));
+    assert_eq!(calculator.divide(&Operands { a: -9, b: 2 }), Ok(-4));
+
+    match calculator.divide(&Operands { a: 1, b: 0 }) {
+        Err(ProtocolError::Rejected { code, message, .. }) => {
+            assert_eq!(code, ErrorCode::InvalidFormat as u8);
+            assert!(message.contains("division by zero"), "{}", message);
+        }
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+
+    let history = calculator.history().unwrap();
+    let results: Vec<_> = history
+        .iter()
+        .map(|e| (e.method.as_str(), e.result))
+        .collect();
+    assert_eq!(
+        results,
+        vec![("add", Some(42)), ("divide", Some(-4)), ("divide", None)]
+    );
+    assert_eq!(history[1].operands, Operands { a: -9, b: 2 });
+
+    calculator.clear().unwrap();
+    assert_eq!(calculator.history(), Ok(Vec::new()));
+
+    let mut client = calculator.into_inner();
+    let methods = client.methods().unwrap();
+    assert_eq!(
+        methods,
+        [
+            "calculator.add",
+            "calculator.clear",
+            "calculator.divide",
+            "calculator.history",
+            "notes.find_tag",
+            "notes.put"
+        ]
+    );
+}
+
+#[test]
+fn notes_stubs_round_trip() {
+    let mut notes = NotesClient::new(connect());
+    let note = |title: &str, tags: &[&str]| Note {
+        title: title.to_string(),
+        body: title.as_bytes().to_vec(),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        pinned: tags.contains(&"urgent"),
+        rank: tags.len() as u8,
+    };
+    assert_eq!(notes.put(&note("milk", &["shopping"])), Ok(1));
+    assert_eq!(notes.put(&note("taxes", &["urgent", "home"])), Ok(2));
+    assert_eq!(notes.put(&note("paint", &["home"])), Ok(3));
+
+    let home = notes.find_tag("home").unwrap();
+    assert_eq!(
+        home,
+        vec![note("taxes", &["urgent", "home"]), note("paint", &["home"])]
+    );
+    assert_eq!(notes.find_tag("garden"), Ok(Vec::new()));
+
+    // Both services share the connection
+    let mut calculator = CalculatorClient::new(notes.into_inner());
+    assert_eq!(calculator.add(&Operands { a: 1, b: 2 }), Ok(3));
+}
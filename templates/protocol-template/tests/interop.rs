@@ -0,0 +1,101 @@
+//! Cross-implementation vector tests
+//!
+//! `tests/vectors/interop.json` is the vector document other
+//! implementations test against. These tests fail when it drifts from the
+//! reference, and run the `vectors` CLI commands end to end.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use protocol_name::interop::{cases, parse_results, reference_results, results_to_json, to_json};
+
+fn cli(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_protocol-name"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn exported_vectors_are_current() {
+    assert!(
+        to_json(&cases()) == include_str!("vectors/interop.json"),
+        "tests/vectors/interop.json is out of date; regenerate it with \
+         protocol-name vectors export --json --out tests/vectors/interop.json"
+    );
+}
+
+#[test]
+fn cli_exports_the_vectors() {
+    let output = cli(&["vectors", "export", "--json"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), to_json(&cases()));
+}
+
+#[test]
+fn cli_accepts_matching_results() {
+    let results = results_to_json(&reference_results(&cases()));
+    let output = cli(&["vectors", "verify", "-"], &results);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, format!("{} passed, 0 failed\n", cases().len()));
+}
+
+#[test]
+fn cli_rejects_diverging_results() {
+    // A port that rejects trailing bytes as a generic format error
+    let mut results = reference_results(&cases());
+    let trailing = results
+        .iter_mut()
+        .find(|r| r.name == "trailing_bytes")
+        .unwrap();
+    trailing.error = Some("InvalidFormat".to_string());
+    let path =
+        std::env::temp_dir().join(format!("protocol-name-results-{}.json", std::process::id()));
+    std::fs::write(&path, results_to_json(&results)).unwrap();
+
+    let output = cli(&["vectors", "verify", path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout
+            .starts_with("FAIL trailing_bytes: expected error TrailingBytes, got InvalidFormat\n"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.ends_with(&format!("{} passed, 1 failed\n", cases().len() - 1)));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("1 of {} cases diverge", cases().len())),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn cli_reports_malformed_results() {
+    let output = cli(
+        &["vectors", "verify", "-"],
+        "{\"results\": [{\"name\": 1}]}",
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("results[0]: missing \"name\""));
+    assert!(parse_results("{\"results\": []}").unwrap().is_empty());
+}
@@ -0,0 +1,103 @@
+//! Codec properties over generated messages and bytes
+//!
+//! Each property runs against `PROTOCOL_PROP_CASES` generated inputs (256
+//! by default) from `PROTOCOL_PROP_SEED`. A failure report names the seed
+//! and the minimal input found by shrinking.
+
+use protocol_name::compress::Compression;
+use protocol_name::prop::{self, ensure_eq, Runner};
+use protocol_name::{
+    decode, decode_all, decode_prefix, decode_strict, encode, read_message, write_message, Message,
+    ProtocolError,
+};
+
+/// Turn a codec error into a property failure
+fn ok<T>(result: Result<T, ProtocolError>) -> Result<T, String> {
+    result.map_err(|e| e.to_string())
+}
+
+// ============================================================================
+// Round Trips
+// ============================================================================
+
+#[test]
+fn decode_inverts_encode() {
+    Runner::new().check(&prop::messages(4096), |message| {
+        let bytes = ok(encode(message))?;
+        ensure_eq(&bytes.len(), &message.encoded_len())?;
+        ensure_eq(&ok(decode(&bytes))?, message)?;
+        ensure_eq(&ok(decode_strict(&bytes))?, message)
+    });
+}
+
+#[test]
+fn read_message_inverts_write_message() {
+    Runner::new().check(&prop::messages(4096), |message| {
+        let mut stream = Vec::new();
+        ok(write_message(&mut stream, message))?;
+        ensure_eq(&stream, &ok(encode(message))?)?;
+        let mut reader = stream.as_slice();
+        ensure_eq(&ok(read_message(&mut reader))?, message)?;
+        ensure_eq(&reader.len(), &0)
+    });
+}
+
+#[test]
+fn compressed_frames_decode_to_the_original() {
+    let compression = Compression::new().with_min_size(0);
+    Runner::new().check(&prop::messages(4096), |message| {
+        let bytes = ok(compression.encode(message))?;
+        if bytes.len() > message.encoded_len() {
+            return Err(format!("compressed frame grew to {} bytes", bytes.len()));
+        }
+        ensure_eq(&ok(decode_strict(&bytes))?, message)
+    });
+}
+
+#[test]
+fn frames_decode_back_to_back() {
+    Runner::new().check(&prop::messages(1024), |message| {
+        let mut bytes = ok(encode(message))?;
+        bytes.extend_from_within(..);
+        let (first, consumed) = ok(decode_prefix(&bytes))?;
+        ensure_eq(&first, message)?;
+        ensure_eq(&consumed, &message.encoded_len())?;
+        let all: Result<Vec<Message>, ProtocolError> = decode_all(&bytes).collect();
+        ensure_eq(&ok(all)?, &vec![message.clone(); 2])
+    });
+}
+
+// ============================================================================
+// Malformed Input
+// ============================================================================
+
+#[test]
+fn decode_never_panics_on_arbitrary_bytes() {
+    Runner::new().check(&prop::bytes(4096), |bytes| {
+        let _ = decode(bytes);
+        Ok(())
+    });
+}
+
+#[test]
+fn decode_never_panics_on_corrupted_frames() {
+    Runner::new().check(&prop::frames(4096), |bytes| {
+        if let Ok((message, consumed)) = decode_prefix(bytes) {
+            // Whatever decodes is a valid message
+            if consumed > bytes.len() {
+                return Err(format!("consumed {consumed} of {} bytes", bytes.len()));
+            }
+            ensure_eq(&ok(decode(&ok(encode(&message))?))?, &message)?;
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn read_message_never_panics_on_arbitrary_streams() {
+    Runner::new().check(&prop::frames(4096), |bytes| {
+        let mut reader = bytes.as_slice();
+        while read_message(&mut reader).is_ok() {}
+        Ok(())
+    });
+}
@@ -0,0 +1,117 @@
+//! Stream resynchronization tests
+//!
+//! Inject garbage between valid frames and check that `FrameReader` in
+//! resync mode recovers every frame after the corruption and reports the
+//! number of bytes it skipped (SPEC.md Section 4.4).
+
+use std::io::{self, Read};
+
+use protocol_name::reader::FrameReader;
+use protocol_name::{encode, Message, ProtocolError};
+
+/// Delivers at most `chunk` bytes per read call
+struct Chunked<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.data.len().min(self.chunk).min(buf.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+fn frame(id: u32, payload: &[u8]) -> Vec<u8> {
+    encode(&Message::request(id, payload)).unwrap()
+}
+
+fn read_all<R: Read>(reader: &mut FrameReader<R>) -> Vec<(u32, usize)> {
+    let mut out = Vec::new();
+    loop {
+        match reader.read_message() {
+            Ok(message) => out.push((message.id, reader.last_skipped())),
+            Err(e) if e.is_clean_eof() => return out,
+            Err(e) => panic!("unexpected decode error in resync mode: {}", e),
+        }
+    }
+}
+
+#[test]
+fn resync_garbage_between_frames() {
+    let mut stream = frame(1, b"first");
+    stream.extend_from_slice(b"\x00\xFFnoise\x13");
+    stream.extend(frame(2, b"second"));
+    stream.extend_from_slice(&[0xAA; 100]);
+    stream.extend(frame(3, b""));
+
+    let mut reader = FrameReader::new(stream.as_slice()).with_resync(true);
+    assert_eq!(read_all(&mut reader), vec![(1, 0), (2, 8), (3, 100)]);
+    assert_eq!(reader.total_skipped(), 108);
+}
+
+#[test]
+fn resync_garbage_containing_magic() {
+    // "TU" followed by an unsupported version and then an unknown type
+    let mut stream = b"TU\x07TU\x01\x42junk".to_vec();
+    stream.extend(frame(7, b"payload"));
+
+    let mut reader = FrameReader::new(stream.as_slice()).with_resync(true);
+    assert_eq!(read_all(&mut reader), vec![(7, 11)]);
+}
+
+#[test]
+fn resync_after_corrupted_header() {
+    let mut second = frame(2, b"lost");
+    second[2] = 0x09; // unsupported version
+
+    let mut stream = frame(1, b"a");
+    stream.extend(&second);
+    stream.extend(frame(3, b"c"));
+
+    let mut reader = FrameReader::new(stream.as_slice()).with_resync(true);
+    assert_eq!(read_all(&mut reader), vec![(1, 0), (3, second.len())]);
+}
+
+#[test]
+fn resync_with_single_byte_reads() {
+    let mut stream = b"garbage".to_vec();
+    stream.extend(frame(1, b"x"));
+    stream.extend_from_slice(b"T"); // lone first byte of MAGIC
+    stream.extend(frame(2, b"y"));
+
+    let source = Chunked {
+        data: &stream,
+        chunk: 1,
+    };
+    let mut reader = FrameReader::new(source).with_resync(true);
+    assert_eq!(read_all(&mut reader), vec![(1, 7), (2, 1)]);
+}
+
+#[test]
+fn resync_on_demand() {
+    let mut stream = frame(1, b"a");
+    stream.extend_from_slice(b"zzz");
+    stream.extend(frame(2, b"b"));
+
+    let mut reader = FrameReader::new(stream.as_slice());
+    assert_eq!(reader.read_message().unwrap().id, 1);
+    assert!(matches!(
+        reader.read_message(),
+        Err(ProtocolError::InvalidMagic { .. })
+    ));
+    assert_eq!(reader.resync().unwrap(), 3);
+    assert_eq!(reader.read_message().unwrap().id, 2);
+}
+
+#[test]
+fn resync_garbage_until_eof() {
+    let mut stream = frame(1, b"a");
+    stream.extend_from_slice(b"trailing noise");
+
+    let mut reader = FrameReader::new(stream.as_slice()).with_resync(true);
+    assert_eq!(reader.read_message().unwrap().id, 1);
+    assert!(reader.read_message().unwrap_err().is_clean_eof());
+}
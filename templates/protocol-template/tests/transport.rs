@@ -0,0 +1,1006 @@
+//! Transport tests
+//!
+//! The same client and server run over TCP, Unix domain sockets, a child
+//! process's stdio and the in-memory pipe. Fault-injection tests use fixed
+//! seeds so every run sees the same faults.
+
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol_name::client::Client;
+use protocol_name::compress::Compression;
+use protocol_name::flow::{Backpressure, Window};
+use protocol_name::reader::FrameReader;
+use protocol_name::rng::Rng;
+use protocol_name::server::Server;
+use protocol_name::session::Sessions;
+use protocol_name::transport::{
+    pipe, ChildProcess, FaultyTransport, SecureStream, Transport, WebSocketStream,
+};
+use protocol_name::{
+    decode, encode, read_message, write_message, ErrorCode, Message, MessageType, ProtocolError,
+};
+
+fn echo(request: Message) -> Message {
+    Message::response(request.id, 0, &request.payload)
+}
+
+/// Exercise a connected client against the echo server
+fn round_trip<T: Transport>(client: &mut Client<T>) {
+    for (i, payload) in [&b"hello"[..], b"", &[0xAB; 70_000]].iter().enumerate() {
+        let response = client.call(payload).unwrap();
+        assert_eq!(response.message_type, MessageType::Response);
+        assert_eq!(response.id, i as u32 + 1);
+        assert_eq!(response.payload, *payload);
+    }
+}
+
+#[test]
+fn tcp_loopback() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Server::new(echo).serve(listener));
+
+    let mut first = Client::connect_tcp(addr).unwrap();
+    let mut second = Client::connect_tcp(addr).unwrap();
+    round_trip(&mut first);
+    round_trip(&mut second);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_loopback() {
+    use std::os::unix::net::UnixListener;
+
+    let path = std::env::temp_dir().join(format!("protocol-name-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || Server::new(echo).serve(listener));
+
+    let mut client = Client::connect_unix(&path).unwrap();
+    round_trip(&mut client);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn stdio_child_process() {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_protocol-name"));
+    command.args(["serve", "--stdio"]);
+
+    let mut client = Client::spawn(command).unwrap();
+    round_trip(&mut client);
+
+    // Closing stdin ends the child's serve loop cleanly
+    let status = client.into_inner().close().unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn stdio_child_reports_decode_errors() {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_protocol-name"));
+    command.args(["serve", "--stdio"]);
+    let mut child = ChildProcess::spawn(command).unwrap();
+
+    // The server opens by granting its flow-control window
+    let grant = protocol_name::read_message(&mut child).unwrap();
+    assert_eq!(grant.message_type, MessageType::WindowUpdate);
+
+    child.write_all(b"not a frame at all").unwrap();
+    let reply = protocol_name::read_message(&mut child).unwrap();
+    assert_eq!(reply.message_type, MessageType::Error);
+    assert_eq!(
+        reply.status,
+        Some(protocol_name::ErrorCode::InvalidFormat as u8)
+    );
+
+    let status = child.close().unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn memory_pipe() {
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || Server::new(echo).serve_connection(server_end));
+
+    let mut client = Client::new(client_end);
+    round_trip(&mut client);
+}
+
+#[test]
+fn split_writes_and_reads_both_ways() {
+    for seed in 0..20 {
+        let (client_end, server_end) = pipe();
+        let server_end = FaultyTransport::new(server_end, seed)
+            .with_split_writes(true)
+            .with_split_reads(true);
+        thread::spawn(move || Server::new(echo).serve_connection(server_end));
+
+        let client_end = FaultyTransport::new(client_end, !seed)
+            .with_split_writes(true)
+            .with_split_reads(true)
+            .with_delay(Duration::from_micros(50));
+        let mut client = Client::new(client_end);
+        round_trip(&mut client);
+        assert!(client.get_ref().stats().short_writes > 0, "seed {}", seed);
+    }
+}
+
+#[test]
+fn resync_recovers_from_corruption() {
+    let (sender, receiver) = pipe();
+    let mut sender = FaultyTransport::new(sender, 11).with_corrupt_rate(0.002);
+
+    let frames: Vec<Message> = (1..=200)
+        .map(|id| Message::request(id, format!("frame {}", id).as_bytes()))
+        .collect();
+    for frame in &frames {
+        sender.write_all(&encode(frame).unwrap()).unwrap();
+    }
+    let corrupted = sender.stats().corrupted;
+    assert!(corrupted > 0);
+    drop(sender);
+
+    let mut reader = FrameReader::new(receiver).with_resync(true);
+    let mut received = Vec::new();
+    loop {
+        match reader.read_message() {
+            Ok(message) => received.push(message),
+            Err(e) if e.is_clean_eof() || e.is_truncated() => break,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    // Intact frames arrive in order. A damaged frame may still be delivered
+    // (corruption in an ID or payload goes undetected without a checksum),
+    // but each one needs at least one corrupted byte.
+    let intact: Vec<&Message> = received.iter().filter(|m| frames.contains(m)).collect();
+    assert!(intact.windows(2).all(|w| w[0].id < w[1].id));
+    assert!(received.len() - intact.len() <= corrupted as usize);
+    assert!(intact.len() >= frames.len() / 2);
+    assert!(reader.total_skipped() > 0);
+}
+
+#[test]
+fn dropped_bytes_surface_as_timeout() {
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || Server::new(echo).serve_connection(server_end));
+
+    // The request never reaches the server, so no response ever arrives
+    let mut client_end = FaultyTransport::new(client_end, 3).with_drop_rate(1.0);
+    client_end
+        .get_mut()
+        .set_read_timeout(Some(Duration::from_millis(50)));
+    let mut client = Client::new(client_end);
+
+    match client.call(b"lost") {
+        Err(ProtocolError::Io { kind, .. }) => assert_eq!(kind, io::ErrorKind::TimedOut),
+        other => panic!("expected timeout, got {:?}", other),
+    }
+}
+
+#[test]
+fn metrics_observe_both_ends() {
+    use protocol_name::observe::Metrics;
+    use std::sync::Arc;
+
+    let server_metrics = Arc::new(Metrics::new());
+    let client_metrics = Arc::new(Metrics::new());
+
+    let (client_end, server_end) = pipe();
+    let server = Server::new(echo).with_observer(server_metrics.clone());
+    let handle = thread::spawn(move || server.serve_connection(server_end));
+
+    let mut client = Client::new(client_end).with_observer(client_metrics.clone());
+    client.call(b"one").unwrap();
+    client.call(b"two").unwrap();
+    client.send(&Message::response(9, 0, b"stray")).unwrap();
+    assert_eq!(client.recv().unwrap().message_type, MessageType::Error);
+    client.get_mut().write_all(b"garbage!!!!!!").unwrap();
+    assert_eq!(client.recv().unwrap().id, 0);
+    drop(client);
+    assert!(handle.join().unwrap().is_err());
+
+    let server = server_metrics.snapshot();
+    assert_eq!(server.connections_opened, 1);
+    assert_eq!(server.connections_active(), 0);
+    assert_eq!(server.frames_received, [2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(server.frames_sent, [0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    assert_eq!(server.decode_errors, [1, 0, 0]);
+    assert_eq!(server.requests, 2);
+
+    let client = client_metrics.snapshot();
+    assert_eq!(client.frames_sent, [2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(client.frames_received, [0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    assert_eq!(client.bytes_sent, server.bytes_received);
+    assert_eq!(client.requests, 2);
+    assert!(client_metrics
+        .to_prometheus()
+        .contains("protocol_name_request_duration_seconds_count 2\n"));
+}
+
+/// An echo server whose handler waits for a signal before each request
+fn gated_server(
+    window: Window,
+) -> (
+    Client<protocol_name::transport::MemoryStream>,
+    mpsc::Sender<()>,
+) {
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let server = Server::new(move |m: Message| {
+        gate.lock().unwrap().recv().unwrap();
+        echo(m)
+    })
+    .with_window(window);
+
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_connection(server_end));
+    (Client::new(client_end), release)
+}
+
+#[test]
+fn flow_control_blocks_until_credit_returns() {
+    let (mut client, release) = gated_server(Window::new(2, 1 << 20));
+    client.send(&Message::request(1, b"a")).unwrap();
+    client.send(&Message::request(2, b"b")).unwrap();
+    assert_eq!(client.credit(), Window::new(0, (1 << 20) - 2));
+
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+    });
+    let started = Instant::now();
+    client.send(&Message::request(3, b"c")).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(40));
+    releaser.join().unwrap();
+
+    // The response read while waiting for credit is still delivered in order
+    for id in 1..=3 {
+        assert_eq!(client.recv().unwrap().id, id);
+    }
+}
+
+#[test]
+fn flow_control_fail_fast() {
+    let (client, release) = gated_server(Window::new(2, 1 << 20));
+    let mut client = client.with_backpressure(Backpressure::FailFast);
+    client.send(&Message::request(1, b"a")).unwrap();
+    client.send(&Message::request(2, b"b")).unwrap();
+    assert!(matches!(
+        client.send(&Message::request(3, b"c")),
+        Err(ProtocolError::WindowExhausted { requests: 0, .. })
+    ));
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    assert_eq!(client.recv().unwrap().id, 1);
+    assert_eq!(client.recv().unwrap().id, 2);
+    // Credit came back ahead of the responses
+    client.send(&Message::request(3, b"c")).unwrap();
+}
+
+#[test]
+fn concurrent_handlers_answer_in_request_order() {
+    // Each request carries how long its handler sleeps, drawn at random
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let server = {
+        let (running, peak) = (Arc::clone(&running), Arc::clone(&peak));
+        Server::new(move |m: Message| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(u64::from(m.payload[0])));
+            running.fetch_sub(1, Ordering::SeqCst);
+            echo(m)
+        })
+    };
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_connection(server_end));
+    let mut client = Client::new(client_end);
+
+    let mut rng = Rng::new(37);
+    let delays: Vec<u8> = (0..32).map(|_| rng.range(0, 40) as u8).collect();
+    for (i, delay) in delays.iter().enumerate() {
+        client
+            .send(&Message::request(i as u32 + 1, &[*delay]))
+            .unwrap();
+    }
+    for (i, delay) in delays.iter().enumerate() {
+        let response = client.recv().unwrap();
+        assert_eq!(
+            (response.id, response.payload.as_slice()),
+            (i as u32 + 1, &[*delay][..])
+        );
+    }
+    assert!(peak.load(Ordering::SeqCst) > 1, "handlers never overlapped");
+}
+
+#[test]
+fn duplicate_in_flight_id_is_rejected() {
+    let (started_tx, started) = mpsc::channel::<u32>();
+    let started_tx = Mutex::new(started_tx);
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let server = Server::new(move |m: Message| {
+        started_tx.lock().unwrap().send(m.id).unwrap();
+        gate.lock().unwrap().recv().unwrap();
+        echo(m)
+    });
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_connection(server_end));
+    let mut client = Client::new(client_end);
+
+    client.send(&Message::request(1, b"a")).unwrap();
+    client.send(&Message::request(1, b"b")).unwrap();
+    client.send(&Message::request(2, b"c")).unwrap();
+    // Requests are read in order, so once request 2 has started the
+    // duplicate has been seen while request 1 was still in flight
+    let mut ids = [started.recv().unwrap(), started.recv().unwrap()];
+    ids.sort();
+    assert_eq!(ids, [1, 2]);
+
+    // The rejection is ordered between the responses
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::response(1, 0, b"a"));
+    let rejected = client.recv().unwrap();
+    assert_eq!(
+        (rejected.message_type, rejected.id),
+        (MessageType::Error, 1)
+    );
+    assert_eq!(rejected.status, Some(ErrorCode::DuplicateId as u8));
+    assert_eq!(client.recv().unwrap(), Message::response(2, 0, b"c"));
+
+    // Once answered, the ID may be reused
+    client.send(&Message::request(1, b"d")).unwrap();
+    release.send(()).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::response(1, 0, b"d"));
+}
+
+#[test]
+fn reorder_limit_caps_concurrent_handlers() {
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let started = Arc::new(AtomicUsize::new(0));
+    let server = {
+        let started = Arc::clone(&started);
+        Server::new(move |m: Message| {
+            started.fetch_add(1, Ordering::SeqCst);
+            if m.id == 1 {
+                gate.lock().unwrap().recv().unwrap();
+            }
+            echo(m)
+        })
+        .with_reorder_limit(2)
+    };
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || server.serve_connection(server_end));
+    let mut client = Client::new(client_end);
+
+    for id in 1..=6 {
+        client.send(&Message::request(id, b"")).unwrap();
+    }
+    // Request 1 blocks; two finished responses fill the buffer
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(started.load(Ordering::SeqCst), 3);
+
+    release.send(()).unwrap();
+    for id in 1..=6 {
+        assert_eq!(client.recv().unwrap().id, id);
+    }
+    assert_eq!(started.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn queued_requests_travel_in_batches() {
+    use protocol_name::batch::BatchWindow;
+    use protocol_name::observe::Metrics;
+
+    let metrics = Arc::new(Metrics::new());
+    let (client_end, server_end) = pipe();
+    let server = Server::new(echo).with_observer(metrics.clone());
+    let handle = thread::spawn(move || server.serve_connection(server_end));
+
+    let window = BatchWindow::new(25, 1 << 20, Duration::from_secs(60));
+    let mut client = Client::new(client_end).with_batch_window(window);
+    let ids: Vec<u32> = (0..100u32)
+        .map(|i| client.queue(&i.to_be_bytes()).unwrap())
+        .collect();
+    for (i, id) in ids.iter().enumerate() {
+        let response = client.recv().unwrap();
+        assert_eq!(response.id, *id);
+        assert_eq!(response.payload, (i as u32).to_be_bytes());
+    }
+    // A plain call still works between batches
+    assert!(client.call(b"single").unwrap().is_success());
+    drop(client);
+    handle.join().unwrap().unwrap();
+
+    let server = metrics.snapshot();
+    assert_eq!(server.frames_received[3], 4);
+    assert_eq!(server.frames_sent[4], 4);
+    assert_eq!(server.requests, 101);
+}
+
+#[test]
+fn publish_subscribe_across_connections() {
+    let server = Server::new(echo);
+    let connect = || {
+        let (client_end, server_end) = pipe();
+        let server = server.clone();
+        thread::spawn(move || server.serve_connection(server_end));
+        Client::new(client_end)
+    };
+    let mut subscriber = connect();
+    let mut publisher = connect();
+
+    let eu = subscriber.subscribe("orders.eu.*").unwrap();
+    let all = subscriber.subscribe("orders.>").unwrap();
+    assert_eq!(publisher.publish("orders.eu.created", b"1").unwrap(), 2);
+    assert_eq!(publisher.publish("orders.us.created", b"2").unwrap(), 1);
+    assert_eq!(publisher.publish("billing.eu", b"3").unwrap(), 0);
+
+    // Events of different subscriptions may interleave either way
+    let mut events: Vec<_> = (0..3).map(|_| subscriber.next_event().unwrap()).collect();
+    events.sort_by_key(|e| (e.subscription, e.data.clone()));
+    let received: Vec<_> = events
+        .iter()
+        .map(|e| (e.subscription, e.topic.as_str(), e.data.as_slice()))
+        .collect();
+    assert_eq!(
+        received,
+        vec![
+            (eu, "orders.eu.created", &b"1"[..]),
+            (all, "orders.eu.created", &b"1"[..]),
+            (all, "orders.us.created", &b"2"[..]),
+        ]
+    );
+
+    // Responses still come back while events are pending
+    assert!(subscriber.call(b"ping").unwrap().is_success());
+
+    subscriber.unsubscribe(all).unwrap();
+    assert_eq!(publisher.publish("orders.us.created", b"4").unwrap(), 0);
+    assert_eq!(publisher.publish("orders.eu.created", b"5").unwrap(), 1);
+    let event = subscriber.next_event().unwrap();
+    assert_eq!((event.subscription, event.data), (eu, b"5".to_vec()));
+
+    let unknown = subscriber.unsubscribe(all);
+    assert!(
+        matches!(unknown, Err(ProtocolError::Rejected { code: 0x01, .. })),
+        "{:?}",
+        unknown
+    );
+}
+
+#[test]
+fn slow_subscribers_drop_by_policy() {
+    use protocol_name::pubsub::{Broker, DropPolicy};
+
+    let oldest = Broker::new().with_queue(2, DropPolicy::DropOldest);
+    let newest = oldest.clone().with_queue(2, DropPolicy::DropNewest);
+    let keeps_latest = oldest.subscribe("ticks").unwrap();
+    let keeps_first = newest.subscribe("ticks").unwrap();
+
+    // Remote publishes reach in-process subscribers of the same broker
+    let (client_end, server_end) = pipe();
+    let server = Server::new(echo).with_broker(oldest);
+    thread::spawn(move || server.serve_connection(server_end));
+    let mut publisher = Client::new(client_end);
+    for i in 0..5u8 {
+        assert_eq!(publisher.publish("ticks", &[i]).unwrap(), 2);
+    }
+
+    let first = keeps_latest.try_recv().unwrap();
+    assert_eq!((first.data, first.dropped), (vec![3], 3));
+    assert_eq!(keeps_latest.try_recv().unwrap().data, vec![4]);
+    assert_eq!(keeps_latest.dropped(), 3);
+
+    assert_eq!(keeps_first.try_recv().unwrap().data, vec![0]);
+    let second = keeps_first.try_recv().unwrap();
+    assert_eq!(second.data, vec![1]);
+    assert_eq!(keeps_first.dropped(), 3);
+    assert!(keeps_first.try_recv().is_none());
+}
+
+#[test]
+fn router_serves_method_calls() {
+    use protocol_name::rpc::Router;
+
+    let router = Router::new()
+        .route("add", |(a, b): (i64, i64)| Ok(a + b))
+        .route("concat", |parts: Vec<String>| Ok(parts.concat()));
+    let (client_end, server_end) = pipe();
+    thread::spawn(move || Server::new(router).serve_connection(server_end));
+
+    let mut client = Client::new(client_end);
+    assert_eq!(client.call_method::<_, i64>("add", &(40i64, 2i64)), Ok(42));
+    let parts = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(
+        client.call_method::<_, String>("concat", &parts),
+        Ok("ab".to_string())
+    );
+    assert_eq!(
+        client.methods(),
+        Ok(vec!["add".to_string(), "concat".to_string()])
+    );
+
+    match client.call_method::<_, ()>("missing", &()) {
+        Err(ProtocolError::Rejected { code, message, .. }) => {
+            assert_eq!(code, ErrorCode::UnknownMethod as u8);
+            assert_eq!(message, "unknown method \"missing\"");
+        }
+        other => panic!("expected UnknownMethod, got {:?}", other),
+    }
+    // Arguments of the wrong type are rejected without closing the connection
+    let wrong = client.call_method::<_, i64>("add", &"forty");
+    assert!(
+        matches!(wrong, Err(ProtocolError::Rejected { code: 0x01, .. })),
+        "{:?}",
+        wrong
+    );
+    assert_eq!(client.call_method::<_, i64>("add", &(1i64, 1i64)), Ok(2));
+}
+
+#[test]
+fn compressed_frames_both_ways() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(echo).with_compression(Compression::new());
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = Client::connect_tcp(addr)
+        .unwrap()
+        .with_compression(Compression::new());
+    round_trip(&mut client);
+
+    // On the wire, a large reply is compressed and a small one is not
+    let json = r#"{"id": 1, "name": "widget", "tags": ["a", "b"]}, "#.repeat(200);
+    let mut raw = std::net::TcpStream::connect(addr).unwrap();
+    let grant = read_message(&mut raw).unwrap();
+    assert_eq!(grant.message_type, MessageType::WindowUpdate);
+    for (id, payload) in [(1, json.as_bytes()), (2, &b"tiny"[..])] {
+        let request = Message::request(id, payload);
+        raw.write_all(&Compression::new().encode(&request).unwrap())
+            .unwrap();
+
+        let mut header = [0u8; 13];
+        raw.read_exact(&mut header).unwrap();
+        let length = u32::from_be_bytes([header[9], header[10], header[11], header[12]]);
+        let compressed = length & 0x8000_0000 != 0;
+        assert_eq!(compressed, payload.len() >= Compression::DEFAULT_MIN_SIZE);
+
+        let mut frame = header.to_vec();
+        frame.resize(13 + (length & 0x7FFF_FFFF) as usize, 0);
+        raw.read_exact(&mut frame[13..]).unwrap();
+        if compressed {
+            assert!(frame.len() < payload.len() / 4);
+        }
+        assert_eq!(decode(&frame).unwrap(), Message::response(id, 0, payload));
+    }
+
+    // A client that never compresses is never sent a compressed reply
+    let mut plain = std::net::TcpStream::connect(addr).unwrap();
+    read_message(&mut plain).unwrap();
+    write_message(&mut plain, &Message::request(1, json.as_bytes())).unwrap();
+    let mut header = [0u8; 13];
+    plain.read_exact(&mut header).unwrap();
+    let length = u32::from_be_bytes([header[9], header[10], header[11], header[12]]);
+    assert_eq!(length, json.len() as u32);
+}
+
+#[test]
+fn encrypted_connections_with_psk() {
+    const PSK: &[u8] = b"shared secret for the loopback test";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(echo)
+        .with_psk(PSK)
+        .unwrap()
+        .with_compression(Compression::new());
+    let (results, closed) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let server = server.clone();
+            let results = results.clone();
+            thread::spawn(move || results.send(server.serve_connection(stream.unwrap())));
+        }
+    });
+
+    let secure = SecureStream::connect(std::net::TcpStream::connect(addr).unwrap(), PSK).unwrap();
+    let mut client = Client::new(secure).with_compression(Compression::new());
+    round_trip(&mut client);
+    drop(client);
+    assert_eq!(closed.recv().unwrap(), Ok(()));
+
+    // Either side notices a different key during the handshake
+    let err = SecureStream::connect(
+        std::net::TcpStream::connect(addr).unwrap(),
+        b"not the shared secret at all",
+    )
+    .unwrap_err();
+    assert!(matches!(err, ProtocolError::HandshakeFailed(_)), "{}", err);
+    assert!(matches!(
+        closed.recv().unwrap(),
+        Err(ProtocolError::HandshakeFailed(_))
+    ));
+
+    // A plaintext frame is refused before any request is handled
+    let mut plain = std::net::TcpStream::connect(addr).unwrap();
+    plain
+        .write_all(&encode(&Message::request(1, b"hello")).unwrap())
+        .unwrap();
+    assert!(!matches!(plain.read(&mut [0u8; 64]), Ok(n) if n > 0));
+    assert!(matches!(
+        closed.recv().unwrap(),
+        Err(ProtocolError::HandshakeFailed(_))
+    ));
+
+    // A forged record ends the connection with a specific error, reported
+    // to the client in a sealed error frame
+    let mut secure =
+        SecureStream::connect(std::net::TcpStream::connect(addr).unwrap(), PSK).unwrap();
+    let mut forged = vec![0, 0, 0, 40, 0, 0, 0, 0, 0, 0, 0, 0];
+    forged.extend_from_slice(&[0x5A; 32]);
+    secure.get_mut().write_all(&forged).unwrap();
+    let grant = read_message(&mut secure).unwrap();
+    assert_eq!(grant.message_type, MessageType::WindowUpdate);
+    let rejection = read_message(&mut secure).unwrap();
+    assert_eq!(rejection.message_type, MessageType::Error);
+    assert_eq!(rejection.status, Some(ErrorCode::InvalidFormat as u8));
+    assert_eq!(
+        closed.recv().unwrap(),
+        Err(ProtocolError::Tampered { sequence: 0 })
+    );
+}
+
+#[test]
+fn websocket_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(echo).with_websocket(true);
+    let (results, closed) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let server = server.clone();
+            let results = results.clone();
+            thread::spawn(move || results.send(server.serve_connection(stream.unwrap())));
+        }
+    });
+
+    let websocket = WebSocketStream::connect(
+        std::net::TcpStream::connect(addr).unwrap(),
+        "localhost",
+        "/",
+    )
+    .unwrap();
+    let mut client = Client::new(websocket);
+    // The pong is absorbed while waiting for responses
+    client.get_mut().ping(b"still there?").unwrap();
+    round_trip(&mut client);
+    // An orderly close ends the connection cleanly
+    client.get_mut().close().unwrap();
+    assert_eq!(closed.recv().unwrap(), Ok(()));
+
+    // A request that is not an upgrade is refused
+    let mut plain = std::net::TcpStream::connect(addr).unwrap();
+    plain
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    plain.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+    assert!(matches!(
+        closed.recv().unwrap(),
+        Err(ProtocolError::WebSocket(_))
+    ));
+}
+
+#[test]
+fn encrypted_websocket_connections() {
+    const PSK: &[u8] = b"shared secret for the loopback test";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        Server::new(echo)
+            .with_websocket(true)
+            .with_psk(PSK)
+            .unwrap()
+            .serve(listener)
+    });
+
+    let websocket = WebSocketStream::connect(
+        std::net::TcpStream::connect(addr).unwrap(),
+        "localhost",
+        "/",
+    )
+    .unwrap();
+    round_trip(&mut Client::new(
+        SecureStream::connect(websocket, PSK).unwrap(),
+    ));
+}
+
+#[test]
+fn cli_serves_websocket_clients() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_protocol-name"))
+        .args(["serve", "--tcp", "127.0.0.1:0", "--websocket"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    io::BufRead::read_line(
+        &mut io::BufReader::new(child.stderr.take().unwrap()),
+        &mut line,
+    )
+    .unwrap();
+    let addr = line.trim().strip_prefix("listening on ").unwrap();
+
+    let websocket = WebSocketStream::connect(
+        std::net::TcpStream::connect(addr).unwrap(),
+        "localhost",
+        "/",
+    )
+    .unwrap();
+    round_trip(&mut Client::new(websocket));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+/// A TCP server with sessions recording each payload it handles
+///
+/// Payloads starting with "slow" report that they started, then wait for a
+/// release.
+struct SessionServer {
+    addr: std::net::SocketAddr,
+    handled: Arc<Mutex<Vec<Vec<u8>>>>,
+    started: mpsc::Receiver<()>,
+    release: mpsc::Sender<()>,
+}
+
+impl SessionServer {
+    fn start() -> Self {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (started_tx, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let (started_tx, released) = (Mutex::new(started_tx), Mutex::new(released));
+        let log = Arc::clone(&handled);
+        let server = Server::new(move |request: Message| {
+            if request.payload.starts_with(b"slow") {
+                started_tx.lock().unwrap().send(()).unwrap();
+                released.lock().unwrap().recv().unwrap();
+            }
+            log.lock().unwrap().push(request.payload.clone());
+            echo(request)
+        })
+        .with_sessions(Sessions::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+        Self {
+            addr,
+            handled,
+            started,
+            release,
+        }
+    }
+
+    fn handled(&self) -> Vec<Vec<u8>> {
+        self.handled.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn replayed_requests_are_answered_from_the_session_cache() {
+    let server = SessionServer::start();
+    let mut client = Client::connect_tcp(server.addr).unwrap();
+    let session = client.open_session().unwrap();
+    assert_eq!(client.call(b"before").unwrap().payload, b"before");
+
+    // Both requests are handled, but the connection is abandoned before
+    // either response is read
+    let id = client.next_id();
+    client.send(&Message::request(id, b"first")).unwrap();
+    let id = client.next_id();
+    client.send(&Message::request(id, b"second")).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.handled().len() < 3 {
+        assert!(Instant::now() < deadline, "requests were not handled");
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(client.unacknowledged().count(), 2);
+
+    let old = client
+        .reconnect(std::net::TcpStream::connect(server.addr).unwrap())
+        .unwrap();
+    drop(old);
+    assert_eq!(client.session(), Some(session));
+    assert_eq!(client.recv().unwrap().payload, b"first");
+    assert_eq!(client.recv().unwrap().payload, b"second");
+    assert_eq!(client.unacknowledged().count(), 0);
+    assert_eq!(client.call(b"after").unwrap().payload, b"after");
+    assert_eq!(
+        server.handled(),
+        [&b"before"[..], b"first", b"second", b"after"]
+    );
+}
+
+#[test]
+fn client_reconnects_and_waits_for_a_request_still_running() {
+    let server = SessionServer::start();
+    let addr = server.addr;
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    let severed = stream.try_clone().unwrap();
+    let mut client = Client::new(stream).with_reconnect(move || std::net::TcpStream::connect(addr));
+    client.open_session().unwrap();
+
+    let call = thread::spawn(move || {
+        let response = client.call(b"slow request");
+        (client, response)
+    });
+    // Drop the connection while the request is being handled; the replay
+    // on the new connection waits for it instead of running it again
+    server.started.recv().unwrap();
+    severed.shutdown(std::net::Shutdown::Both).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    server.release.send(()).unwrap();
+
+    let (mut client, response) = call.join().unwrap();
+    assert_eq!(response.unwrap().payload, b"slow request");
+    assert_eq!(client.call(b"next").unwrap().payload, b"next");
+    assert_eq!(server.handled(), [&b"slow request"[..], b"next"]);
+}
+
+#[test]
+fn unknown_sessions_cannot_be_resumed() {
+    let server = SessionServer::start();
+    let mut client = Client::connect_tcp(server.addr).unwrap();
+    client.open_session().unwrap();
+    let id = client.next_id();
+    client.send(&Message::request(id, b"lost")).unwrap();
+
+    // A server that does not know the session cannot say whether the
+    // request ran, so the client keeps it
+    let other = SessionServer::start();
+    match client.reconnect(std::net::TcpStream::connect(other.addr).unwrap()) {
+        Err(ProtocolError::Rejected { code, .. }) => {
+            assert_eq!(code, ErrorCode::UnknownSession as u8)
+        }
+        other => panic!("expected UnknownSession, got {:?}", other.map(|_| ())),
+    }
+    let kept: Vec<_> = client.unacknowledged().map(|m| m.payload.clone()).collect();
+    assert_eq!(kept, [b"lost".to_vec()]);
+    assert!(other.handled().is_empty());
+
+    // Without sessions, opening one fails the same way
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || Server::new(echo).serve(listener));
+    let mut client = Client::connect_tcp(addr).unwrap();
+    assert!(matches!(
+        client.open_session(),
+        Err(ProtocolError::Rejected { code, .. }) if code == ErrorCode::UnknownSession as u8
+    ));
+}
+
+/// A front service whose handler calls a back service over TCP, as one
+/// hop of a service chain
+fn service_chain(front_work: Duration) -> (std::net::SocketAddr, mpsc::Receiver<Option<u32>>) {
+    let (seen, budgets) = mpsc::channel();
+    let back = TcpListener::bind("127.0.0.1:0").unwrap();
+    let back_addr = back.local_addr().unwrap();
+    let seen = Mutex::new(seen);
+    thread::spawn(move || {
+        Server::new(move |request: Message| {
+            let budget = request.options.and_then(|options| options.budget_ms());
+            seen.lock().unwrap().send(budget).unwrap();
+            echo(request)
+        })
+        .serve(back)
+    });
+
+    let front = TcpListener::bind("127.0.0.1:0").unwrap();
+    let front_addr = front.local_addr().unwrap();
+    thread::spawn(move || {
+        Server::new(move |request: Message| {
+            thread::sleep(front_work);
+            let mut downstream = Client::connect_tcp(back_addr).unwrap();
+            match downstream.call(&request.payload) {
+                Ok(response) => Message::response(request.id, 0, &response.payload),
+                Err(e) => Message::error(request.id, e.error_code() as u8, &e.to_string()),
+            }
+        })
+        .serve(front)
+    });
+    (front_addr, budgets)
+}
+
+#[test]
+fn remaining_budget_propagates_downstream() {
+    let (addr, budgets) = service_chain(Duration::from_millis(50));
+    let mut client = Client::connect_tcp(addr)
+        .unwrap()
+        .with_timeout(Duration::from_secs(2));
+    assert_eq!(client.call(b"hop").unwrap().payload, b"hop");
+
+    // The back service got what was left after the front service's work
+    let budget = budgets
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap()
+        .unwrap();
+    assert!(budget > 1_000 && budget <= 1_950, "budget {} ms", budget);
+
+    // Without a deadline upstream, none is invented downstream
+    let mut client = Client::connect_tcp(addr).unwrap();
+    client.call(b"hop").unwrap();
+    assert_eq!(budgets.recv_timeout(Duration::from_secs(5)).unwrap(), None);
+}
+
+#[test]
+fn spent_budget_stops_the_chain() {
+    let (addr, budgets) = service_chain(Duration::from_millis(150));
+    let mut client = Client::connect_tcp(addr)
+        .unwrap()
+        .with_timeout(Duration::from_millis(100));
+    let response = client.call(b"hop").unwrap();
+    assert_eq!(response.message_type, MessageType::Error);
+    assert_eq!(response.status, Some(ErrorCode::DeadlineExceeded as u8));
+    // The back service was never called
+    assert!(budgets.recv_timeout(Duration::from_millis(200)).is_err());
+}
+
+#[test]
+fn urgent_requests_overtake_queued_ones() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (release, gate) = mpsc::channel::<()>();
+    let gate = Mutex::new(gate);
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&started);
+    thread::spawn(move || {
+        Server::new(move |request: Message| {
+            if request.payload == b"block" {
+                gate.lock().unwrap().recv().unwrap();
+            }
+            log.lock().unwrap().push(request.payload.clone());
+            echo(request)
+        })
+        .with_workers(1)
+        .serve(listener)
+    });
+
+    let mut blocker = Client::connect_tcp(addr).unwrap();
+    let id = blocker.next_id();
+    blocker.send(&Message::request(id, b"block")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // Queued behind the blocker on separate connections, lowest priority first
+    let callers: Vec<_> = [(b"low", 10u8), (b"mid", 128), (b"top", 250)]
+        .into_iter()
+        .map(|(payload, priority)| {
+            let handle = thread::spawn(move || {
+                let mut client = Client::connect_tcp(addr).unwrap().with_priority(priority);
+                client.call(payload).unwrap()
+            });
+            thread::sleep(Duration::from_millis(50));
+            handle
+        })
+        .collect();
+    release.send(()).unwrap();
+    for caller in callers {
+        assert!(caller.join().unwrap().is_success());
+    }
+    assert!(blocker.recv().unwrap().is_success());
+    let order = started.lock().unwrap().clone();
+    assert_eq!(order, [&b"block"[..], b"top", b"mid", b"low"]);
+}
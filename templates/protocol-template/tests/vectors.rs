@@ -3,13 +3,22 @@
 //! These tests verify the implementation against the specification.
 //! Each test corresponds to a vector in SPEC.md Section 7.
 
-use protocol_name::{decode, encode, Message, MessageType, ProtocolError};
+use protocol_name::batch::{Batch, BatchResponse};
+use protocol_name::compress::Compression;
+use protocol_name::deadline::RequestOptions;
+use protocol_name::pubsub::{Event, Publication};
+use protocol_name::rpc::MethodCall;
+use protocol_name::session::{Resume, SessionId};
+use protocol_name::{
+    decode, decode_prefix, decode_strict, encode, Field, Message, MessageType, ProtocolError,
+};
 
 // ============================================================================
 // Valid Message Vectors
 // ============================================================================
 
 #[test]
+#[rustfmt::skip]
 fn vector_minimal_request() {
     // From SPEC.md Section 7.1
     // Minimal request with empty payload
@@ -30,6 +39,7 @@ fn vector_minimal_request() {
 }
 
 #[test]
+#[rustfmt::skip]
 fn vector_request_with_payload() {
     // From SPEC.md Section 7.1
     // Request with "hello" payload
@@ -51,6 +61,7 @@ fn vector_request_with_payload() {
 }
 
 #[test]
+#[rustfmt::skip]
 fn vector_success_response() {
     // Response with status 0 (success)
     let bytes: Vec<u8> = vec![
@@ -73,6 +84,7 @@ fn vector_success_response() {
 }
 
 #[test]
+#[rustfmt::skip]
 fn vector_error_response() {
     // Error message
     let bytes: Vec<u8> = vec![
@@ -93,11 +105,238 @@ fn vector_error_response() {
     assert_eq!(message.payload, b"error");
 }
 
+#[test]
+#[rustfmt::skip]
+fn vector_window_update() {
+    // From SPEC.md Section 7.1
+    // Grant of 32 requests and 4 MiB
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x03,       // Type: WindowUpdate
+        0x00, 0x00, 0x00, 0x00, // ID: 0
+        0x00, 0x00, 0x00, 0x08, // Payload length: 8
+        0x00, 0x00, 0x00, 0x20, // Requests: 32
+        0x00, 0x40, 0x00, 0x00, // Bytes: 4194304
+    ];
+
+    let message = decode(&bytes).expect("Should decode window update");
+
+    assert_eq!(message.message_type, MessageType::WindowUpdate);
+    assert_eq!(message.status, None);
+    assert_eq!(message, Message::window_update(32, 4 * 1024 * 1024));
+    assert_eq!(encode(&message).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_batch() {
+    // From SPEC.md Section 7.1
+    // Batch 1 carrying request 2 ("hi") and request 3 (empty)
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x04,       // Type: Batch
+        0x00, 0x00, 0x00, 0x01, // ID: 1
+        0x00, 0x00, 0x00, 0x16, // Payload length: 22
+        0x00, 0x00, 0x00, 0x02, // Count: 2
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x68, 0x69, // Request 2: "hi"
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, // Request 3: empty
+    ];
+
+    let message = decode(&bytes).expect("Should decode batch");
+
+    let mut batch = Batch::new(1);
+    batch.push(2, b"hi");
+    batch.push(3, b"");
+    assert_eq!(message.status, None);
+    assert_eq!(Batch::from_message(&message), Ok(batch.clone()));
+    assert_eq!(encode(&batch.to_message()).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_batch_response() {
+    // From SPEC.md Section 7.1
+    // Results for batch 1: success for request 2, DuplicateId for request 3
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x05,       // Type: BatchResponse
+        0x00, 0x00, 0x00, 0x01, // ID: 1
+        0x00, 0x00, 0x00, 0x1A, // Payload length: 26
+        0x00, 0x00, 0x00, 0x02, // Count: 2
+        0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x68, 0x69, // Response 2, status 0: "hi"
+        0xFF, 0x00, 0x00, 0x00, 0x03, 0x05, 0x00, 0x00, 0x00, 0x00, // Error 3, DuplicateId
+    ];
+
+    let message = decode(&bytes).expect("Should decode batch response");
+
+    let response = BatchResponse::new(1, vec![Message::response(2, 0, b"hi"), Message::error(3, 0x05, "")]);
+    assert_eq!(BatchResponse::from_message(&message), Ok(response.clone()));
+    assert_eq!(encode(&response.to_message().unwrap()).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_method_call() {
+    // From SPEC.md Section 7.1
+    // Method call 3: add(1, 300) with arguments (u32, u32)
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x03, // ID: 3
+        0x00, 0x00, 0x00, 0x07, // Payload length: 7
+        0x03, 0x61, 0x64, 0x64, // Method: "add"
+        0x01, 0xAC, 0x02, // Args: 1, 300
+    ];
+
+    let message = decode(&bytes).expect("Should decode method call");
+    let call = MethodCall::from_message(&message).unwrap();
+    assert_eq!(call.method, "add");
+    assert_eq!(call.args_as::<(u32, u32)>(), Ok((1, 300)));
+    assert_eq!(encode(&MethodCall::new("add", &(1u32, 300u32)).to_message(3)).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_subscribe() {
+    // From SPEC.md Section 7.1
+    // Subscribe 2 to "a.*"
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x06,       // Type: Subscribe
+        0x00, 0x00, 0x00, 0x02, // ID: 2
+        0x00, 0x00, 0x00, 0x03, // Payload length: 3
+        0x61, 0x2E, 0x2A, // Pattern: "a.*"
+    ];
+
+    let message = decode(&bytes).expect("Should decode subscribe");
+    assert_eq!(message, Message::subscribe(2, "a.*"));
+    assert_eq!(encode(&message).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_publish() {
+    // From SPEC.md Section 7.1
+    // Publish 4 of "hi" to "a.b"
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x08,       // Type: Publish
+        0x00, 0x00, 0x00, 0x04, // ID: 4
+        0x00, 0x00, 0x00, 0x07, // Payload length: 7
+        0x00, 0x03, 0x61, 0x2E, 0x62, // Topic: "a.b"
+        0x68, 0x69, // Data: "hi"
+    ];
+
+    let message = decode(&bytes).expect("Should decode publish");
+    let publication = Publication::new("a.b", b"hi");
+    assert_eq!(message.status, None);
+    assert_eq!(Publication::from_message(&message), Ok(publication.clone()));
+    assert_eq!(encode(&publication.to_message(4)).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_event() {
+    // From SPEC.md Section 7.1
+    // Event for subscription 2 after one dropped event
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x09,       // Type: Event
+        0x00, 0x00, 0x00, 0x02, // ID: subscription 2
+        0x00, 0x00, 0x00, 0x0B, // Payload length: 11
+        0x00, 0x00, 0x00, 0x01, // Dropped: 1
+        0x00, 0x03, 0x61, 0x2E, 0x62, // Topic: "a.b"
+        0x68, 0x69, // Data: "hi"
+    ];
+
+    let message = decode(&bytes).expect("Should decode event");
+    let event = Event {
+        subscription: 2,
+        dropped: 1,
+        topic: "a.b".to_string(),
+        data: b"hi".to_vec(),
+    };
+    assert_eq!(Event::from_message(&message), Ok(event.clone()));
+    assert_eq!(encode(&event.to_message()).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_resume() {
+    // From SPEC.md Section 7.1
+    // Resume 6 of session 0102...10, replaying request 5
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x0A,       // Type: Resume
+        0x00, 0x00, 0x00, 0x06, // ID: 6
+        0x00, 0x00, 0x00, 0x14, // Payload length: 20
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // Session ID
+        0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
+        0x00, 0x00, 0x00, 0x05, // Replay: request 5
+    ];
+
+    let message = decode(&bytes).expect("Should decode resume");
+    let resume = Resume::new(SessionId(std::array::from_fn(|i| i as u8 + 1)), vec![5]);
+    assert_eq!(Resume::from_message(&message), Ok(resume.clone()));
+    assert_eq!(encode(&resume.to_message(6)).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_compressed_request() {
+    // From SPEC.md Section 7.1
+    // Request 5 carrying "abcabcabcabc", compressed
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x05, // ID: 5
+        0x80, 0x00, 0x00, 0x0A, // Payload length: 10, compressed
+        0x00, 0x00, 0x00, 0x0C, // Original size: 12
+        0x4B, 0x4C, 0x4A, 0x86, 0x23, 0x00, // DEFLATE: "abc", match 9 at distance 3
+    ];
+
+    let message = decode(&bytes).expect("Should decode compressed request");
+    assert_eq!(message, Message::request(5, b"abcabcabcabc"));
+    assert_eq!(Compression::new().with_min_size(0).encode(&message).unwrap(), bytes);
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_request_with_options() {
+    // From SPEC.md Section 7.1
+    // Request 7 carrying "hi" with a 250 ms budget and priority 200
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x07, // ID: 7
+        0x40, 0x00, 0x00, 0x02, // Payload length: 2, options follow
+        0x00, 0x00, 0x00, 0xFA, // Budget: 250 ms
+        0xC8,       // Priority: 200
+        0x68, 0x69, // Payload: "hi"
+    ];
+
+    let message = decode_strict(&bytes).expect("Should decode request with options");
+    let options = RequestOptions::new().with_budget_ms(250).with_priority(200);
+    assert_eq!(message, Message::request(7, b"hi").with_options(options));
+    assert_eq!(encode(&message).unwrap(), bytes);
+}
+
 // ============================================================================
 // Invalid Message Vectors
 // ============================================================================
 
 #[test]
+#[rustfmt::skip]
 fn vector_invalid_magic() {
     // From SPEC.md Section 7.2
     let bytes: Vec<u8> = vec![
@@ -109,10 +348,14 @@ fn vector_invalid_magic() {
     ];
 
     let result = decode(&bytes);
-    assert!(matches!(result, Err(ProtocolError::InvalidMagic([0x00, 0x00]))));
+    assert!(matches!(
+        result,
+        Err(ProtocolError::InvalidMagic { offset: 0, expected: [0x54, 0x55], actual: [0x00, 0x00] })
+    ));
 }
 
 #[test]
+#[rustfmt::skip]
 fn vector_unknown_type() {
     // From SPEC.md Section 7.2
     let bytes: Vec<u8> = vec![
@@ -124,10 +367,11 @@ fn vector_unknown_type() {
     ];
 
     let result = decode(&bytes);
-    assert!(matches!(result, Err(ProtocolError::UnknownType(0x99))));
+    assert!(matches!(result, Err(ProtocolError::UnknownType { offset: 3, actual: 0x99 })));
 }
 
 #[test]
+#[rustfmt::skip]
 fn vector_unsupported_version() {
     let bytes: Vec<u8> = vec![
         0x54, 0x55, // Magic: "TU"
@@ -138,7 +382,50 @@ fn vector_unsupported_version() {
     ];
 
     let result = decode(&bytes);
-    assert!(matches!(result, Err(ProtocolError::UnsupportedVersion(0x99))));
+    assert!(matches!(
+        result,
+        Err(ProtocolError::UnsupportedVersion { offset: 2, expected: 1, actual: 0x99 })
+    ));
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_trailing_bytes() {
+    // From SPEC.md Section 5.2
+    // Minimal request followed by one extra byte
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x01, // ID: 1
+        0x00, 0x00, 0x00, 0x00, // Payload length: 0
+        0x54,       // Trailing byte
+    ];
+
+    assert!(matches!(decode_strict(&bytes), Err(ProtocolError::TrailingBytes(1))));
+    assert_eq!(decode_prefix(&bytes).map(|(_, n)| n), Ok(12));
+}
+
+#[test]
+#[rustfmt::skip]
+fn vector_compressed_payload_too_large() {
+    // From SPEC.md Section 7.2
+    // Compressed payload declaring 2 MiB: rejected before inflating
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x01, // ID: 1
+        0x80, 0x00, 0x00, 0x06, // Payload length: 6, compressed
+        0x00, 0x20, 0x00, 0x00, // Original size: 2 MiB
+        0x03, 0x00, // DEFLATE: empty
+    ];
+
+    let result = decode(&bytes);
+    assert!(matches!(
+        result,
+        Err(ProtocolError::PayloadTooLarge { offset: 8, size: 0x20_0000, max: 0x10_0000 })
+    ));
 }
 
 #[test]
@@ -147,7 +434,15 @@ fn vector_incomplete_message() {
     let bytes: Vec<u8> = vec![0x54, 0x55, 0x01];
 
     let result = decode(&bytes);
-    assert!(matches!(result, Err(ProtocolError::IncompleteMessage)));
+    assert!(matches!(
+        result,
+        Err(ProtocolError::IncompleteMessage {
+            offset: 3,
+            field: Field::Type,
+            expected: 1,
+            actual: 0
+        })
+    ));
 }
 
 // ============================================================================
@@ -208,3 +503,30 @@ fn roundtrip_large_payload() {
     assert_eq!(decoded.payload.len(), 10000);
     assert_eq!(decoded.payload, payload);
 }
+
+// ============================================================================
+// Typed Payload Vectors
+// ============================================================================
+
+#[test]
+#[rustfmt::skip]
+fn vector_typed_payload() {
+    // From SPEC.md Section 3.4
+    // Request carrying ("add", [1, 300]) as (String, Vec<u32>)
+    let bytes: Vec<u8> = vec![
+        0x54, 0x55, // Magic: "TU"
+        0x01,       // Version: 1
+        0x01,       // Type: Request
+        0x00, 0x00, 0x00, 0x07, // ID: 7
+        0x00, 0x00, 0x00, 0x08, // Payload length: 8
+        0x03, 0x61, 0x64, 0x64, // String: len 3, "add"
+        0x02, 0x01, 0xAC, 0x02, // Vec<u32>: count 2, 1, 300
+    ];
+
+    let message = decode(&bytes).expect("Should decode typed request");
+    let (method, args): (String, Vec<u32>) = message.payload_as().expect("Should decode payload");
+
+    assert_eq!(method, "add");
+    assert_eq!(args, vec![1, 300]);
+    assert_eq!(encode(&Message::request_typed(7, &("add", vec![1u32, 300]))).unwrap(), bytes);
+}